@@ -0,0 +1,164 @@
+// Compares the pre-decoded dispatch loop (VM::execute), with and without quickening, against
+// decoding every instruction as it is reached (VM::execute_bytecode), the closure-threaded VM
+// and the register VM on the iterative fib loop. The superinstructions row runs the
+// optimizer's output, where the loop test, decrement and pair of loads are fused. Built with
+// the jit feature, a last row has fib compiled to machine code after its first call.
+//
+//     cargo bench --bench dispatch
+
+use fvm::assembler::assemble_source;
+use fvm::bytecode::Bytecode;
+use fvm::error::VMError;
+use fvm::optimizer::optimize;
+use fvm::regvm::RegisterVM;
+use fvm::threaded::ThreadedVM;
+use fvm::vm::VM;
+use std::io;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 10;
+
+const FIB_LOOP: &str = "
+func fib 1
+pshl arg0
+pshc 2
+sub
+strl arg0
+pshc 0
+strl a
+pshc 1
+strl b
+label loop
+pshl arg0
+pshc 0
+lteq
+jmpt end
+pshl a
+pshl b
+add
+strl temp
+pshl b
+strl a
+pshl temp
+strl b
+pshl arg0
+pshc 1
+sub
+strl arg0
+jump loop
+label end
+pshl b
+endf
+
+main
+pshc 20000
+strg calls
+label again
+pshc 90
+callf fib
+pop
+pshg calls
+pshc 1
+sub
+strg calls
+pshg calls
+pshc 0
+grth
+jmpt again
+";
+
+fn time(bytecode: &Bytecode, execute: fn(&mut VM) -> Result<(), VMError>) -> Duration {
+    let mut vm = VM::new(256);
+    #[cfg(feature = "jit")]
+    vm.set_jit_threshold(None);
+    vm.set_output(Box::new(io::sink()));
+    vm.load_code(bytecode.clone()).unwrap();
+    let start = Instant::now();
+    execute(&mut vm).unwrap();
+    start.elapsed()
+}
+
+fn time_registers(bytecode: &Bytecode) -> Duration {
+    let mut vm = RegisterVM::new();
+    vm.set_output(Box::new(io::sink()));
+    vm.load_code(bytecode.clone()).unwrap();
+    let start = Instant::now();
+    vm.execute().unwrap();
+    start.elapsed()
+}
+
+fn time_closures(bytecode: &Bytecode) -> Duration {
+    let mut vm = ThreadedVM::new(256);
+    vm.set_output(Box::new(io::sink()));
+    vm.load_code(bytecode.clone()).unwrap();
+    let start = Instant::now();
+    vm.execute().unwrap();
+    start.elapsed()
+}
+
+#[cfg(feature = "jit")]
+fn time_jit(bytecode: &Bytecode) -> Duration {
+    let mut vm = VM::new(256);
+    vm.set_jit_threshold(Some(1));
+    vm.set_output(Box::new(io::sink()));
+    vm.load_code(bytecode.clone()).unwrap();
+    let start = Instant::now();
+    vm.execute().unwrap();
+    start.elapsed()
+}
+
+fn main() {
+    let bytecode = assemble_source(FIB_LOOP).unwrap();
+    let fused = optimize(bytecode.clone()).unwrap();
+    let unquickened = |vm: &mut VM| {
+        vm.set_quickening(false);
+        vm.execute()
+    };
+    // Alternate them and keep the best of each, to keep machine noise out of the comparison
+    let mut per_step = Duration::MAX;
+    let mut decoded = Duration::MAX;
+    let mut quickened = Duration::MAX;
+    let mut superinstructions = Duration::MAX;
+    let mut closures = Duration::MAX;
+    let mut registers = Duration::MAX;
+    for _ in 0..RUNS {
+        per_step = per_step.min(time(&bytecode, VM::execute_bytecode));
+        decoded = decoded.min(time(&bytecode, unquickened));
+        quickened = quickened.min(time(&bytecode, VM::execute));
+        superinstructions = superinstructions.min(time(&fused, VM::execute));
+        closures = closures.min(time_closures(&bytecode));
+        registers = registers.min(time_registers(&bytecode));
+    }
+    #[cfg(feature = "jit")]
+    let jit = (0..RUNS).map(|_| time_jit(&bytecode)).min().unwrap();
+    let speedup = |time: Duration| per_step.as_secs_f64() / time.as_secs_f64();
+    println!("fib loop, best of {} runs", RUNS);
+    println!("  decode per step: {:>10.3?}", per_step);
+    println!(
+        "  pre-decoded:     {:>10.3?}  {:.2}x",
+        decoded,
+        speedup(decoded)
+    );
+    println!(
+        "  quickened:       {:>10.3?}  {:.2}x",
+        quickened,
+        speedup(quickened)
+    );
+    println!(
+        "  superinstrs:     {:>10.3?}  {:.2}x",
+        superinstructions,
+        speedup(superinstructions)
+    );
+    println!(
+        "  closures:        {:>10.3?}  {:.2}x",
+        closures,
+        speedup(closures)
+    );
+    println!(
+        "  register:        {:>10.3?}  {:.2}x",
+        registers,
+        speedup(registers)
+    );
+    #[cfg(feature = "jit")]
+    println!("  jit:             {:>10.3?}  {:.2}x", jit, speedup(jit));
+}
@@ -0,0 +1,86 @@
+// Compares the Value enum against the NaN-boxed PackedValue as stack storage: copying a
+// working set of mixed values around, and packing values on and off the stack. For the
+// effect on whole programs compare
+//
+//     cargo bench --bench dispatch
+//     cargo bench --bench dispatch --features nanbox
+
+use fvm::packed::PackedValue;
+use fvm::value::{HeapString, Value};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 10;
+const SLOTS: usize = 1024;
+const ROUNDS: usize = 2000;
+
+fn working_set() -> Vec<Value> {
+    (0..SLOTS)
+        .map(|i| match i % 8 {
+            0 => Value::Float(i as f64 * 0.5),
+            1 => Value::Bool(i % 3 == 0),
+            2 => Value::String(HeapString::new(format!("s{}", i))),
+            3 => Value::new_array(vec![Value::Int(i as i64)]),
+            _ => Value::Int(i as i64),
+        })
+        .collect()
+}
+
+fn best(mut run: impl FnMut()) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        run();
+        best = best.min(start.elapsed());
+    }
+    best
+}
+
+// Every slot is cloned out and written back one place along, like locals being pushed and
+// stored
+fn shuffle<T: Clone>(slots: &mut [T]) {
+    for _ in 0..ROUNDS {
+        for i in 1..slots.len() {
+            slots[i - 1] = black_box(slots[i].clone());
+        }
+        slots.rotate_left(1);
+    }
+}
+
+fn main() {
+    let mut values = working_set();
+    let mut packed: Vec<PackedValue> = working_set().into_iter().map(PackedValue::from).collect();
+    let enum_shuffle = best(|| shuffle(&mut values));
+    let packed_shuffle = best(|| shuffle(&mut packed));
+
+    let source = working_set();
+    let round_trip = best(|| {
+        for _ in 0..ROUNDS {
+            for val in &source {
+                black_box(Value::from(PackedValue::from(val.clone())));
+            }
+        }
+    });
+    let clone_only = best(|| {
+        for _ in 0..ROUNDS {
+            for val in &source {
+                black_box(val.clone());
+            }
+        }
+    });
+
+    println!(
+        "slot size: Value {} bytes, PackedValue {} bytes",
+        size_of::<Value>(),
+        size_of::<PackedValue>()
+    );
+    println!(
+        "copying {} slots x {} rounds, best of {}",
+        SLOTS, ROUNDS, RUNS
+    );
+    println!("  Value:       {:>10.3?}", enum_shuffle);
+    println!("  PackedValue: {:>10.3?}", packed_shuffle);
+    println!("push/pop conversion, same values");
+    println!("  clone only:  {:>10.3?}", clone_only);
+    println!("  pack+unpack: {:>10.3?}", round_trip);
+}
@@ -0,0 +1,609 @@
+// Simplification and differentiation of expression trees.
+//
+// Simplifying gathers a sum into terms and each term into a numeric coefficient times powers
+// of distinct bases. Numbers fold, like terms and like factors combine (x + x is 2*x, x*x is
+// x^2), terms with a zero coefficient and factors with a zero exponent drop out, and a
+// coefficient of one isn't written. Products aren't expanded, but a multiple of a sum inside a
+// sum is distributed so that it can cancel. Factors are kept in a fixed order, so x*y and y*x
+// are the same term.
+
+use crate::error::VMError;
+use crate::expr::{BinOp, Expr};
+use crate::math::MathFn;
+use crate::ops;
+use crate::value::{HeapExpr, Value};
+use num_complex::Complex64;
+use std::rc::Rc;
+
+fn num(val: Value) -> HeapExpr {
+    Rc::new(Expr::Num(val))
+}
+fn int(v: i64) -> HeapExpr {
+    num(Value::Int(v))
+}
+fn binary(op: BinOp, l: HeapExpr, r: HeapExpr) -> HeapExpr {
+    Rc::new(Expr::Binary(op, l, r))
+}
+fn call(f: MathFn, arg: &HeapExpr) -> HeapExpr {
+    Rc::new(Expr::Apply(f, vec![arg.clone()]))
+}
+// base^exp, or just base for an exponent of one
+fn power(base: HeapExpr, exp: HeapExpr) -> HeapExpr {
+    match number(&exp) {
+        Some(n) if is_one(n) => base,
+        _ => Rc::new(Expr::Apply(MathFn::Pow, vec![base, exp])),
+    }
+}
+fn product(factors: Vec<HeapExpr>) -> Option<HeapExpr> {
+    factors.into_iter().reduce(|l, r| binary(BinOp::Mul, l, r))
+}
+
+fn number(e: &Expr) -> Option<&Value> {
+    match e {
+        Expr::Num(val) => Some(val),
+        _ => None,
+    }
+}
+fn powers(e: &Expr) -> Option<(&HeapExpr, &HeapExpr)> {
+    match e {
+        Expr::Apply(MathFn::Pow, args) if let [base, exp] = &args[..] => Some((base, exp)),
+        _ => None,
+    }
+}
+fn is_sum(e: &Expr) -> bool {
+    matches!(e, Expr::Binary(BinOp::Add | BinOp::Sub, _, _))
+}
+
+fn is_exact(val: &Value) -> bool {
+    matches!(val, Value::Int(_) | Value::BigInt(_) | Value::Rational(_))
+}
+fn is_integer(val: &Value) -> bool {
+    matches!(val, Value::Int(_) | Value::BigInt(_))
+}
+fn is_zero(val: &Value) -> bool {
+    val.as_complex() == Some(Complex64::ZERO)
+}
+fn is_one(val: &Value) -> bool {
+    val.as_complex() == Some(Complex64::ONE)
+}
+// Real and below zero
+fn is_negative(val: &Value) -> bool {
+    val.as_f64().is_some_and(|v| v < 0.0)
+}
+
+// The VM's arithmetic on borrowed numbers
+fn arith(op: ops::BinaryOp, l: &Value, r: &Value) -> Result<Value, VMError> {
+    op(l.clone(), r.clone())
+}
+fn negate(val: &Value) -> Result<Value, VMError> {
+    arith(ops::mul, &Value::Int(-1), val)
+}
+
+// A product as a coefficient and powers of distinct bases
+#[derive(PartialEq)]
+struct Product {
+    coeff: Value,
+    factors: Vec<(HeapExpr, HeapExpr)>, // base, exponent
+}
+
+impl Product {
+    fn of(e: &HeapExpr) -> Result<Product, VMError> {
+        let mut product = Product {
+            coeff: Value::Int(1),
+            factors: Vec::new(),
+        };
+        product.collect(e, &Value::Int(1))?;
+        product
+            .factors
+            .retain(|(_, exp)| !number(exp).is_some_and(is_zero));
+        product
+            .factors
+            .sort_by_cached_key(|(base, _)| base.to_string());
+        Ok(product)
+    }
+
+    // Multiply in e raised to the integer power exp
+    fn collect(&mut self, e: &HeapExpr, exp: &Value) -> Result<(), VMError> {
+        match &**e {
+            Expr::Binary(BinOp::Mul, l, r) => {
+                self.collect(l, exp)?;
+                self.collect(r, exp)
+            }
+            Expr::Binary(BinOp::Div, l, r) => {
+                self.collect(l, exp)?;
+                self.collect(r, &negate(exp)?)
+            }
+            _ if let Some((base, n)) = powers(e) => {
+                let n = n.simplify()?;
+                match number(&n) {
+                    // (x^a)^n is x^(a*n) for integer n, but (x^2)^(1/2) isn't x
+                    Some(n) if is_integer(n) => self.collect(base, &arith(ops::mul, exp, n)?),
+                    _ => {
+                        let n = scale(&n, exp)?;
+                        self.push(base.simplify()?, n)
+                    }
+                }
+            }
+            _ => {
+                let simple = e.simplify()?;
+                match &*simple {
+                    Expr::Binary(BinOp::Mul | BinOp::Div, _, _) => self.collect(&simple, exp),
+                    _ if powers(&simple).is_some() => self.collect(&simple, exp),
+                    _ => self.push(simple, num(exp.clone())),
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, base: HeapExpr, exp: HeapExpr) -> Result<(), VMError> {
+        // Numbers fold, except an exact root like 2^(1/2), which would lose exactness
+        if let (Some(b), Some(n)) = (number(&base), number(&exp))
+            && (is_integer(n) || !is_exact(b) || !is_exact(n))
+        {
+            self.coeff = arith(ops::mul, &self.coeff, &arith(ops::pow, b, n)?)?;
+            return Ok(());
+        }
+        match self.factors.iter_mut().find(|(b, _)| *b == base) {
+            Some((_, sum)) => *sum = add(sum, &exp)?,
+            None => self.factors.push((base, exp)),
+        }
+        Ok(())
+    }
+
+    fn build(self) -> HeapExpr {
+        if is_zero(&self.coeff) {
+            return int(0);
+        }
+        if self.factors.is_empty() {
+            return num(self.coeff);
+        }
+        let mut numer: Vec<HeapExpr> = Vec::new();
+        let mut denom: Vec<HeapExpr> = Vec::new();
+        // A fraction's denominator goes below the line, so 1/2*x is x/2
+        let top = match &self.coeff {
+            Value::Rational(r) => {
+                denom.push(num(Value::from_bigint(r.denom().clone())));
+                Value::from_bigint(r.numer().clone())
+            }
+            coeff => coeff.clone(),
+        };
+        for (base, exp) in self.factors {
+            match number(&exp) {
+                Some(n) if is_negative(n) => {
+                    let n = negate(n).expect("a negative number negates");
+                    denom.push(power(base, num(n)));
+                }
+                _ => numer.push(power(base, exp)),
+            }
+        }
+        if !is_one(&top) || numer.is_empty() {
+            numer.insert(0, num(top));
+        }
+        let numer = product(numer).expect("holds the coefficient if nothing else");
+        match product(denom) {
+            Some(denom) => binary(BinOp::Div, numer, denom),
+            None => numer,
+        }
+    }
+}
+
+// e1 + e2 for exponents, which are often both numbers
+fn add(e1: &HeapExpr, e2: &HeapExpr) -> Result<HeapExpr, VMError> {
+    match (number(e1), number(e2)) {
+        (Some(l), Some(r)) => Ok(num(arith(ops::add, l, r)?)),
+        _ => binary(BinOp::Add, e1.clone(), e2.clone()).simplify(),
+    }
+}
+
+// e*k for an exponent e and number k
+fn scale(e: &HeapExpr, k: &Value) -> Result<HeapExpr, VMError> {
+    match number(e) {
+        Some(n) => Ok(num(arith(ops::mul, n, k)?)),
+        None if is_one(k) => Ok(e.clone()),
+        None => binary(BinOp::Mul, num(k.clone()), e.clone()).simplify(),
+    }
+}
+
+// A sum as terms with distinct products of powers, each with its own coefficient
+struct Sum {
+    terms: Vec<Product>,
+}
+
+impl Sum {
+    // Add in e times the number coeff
+    fn collect(&mut self, e: &HeapExpr, coeff: &Value) -> Result<(), VMError> {
+        match &**e {
+            Expr::Binary(BinOp::Add, l, r) => {
+                self.collect(l, coeff)?;
+                self.collect(r, coeff)
+            }
+            Expr::Binary(BinOp::Sub, l, r) => {
+                self.collect(l, coeff)?;
+                self.collect(r, &negate(coeff)?)
+            }
+            _ => {
+                let simple = e.simplify()?;
+                if is_sum(&simple) {
+                    return self.collect(&simple, coeff);
+                }
+                let mut term = Product::of(&simple)?;
+                term.coeff = arith(ops::mul, &term.coeff, coeff)?;
+                // A multiple of a sum is distributed, so 2*(x + 1) - 2*x cancels
+                if let [(base, exp)] = &term.factors[..]
+                    && number(exp).is_some_and(is_one)
+                    && is_sum(base)
+                {
+                    return self.collect(&base.clone(), &term.coeff);
+                }
+                match self.terms.iter_mut().find(|t| t.factors == term.factors) {
+                    Some(like) => like.coeff = arith(ops::add, &like.coeff, &term.coeff)?,
+                    None => self.terms.push(term),
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn build(mut self) -> Result<HeapExpr, VMError> {
+        self.terms.retain(|term| !is_zero(&term.coeff));
+        // The constant goes last, as in x + 1, but a positive term leads, as in 1 - x^2
+        self.terms.sort_by_key(|term| term.factors.is_empty());
+        if let Some(idx) = self.terms.iter().position(|term| !is_negative(&term.coeff)) {
+            self.terms[..=idx].rotate_right(1);
+        }
+        let mut sum: Option<HeapExpr> = None;
+        for mut term in self.terms {
+            sum = Some(match sum {
+                None => term.build(),
+                Some(sum) if is_negative(&term.coeff) => {
+                    term.coeff = negate(&term.coeff)?;
+                    binary(BinOp::Sub, sum, term.build())
+                }
+                Some(sum) => binary(BinOp::Add, sum, term.build()),
+            });
+        }
+        Ok(sum.unwrap_or_else(|| int(0)))
+    }
+}
+
+fn simplify_apply(f: MathFn, args: Vec<HeapExpr>) -> Result<HeapExpr, VMError> {
+    let numbers: Option<Vec<Value>> = args.iter().map(|a| number(a).cloned()).collect();
+    if let Some(numbers) = numbers {
+        let val = f.apply(&numbers)?;
+        // sqrt(2) stays as it is rather than becoming a Float
+        if is_exact(&val) || !numbers.iter().all(is_exact) {
+            return Ok(num(val));
+        }
+    }
+    let exact =
+        |e: &Expr, test: fn(&Value) -> bool| number(e).is_some_and(|v| is_exact(v) && test(v));
+    match (f, &args[..]) {
+        (MathFn::Exp, [u]) if exact(u, is_zero) => Ok(int(1)),
+        (MathFn::Ln, [u]) if exact(u, is_one) => Ok(int(0)),
+        (MathFn::Exp, [u]) | (MathFn::Ln, [u])
+            if let Expr::Apply(inner, inner_args) = &**u
+                && let [v] = &inner_args[..]
+                && matches!(
+                    (f, inner),
+                    (MathFn::Exp, MathFn::Ln) | (MathFn::Ln, MathFn::Exp)
+                ) =>
+        {
+            Ok(v.clone())
+        }
+        _ => Ok(Rc::new(Expr::Apply(f, args))),
+    }
+}
+
+impl Expr {
+    pub fn simplify(self: &Rc<Self>) -> Result<HeapExpr, VMError> {
+        match &**self {
+            Expr::Num(_) | Expr::Symbol(_) => Ok(self.clone()),
+            _ if is_sum(self) => {
+                let mut sum = Sum { terms: Vec::new() };
+                sum.collect(self, &Value::Int(1))?;
+                sum.build()
+            }
+            Expr::Binary(..) => Ok(Product::of(self)?.build()),
+            _ if powers(self).is_some() => Ok(Product::of(self)?.build()),
+            Expr::Apply(f, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| a.simplify())
+                    .collect::<Result<Vec<HeapExpr>, VMError>>()?;
+                simplify_apply(*f, args)
+            }
+        }
+    }
+
+    // The simplified derivative with respect to the symbol name
+    pub fn differentiate(self: &Rc<Self>, name: &str) -> Result<HeapExpr, VMError> {
+        derivative(self, name)?.simplify()
+    }
+}
+
+fn derivative(e: &HeapExpr, name: &str) -> Result<HeapExpr, VMError> {
+    if !e.contains(name) {
+        return Ok(int(0));
+    }
+    let d = |e: &HeapExpr| derivative(e, name);
+    let derivative = match &**e {
+        // Only the symbol itself contains it
+        Expr::Num(_) | Expr::Symbol(_) => int(1),
+        Expr::Binary(op @ (BinOp::Add | BinOp::Sub), l, r) => binary(*op, d(l)?, d(r)?),
+        // (uv)' = u'v + uv'
+        Expr::Binary(BinOp::Mul, l, r) => binary(
+            BinOp::Add,
+            binary(BinOp::Mul, d(l)?, r.clone()),
+            binary(BinOp::Mul, l.clone(), d(r)?),
+        ),
+        // (u/v)' = (u'v - uv')/v^2
+        Expr::Binary(BinOp::Div, l, r) => binary(
+            BinOp::Div,
+            binary(
+                BinOp::Sub,
+                binary(BinOp::Mul, d(l)?, r.clone()),
+                binary(BinOp::Mul, l.clone(), d(r)?),
+            ),
+            power(r.clone(), int(2)),
+        ),
+        _ if let Some((base, exp)) = powers(e) => {
+            if !exp.contains(name) {
+                // (u^n)' = n*u^(n - 1)*u'
+                let n_minus_one = binary(BinOp::Sub, exp.clone(), int(1));
+                let factors = vec![exp.clone(), power(base.clone(), n_minus_one), d(base)?];
+                product(factors).expect("three factors")
+            } else if !base.contains(name) {
+                // (a^v)' = a^v*ln(a)*v'
+                product(vec![e.clone(), call(MathFn::Ln, base), d(exp)?]).expect("three factors")
+            } else {
+                // (u^v)' = u^v*(v'*ln(u) + v*u'/u)
+                let by_exp = binary(BinOp::Mul, d(exp)?, call(MathFn::Ln, base));
+                let by_base = binary(
+                    BinOp::Div,
+                    binary(BinOp::Mul, exp.clone(), d(base)?),
+                    base.clone(),
+                );
+                binary(BinOp::Mul, e.clone(), binary(BinOp::Add, by_exp, by_base))
+            }
+        }
+        // atan2(y, x)' = (x*y' - y*x')/(x^2 + y^2)
+        Expr::Apply(MathFn::Atan2, args) if let [y, x] = &args[..] => binary(
+            BinOp::Div,
+            binary(
+                BinOp::Sub,
+                binary(BinOp::Mul, x.clone(), d(y)?),
+                binary(BinOp::Mul, y.clone(), d(x)?),
+            ),
+            binary(
+                BinOp::Add,
+                power(x.clone(), int(2)),
+                power(y.clone(), int(2)),
+            ),
+        ),
+        // hypot(x, y)' = (x*x' + y*y')/hypot(x, y)
+        Expr::Apply(MathFn::Hypot, args) if let [x, y] = &args[..] => binary(
+            BinOp::Div,
+            binary(
+                BinOp::Add,
+                binary(BinOp::Mul, x.clone(), d(x)?),
+                binary(BinOp::Mul, y.clone(), d(y)?),
+            ),
+            e.clone(),
+        ),
+        // Chain rule: f(u)' = f'(u)*u'
+        Expr::Apply(f, args) if let [u] = &args[..] => {
+            binary(BinOp::Mul, outer_derivative(*f, u)?, d(u)?)
+        }
+        Expr::Apply(f, _) => return Err(VMError::NotDifferentiable(*f)),
+    };
+    Ok(derivative)
+}
+
+// f'(u)
+fn outer_derivative(f: MathFn, u: &HeapExpr) -> Result<HeapExpr, VMError> {
+    let recip = |e: HeapExpr| binary(BinOp::Div, int(1), e);
+    let squared = |e: HeapExpr| power(e, int(2));
+    // sqrt(1 - u^2)
+    let cofunction = || {
+        let difference = binary(BinOp::Sub, int(1), squared(u.clone()));
+        call(MathFn::Sqrt, &difference)
+    };
+    // 1/(u*ln(base))
+    let log = |base: i64| recip(binary(BinOp::Mul, u.clone(), call(MathFn::Ln, &int(base))));
+    let derivative = match f {
+        // 1/(2*sqrt(u))
+        MathFn::Sqrt => recip(binary(BinOp::Mul, int(2), call(MathFn::Sqrt, u))),
+        // 1/(3*cbrt(u)^2)
+        MathFn::Cbrt => recip(binary(BinOp::Mul, int(3), squared(call(MathFn::Cbrt, u)))),
+        MathFn::Exp => call(MathFn::Exp, u),
+        MathFn::Ln => recip(u.clone()),
+        MathFn::Log10 => log(10),
+        MathFn::Log2 => log(2),
+        // u/|u|, for real u
+        MathFn::Abs => binary(BinOp::Div, u.clone(), call(MathFn::Abs, u)),
+        MathFn::Sin => call(MathFn::Cos, u),
+        MathFn::Cos => binary(BinOp::Mul, int(-1), call(MathFn::Sin, u)),
+        MathFn::Tan => recip(squared(call(MathFn::Cos, u))),
+        MathFn::Asin => recip(cofunction()),
+        MathFn::Acos => binary(BinOp::Div, int(-1), cofunction()),
+        MathFn::Atan => recip(binary(BinOp::Add, squared(u.clone()), int(1))),
+        MathFn::Sinh => call(MathFn::Cosh, u),
+        MathFn::Cosh => call(MathFn::Sinh, u),
+        MathFn::Tanh => recip(squared(call(MathFn::Cosh, u))),
+        _ => return Err(VMError::NotDifferentiable(f)),
+    };
+    Ok(derivative)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::expr;
+
+    fn x() -> Value {
+        Expr::symbol("x")
+    }
+    fn y() -> Value {
+        Expr::symbol("y")
+    }
+    fn add(l: Value, r: Value) -> Value {
+        ops::add(l, r).unwrap()
+    }
+    fn sub(l: Value, r: Value) -> Value {
+        ops::sub(l, r).unwrap()
+    }
+    fn mul(l: Value, r: Value) -> Value {
+        ops::mul(l, r).unwrap()
+    }
+    fn div(l: Value, r: Value) -> Value {
+        ops::div(l, r).unwrap()
+    }
+    fn pow(base: Value, exp: Value) -> Value {
+        MathFn::Pow.apply(&[base, exp]).unwrap()
+    }
+    fn call(f: MathFn, arg: Value) -> Value {
+        f.apply(&[arg]).unwrap()
+    }
+    // f(val) as a tree, as substituting a number for x in f(x) leaves it
+    fn unevaluated(f: MathFn, args: &[Value]) -> Value {
+        let args = args
+            .iter()
+            .map(|val| Rc::new(Expr::Num(val.clone())))
+            .collect();
+        Value::Expr(Rc::new(Expr::Apply(f, args)))
+    }
+    fn int(v: i64) -> Value {
+        Value::Int(v)
+    }
+    fn half() -> Value {
+        Value::from_rational("1/2".parse().unwrap())
+    }
+    fn show(val: Value) -> String {
+        match val {
+            Value::Expr(e) => e.to_string(),
+            val => format!("{:?}", val),
+        }
+    }
+    fn simplify(val: Value) -> String {
+        show(expr::simplify(val).unwrap())
+    }
+    fn dx(val: Value) -> String {
+        show(expr::differentiate(val, &Value::String(Rc::new("x".to_string()))).unwrap())
+    }
+
+    #[test]
+    fn identities() {
+        assert_eq!(simplify(add(x(), int(0))), "x");
+        assert_eq!(simplify(mul(x(), int(1))), "x");
+        assert_eq!(simplify(mul(int(0), x())), "Int(0)");
+        assert_eq!(simplify(pow(x(), int(1))), "x");
+        assert_eq!(simplify(pow(x(), int(0))), "Int(1)");
+        assert_eq!(simplify(div(x(), x())), "Int(1)");
+        assert_eq!(simplify(call(MathFn::Exp, call(MathFn::Ln, x()))), "x");
+        assert_eq!(simplify(unevaluated(MathFn::Ln, &[int(1)])), "Int(0)");
+        assert_eq!(simplify(unevaluated(MathFn::Exp, &[int(0)])), "Int(1)");
+    }
+
+    #[test]
+    fn constant_folding() {
+        assert_eq!(simplify(add(add(int(1), x()), int(2))), "x + 3");
+        assert_eq!(simplify(mul(mul(int(2), x()), int(3))), "6*x");
+        assert_eq!(simplify(div(x(), int(2))), "x/2");
+        assert_eq!(simplify(pow(int(2), int(10))), "Int(1024)");
+        // Exact roots aren't turned into Floats, inexact ones are folded
+        assert_eq!(simplify(unevaluated(MathFn::Sqrt, &[int(2)])), "sqrt(2)");
+        assert_eq!(
+            simplify(unevaluated(MathFn::Pow, &[int(2), half()])),
+            "2^(1/2)"
+        );
+        let root = unevaluated(MathFn::Sqrt, &[Value::Float(2.25)]);
+        assert_eq!(simplify(root), "Float(1.5)");
+    }
+
+    #[test]
+    fn like_terms() {
+        assert_eq!(simplify(add(x(), x())), "2*x");
+        assert_eq!(simplify(add(mul(int(2), x()), mul(int(3), x()))), "5*x");
+        assert_eq!(simplify(sub(x(), x())), "Int(0)");
+        assert_eq!(simplify(sub(mul(x(), y()), mul(y(), x()))), "Int(0)");
+        // 2*(x + 1) - 2*x
+        let twice = mul(int(2), add(x(), int(1)));
+        assert_eq!(simplify(sub(twice, mul(int(2), x()))), "Int(2)");
+        assert_eq!(simplify(sub(add(x(), y()), mul(int(3), y()))), "x - 2*y");
+    }
+
+    #[test]
+    fn power_rules() {
+        assert_eq!(simplify(mul(x(), x())), "x^2");
+        assert_eq!(simplify(mul(pow(x(), int(2)), pow(x(), int(3)))), "x^5");
+        assert_eq!(simplify(pow(pow(x(), int(2)), int(3))), "x^6");
+        assert_eq!(simplify(div(pow(x(), int(2)), pow(x(), int(5)))), "1/x^3");
+        assert_eq!(simplify(pow(mul(x(), y()), int(2))), "x^2*y^2");
+        assert_eq!(simplify(mul(pow(x(), y()), x())), "x^(y + 1)");
+        // Only integer powers of powers combine
+        assert_eq!(simplify(pow(pow(x(), half()), int(2))), "x");
+        assert_eq!(simplify(pow(pow(x(), int(2)), half())), "(x^2)^(1/2)");
+    }
+
+    #[test]
+    fn textbook_derivatives() {
+        assert_eq!(dx(int(7)), "Int(0)");
+        assert_eq!(dx(x()), "Int(1)");
+        assert_eq!(dx(y()), "Int(0)");
+        assert_eq!(dx(mul(x(), y())), "y");
+        assert_eq!(dx(pow(x(), int(3))), "3*x^2");
+        // x^2 + 3x + 1
+        let quadratic = add(add(pow(x(), int(2)), mul(int(3), x())), int(1));
+        assert_eq!(dx(quadratic), "2*x + 3");
+        assert_eq!(dx(div(int(1), x())), "-1/x^2");
+        assert_eq!(dx(pow(x(), half())), "1/(2*x^(1/2))");
+        assert_eq!(dx(call(MathFn::Sqrt, x())), "1/(2*sqrt(x))");
+        assert_eq!(dx(call(MathFn::Exp, x())), "exp(x)");
+        assert_eq!(dx(call(MathFn::Ln, x())), "1/x");
+        assert_eq!(dx(pow(int(2), x())), "2^x*ln(2)");
+        assert_eq!(dx(pow(x(), x())), "(ln(x) + 1)*x^x");
+    }
+
+    #[test]
+    fn product_quotient_and_chain_rules() {
+        assert_eq!(dx(mul(x(), call(MathFn::Exp, x()))), "exp(x) + exp(x)*x");
+        assert_eq!(dx(div(x(), add(x(), int(1)))), "1/(x + 1)^2");
+        // (x^2 + 1)^3
+        let cubed = pow(add(pow(x(), int(2)), int(1)), int(3));
+        assert_eq!(dx(cubed), "6*x*(x^2 + 1)^2");
+        assert_eq!(dx(call(MathFn::Exp, mul(int(2), x()))), "2*exp(2*x)");
+        assert_eq!(dx(call(MathFn::Ln, pow(x(), int(2)))), "2/x");
+        let root = call(MathFn::Sqrt, add(pow(x(), int(2)), int(1)));
+        assert_eq!(dx(root), "x/sqrt(x^2 + 1)");
+        assert!(matches!(
+            expr::differentiate(call(MathFn::Arg, x()), &Value::String(Rc::new("x".into()))),
+            Err(VMError::NotDifferentiable(MathFn::Arg))
+        ));
+    }
+
+    #[test]
+    fn elementary_function_derivatives() {
+        assert_eq!(dx(call(MathFn::Sin, x())), "cos(x)");
+        assert_eq!(dx(call(MathFn::Cos, x())), "-1*sin(x)");
+        assert_eq!(dx(call(MathFn::Tan, x())), "1/cos(x)^2");
+        assert_eq!(dx(call(MathFn::Asin, x())), "1/sqrt(1 - x^2)");
+        assert_eq!(dx(call(MathFn::Acos, x())), "-1/sqrt(1 - x^2)");
+        assert_eq!(dx(call(MathFn::Atan, x())), "1/(x^2 + 1)");
+        assert_eq!(dx(call(MathFn::Tanh, x())), "1/cosh(x)^2");
+        assert_eq!(dx(call(MathFn::Log10, x())), "1/(ln(10)*x)");
+        assert_eq!(dx(call(MathFn::Cbrt, x())), "1/(3*cbrt(x)^2)");
+        assert_eq!(dx(call(MathFn::Sin, pow(x(), int(2)))), "2*cos(x^2)*x");
+        let hypot = MathFn::Hypot.apply(&[x(), int(3)]).unwrap();
+        assert_eq!(dx(hypot), "x/hypot(x, 3)");
+        let angle = MathFn::Atan2.apply(&[int(1), x()]).unwrap();
+        assert_eq!(dx(angle), "-1/(x^2 + 1)");
+        assert!(matches!(
+            expr::differentiate(
+                call(MathFn::Floor, x()),
+                &Value::String(Rc::new("x".into()))
+            ),
+            Err(VMError::NotDifferentiable(MathFn::Floor))
+        ));
+    }
+}
@@ -1,14 +1,35 @@
+use crate::bytecode::Bytecode;
+use crate::constexpr::eval_const_expr;
 use crate::error::AssemblerError;
 use crate::function::Function;
+use crate::math::{MathFn, Rounding};
 use crate::opcode::OpCode;
+use crate::optimizer::optimize;
+use crate::preprocessor::{SourceLine, SourceLoc, preprocess, preprocess_file};
 use crate::value::{HeapString, Value};
+use num_bigint::BigInt;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
 
 struct FixLabel {
     offset: usize,
     label: String,
+    function: Option<String>,
+    line: SourceLoc,
+}
+
+// Labels are keyed by `function.name` when function scoped, or by `name` at file level
+struct Label {
+    address: u32,
+    function: Option<String>,
+}
+
+// Function and global operands referenced before their definition, patched after the main pass
+struct FixSymbol {
+    offset: usize,
+    name: String,
+    line: SourceLoc,
 }
 
 struct CurFunc {
@@ -17,19 +38,127 @@ struct CurFunc {
     done: bool,
 }
 
-pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), AssemblerError> {
-    let file = File::open("program.fasm")?;
-    let reader = BufReader::new(file);
-    let mut linenum = 0;
+// The mnemonics and directives assemble_lines reads, besides the math functions
+const KEYWORDS: &[&str] = &[
+    "main",
+    "add",
+    "sub",
+    "mul",
+    "div",
+    "divi",
+    "mod",
+    "divx",
+    "wadd",
+    "wsub",
+    "wmul",
+    "cadd",
+    "csub",
+    "cmul",
+    "sadd",
+    "ssub",
+    "smul",
+    "pshc",
+    "pshi",
+    "const",
+    "pshl",
+    "strl",
+    "strk",
+    "strg",
+    "pshg",
+    "pop",
+    "box",
+    "unbox",
+    "setbox",
+    "array",
+    "arrayset",
+    "arrayget",
+    "arraypush",
+    "arraypop",
+    "arraylen",
+    "label",
+    "jump",
+    "jmpf",
+    "jmpt",
+    "equl",
+    "nteq",
+    "lsth",
+    "grth",
+    "gteq",
+    "lteq",
+    "not",
+    "and",
+    "or",
+    "ideq",
+    "band",
+    "bor",
+    "bxor",
+    "bnot",
+    "shl",
+    "shr",
+    "ushr",
+    "rotl",
+    "rotr",
+    "popc",
+    "clz",
+    "ctz",
+    "func",
+    "endf",
+    "callf",
+    "prnt",
+    "sym",
+    "subs",
+    "eval",
+    "exeq",
+    "simp",
+    "diff",
+    "toint",
+    "toflt",
+];
+
+// Whether a name is read as an instruction or directive
+pub fn is_keyword(name: &str) -> bool {
+    KEYWORDS.contains(&name) || MathFn::from_mnemonic(name).is_some()
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct AssemblerOptions {
+    // Directories searched, in order, for include files not found next to the including file
+    pub include_paths: Vec<PathBuf>,
+    // Run the peephole optimizer over the assembled bytecode
+    pub optimize: bool,
+}
+
+pub fn assemble() -> Result<Bytecode, AssemblerError> {
+    assemble_file(Path::new("program.fasm"), &AssemblerOptions::default())
+}
+
+pub fn assemble_file(path: &Path, options: &AssemblerOptions) -> Result<Bytecode, AssemblerError> {
+    let lines = preprocess_file(path, &options.include_paths)?;
+    let bytecode = assemble_lines(lines)?;
+    if options.optimize {
+        return Ok(optimize(bytecode)?);
+    }
+    Ok(bytecode)
+}
+
+pub fn assemble_source(source: &str) -> Result<Bytecode, AssemblerError> {
+    let lines = preprocess(source, &[])?;
+    assemble_lines(lines)
+}
+
+fn assemble_lines(lines: Vec<SourceLine>) -> Result<Bytecode, AssemblerError> {
     // Vectors for binary format
     let mut bin_vec: Vec<u8> = Vec::new();
     let mut consts: Vec<Value> = Vec::new();
     let mut functions: Vec<Function> = Vec::new();
 
     let mut globals_names: HashMap<String, u16> = HashMap::new();
-    let mut labels: HashMap<String, u32> = HashMap::new();
+    let mut labels: HashMap<String, Label> = HashMap::new();
     let mut fix_labels: Vec<FixLabel> = Vec::new();
+    let mut fix_functions: Vec<FixSymbol> = Vec::new();
+    let mut fix_globals: Vec<FixSymbol> = Vec::new();
     let mut func_names: HashMap<String, usize> = HashMap::new();
+    let mut constants: HashMap<String, Value> = HashMap::new();
     let mut entry = 0;
     let mut current_function: CurFunc = CurFunc {
         name: "".to_string(),
@@ -37,10 +166,9 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
         done: true,
     };
 
-    for line in reader.lines() {
-        linenum += 1;
-        let line = line?;
-        let data: Vec<&str> = line.split(" ").collect();
+    for line in lines {
+        let linenum = line.loc;
+        let data: Vec<&str> = line.text.split(" ").collect();
         let op = data[0];
         match op {
             "main" => {
@@ -89,34 +217,165 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                 }
                 bin_vec.push(OpCode::DivInt as u8);
             }
-            "pshc" => {
-                if data.len() != 2 {
+            "mod" => {
+                if data.len() > 1 {
                     return Err(AssemblerError::InvalidArgument(
-                        "Expected one argument".to_string(),
+                        "Expected zero arguments".to_string(),
                     ));
                 }
-                let arg = parse_literal(data[1], linenum);
-                let idx: u16;
-                match arg {
-                    Ok(val) => {
-                        let index = consts.iter().position(|x| *x == val);
-                        match index {
-                            Some(val) => {
-                                idx = val as u16;
-                            }
-                            None => {
-                                consts.push(val);
-                                idx = (consts.len() - 1) as u16;
-                            }
-                        }
-                    }
-                    Err(e) => return Err(e),
+                bin_vec.push(OpCode::Mod as u8);
+            }
+            "divx" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::DivExact as u8);
+            }
+            "wadd" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::AddWrap as u8);
+            }
+            "wsub" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::SubWrap as u8);
+            }
+            "wmul" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
                 }
+                bin_vec.push(OpCode::MulWrap as u8);
+            }
+            "cadd" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::AddChecked as u8);
+            }
+            "csub" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::SubChecked as u8);
+            }
+            "cmul" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::MulChecked as u8);
+            }
+            "sadd" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::AddSat as u8);
+            }
+            "ssub" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::SubSat as u8);
+            }
+            "smul" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::MulSat as u8);
+            }
+            "pshc" => {
+                if data.len() < 2 {
+                    return Err(AssemblerError::InvalidArgument(format!(
+                        "Expected one argument at line: {}",
+                        linenum
+                    )));
+                }
+                let operand = data[1..].join(" ");
+                let val = match parse_literal(&operand, &linenum) {
+                    // A lone name that isn't a constant is pushed as an identifier, as it was
+                    // before constants existed
+                    Ok(Value::Ident(name)) if data.len() == 2 && !constants.contains_key(&name) => {
+                        Value::Ident(name)
+                    }
+                    _ => eval_const_expr(&operand, &constants, &linenum)?,
+                };
+                let idx = match consts.iter().position(|x| *x == val) {
+                    Some(val) => val as u16,
+                    None => {
+                        consts.push(val);
+                        (consts.len() - 1) as u16
+                    }
+                };
                 let final_arg = idx.to_le_bytes();
                 bin_vec.push(OpCode::PushConst as u8);
                 bin_vec.push(final_arg[0]);
                 bin_vec.push(final_arg[1]);
             }
+            "pshi" => {
+                if data.len() < 2 {
+                    return Err(AssemblerError::InvalidArgument(format!(
+                        "Expected one argument at line: {}",
+                        linenum
+                    )));
+                }
+                match eval_const_expr(&data[1..].join(" "), &constants, &linenum)? {
+                    Value::Int(val) if i16::try_from(val).is_ok() => {
+                        let final_arg = i16::to_le_bytes(val as i16);
+                        bin_vec.push(OpCode::PushImmediate as u8);
+                        bin_vec.push(final_arg[0]);
+                        bin_vec.push(final_arg[1]);
+                    }
+                    _ => {
+                        return Err(AssemblerError::InvalidArgument(format!(
+                            "Expected 16bit integer at line: {}",
+                            linenum
+                        )));
+                    }
+                }
+            }
+            "const" => {
+                if data.len() < 4 || data[2] != "=" {
+                    return Err(AssemblerError::InvalidArgument(format!(
+                        "Expected const <name> = <expression> at line: {}",
+                        linenum
+                    )));
+                }
+                let Value::Ident(name) = parse_literal(data[1], &linenum)? else {
+                    return Err(AssemblerError::InvalidArgument(format!(
+                        "Expected constant identifier at line: {}",
+                        linenum
+                    )));
+                };
+                let val = eval_const_expr(&data[3..].join(" "), &constants, &linenum)?;
+                if constants.insert(name.clone(), val).is_some() {
+                    return Err(AssemblerError::InvalidArgument(format!(
+                        "Duplicate constant: {}, at line: {}",
+                        name, linenum
+                    )));
+                }
+            }
             "pshl" => {
                 if data.len() != 2 {
                     return Err(AssemblerError::InvalidArgument(
@@ -130,7 +389,7 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                         linenum
                     )));
                 }
-                let val = parse_literal(data[1], linenum)?;
+                let val = parse_literal(data[1], &linenum)?;
                 match val {
                     Value::Ident(ident) => {
                         if let Some(idx) = current_function.locals.get(&ident) {
@@ -151,7 +410,13 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                     }
                 }
             }
-            "strl" => {
+            "strl" | "strk" => {
+                // strk stores the top of the stack without popping it
+                let opcode = if op == "strl" {
+                    OpCode::StoreLocal
+                } else {
+                    OpCode::StoreLocalKeep
+                };
                 if data.len() != 2 {
                     return Err(AssemblerError::InvalidArgument(
                         "Expected one argument".to_string(),
@@ -163,17 +428,17 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                         linenum
                     )));
                 }
-                let val = parse_literal(data[1], linenum)?;
+                let val = parse_literal(data[1], &linenum)?;
                 match val {
                     Value::Ident(ident) => {
                         if let Some(idx) = current_function.locals.get(&ident) {
-                            bin_vec.push(OpCode::StoreLocal as u8);
+                            bin_vec.push(opcode as u8);
                             bin_vec.push(*idx);
                         } else {
                             // println!("storelocal {}", ident);
                             let idx = current_function.locals.len();
                             current_function.locals.insert(ident, idx as u8);
-                            bin_vec.push(OpCode::StoreLocal as u8);
+                            bin_vec.push(opcode as u8);
                             bin_vec.push(idx as u8);
                         }
                     }
@@ -191,14 +456,14 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                         "Expected one argument".to_string(),
                     ));
                 }
-                let arg = parse_literal(data[1], linenum);
+                let arg = parse_literal(data[1], &linenum);
                 let idx: u16;
                 match arg {
                     Ok(Value::Ident(name)) => {
                         if let Some(id) = globals_names.get(name.as_str()) {
                             idx = *id;
                         } else {
-                            let id = globals_names.iter().count() as u16;
+                            let id = globals_names.len() as u16;
                             let _ = globals_names.insert(name, id);
                             idx = id;
                         }
@@ -221,17 +486,19 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                         "Expected one argument".to_string(),
                     ));
                 }
-                let arg = parse_literal(data[1], linenum);
+                let arg = parse_literal(data[1], &linenum);
                 let idx: u16;
                 match arg {
                     Ok(Value::Ident(name)) => {
                         if let Some(id) = globals_names.get(name.as_str()) {
                             idx = *id;
                         } else {
-                            return Err(AssemblerError::InvalidArgument(format!(
-                                "Expected global identifier at line: {}",
-                                linenum
-                            )));
+                            fix_globals.push(FixSymbol {
+                                offset: bin_vec.len() + 1,
+                                name,
+                                line: linenum.clone(),
+                            });
+                            idx = 0;
                         }
                     }
                     _ => {
@@ -282,17 +549,18 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                 bin_vec.push(OpCode::SetBox as u8);
             }
             "array" => {
-                if data.len() != 2 {
-                    return Err(AssemblerError::InvalidArgument(
-                        "Expected one argument".to_string(),
-                    ));
+                if data.len() < 2 {
+                    return Err(AssemblerError::InvalidArgument(format!(
+                        "Expected one argument at line: {}",
+                        linenum
+                    )));
                 }
-                let arg = parse_literal(data[1], linenum)?;
+                let arg = eval_const_expr(&data[1..].join(" "), &constants, &linenum)?;
                 match arg {
                     Value::Int(size) => {
-                        if size > 256 {
+                        if !(0..256).contains(&size) {
                             return Err(AssemblerError::InvalidArgument(format!(
-                                "Expected array size <= 256 at line: {}",
+                                "Expected array size < 256 at line: {}",
                                 linenum
                             )));
                         }
@@ -354,16 +622,35 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
             }
             // Control Flow
             "label" => {
-                if data.len() != 2 {
-                    return Err(AssemblerError::InvalidArgument(
-                        "Expected one argument".to_string(),
-                    ));
+                // label <name> is scoped to the enclosing function, label global <name> is file level
+                let global = data.len() == 3 && data[1] == "global";
+                if data.len() != 2 && !global {
+                    return Err(AssemblerError::InvalidArgument(format!(
+                        "Expected one argument at line: {}",
+                        linenum
+                    )));
                 }
-                let arg = parse_literal(data[1], linenum)?;
+                let arg = parse_literal(data[data.len() - 1].trim_start_matches('.'), &linenum)?;
                 bin_vec.push(OpCode::NoOp as u8);
                 match arg {
                     Value::Ident(name) => {
-                        labels.insert(name, (bin_vec.len() - 1) as u32);
+                        let function =
+                            (!current_function.done).then(|| current_function.name.clone());
+                        let key = if global || function.is_none() {
+                            name
+                        } else {
+                            format!("{}.{}", current_function.name, name)
+                        };
+                        let label = Label {
+                            address: (bin_vec.len() - 1) as u32,
+                            function,
+                        };
+                        if labels.insert(key.clone(), label).is_some() {
+                            return Err(AssemblerError::DuplicateLabel(format!(
+                                "Duplicate label: {}, at line: {}",
+                                key, linenum
+                            )));
+                        }
                     }
                     _ => {
                         return Err(AssemblerError::InvalidArgument(format!(
@@ -379,36 +666,15 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                         "Expected one argument".to_string(),
                     ));
                 }
-                let arg = parse_literal(data[1], linenum)?;
-                match arg {
-                    Value::Ident(name) => {
-                        // labels.insert(name, (bin_vec.len() - 1) as u32);
-                        bin_vec.push(OpCode::Jump as u8);
-                        if let Some(target) = labels.get(&name) {
-                            let location = u32::to_le_bytes(*target);
-                            bin_vec.push(location[0]);
-                            bin_vec.push(location[1]);
-                            bin_vec.push(location[2]);
-                            bin_vec.push(location[3]);
-                        } else {
-                            fix_labels.push(FixLabel {
-                                offset: bin_vec.len(),
-                                label: name,
-                            });
-                            let location = u32::to_le_bytes(0);
-                            bin_vec.push(location[0]);
-                            bin_vec.push(location[1]);
-                            bin_vec.push(location[2]);
-                            bin_vec.push(location[3]);
-                        };
-                    }
-                    _ => {
-                        return Err(AssemblerError::InvalidArgument(format!(
-                            "Expected label identifier at line: {}",
-                            linenum
-                        )));
-                    }
-                }
+                let function = (!current_function.done).then(|| current_function.name.clone());
+                emit_jump(
+                    &mut bin_vec,
+                    &mut fix_labels,
+                    OpCode::Jump,
+                    data[1],
+                    function,
+                    &linenum,
+                )?;
             }
             "jmpf" => {
                 if data.len() != 2 {
@@ -416,36 +682,15 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                         "Expected one argument".to_string(),
                     ));
                 }
-                let arg = parse_literal(data[1], linenum)?;
-                match arg {
-                    Value::Ident(name) => {
-                        // labels.insert(name, (bin_vec.len() - 1) as u32);
-                        bin_vec.push(OpCode::JumpIfFalse as u8);
-                        if let Some(target) = labels.get(&name) {
-                            let location = u32::to_le_bytes(*target);
-                            bin_vec.push(location[0]);
-                            bin_vec.push(location[1]);
-                            bin_vec.push(location[2]);
-                            bin_vec.push(location[3]);
-                        } else {
-                            fix_labels.push(FixLabel {
-                                offset: bin_vec.len(),
-                                label: name,
-                            });
-                            let location = u32::to_le_bytes(0);
-                            bin_vec.push(location[0]);
-                            bin_vec.push(location[1]);
-                            bin_vec.push(location[2]);
-                            bin_vec.push(location[3]);
-                        };
-                    }
-                    _ => {
-                        return Err(AssemblerError::InvalidArgument(format!(
-                            "Expected label identifier at line: {}",
-                            linenum
-                        )));
-                    }
-                }
+                let function = (!current_function.done).then(|| current_function.name.clone());
+                emit_jump(
+                    &mut bin_vec,
+                    &mut fix_labels,
+                    OpCode::JumpIfFalse,
+                    data[1],
+                    function,
+                    &linenum,
+                )?;
             }
             "jmpt" => {
                 if data.len() != 2 {
@@ -453,36 +698,15 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                         "Expected one argument".to_string(),
                     ));
                 }
-                let arg = parse_literal(data[1], linenum)?;
-                match arg {
-                    Value::Ident(name) => {
-                        // labels.insert(name, (bin_vec.len() - 1) as u32);
-                        bin_vec.push(OpCode::JumpIfTrue as u8);
-                        if let Some(target) = labels.get(&name) {
-                            let location = u32::to_le_bytes(*target);
-                            bin_vec.push(location[0]);
-                            bin_vec.push(location[1]);
-                            bin_vec.push(location[2]);
-                            bin_vec.push(location[3]);
-                        } else {
-                            fix_labels.push(FixLabel {
-                                offset: bin_vec.len(),
-                                label: name,
-                            });
-                            let location = u32::to_le_bytes(0);
-                            bin_vec.push(location[0]);
-                            bin_vec.push(location[1]);
-                            bin_vec.push(location[2]);
-                            bin_vec.push(location[3]);
-                        };
-                    }
-                    _ => {
-                        return Err(AssemblerError::InvalidArgument(format!(
-                            "Expected label identifier at line: {}",
-                            linenum
-                        )));
-                    }
-                }
+                let function = (!current_function.done).then(|| current_function.name.clone());
+                emit_jump(
+                    &mut bin_vec,
+                    &mut fix_labels,
+                    OpCode::JumpIfTrue,
+                    data[1],
+                    function,
+                    &linenum,
+                )?;
             }
 
             // Comparisons and operators
@@ -558,6 +782,110 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                 }
                 bin_vec.push(OpCode::LogicalOr as u8);
             }
+            "ideq" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::Identical as u8);
+            }
+            "band" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::BitAnd as u8);
+            }
+            "bor" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::BitOr as u8);
+            }
+            "bxor" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::BitXor as u8);
+            }
+            "bnot" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::BitNot as u8);
+            }
+            "shl" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::Shl as u8);
+            }
+            "shr" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::Shr as u8);
+            }
+            "ushr" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::UShr as u8);
+            }
+            "rotl" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::RotateLeft as u8);
+            }
+            "rotr" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::RotateRight as u8);
+            }
+            "popc" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::PopCount as u8);
+            }
+            "clz" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::LeadingZeros as u8);
+            }
+            "ctz" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::TrailingZeros as u8);
+            }
 
             // Functions
             "func" => {
@@ -566,8 +894,8 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                         "Expected two argument".to_string(),
                     ));
                 }
-                let ident = parse_literal(data[1], linenum)?;
-                let arity = parse_literal(data[2], linenum)?;
+                let ident = parse_literal(data[1], &linenum)?;
+                let arity = parse_literal(data[2], &linenum)?;
                 if !current_function.done {
                     return Err(AssemblerError::InvalidFunctionLocation(format!(
                         "Cannot create function inside function at line: {}",
@@ -590,7 +918,7 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                             current_function.name = id;
                             for n in 0..num {
                                 current_function.locals.insert(
-                                    format!("arg{}", n.to_string()),
+                                    format!("arg{}", n),
                                     current_function.locals.len() as u8,
                                 );
                             }
@@ -637,20 +965,23 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                         linenum
                     )));
                 }
-                let val = parse_literal(data[1], linenum)?;
+                let val = parse_literal(data[1], &linenum)?;
                 match val {
                     Value::Ident(ident) => {
-                        if let Some(idx) = func_names.get(&ident) {
-                            bin_vec.push(OpCode::CallFunction as u8);
-                            let arg = u16::to_le_bytes(*idx as u16);
-                            bin_vec.push(arg[0]);
-                            bin_vec.push(arg[1]);
+                        bin_vec.push(OpCode::CallFunction as u8);
+                        let idx = if let Some(idx) = func_names.get(&ident) {
+                            *idx
                         } else {
-                            return Err(AssemblerError::InvalidFunctionCall(format!(
-                                "Function doesn't exist at line: {}",
-                                linenum
-                            )));
-                        }
+                            fix_functions.push(FixSymbol {
+                                offset: bin_vec.len(),
+                                name: ident,
+                                line: linenum.clone(),
+                            });
+                            0
+                        };
+                        let arg = u16::to_le_bytes(idx as u16);
+                        bin_vec.push(arg[0]);
+                        bin_vec.push(arg[1]);
                     }
                     _ => {
                         return Err(AssemblerError::InvalidArgument(format!(
@@ -670,6 +1001,112 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
                 }
                 bin_vec.push(OpCode::Print as u8);
             }
+
+            // Symbolic
+            "sym" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::Symbol as u8);
+            }
+            "subs" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::Substitute as u8);
+            }
+            "eval" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::Evaluate as u8);
+            }
+            "exeq" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::ExprEqual as u8);
+            }
+            "simp" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::Simplify as u8);
+            }
+            "diff" => {
+                if data.len() != 2 {
+                    return Err(AssemblerError::InvalidArgument(format!(
+                        "Expected one variable name at line: {}",
+                        linenum
+                    )));
+                }
+                // The name is stored as a String const
+                let val = Value::String(HeapString::new(data[1].to_string()));
+                let idx = match consts.iter().position(|x| *x == val) {
+                    Some(val) => val as u16,
+                    None => {
+                        consts.push(val);
+                        (consts.len() - 1) as u16
+                    }
+                };
+                let final_arg = idx.to_le_bytes();
+                bin_vec.push(OpCode::Differentiate as u8);
+                bin_vec.push(final_arg[0]);
+                bin_vec.push(final_arg[1]);
+            }
+
+            // Conversions
+            "toint" => {
+                // Truncates unless given a rounding mode
+                let mode = match data.get(1) {
+                    None => Rounding::Trunc,
+                    Some(name) if data.len() == 2 => {
+                        Rounding::from_mnemonic(name).ok_or_else(|| {
+                            AssemblerError::InvalidArgument(format!(
+                                "Invalid rounding mode: {}, at line: {}",
+                                name, linenum
+                            ))
+                        })?
+                    }
+                    Some(_) => {
+                        return Err(AssemblerError::InvalidArgument(format!(
+                            "Expected at most one rounding mode at line: {}",
+                            linenum
+                        )));
+                    }
+                };
+                bin_vec.push(OpCode::ToInt as u8);
+                bin_vec.push(mode as u8);
+            }
+            "toflt" => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::ToFloat as u8);
+            }
+
+            // Math, one mnemonic per function
+            name if let Some(f) = MathFn::from_mnemonic(name) => {
+                if data.len() > 1 {
+                    return Err(AssemblerError::InvalidArgument(
+                        "Expected zero arguments".to_string(),
+                    ));
+                }
+                bin_vec.push(OpCode::Math as u8);
+                bin_vec.push(f as u8);
+            }
             _ => {
                 return Err(AssemblerError::InvalidOpcode(format!(
                     "Invalid OpCode: {}, at line: {}",
@@ -680,24 +1117,121 @@ pub fn assemble() -> Result<(usize, Vec<Value>, Vec<Function>, Vec<u8>), Assembl
     }
 
     for label in fix_labels {
-        if let Some(loc) = labels.get(&label.label) {
-            let bytes = u32::to_le_bytes(*loc);
+        // A local label shadows a file level one of the same name; `.name` only looks locally
+        let local = label.label.strip_prefix('.');
+        let name = local.unwrap_or(&label.label);
+        let scoped = label
+            .function
+            .as_ref()
+            .and_then(|fname| labels.get(&format!("{}.{}", fname, name)));
+        let target = match (scoped, local) {
+            (Some(target), _) => Some(target),
+            (None, None) => labels.get(name),
+            (None, Some(_)) => None,
+        };
+        if let Some(target) = target {
+            if target.function != label.function {
+                return Err(AssemblerError::InvalidJumpTarget(format!(
+                    "Jump to label: {} crosses a function boundary at line: {}",
+                    label.label, label.line
+                )));
+            }
+            let bytes = u32::to_le_bytes(target.address);
             bin_vec[label.offset] = bytes[0];
             bin_vec[label.offset + 1] = bytes[1];
             bin_vec[label.offset + 2] = bytes[2];
             bin_vec[label.offset + 3] = bytes[3];
         } else {
             return Err(AssemblerError::InvalidJumpTarget(format!(
-                "Invalid jump target: {}",
-                label.label
+                "Invalid jump target: {}, at line: {}",
+                label.label, label.line
             )));
         }
     }
 
-    Ok((entry, consts, functions, bin_vec))
+    let mut undefined: Vec<(&str, String, Vec<SourceLoc>)> = Vec::new();
+    for fix in fix_functions {
+        let idx = func_names.get(&fix.name).map(|idx| *idx as u16);
+        patch_symbol(&mut bin_vec, fix, idx, "function", &mut undefined);
+    }
+    for fix in fix_globals {
+        let idx = globals_names.get(&fix.name).copied();
+        patch_symbol(&mut bin_vec, fix, idx, "global", &mut undefined);
+    }
+    if !undefined.is_empty() {
+        let report: Vec<String> = undefined
+            .iter()
+            .map(|(kind, name, lines)| {
+                let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+                format!("{} {} used at lines: {}", kind, name, lines.join(", "))
+            })
+            .collect();
+        return Err(AssemblerError::UndefinedSymbol(format!(
+            "Undefined symbols: {}",
+            report.join("; ")
+        )));
+    }
+
+    Ok(Bytecode {
+        entry,
+        consts,
+        functions,
+        code: bin_vec,
+    })
 }
 
-fn parse_literal(s: &str, line: i32) -> Result<Value, AssemblerError> {
+// Emit a jump with a placeholder target, resolved against the label scopes once every label is known
+fn emit_jump(
+    bin_vec: &mut Vec<u8>,
+    fix_labels: &mut Vec<FixLabel>,
+    opcode: OpCode,
+    arg: &str,
+    function: Option<String>,
+    line: &SourceLoc,
+) -> Result<(), AssemblerError> {
+    match parse_literal(arg.trim_start_matches('.'), line)? {
+        Value::Ident(_) => {
+            bin_vec.push(opcode as u8);
+            fix_labels.push(FixLabel {
+                offset: bin_vec.len(),
+                label: arg.trim().to_string(),
+                function,
+                line: line.clone(),
+            });
+            bin_vec.extend_from_slice(&u32::to_le_bytes(0));
+            Ok(())
+        }
+        _ => Err(AssemblerError::InvalidArgument(format!(
+            "Expected label identifier at line: {}",
+            line
+        ))),
+    }
+}
+
+// Write a resolved u16 symbol index into the placeholder, or record the use site as undefined
+fn patch_symbol<'a>(
+    bin_vec: &mut [u8],
+    fix: FixSymbol,
+    idx: Option<u16>,
+    kind: &'a str,
+    undefined: &mut Vec<(&'a str, String, Vec<SourceLoc>)>,
+) {
+    if let Some(idx) = idx {
+        let bytes = u16::to_le_bytes(idx);
+        bin_vec[fix.offset] = bytes[0];
+        bin_vec[fix.offset + 1] = bytes[1];
+    } else if let Some(entry) = undefined
+        .iter_mut()
+        .find(|(k, name, _)| *k == kind && *name == fix.name)
+    {
+        entry.2.push(fix.line);
+    } else {
+        undefined.push((kind, fix.name, vec![fix.line]));
+    }
+}
+
+#[allow(clippy::needless_return)]
+fn parse_literal(s: &str, line: impl Display) -> Result<Value, AssemblerError> {
     let arg = s.trim();
     if arg.starts_with('"') && arg.ends_with('"') || arg.starts_with('\'') && arg.ends_with('\'') {
         let content = &arg[1..arg.len() - 1];
@@ -707,7 +1241,7 @@ fn parse_literal(s: &str, line: i32) -> Result<Value, AssemblerError> {
         return Ok(Value::Bool(arg.parse().unwrap()));
     }
     if arg.contains('.') {
-        return arg.parse::<f64>().map(|x| Value::Float(x)).map_err(|_| {
+        return arg.parse::<f64>().map(Value::Float).map_err(|_| {
             AssemblerError::InvalidLiteral(format!("Invalid Float at line: {}", line))
         });
     }
@@ -721,6 +1255,10 @@ fn parse_literal(s: &str, line: i32) -> Result<Value, AssemblerError> {
             return Ok(Value::Int(val));
         }
         Err(_) => {
+            // Too big for an Int
+            if let Ok(big) = arg.parse::<BigInt>() {
+                return Ok(Value::from_bigint(big));
+            }
             if let Some(ch) = arg.chars().next() {
                 if ch.is_alphabetic() {
                     return Ok(Value::Ident(arg.to_string()));
@@ -745,6 +1283,20 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn keywords_are_all_read() {
+        for name in KEYWORDS {
+            let result = assemble_source(&format!("main\n{}", name));
+            assert!(
+                !matches!(result, Err(AssemblerError::InvalidOpcode(_))),
+                "{}",
+                name
+            );
+        }
+        assert!(is_keyword("sqrt"));
+        assert!(!is_keyword("addto"));
+    }
+
     #[test]
     fn parse_string() {
         let result = parse_literal("\"this is a test\"", 0);
@@ -779,6 +1331,13 @@ mod tests {
         assert_eq!(result.unwrap(), Value::Int(123));
     }
 
+    #[test]
+    fn parse_big_int() {
+        let result = parse_literal("-123456789012345678901234567890", 0);
+        let expected: BigInt = "-123456789012345678901234567890".parse().unwrap();
+        assert_eq!(result.unwrap(), Value::BigInt(expected.into()));
+    }
+
     #[test]
     fn parse_ident() {
         let result = parse_literal("testIdent", 0);
@@ -802,4 +1361,102 @@ mod tests {
     fn parse_empty_arg() {
         let _ = parse_literal("", 0).unwrap();
     }
+
+    #[test]
+    fn forward_function_call() {
+        let source = "func isEven 1\npshl arg0\ncallf isOdd\nendf\nfunc isOdd 1\npshl arg0\ncallf isEven\nendf\nmain\npshc 4\ncallf isOdd\nprnt";
+        let bytecode = assemble_source(source).unwrap();
+        // isEven's call is patched to isOdd (index 1), isOdd's to isEven (index 0)
+        assert_eq!(bytecode.code[3..6], [OpCode::CallFunction as u8, 1, 0]);
+        assert_eq!(bytecode.code[10..13], [OpCode::CallFunction as u8, 0, 0]);
+    }
+
+    #[test]
+    fn forward_global_reference() {
+        let source =
+            "func get 0\npshg counter\nendf\nmain\npshc 1\nstrg other\npshc 2\nstrg counter";
+        let bytecode = assemble_source(source).unwrap();
+        assert_eq!(bytecode.code[1..4], [OpCode::PushGlobal as u8, 1, 0]);
+    }
+
+    #[test]
+    fn undefined_symbols_list_every_use() {
+        let source = "main\ncallf missing\npshg nothing\ncallf missing";
+        match assemble_source(source) {
+            Err(AssemblerError::UndefinedSymbol(msg)) => {
+                assert!(msg.contains("function missing used at lines: 2, 4"));
+                assert!(msg.contains("global nothing used at lines: 3"));
+            }
+            other => panic!("Expected undefined symbol error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn labels_are_function_scoped() {
+        let source =
+            "func a 0\nlabel loop\njump loop\nendf\nfunc b 0\nlabel loop\njump .loop\nendf";
+        let bytecode = assemble_source(source).unwrap();
+        assert_eq!(bytecode.code[2..7], [OpCode::Jump as u8, 1, 0, 0, 0]);
+        assert_eq!(bytecode.code[10..15], [OpCode::Jump as u8, 9, 0, 0, 0]);
+    }
+
+    #[test]
+    fn global_labels_are_file_level() {
+        let source = "main\njump done\nlabel global done\nprnt";
+        let bytecode = assemble_source(source).unwrap();
+        assert_eq!(bytecode.code[1..6], [OpCode::Jump as u8, 6, 0, 0, 0]);
+    }
+
+    #[test]
+    fn jump_across_function_boundary() {
+        let source = "func a 0\nlabel global inside\nendf\nmain\njump inside";
+        match assemble_source(source) {
+            Err(AssemblerError::InvalidJumpTarget(msg)) => {
+                assert!(msg.contains("crosses a function boundary at line: 5"))
+            }
+            other => panic!("Expected invalid jump target, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn duplicate_label_in_scope() {
+        let source = "func a 0\nlabel loop\nlabel loop\nendf";
+        assert!(matches!(
+            assemble_source(source),
+            Err(AssemblerError::DuplicateLabel(_))
+        ));
+    }
+
+    #[test]
+    fn macro_diagnostics_show_body_and_invocation() {
+        let source = "macro broken\nbogus\nendm\nmain\nbroken";
+        match assemble_source(source) {
+            Err(AssemblerError::InvalidOpcode(msg)) => assert_eq!(
+                msg,
+                "Invalid OpCode: bogus, at line: 2, in macro broken expanded at line 5"
+            ),
+            other => panic!("Expected invalid opcode, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn undefined_names_are_pushed_as_identifiers() {
+        let bytecode = assemble_source("const SIZE = 3\nmain\npshc SIZE\npshc foo").unwrap();
+        assert_eq!(
+            bytecode.consts,
+            [Value::Int(3), Value::Ident("foo".to_string())]
+        );
+        assert!(assemble_source("main\npshc foo + 1").is_err());
+    }
+
+    #[test]
+    fn const_directives_share_the_const_pool() {
+        let source = "const SIZE = 2 + 1\nconst LAST = SIZE - 1\nmain\npshc SIZE * 2\npshc 6\npshc LAST\narray SIZE\npshi -LAST";
+        let bytecode = assemble_source(source).unwrap();
+        assert_eq!(bytecode.consts, [Value::Int(6), Value::Int(2)]);
+        assert_eq!(
+            bytecode.code[1..],
+            [16, 0, 0, 16, 0, 0, 16, 1, 0, 0x1A, 3, 0x16, 0xFE, 0xFF]
+        );
+    }
 }
@@ -0,0 +1,560 @@
+use crate::bytecode::Bytecode;
+use crate::error::BuilderError;
+use crate::function::Function;
+use crate::math::{MathFn, Rounding};
+use crate::opcode::OpCode;
+use crate::value::{HeapString, Value};
+use crate::verifier::verify;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FunctionId(u16);
+
+struct CurFunc {
+    id: FunctionId,
+    locals: u8,
+}
+
+// Typed emitter for compiler front ends. Mirrors what the assembler produces: labels and
+// function starts are NoOps, functions end in Return, and the const pool is deduplicated.
+// Misuse is recorded and reported by build(), so emit calls can be chained freely.
+pub struct BytecodeBuilder {
+    code: Vec<u8>,
+    consts: Vec<Value>,
+    functions: Vec<Function>,
+    defined: Vec<bool>,
+    labels: Vec<Option<u32>>,
+    fix_labels: Vec<(usize, Label)>,
+    entry: usize,
+    current: Option<CurFunc>,
+    error: Option<BuilderError>,
+}
+
+impl Default for BytecodeBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BytecodeBuilder {
+    pub fn new() -> Self {
+        Self {
+            code: Vec::new(),
+            consts: Vec::new(),
+            functions: Vec::new(),
+            defined: Vec::new(),
+            labels: Vec::new(),
+            fix_labels: Vec::new(),
+            entry: 0,
+            current: None,
+            error: None,
+        }
+    }
+
+    fn fail(&mut self, error: BuilderError) -> &mut Self {
+        self.error.get_or_insert(error);
+        self
+    }
+    fn op(&mut self, opcode: OpCode) -> &mut Self {
+        self.code.push(opcode as u8);
+        self
+    }
+    fn op_u8(&mut self, opcode: OpCode, arg: u8) -> &mut Self {
+        self.code.push(opcode as u8);
+        self.code.push(arg);
+        self
+    }
+    fn op_u16(&mut self, opcode: OpCode, arg: u16) -> &mut Self {
+        self.code.push(opcode as u8);
+        self.code.extend_from_slice(&arg.to_le_bytes());
+        self
+    }
+    fn op_jump(&mut self, opcode: OpCode, label: Label) -> &mut Self {
+        if label.0 >= self.labels.len() {
+            return self.fail(BuilderError::UnknownLabel(label.0));
+        }
+        self.code.push(opcode as u8);
+        self.fix_labels.push((self.code.len(), label));
+        self.code.extend_from_slice(&u32::to_le_bytes(0));
+        self
+    }
+    fn use_local(&mut self, slot: u8) -> bool {
+        match self.current.as_mut() {
+            // Local counts are a u8, so there's no room for slot 255
+            Some(func) => match slot.checked_add(1) {
+                Some(count) => {
+                    func.locals = func.locals.max(count);
+                    true
+                }
+                None => {
+                    self.fail(BuilderError::TooManyLocals);
+                    false
+                }
+            },
+            None => {
+                self.fail(BuilderError::NotInFunction);
+                false
+            }
+        }
+    }
+
+    // Program structure
+    pub fn begin_main(&mut self) -> &mut Self {
+        self.entry = self.code.len();
+        self.op(OpCode::NoOp)
+    }
+    pub fn declare_function(&mut self, arity: u8) -> FunctionId {
+        if self.functions.len() > u16::MAX as usize {
+            // Never valid, since build() reports the error first
+            self.fail(BuilderError::TooManyFunctions);
+            return FunctionId(u16::MAX);
+        }
+        self.functions.push(Function {
+            address: 0,
+            arity,
+            locals: arity,
+        });
+        self.defined.push(false);
+        FunctionId((self.functions.len() - 1) as u16)
+    }
+    pub fn begin_function(&mut self, func: FunctionId) -> &mut Self {
+        if self.current.is_some() {
+            return self.fail(BuilderError::NestedFunction);
+        }
+        let idx = func.0 as usize;
+        if idx >= self.defined.len() {
+            return self.fail(BuilderError::UnknownFunction(func.0));
+        }
+        if self.defined[idx] {
+            return self.fail(BuilderError::DuplicateFunction(func.0));
+        }
+        self.defined[idx] = true;
+        self.functions[idx].address = self.code.len();
+        self.current = Some(CurFunc {
+            id: func,
+            locals: self.functions[idx].arity,
+        });
+        self.op(OpCode::NoOp)
+    }
+    pub fn end_function(&mut self) -> &mut Self {
+        match self.current.take() {
+            Some(func) => {
+                self.functions[func.id.0 as usize].locals = func.locals;
+                self.op(OpCode::Return)
+            }
+            None => self.fail(BuilderError::NotInFunction),
+        }
+    }
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+    pub fn bind_label(&mut self, label: Label) -> &mut Self {
+        match self.labels.get(label.0) {
+            None => return self.fail(BuilderError::UnknownLabel(label.0)),
+            Some(Some(_)) => return self.fail(BuilderError::DuplicateLabel(label.0)),
+            Some(None) => self.labels[label.0] = Some(self.code.len() as u32),
+        }
+        self.op(OpCode::NoOp)
+    }
+
+    // Arithmetic
+    pub fn add(&mut self) -> &mut Self {
+        self.op(OpCode::Add)
+    }
+    pub fn sub(&mut self) -> &mut Self {
+        self.op(OpCode::Sub)
+    }
+    pub fn mul(&mut self) -> &mut Self {
+        self.op(OpCode::Mul)
+    }
+    pub fn div(&mut self) -> &mut Self {
+        self.op(OpCode::Div)
+    }
+    pub fn div_int(&mut self) -> &mut Self {
+        self.op(OpCode::DivInt)
+    }
+    pub fn modulo(&mut self) -> &mut Self {
+        self.op(OpCode::Mod)
+    }
+    pub fn div_exact(&mut self) -> &mut Self {
+        self.op(OpCode::DivExact)
+    }
+    pub fn add_wrap(&mut self) -> &mut Self {
+        self.op(OpCode::AddWrap)
+    }
+    pub fn sub_wrap(&mut self) -> &mut Self {
+        self.op(OpCode::SubWrap)
+    }
+    pub fn mul_wrap(&mut self) -> &mut Self {
+        self.op(OpCode::MulWrap)
+    }
+    pub fn add_checked(&mut self) -> &mut Self {
+        self.op(OpCode::AddChecked)
+    }
+    pub fn sub_checked(&mut self) -> &mut Self {
+        self.op(OpCode::SubChecked)
+    }
+    pub fn mul_checked(&mut self) -> &mut Self {
+        self.op(OpCode::MulChecked)
+    }
+    pub fn add_sat(&mut self) -> &mut Self {
+        self.op(OpCode::AddSat)
+    }
+    pub fn sub_sat(&mut self) -> &mut Self {
+        self.op(OpCode::SubSat)
+    }
+    pub fn mul_sat(&mut self) -> &mut Self {
+        self.op(OpCode::MulSat)
+    }
+
+    // Memory/Stack Manipulation
+    pub fn push_const(&mut self, val: Value) -> &mut Self {
+        match self.const_index(val) {
+            Some(idx) => self.op_u16(OpCode::PushConst, idx),
+            None => self.fail(BuilderError::TooManyConstants),
+        }
+    }
+    // Index of val in the const pool, added if it isn't there yet
+    fn const_index(&mut self, val: Value) -> Option<u16> {
+        let idx = match self.consts.iter().position(|x| *x == val) {
+            Some(idx) => idx,
+            None => {
+                self.consts.push(val);
+                self.consts.len() - 1
+            }
+        };
+        u16::try_from(idx).ok()
+    }
+    pub fn push_immediate(&mut self, val: i16) -> &mut Self {
+        self.op_u16(OpCode::PushImmediate, val as u16)
+    }
+    pub fn push_local(&mut self, slot: u8) -> &mut Self {
+        if self.use_local(slot) {
+            self.op_u8(OpCode::PushLocal, slot);
+        }
+        self
+    }
+    pub fn store_local(&mut self, slot: u8) -> &mut Self {
+        if self.use_local(slot) {
+            self.op_u8(OpCode::StoreLocal, slot);
+        }
+        self
+    }
+    pub fn store_local_keep(&mut self, slot: u8) -> &mut Self {
+        if self.use_local(slot) {
+            self.op_u8(OpCode::StoreLocalKeep, slot);
+        }
+        self
+    }
+    pub fn push_global(&mut self, idx: u16) -> &mut Self {
+        self.op_u16(OpCode::PushGlobal, idx)
+    }
+    pub fn store_global(&mut self, idx: u16) -> &mut Self {
+        self.op_u16(OpCode::StoreGlobal, idx)
+    }
+    pub fn pop(&mut self) -> &mut Self {
+        self.op(OpCode::Pop)
+    }
+    pub fn new_box(&mut self) -> &mut Self {
+        self.op(OpCode::Box)
+    }
+    pub fn unbox(&mut self) -> &mut Self {
+        self.op(OpCode::Unbox)
+    }
+    pub fn set_box(&mut self) -> &mut Self {
+        self.op(OpCode::SetBox)
+    }
+    pub fn array(&mut self, size: u8) -> &mut Self {
+        self.op_u8(OpCode::Array, size)
+    }
+    pub fn array_set(&mut self) -> &mut Self {
+        self.op(OpCode::ArraySet)
+    }
+    pub fn array_get(&mut self) -> &mut Self {
+        self.op(OpCode::ArrayGet)
+    }
+    pub fn array_push(&mut self) -> &mut Self {
+        self.op(OpCode::ArrayPush)
+    }
+    pub fn array_pop(&mut self) -> &mut Self {
+        self.op(OpCode::ArrayPop)
+    }
+    pub fn array_len(&mut self) -> &mut Self {
+        self.op(OpCode::ArrayLen)
+    }
+
+    // Control Flow
+    pub fn jump(&mut self, label: Label) -> &mut Self {
+        self.op_jump(OpCode::Jump, label)
+    }
+    pub fn jump_if_false(&mut self, label: Label) -> &mut Self {
+        self.op_jump(OpCode::JumpIfFalse, label)
+    }
+    pub fn jump_if_true(&mut self, label: Label) -> &mut Self {
+        self.op_jump(OpCode::JumpIfTrue, label)
+    }
+
+    // Comparisons and other operators
+    pub fn equal(&mut self) -> &mut Self {
+        self.op(OpCode::Equal)
+    }
+    pub fn not_equal(&mut self) -> &mut Self {
+        self.op(OpCode::NotEqual)
+    }
+    pub fn less_than(&mut self) -> &mut Self {
+        self.op(OpCode::LessThan)
+    }
+    pub fn greater_than(&mut self) -> &mut Self {
+        self.op(OpCode::GreaterThan)
+    }
+    pub fn greater_equal(&mut self) -> &mut Self {
+        self.op(OpCode::GreaterEqual)
+    }
+    pub fn less_equal(&mut self) -> &mut Self {
+        self.op(OpCode::LessEqual)
+    }
+    pub fn not(&mut self) -> &mut Self {
+        self.op(OpCode::Not)
+    }
+    pub fn logical_and(&mut self) -> &mut Self {
+        self.op(OpCode::LogicalAnd)
+    }
+    pub fn logical_or(&mut self) -> &mut Self {
+        self.op(OpCode::LogicalOr)
+    }
+    pub fn identical(&mut self) -> &mut Self {
+        self.op(OpCode::Identical)
+    }
+
+    // Bitwise
+    pub fn bit_and(&mut self) -> &mut Self {
+        self.op(OpCode::BitAnd)
+    }
+    pub fn bit_or(&mut self) -> &mut Self {
+        self.op(OpCode::BitOr)
+    }
+    pub fn bit_xor(&mut self) -> &mut Self {
+        self.op(OpCode::BitXor)
+    }
+    pub fn bit_not(&mut self) -> &mut Self {
+        self.op(OpCode::BitNot)
+    }
+    pub fn shl(&mut self) -> &mut Self {
+        self.op(OpCode::Shl)
+    }
+    pub fn shr(&mut self) -> &mut Self {
+        self.op(OpCode::Shr)
+    }
+    pub fn ushr(&mut self) -> &mut Self {
+        self.op(OpCode::UShr)
+    }
+    pub fn rotate_left(&mut self) -> &mut Self {
+        self.op(OpCode::RotateLeft)
+    }
+    pub fn rotate_right(&mut self) -> &mut Self {
+        self.op(OpCode::RotateRight)
+    }
+    pub fn pop_count(&mut self) -> &mut Self {
+        self.op(OpCode::PopCount)
+    }
+    pub fn leading_zeros(&mut self) -> &mut Self {
+        self.op(OpCode::LeadingZeros)
+    }
+    pub fn trailing_zeros(&mut self) -> &mut Self {
+        self.op(OpCode::TrailingZeros)
+    }
+
+    // Math
+    pub fn math(&mut self, f: MathFn) -> &mut Self {
+        self.op_u8(OpCode::Math, f as u8)
+    }
+    pub fn to_int(&mut self, mode: Rounding) -> &mut Self {
+        self.op_u8(OpCode::ToInt, mode as u8)
+    }
+    pub fn to_float(&mut self) -> &mut Self {
+        self.op(OpCode::ToFloat)
+    }
+
+    // Symbolic
+    pub fn symbol(&mut self) -> &mut Self {
+        self.op(OpCode::Symbol)
+    }
+    pub fn substitute(&mut self) -> &mut Self {
+        self.op(OpCode::Substitute)
+    }
+    pub fn evaluate(&mut self) -> &mut Self {
+        self.op(OpCode::Evaluate)
+    }
+    pub fn expr_equal(&mut self) -> &mut Self {
+        self.op(OpCode::ExprEqual)
+    }
+    pub fn simplify(&mut self) -> &mut Self {
+        self.op(OpCode::Simplify)
+    }
+    // Differentiate with respect to the symbol named var
+    pub fn differentiate(&mut self, var: &str) -> &mut Self {
+        match self.const_index(Value::String(HeapString::new(var.to_string()))) {
+            Some(idx) => self.op_u16(OpCode::Differentiate, idx),
+            None => self.fail(BuilderError::TooManyConstants),
+        }
+    }
+
+    // Functions
+    pub fn call(&mut self, func: FunctionId) -> &mut Self {
+        if func.0 as usize >= self.functions.len() {
+            return self.fail(BuilderError::UnknownFunction(func.0));
+        }
+        self.op_u16(OpCode::CallFunction, func.0)
+    }
+    pub fn ret(&mut self) -> &mut Self {
+        self.op(OpCode::Return)
+    }
+
+    // Testing ops
+    pub fn print(&mut self) -> &mut Self {
+        self.op(OpCode::Print)
+    }
+    pub fn no_op(&mut self) -> &mut Self {
+        self.op(OpCode::NoOp)
+    }
+
+    // Patch jump targets, check every declared function got a body, and verify the result
+    pub fn build(mut self) -> Result<Bytecode, BuilderError> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        if self.current.is_some() {
+            return Err(BuilderError::UnterminatedFunction);
+        }
+        if let Some(idx) = self.defined.iter().position(|d| !d) {
+            return Err(BuilderError::UndefinedFunction(idx as u16));
+        }
+        for (offset, label) in &self.fix_labels {
+            // op_jump only records labels from this builder
+            let Some(target) = self.labels[label.0] else {
+                return Err(BuilderError::UnboundLabel(label.0));
+            };
+            self.code[*offset..*offset + 4].copy_from_slice(&target.to_le_bytes());
+        }
+        let bytecode = Bytecode {
+            entry: self.entry,
+            consts: self.consts,
+            functions: self.functions,
+            code: self.code,
+        };
+        verify(&bytecode)?;
+        Ok(bytecode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::assembler::assemble_source;
+
+    #[test]
+    fn matches_assembler_output() {
+        let source = "func countdown 1\nlabel loop\npshl arg0\npshc 0\nlteq\njmpt end\npshl arg0\npshc 1\nsub\nstrl arg0\njump loop\nlabel end\npshl arg0\nendf\nmain\npshc 10\ncallf countdown\nprnt";
+        let expected = assemble_source(source).unwrap();
+
+        let mut b = BytecodeBuilder::new();
+        let countdown = b.declare_function(1);
+        let (top, end) = (b.new_label(), b.new_label());
+        b.begin_function(countdown).bind_label(top);
+        b.push_local(0)
+            .push_const(Value::Int(0))
+            .less_equal()
+            .jump_if_true(end);
+        b.push_local(0)
+            .push_const(Value::Int(1))
+            .sub()
+            .store_local(0);
+        b.jump(top).bind_label(end).push_local(0).end_function();
+        b.begin_main()
+            .push_const(Value::Int(10))
+            .call(countdown)
+            .print();
+        let built = b.build().unwrap();
+
+        assert_eq!(built.code, expected.code);
+        assert_eq!(built.consts, expected.consts);
+        assert_eq!(built.entry, expected.entry);
+        assert_eq!(built.functions[0].address, expected.functions[0].address);
+        assert_eq!(built.functions[0].locals, expected.functions[0].locals);
+    }
+
+    #[test]
+    fn counts_locals_past_arity() {
+        let mut b = BytecodeBuilder::new();
+        let f = b.declare_function(2);
+        b.begin_function(f)
+            .push_local(1)
+            .store_local(4)
+            .push_local(4)
+            .end_function();
+        let built = b.build().unwrap();
+        assert_eq!(
+            (built.functions[0].arity, built.functions[0].locals),
+            (2, 5)
+        );
+    }
+
+    #[test]
+    fn reports_misuse() {
+        let mut b = BytecodeBuilder::new();
+        let label = b.new_label();
+        b.begin_main().jump(label);
+        assert_eq!(b.build().unwrap_err(), BuilderError::UnboundLabel(0));
+
+        let mut b = BytecodeBuilder::new();
+        b.declare_function(0);
+        assert_eq!(b.build().unwrap_err(), BuilderError::UndefinedFunction(0));
+
+        let mut b = BytecodeBuilder::new();
+        b.begin_main().store_local(0);
+        assert_eq!(b.build().unwrap_err(), BuilderError::NotInFunction);
+
+        let mut b = BytecodeBuilder::new();
+        let func = b.declare_function(0);
+        b.begin_function(func).store_local(255).end_function();
+        assert_eq!(b.build().unwrap_err(), BuilderError::TooManyLocals);
+    }
+
+    #[test]
+    fn ids_from_another_builder() {
+        let mut other = BytecodeBuilder::new();
+        let (label, func) = (other.new_label(), other.declare_function(0));
+
+        let mut b = BytecodeBuilder::new();
+        b.begin_main().jump(label);
+        assert_eq!(b.build().unwrap_err(), BuilderError::UnknownLabel(0));
+
+        let mut b = BytecodeBuilder::new();
+        b.begin_main().bind_label(label);
+        assert_eq!(b.build().unwrap_err(), BuilderError::UnknownLabel(0));
+
+        let mut b = BytecodeBuilder::new();
+        b.begin_function(func);
+        assert_eq!(b.build().unwrap_err(), BuilderError::UnknownFunction(0));
+
+        let mut b = BytecodeBuilder::new();
+        b.begin_main().call(func);
+        assert_eq!(b.build().unwrap_err(), BuilderError::UnknownFunction(0));
+    }
+
+    #[test]
+    fn slot_254_needs_255_locals() {
+        let mut b = BytecodeBuilder::new();
+        let func = b.declare_function(0);
+        b.begin_function(func)
+            .push_immediate(0)
+            .store_local(254)
+            .end_function();
+        b.begin_main().call(func);
+        assert_eq!(b.build().unwrap().functions[0].locals, 255);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{function::Function, value::Value};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Bytecode {
     pub entry: usize,
     pub consts: Vec<Value>,
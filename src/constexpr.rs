@@ -0,0 +1,387 @@
+use crate::error::{AssemblerError, VMError};
+use crate::ops;
+use crate::value::{HeapString, Value};
+use num_bigint::BigInt;
+use num_complex::Complex64;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(Value),
+    Name(String),
+    Op(&'static str),
+    LeftParen,
+    RightParen,
+    Comma,
+}
+
+// Binary operators from loosest to tightest binding, C style
+const PRECEDENCE: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    consts: &'a HashMap<String, Value>,
+}
+
+// Evaluate an assembly time expression over literals and previously defined constants
+pub fn eval_const_expr(
+    text: &str,
+    consts: &HashMap<String, Value>,
+    line: impl Display,
+) -> Result<Value, AssemblerError> {
+    let error =
+        |msg: String| AssemblerError::InvalidExpression(format!("{} at line: {}", msg, line));
+    let tokens = tokenize(text).map_err(error)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        consts,
+    };
+    let value = parser.expr(0).map_err(error)?;
+    if parser.pos < parser.tokens.len() {
+        return Err(error(format!(
+            "Unexpected {:?} in expression",
+            parser.tokens[parser.pos]
+        )));
+    }
+    Ok(value)
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.trim().chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        let start = i;
+        if ch.is_whitespace() {
+            i += 1;
+        } else if ch == '"' || ch == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != ch {
+                i += 1;
+            }
+            if i == chars.len() {
+                return Err("Unterminated string".to_string());
+            }
+            let content: String = chars[start + 1..i].iter().collect();
+            tokens.push(Token::Literal(Value::String(HeapString::new(content))));
+            i += 1;
+        } else if ch.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            let literal: String = chars[start..i].iter().collect();
+            tokens.push(Token::Literal(parse_number(&literal)?));
+        } else if ch.is_alphabetic() || ch == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let name: String = chars[start..i].iter().collect();
+            match name.as_str() {
+                "true" => tokens.push(Token::Literal(Value::Bool(true))),
+                "false" => tokens.push(Token::Literal(Value::Bool(false))),
+                _ => tokens.push(Token::Name(name)),
+            }
+        } else {
+            let two: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+            let op = match (two.as_str(), ch) {
+                ("<<", _) => "<<",
+                (">>", _) => ">>",
+                (_, '+') => "+",
+                (_, '-') => "-",
+                (_, '*') => "*",
+                (_, '/') => "/",
+                (_, '%') => "%",
+                (_, '&') => "&",
+                (_, '|') => "|",
+                (_, '^') => "^",
+                (_, '~') => "~",
+                (_, '(') => "(",
+                (_, ')') => ")",
+                (_, ',') => ",",
+                _ => return Err(format!("Unexpected character '{}' in expression", ch)),
+            };
+            i += op.len();
+            tokens.push(match op {
+                "(" => Token::LeftParen,
+                ")" => Token::RightParen,
+                "," => Token::Comma,
+                _ => Token::Op(op),
+            });
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(literal: &str) -> Result<Value, String> {
+    let invalid = || format!("Invalid number: {}", literal);
+    // An imaginary literal, like 3i; 2+3i is then an ordinary addition
+    if let Some(imag) = literal.strip_suffix('i') {
+        let imag = parse_number(imag)?.as_f64().ok_or_else(invalid)?;
+        return Ok(Value::Complex(Complex64::new(0.0, imag)));
+    }
+    if let Some(hex) = literal.strip_prefix("0x") {
+        return i64::from_str_radix(hex, 16)
+            .map(Value::Int)
+            .map_err(|_| invalid());
+    }
+    if let Some(bin) = literal.strip_prefix("0b") {
+        return i64::from_str_radix(bin, 2)
+            .map(Value::Int)
+            .map_err(|_| invalid());
+    }
+    if literal.contains('.') {
+        return literal
+            .parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| invalid());
+    }
+    literal
+        .parse::<BigInt>()
+        .map(Value::from_bigint)
+        .map_err(|_| invalid())
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn expr(&mut self, level: usize) -> Result<Value, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = self.expr(level + 1)?;
+        while let Some(Token::Op(op)) = self.peek() {
+            let op = *op;
+            if !PRECEDENCE[level].contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.expr(level + 1)?;
+            lhs = binary(op, lhs, rhs)?;
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Value, String> {
+        match self.peek().cloned() {
+            Some(Token::Op("-")) => {
+                self.pos += 1;
+                match self.unary()? {
+                    val @ (Value::Int(_) | Value::BigInt(_) | Value::Rational(_)) => {
+                        arithmetic(ops::sub, Value::Int(0), val)
+                    }
+                    Value::Float(v) => Ok(Value::Float(-v)),
+                    Value::Complex(v) => Ok(Value::Complex(-v)),
+                    v => Err(format!("Cannot negate {:?}", v)),
+                }
+            }
+            Some(Token::Op("~")) => {
+                self.pos += 1;
+                match self.unary()? {
+                    Value::Int(v) => Ok(Value::Int(!v)),
+                    v => Err(format!("Cannot bitwise invert {:?}", v)),
+                }
+            }
+            _ => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Value, String> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Literal(val)) => Ok(val),
+            Some(Token::Name(name)) if self.peek() == Some(&Token::LeftParen) => self.call(&name),
+            Some(Token::Name(name)) => match self.consts.get(&name) {
+                Some(val) => Ok(val.clone()),
+                None => Err(format!("Undefined constant: {}", name)),
+            },
+            Some(Token::LeftParen) => {
+                let val = self.expr(0)?;
+                self.expect(Token::RightParen, ")")?;
+                Ok(val)
+            }
+            Some(token) => Err(format!("Unexpected {:?} in expression", token)),
+            None => Err("Unexpected end of expression".to_string()),
+        }
+    }
+
+    fn expect(&mut self, token: Token, text: &str) -> Result<(), String> {
+        if self.peek() != Some(&token) {
+            return Err(format!("Expected '{}'", text));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // rat(n, d), the exact fraction n/d. / itself is always the VM's div, so 7/2 is 3.
+    fn call(&mut self, name: &str) -> Result<Value, String> {
+        if name != "rat" {
+            return Err(format!("Unknown function: {}", name));
+        }
+        self.expect(Token::LeftParen, "(")?;
+        let numer = self.expr(0)?;
+        self.expect(Token::Comma, ",")?;
+        let denom = self.expr(0)?;
+        self.expect(Token::RightParen, ")")?;
+        if numer.as_rational().is_none() || denom.as_rational().is_none() {
+            return Err(format!(
+                "Cannot make a fraction of {:?} and {:?}",
+                numer, denom
+            ));
+        }
+        arithmetic(ops::div_exact, numer, denom)
+    }
+}
+
+// Arithmetic is the VM's, so results too big for an Int are BigInts here as well
+fn arithmetic(op: ops::BinaryOp, lhs: Value, rhs: Value) -> Result<Value, String> {
+    op(lhs, rhs).map_err(|err| match err {
+        VMError::DivisionByZero => "Division by zero".to_string(),
+        err => format!("{:?}", err),
+    })
+}
+
+fn binary(op: &str, lhs: Value, rhs: Value) -> Result<Value, String> {
+    // Numbers of any types, promoted as they are in the VM
+    let is_number = |val: &Value| val.as_complex().is_some();
+    if is_number(&lhs) && is_number(&rhs) {
+        match op {
+            "+" => return arithmetic(ops::add, lhs, rhs),
+            "-" => return arithmetic(ops::sub, lhs, rhs),
+            "*" => return arithmetic(ops::mul, lhs, rhs),
+            "/" => return arithmetic(ops::div, lhs, rhs),
+            "%" => return arithmetic(ops::modulo, lhs, rhs),
+            _ => {}
+        }
+    }
+    match (&lhs, &rhs) {
+        (Value::Int(l), Value::Int(r)) => {
+            let (l, r) = (*l, *r);
+            match op {
+                "&" => Ok(Value::Int(l & r)),
+                "|" => Ok(Value::Int(l | r)),
+                "^" => Ok(Value::Int(l ^ r)),
+                "<<" | ">>" if !(0..64).contains(&r) => {
+                    Err(format!("Shift count out of range: {}", r))
+                }
+                "<<" => Ok(Value::Int(l << r)),
+                ">>" => Ok(Value::Int(l >> r)),
+                _ => Err(format!("Unknown operator {}", op)),
+            }
+        }
+        _ => Err(format!("Cannot apply {} to {:?} and {:?}", op, lhs, rhs)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn eval(text: &str) -> Result<Value, AssemblerError> {
+        let mut consts = HashMap::new();
+        consts.insert("WIDTH".to_string(), Value::Int(640));
+        consts.insert("SCALE".to_string(), Value::Float(0.5));
+        eval_const_expr(text, &consts, 0)
+    }
+
+    #[test]
+    fn precedence_and_parentheses() {
+        assert_eq!(eval("1 + 2 * 3").unwrap(), Value::Int(7));
+        assert_eq!(eval("(1 + 2) * 3").unwrap(), Value::Int(9));
+        assert_eq!(eval("-(4 - 10) % 4").unwrap(), Value::Int(2));
+    }
+
+    #[test]
+    fn bit_operations() {
+        assert_eq!(eval("1 << 4 | 0x0F & ~3").unwrap(), Value::Int(28));
+        assert_eq!(eval("0b1010 ^ 0b0110").unwrap(), Value::Int(12));
+        assert!(eval("1 << 64").is_err());
+    }
+
+    #[test]
+    fn constants_and_float_promotion() {
+        assert_eq!(eval("WIDTH / 2").unwrap(), Value::Int(320));
+        assert_eq!(eval("WIDTH * SCALE").unwrap(), Value::Float(320.0));
+        assert_eq!(eval("7.5 % 2").unwrap(), Value::Float(1.5));
+        assert!(eval("SCALE & 1").is_err());
+        assert!(eval("HEIGHT + 1").is_err());
+    }
+
+    #[test]
+    fn big_integers() {
+        let big = |digits: &str| Value::from_bigint(digits.parse().unwrap());
+        assert_eq!(
+            eval("9223372036854775807 + 1").unwrap(),
+            big("9223372036854775808")
+        );
+        assert_eq!(
+            eval("100000000000000000000 / 10").unwrap(),
+            big("10000000000000000000")
+        );
+        assert_eq!(
+            eval("-(-9223372036854775807 - 1)").unwrap(),
+            big("9223372036854775808")
+        );
+        assert_eq!(
+            eval("100000000000000000000 - 99999999999999999999").unwrap(),
+            Value::Int(1)
+        );
+    }
+
+    #[test]
+    fn rational_fractions() {
+        let rational = |text: &str| Value::from_rational(text.parse().unwrap());
+        assert_eq!(eval("rat(3, 4)").unwrap(), rational("3/4"));
+        assert_eq!(eval("-rat(6, 8)").unwrap(), rational("-3/4"));
+        assert_eq!(eval("rat(1, 3) + rat(1, 6)").unwrap(), rational("1/2"));
+        assert_eq!(eval("rat(2, 3) * 3").unwrap(), Value::Int(2));
+        assert_eq!(eval("rat(1, 2) * SCALE").unwrap(), Value::Float(0.25));
+        assert_eq!(eval("rat(WIDTH, 3 * 4)").unwrap(), rational("160/3"));
+        assert_eq!(eval("rat(rat(1, 2), 3)").unwrap(), rational("1/6"));
+        assert!(eval("rat(1, 0)").is_err());
+        assert!(eval("rat(SCALE, 2)").is_err());
+        assert!(eval("rat(1)").is_err());
+        assert!(eval("frac(1, 2)").is_err());
+    }
+
+    #[test]
+    fn division_is_the_same_spaced_or_not() {
+        assert_eq!(eval("7/2").unwrap(), Value::Int(3));
+        assert_eq!(eval("7 / 2").unwrap(), Value::Int(3));
+        assert_eq!(eval("3/4").unwrap(), Value::Int(0));
+        assert_eq!(eval("1/2i").unwrap(), eval("1 / 2i").unwrap());
+    }
+
+    #[test]
+    fn complex_literals() {
+        let complex = |re, im| Value::Complex(Complex64::new(re, im));
+        assert_eq!(eval("2+3i").unwrap(), complex(2.0, 3.0));
+        assert_eq!(eval("-1.5i").unwrap(), complex(0.0, -1.5));
+        assert_eq!(eval("(1+2i) * (3-1i)").unwrap(), complex(5.0, 5.0));
+        assert_eq!(eval("SCALE + 0.5i").unwrap(), complex(0.5, 0.5));
+        assert!(eval("1i / 0").is_err());
+        assert!(eval("1i & 1").is_err());
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert!(eval("1 +").is_err());
+        assert!(eval("(1 + 2").is_err());
+        assert!(eval("3 / 0").is_err());
+        assert!(eval("1 2").is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::bytecode::Bytecode;
+use crate::error::VMError;
+use crate::instr::Instr;
+use std::fmt::Write;
+
+// Assembler spelling of an instruction. Superinstructions, which the assembler never emits,
+// get names of their own.
+fn mnemonic(instr: Instr) -> &'static str {
+    match instr.generic() {
+        Instr::Add => "add",
+        Instr::Sub => "sub",
+        Instr::Mul => "mul",
+        Instr::Div => "div",
+        Instr::DivInt => "divi",
+        Instr::Mod => "mod",
+        Instr::DivExact => "divx",
+        Instr::AddWrap => "wadd",
+        Instr::SubWrap => "wsub",
+        Instr::MulWrap => "wmul",
+        Instr::AddChecked => "cadd",
+        Instr::SubChecked => "csub",
+        Instr::MulChecked => "cmul",
+        Instr::AddSat => "sadd",
+        Instr::SubSat => "ssub",
+        Instr::MulSat => "smul",
+        Instr::PushConst(_) => "pshc",
+        Instr::PushLocal(_) => "pshl",
+        Instr::StoreLocal(_) => "strl",
+        Instr::PushGlobal(_) => "pshg",
+        Instr::StoreGlobal(_) => "strg",
+        Instr::Pop => "pop",
+        Instr::PushImmediate(_) => "pshi",
+        Instr::Box => "box",
+        Instr::Unbox => "unbox",
+        Instr::SetBox => "setbox",
+        Instr::Array(_) => "array",
+        Instr::ArraySet => "arrayset",
+        Instr::ArrayGet => "arrayget",
+        Instr::ArrayPush => "arraypush",
+        Instr::ArrayPop => "arraypop",
+        Instr::ArrayLen => "arraylen",
+        Instr::StoreLocalKeep(_) => "strk",
+        Instr::Jump(_) => "jump",
+        Instr::JumpIfFalse(_) => "jmpf",
+        Instr::JumpIfTrue(_) => "jmpt",
+        Instr::Equal => "equl",
+        Instr::NotEqual => "nteq",
+        Instr::LessThan => "lsth",
+        Instr::GreaterThan => "grth",
+        Instr::GreaterEqual => "gteq",
+        Instr::LessEqual => "lteq",
+        Instr::Not => "not",
+        Instr::LogicalAnd => "and",
+        Instr::LogicalOr => "or",
+        Instr::Identical => "ideq",
+        Instr::BitAnd => "band",
+        Instr::BitOr => "bor",
+        Instr::BitXor => "bxor",
+        Instr::BitNot => "bnot",
+        Instr::Shl => "shl",
+        Instr::Shr => "shr",
+        Instr::UShr => "ushr",
+        Instr::RotateLeft => "rotl",
+        Instr::RotateRight => "rotr",
+        Instr::PopCount => "popc",
+        Instr::LeadingZeros => "clz",
+        Instr::TrailingZeros => "ctz",
+        Instr::Math(f) => f.mnemonic(),
+        Instr::ToInt(_) => "toint",
+        Instr::ToFloat => "toflt",
+        Instr::Symbol => "sym",
+        Instr::Substitute => "subs",
+        Instr::Evaluate => "eval",
+        Instr::ExprEqual => "exeq",
+        Instr::Simplify => "simp",
+        Instr::Differentiate(_) => "diff",
+        Instr::CallFunction(_) => "callf",
+        Instr::Return => "ret",
+        Instr::Print => "prnt",
+        Instr::NoOp => "noop",
+        Instr::AddLocalConst(..) => "addlc",
+        Instr::IncLocal(..) => "incl",
+        Instr::CompareLocalConstJump(..) => "cmpj",
+        Instr::PushLocalPair(..) => "pshl2",
+        _ => "?",
+    }
+}
+
+// Operands as text. Locals are shown by index, consts by index and value, jump targets and
+// functions by byte address.
+fn operands(instr: Instr, bytecode: &Bytecode) -> String {
+    let constant = |idx: u16| match bytecode.consts.get(idx as usize) {
+        Some(val) => format!("#{} ({:?})", idx, val),
+        None => format!("#{} (invalid)", idx),
+    };
+    match instr {
+        Instr::PushConst(idx) | Instr::Differentiate(idx) => constant(idx),
+        Instr::PushLocal(local) | Instr::StoreLocal(local) | Instr::StoreLocalKeep(local) => {
+            format!("{}", local)
+        }
+        Instr::PushGlobal(idx) | Instr::StoreGlobal(idx) => format!("g{}", idx),
+        Instr::PushImmediate(val) => format!("{}", val),
+        Instr::Array(count) => format!("{}", count),
+        Instr::ToInt(mode) => mode.mnemonic().to_string(),
+        Instr::Jump(target) | Instr::JumpIfFalse(target) | Instr::JumpIfTrue(target) => {
+            format!("@{:04}", target)
+        }
+        Instr::CallFunction(idx) => match bytecode.functions.get(idx as usize) {
+            Some(func) => format!("f{} (@{:04})", idx, func.address),
+            None => format!("f{} (invalid)", idx),
+        },
+        Instr::AddLocalConst(local, idx) => format!("{}, {}", local, constant(idx)),
+        Instr::IncLocal(local, delta) => format!("{}, {}", local, delta),
+        Instr::CompareLocalConstJump(local, idx, cond, target) => {
+            let (cmp, jump_if) = Instr::split_condition(cond).expect("decoded condition");
+            let jump = if jump_if { "jmpt" } else { "jmpf" };
+            format!(
+                "{}, {}, {} {} @{:04}",
+                local,
+                constant(idx),
+                mnemonic(cmp),
+                jump,
+                target
+            )
+        }
+        Instr::PushLocalPair(first, second) => format!("{}, {}", first, second),
+        _ => String::new(),
+    }
+}
+
+// A listing of the bytecode, one instruction per line with its byte address. Lines where a
+// function or the entry point starts are preceded by a header.
+pub fn disassemble(bytecode: &Bytecode) -> Result<String, VMError> {
+    let code = &bytecode.code;
+    let mut listing = String::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        for (idx, func) in bytecode.functions.iter().enumerate() {
+            if func.address == offset {
+                let _ = writeln!(
+                    listing,
+                    "func f{} (arity {}, locals {})",
+                    idx, func.arity, func.locals
+                );
+            }
+        }
+        if bytecode.entry == offset {
+            listing.push_str("main\n");
+        }
+        let instr = Instr::decode_at(code, offset)?;
+        let args = operands(instr, bytecode);
+        let line = format!("{:04}  {} {}", offset, mnemonic(instr), args);
+        listing.push_str(line.trim_end());
+        listing.push('\n');
+        offset += instr.size();
+    }
+    if bytecode.entry == code.len() {
+        listing.push_str("main\n");
+    }
+    Ok(listing)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::optimizer::optimize;
+
+    #[test]
+    fn lists_fused_code() {
+        let source = "func count 1\nlabel loop\npshl arg0\npshc 0\nlteq\njmpt end\n\
+                      pshl arg0\npshc -1\nadd\nstrl arg0\njump loop\nlabel end\n\
+                      pshl arg0\npshc 3\nadd\nendf\nmain\npshi 5\ncallf count\nprnt";
+        let optimized = optimize(assemble_source(source).unwrap()).unwrap();
+        let listing = disassemble(&optimized).unwrap();
+        assert_eq!(
+            listing,
+            "func f0 (arity 1, locals 1)\n\
+             0000  cmpj 0, #0 (Int(0)), lteq jmpt @0018\n\
+             0009  incl 0, -1\n\
+             0013  jump @0000\n\
+             0018  addlc 0, #1 (Int(3))\n\
+             0022  ret\n\
+             main\n\
+             0023  pshi 5\n\
+             0026  callf f0 (@0000)\n\
+             0029  prnt\n"
+        );
+    }
+}
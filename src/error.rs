@@ -1,5 +1,6 @@
 use std::io;
 
+use crate::math::MathFn;
 use crate::value::Value;
 
 #[derive(Debug)]
@@ -9,17 +10,22 @@ pub enum VMError {
     StackUnderflow,
     InvalidStackValueType(Value, Value), // (Expected, Received)
     NotInFrame,
+    InconsistentStackDepth(usize), // Instruction reached at more than one depth
 
     // Index Errors
     InvalidLocalIndex(u16),
     InvalidGlobalIndex(u16),
     InvalidConstantIndex(u16),
     InvalidFunctionIndex(u16),
+    InvalidJumpTarget(usize),
 
     // Opcode Errors
     InvalidOpcode(u8),
     InvalidOperandCount(u8, u8),
     InvalidOperandSize(u8, u8),
+    InvalidCondition(u8),
+    InvalidMathFunction(u8),
+    InvalidRoundingMode(u8),
 
     // Operand Errors
     InvalidOperandType(Value, Value),
@@ -27,10 +33,30 @@ pub enum VMError {
 
     // Arithmetic Errors
     DivisionByZero,
+    OutsideDomain(MathFn, Value), // (function, argument)
+    IntegerOverflow,              // Under Overflow::Checked
+    ShiftOutOfRange(Value),       // Shift and rotate counts are 0 to 63
+
+    // Conversion Errors
+    NaNConversion,     // NaN has no integer value
+    OutOfRange(Value), // Too big for the type converted to
+
+    // Symbolic Errors
+    UnboundSymbol(String), // Evaluated before a value was substituted
+    NotDifferentiable(MathFn),
 
     //Array Errors
     IndexOutsideRangeOfArray(usize, usize), // (index, size)
     CouldNotPopArray,
+
+    // Output Errors
+    IoError(io::Error),
+}
+
+impl From<io::Error> for VMError {
+    fn from(error: io::Error) -> Self {
+        VMError::IoError(error)
+    }
 }
 
 #[derive(Debug)]
@@ -40,11 +66,18 @@ pub enum AssemblerError {
     InvalidArgument(String),
     InvalidLiteral(String),
     InvalidJumpTarget(String),
+    DuplicateLabel(String),
     InvalidFunctionLocation(String),
     AccessLocalOutsideFunction(String),
     InvalidFunctionEnd(String),
     InvalidFunctionCall(String),
     InvalidIdentifier(String),
+    UndefinedSymbol(String),
+    InvalidMacro(String),
+    MacroRecursionLimit(String),
+    InvalidInclude(String),
+    InvalidExpression(String),
+    InvalidBytecode(VerifyError),
     UnexpectedEof,
 }
 
@@ -54,6 +87,12 @@ impl From<io::Error> for AssemblerError {
     }
 }
 
+impl From<VerifyError> for AssemblerError {
+    fn from(error: VerifyError) -> Self {
+        AssemblerError::InvalidBytecode(error)
+    }
+}
+
 #[derive(Debug)]
 pub enum JEFError {
     IoError(io::Error),
@@ -75,3 +114,41 @@ impl From<serde_json::Error> for JEFError {
         JEFError::SerdeJson(err)
     }
 }
+
+// Offsets are positions in the code vector
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    InvalidOpcode(usize, u8),
+    TruncatedInstruction(usize),
+    InvalidConstantIndex(usize, u16),
+    InvalidFunctionIndex(usize, u16),
+    InvalidCondition(usize, u8),
+    InvalidMathFunction(usize, u8),
+    InvalidRoundingMode(usize, u8),
+    InvalidJumpTarget(usize, usize), // (offset, target)
+    InvalidFunction(usize),
+    InvalidEntry(usize),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BuilderError {
+    UnboundLabel(usize),
+    DuplicateLabel(usize),
+    UnknownLabel(usize), // From another builder
+    UndefinedFunction(u16),
+    DuplicateFunction(u16),
+    UnknownFunction(u16), // From another builder
+    NestedFunction,
+    UnterminatedFunction,
+    NotInFunction,
+    TooManyConstants,
+    TooManyFunctions,
+    TooManyLocals,
+    Verify(VerifyError),
+}
+
+impl From<VerifyError> for BuilderError {
+    fn from(error: VerifyError) -> Self {
+        BuilderError::Verify(error)
+    }
+}
@@ -0,0 +1,325 @@
+// Symbolic expressions for computer algebra. An Expr is an immutable tree of numeric leaves,
+// symbols, arithmetic and math function applications, with subtrees shared through Rc.
+// Arithmetic with a Value::Expr operand builds a bigger tree instead of computing a number;
+// substituting values for every symbol and evaluating gives the number back. Powers are
+// applications of pow, shown as x^2. Simplification and differentiation are in algebra.rs.
+
+use crate::error::VMError;
+use crate::math::MathFn;
+use crate::ops;
+use crate::value::{HeapExpr, Value};
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    // Int, BigInt, Rational, Float or Complex
+    Num(Value),
+    Symbol(Rc<str>),
+    Binary(BinOp, HeapExpr, HeapExpr),
+    Apply(MathFn, Vec<HeapExpr>),
+}
+
+impl Expr {
+    pub fn symbol(name: &str) -> Value {
+        Value::Expr(Rc::new(Expr::Symbol(name.into())))
+    }
+    // A value as an expression: an Expr as itself, a number as a leaf. None for anything else.
+    pub fn from_value(val: &Value) -> Option<HeapExpr> {
+        match val {
+            Value::Expr(e) => Some(e.clone()),
+            Value::Int(_)
+            | Value::BigInt(_)
+            | Value::Rational(_)
+            | Value::Float(_)
+            | Value::Complex(_) => Some(Rc::new(Expr::Num(val.clone()))),
+            _ => None,
+        }
+    }
+
+    // A copy with every use of the symbol name replaced, sharing the subtrees that don't use it
+    pub fn substitute(self: &Rc<Self>, name: &str, with: &HeapExpr) -> HeapExpr {
+        match &**self {
+            Expr::Symbol(sym) if **sym == *name => with.clone(),
+            Expr::Num(_) | Expr::Symbol(_) => self.clone(),
+            Expr::Binary(op, l, r) => {
+                let (nl, nr) = (l.substitute(name, with), r.substitute(name, with));
+                if Rc::ptr_eq(l, &nl) && Rc::ptr_eq(r, &nr) {
+                    return self.clone();
+                }
+                Rc::new(Expr::Binary(*op, nl, nr))
+            }
+            Expr::Apply(f, args) => {
+                let new: Vec<HeapExpr> = args.iter().map(|a| a.substitute(name, with)).collect();
+                if args.iter().zip(&new).all(|(a, n)| Rc::ptr_eq(a, n)) {
+                    return self.clone();
+                }
+                Rc::new(Expr::Apply(*f, new))
+            }
+        }
+    }
+
+    // Whether the symbol name appears anywhere in the tree
+    pub fn contains(&self, name: &str) -> bool {
+        match self {
+            Expr::Num(_) => false,
+            Expr::Symbol(sym) => **sym == *name,
+            Expr::Binary(_, l, r) => l.contains(name) || r.contains(name),
+            Expr::Apply(_, args) => args.iter().any(|a| a.contains(name)),
+        }
+    }
+
+    // The number an expression stands for, with the VM's arithmetic. Division is exact, as in
+    // divx. Fails on a symbol that hasn't been substituted.
+    pub fn evaluate(&self) -> Result<Value, VMError> {
+        match self {
+            Expr::Num(val) => Ok(val.clone()),
+            Expr::Symbol(name) => Err(VMError::UnboundSymbol(name.to_string())),
+            Expr::Binary(op, l, r) => {
+                let (l, r) = (l.evaluate()?, r.evaluate()?);
+                match op {
+                    BinOp::Add => ops::add(l, r),
+                    BinOp::Sub => ops::sub(l, r),
+                    BinOp::Mul => ops::mul(l, r),
+                    BinOp::Div => ops::div_exact(l, r),
+                }
+            }
+            Expr::Apply(f, args) => {
+                let args = args
+                    .iter()
+                    .map(|a| a.evaluate())
+                    .collect::<Result<Vec<Value>, VMError>>()?;
+                f.apply(&args)
+            }
+        }
+    }
+
+    // Binding strength when printed, to know where parentheses are needed
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary(BinOp::Add | BinOp::Sub, _, _) => 1,
+            Expr::Num(Value::Complex(_)) => 1,
+            Expr::Binary(BinOp::Mul | BinOp::Div, _, _) => 2,
+            Expr::Num(Value::Rational(_)) => 2,
+            Expr::Num(Value::Int(v)) if *v < 0 => 2,
+            Expr::Num(Value::Float(v)) if *v < 0.0 => 2,
+            Expr::Apply(MathFn::Pow, _) => 3,
+            _ => 4,
+        }
+    }
+}
+
+// An arithmetic op with an Expr operand as a tree. None when neither operand is an Expr, or
+// the other one isn't a number, so the op can fail as usual.
+pub fn binary(op: BinOp, lop: &Value, rop: &Value) -> Option<Value> {
+    if !matches!((lop, rop), (Value::Expr(_), _) | (_, Value::Expr(_))) {
+        return None;
+    }
+    let (l, r) = (Expr::from_value(lop)?, Expr::from_value(rop)?);
+    Some(Value::Expr(Rc::new(Expr::Binary(op, l, r))))
+}
+
+// A math function applied to an Expr argument as a tree, like binary
+pub fn apply(f: MathFn, args: &[Value]) -> Option<Value> {
+    if args.len() != f.arity() || !args.iter().any(|a| matches!(a, Value::Expr(_))) {
+        return None;
+    }
+    let args = args
+        .iter()
+        .map(Expr::from_value)
+        .collect::<Option<Vec<HeapExpr>>>()?;
+    Some(Value::Expr(Rc::new(Expr::Apply(f, args))))
+}
+
+// The symbolic opcodes
+
+// A symbol named by a String
+pub fn symbol(name: Value) -> Result<Value, VMError> {
+    match name {
+        Value::String(name) => Ok(Expr::symbol(&name)),
+        _ => Err(VMError::InvalidUnaryOperandType(name)),
+    }
+}
+
+// Replace a symbol, given as one or by name, with a number or another expression
+pub fn substitute(expr: Value, sym: Value, val: Value) -> Result<Value, VMError> {
+    let name: Rc<str> = match &sym {
+        Value::Expr(e) if let Expr::Symbol(name) = &**e => name.clone(),
+        Value::String(name) => name.as_str().into(),
+        _ => return Err(VMError::InvalidOperandType(expr, sym)),
+    };
+    let Some(with) = Expr::from_value(&val) else {
+        return Err(VMError::InvalidOperandType(sym, val));
+    };
+    match &expr {
+        Value::Expr(e) => Ok(from_expr(e.substitute(&name, &with))),
+        _ if Expr::from_value(&expr).is_some() => Ok(expr),
+        _ => Err(VMError::InvalidOperandType(expr, val)),
+    }
+}
+
+// The number an expression stands for; numbers are left as they are
+pub fn evaluate(val: Value) -> Result<Value, VMError> {
+    match val {
+        Value::Expr(e) => e.evaluate(),
+        _ if Expr::from_value(&val).is_some() => Ok(val),
+        _ => Err(VMError::InvalidUnaryOperandType(val)),
+    }
+}
+
+// Simplify an expression; numbers are left as they are
+pub fn simplify(val: Value) -> Result<Value, VMError> {
+    match val {
+        Value::Expr(e) => Ok(from_expr(e.simplify()?)),
+        _ if Expr::from_value(&val).is_some() => Ok(val),
+        _ => Err(VMError::InvalidUnaryOperandType(val)),
+    }
+}
+
+// The simplified derivative with respect to the symbol named by var. A number's is zero.
+pub fn differentiate(val: Value, var: &Value) -> Result<Value, VMError> {
+    let Value::String(name) = var else {
+        return Err(VMError::InvalidOperandType(val, var.clone()));
+    };
+    match val {
+        Value::Expr(e) => Ok(from_expr(e.differentiate(name)?)),
+        _ if Expr::from_value(&val).is_some() => Ok(Value::Int(0)),
+        _ => Err(VMError::InvalidUnaryOperandType(val)),
+    }
+}
+
+// An expression as a Value, unwrapping a lone number
+fn from_expr(e: HeapExpr) -> Value {
+    match &*e {
+        Expr::Num(val) => val.clone(),
+        _ => Value::Expr(e),
+    }
+}
+
+// Whether two values are the same tree, rather than the same number: x + 1 and 1 + x differ
+pub fn structural_equal(lop: Value, rop: Value) -> Result<Value, VMError> {
+    Ok(Value::Bool(lop == rop))
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Num(Value::Complex(v)) => write!(f, "{}", v),
+            Expr::Num(Value::Int(v)) => write!(f, "{}", v),
+            Expr::Num(Value::BigInt(v)) => write!(f, "{}", v),
+            Expr::Num(Value::Rational(v)) => write!(f, "{}", v),
+            Expr::Num(Value::Float(v)) => write!(f, "{:?}", v),
+            Expr::Num(val) => write!(f, "{:?}", val),
+            Expr::Symbol(name) => f.write_str(name),
+            Expr::Binary(op, l, r) => {
+                let (prec, symbol) = match op {
+                    BinOp::Add => (1, " + "),
+                    BinOp::Sub => (1, " - "),
+                    BinOp::Mul => (2, "*"),
+                    BinOp::Div => (2, "/"),
+                };
+                // Left associative, so the right side needs parentheses at equal precedence.
+                // The tree shows exactly, even where it doesn't matter, as in x + (y + z).
+                write_operand(f, l, prec)?;
+                f.write_str(symbol)?;
+                write_operand(f, r, prec + 1)
+            }
+            // Right associative, so only the base needs parentheses at equal precedence
+            Expr::Apply(MathFn::Pow, args) if let [base, exp] = &args[..] => {
+                write_operand(f, base, 4)?;
+                f.write_str("^")?;
+                write_operand(f, exp, 3)
+            }
+            Expr::Apply(func, args) if args.is_empty() => f.write_str(func.mnemonic()),
+            Expr::Apply(func, args) => {
+                write!(f, "{}(", func.mnemonic())?;
+                for (idx, arg) in args.iter().enumerate() {
+                    if idx > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+fn write_operand(f: &mut fmt::Formatter<'_>, expr: &Expr, min_prec: u8) -> fmt::Result {
+    if expr.precedence() < min_prec {
+        return write!(f, "({})", expr);
+    }
+    write!(f, "{}", expr)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn x() -> Value {
+        Expr::symbol("x")
+    }
+
+    #[test]
+    fn arithmetic_builds_trees() {
+        let expr = ops::mul(ops::add(x(), Value::Int(1)).unwrap(), x()).unwrap();
+        let Value::Expr(tree) = &expr else {
+            panic!("arithmetic on a symbol gives an Expr");
+        };
+        assert_eq!(tree.to_string(), "(x + 1)*x");
+        let expr = ops::sub(Value::Int(2), ops::sub(x(), Expr::symbol("y")).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", expr), "Expr(2 - (x - y))");
+        let root = MathFn::Sqrt
+            .apply(&[ops::div(x(), Value::Int(2)).unwrap()])
+            .unwrap();
+        assert_eq!(format!("{:?}", root), "Expr(sqrt(x/2))");
+        assert!(ops::add(x(), Value::Bool(true)).is_err());
+    }
+
+    #[test]
+    fn substitute_and_evaluate() {
+        // x*x/2 - y, with y = 1 and then x = 3
+        let half_square = ops::div(ops::mul(x(), x()).unwrap(), Value::Int(2)).unwrap();
+        let expr = ops::sub(half_square, Expr::symbol("y")).unwrap();
+        let partial = substitute(expr, Value::String(Rc::new("y".into())), Value::Int(1)).unwrap();
+        assert!(matches!(
+            evaluate(partial.clone()),
+            Err(VMError::UnboundSymbol(name)) if name == "x"
+        ));
+        let full = substitute(partial, x(), Value::Int(3)).unwrap();
+        assert_eq!(
+            evaluate(full).unwrap(),
+            Value::from_rational("7/2".parse().unwrap())
+        );
+        // Substituting an expression for a symbol
+        let shifted = substitute(
+            ops::mul(x(), x()).unwrap(),
+            x(),
+            ops::add(x(), Value::Int(1)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(format!("{:?}", shifted), "Expr((x + 1)*(x + 1))");
+    }
+
+    #[test]
+    fn structural_equality() {
+        let l = ops::add(x(), Value::Int(1)).unwrap();
+        let r = ops::add(x(), Value::Int(1)).unwrap();
+        let swapped = ops::add(Value::Int(1), x()).unwrap();
+        assert_eq!(structural_equal(l.clone(), r).unwrap(), Value::Bool(true));
+        assert_eq!(structural_equal(l, swapped).unwrap(), Value::Bool(false));
+        assert_eq!(
+            structural_equal(x(), Value::Int(1)).unwrap(),
+            Value::Bool(false)
+        );
+    }
+}
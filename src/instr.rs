@@ -0,0 +1,532 @@
+use crate::error::VMError;
+use crate::math::{MathFn, Rounding};
+use crate::opcode::OpCode;
+use crate::ops::{self, Overflow};
+
+// Condition operand of CompareLocalConstJump: the comparison's opcode, with this bit set to
+// jump when the comparison fails rather than when it holds
+pub const JUMP_IF_FALSE: u8 = 0x80;
+
+// A single decoded instruction. Jump operands hold a byte address in encoded code and an
+// instruction index once the code has been indexed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Instr {
+    // Arithmetic
+    Add,
+    Sub,
+    Mul,
+    Div,
+    DivInt,
+    Mod,
+    DivExact,
+    AddWrap,
+    SubWrap,
+    MulWrap,
+    AddChecked,
+    SubChecked,
+    MulChecked,
+    AddSat,
+    SubSat,
+    MulSat,
+
+    // Memory/Stack Manipulation
+    PushConst(u16),
+    PushLocal(u8),
+    StoreLocal(u8),
+    PushGlobal(u16),
+    StoreGlobal(u16),
+    Pop,
+    PushImmediate(i16),
+    Box,
+    Unbox,
+    SetBox,
+    Array(u8),
+    ArraySet,
+    ArrayGet,
+    ArrayPush,
+    ArrayPop,
+    ArrayLen,
+    StoreLocalKeep(u8),
+
+    // Control Flow
+    Jump(u32),
+    JumpIfFalse(u32),
+    JumpIfTrue(u32),
+
+    // Comparisons and other operators
+    Equal,
+    NotEqual,
+    LessThan,
+    GreaterThan,
+    GreaterEqual,
+    LessEqual,
+    Not,
+    LogicalAnd,
+    LogicalOr,
+    Identical,
+
+    // Math
+    Math(MathFn),
+    ToInt(Rounding),
+    ToFloat,
+
+    // Symbolic
+    Symbol,
+    Substitute,
+    Evaluate,
+    ExprEqual,
+    Simplify,
+    Differentiate(u16),
+
+    // Functions
+    CallFunction(u16),
+    Return,
+
+    // Bitwise
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitNot,
+    Shl,
+    Shr,
+    UShr,
+    RotateLeft,
+    RotateRight,
+    PopCount,
+    LeadingZeros,
+    TrailingZeros,
+
+    // Testing ops
+    Print,
+
+    // No Op
+    NoOp,
+
+    // Superinstructions
+    AddLocalConst(u8, u16),                  // local, const
+    IncLocal(u8, i16),                       // local, delta
+    CompareLocalConstJump(u8, u16, u8, u32), // local, const, condition, target
+    PushLocalPair(u8, u8),
+
+    // Quickened: int-specialized forms the VM rewrites generic ops into at runtime. They are
+    // never decoded, and encode as their generic op.
+    AddInt,
+    SubInt,
+    MulInt,
+    EqualInt,
+    NotEqualInt,
+    LessThanInt,
+    GreaterThanInt,
+    GreaterEqualInt,
+    LessEqualInt,
+}
+
+impl Instr {
+    // Decode the instruction starting at offset
+    pub fn decode_at(code: &[u8], offset: usize) -> Result<Instr, VMError> {
+        let opcode = OpCode::try_from(code[offset])?;
+        if offset + opcode.size() > code.len() {
+            return Err(VMError::InvalidOperandSize(
+                code[offset],
+                (code.len() - offset - 1) as u8,
+            ));
+        }
+        let u8_arg = || code[offset + 1];
+        let u16_arg = || u16::from_le_bytes([code[offset + 1], code[offset + 2]]);
+        let u16_at = |at: usize| u16::from_le_bytes([code[offset + at], code[offset + at + 1]]);
+        let u32_at = |at: usize| {
+            u32::from_le_bytes([
+                code[offset + at],
+                code[offset + at + 1],
+                code[offset + at + 2],
+                code[offset + at + 3],
+            ])
+        };
+        let u32_arg = || u32_at(1);
+        let instr = match opcode {
+            OpCode::Add => Instr::Add,
+            OpCode::Sub => Instr::Sub,
+            OpCode::Mul => Instr::Mul,
+            OpCode::Div => Instr::Div,
+            OpCode::DivInt => Instr::DivInt,
+            OpCode::Mod => Instr::Mod,
+            OpCode::DivExact => Instr::DivExact,
+            OpCode::AddWrap => Instr::AddWrap,
+            OpCode::SubWrap => Instr::SubWrap,
+            OpCode::MulWrap => Instr::MulWrap,
+            OpCode::AddChecked => Instr::AddChecked,
+            OpCode::SubChecked => Instr::SubChecked,
+            OpCode::MulChecked => Instr::MulChecked,
+            OpCode::AddSat => Instr::AddSat,
+            OpCode::SubSat => Instr::SubSat,
+            OpCode::MulSat => Instr::MulSat,
+            OpCode::PushConst => Instr::PushConst(u16_arg()),
+            OpCode::PushLocal => Instr::PushLocal(u8_arg()),
+            OpCode::StoreLocal => Instr::StoreLocal(u8_arg()),
+            OpCode::PushGlobal => Instr::PushGlobal(u16_arg()),
+            OpCode::StoreGlobal => Instr::StoreGlobal(u16_arg()),
+            OpCode::Pop => Instr::Pop,
+            OpCode::PushImmediate => Instr::PushImmediate(u16_arg() as i16),
+            OpCode::Box => Instr::Box,
+            OpCode::Unbox => Instr::Unbox,
+            OpCode::SetBox => Instr::SetBox,
+            OpCode::Array => Instr::Array(u8_arg()),
+            OpCode::ArraySet => Instr::ArraySet,
+            OpCode::ArrayGet => Instr::ArrayGet,
+            OpCode::ArrayPush => Instr::ArrayPush,
+            OpCode::ArrayPop => Instr::ArrayPop,
+            OpCode::ArrayLen => Instr::ArrayLen,
+            OpCode::StoreLocalKeep => Instr::StoreLocalKeep(u8_arg()),
+            OpCode::Jump => Instr::Jump(u32_arg()),
+            OpCode::JumpIfFalse => Instr::JumpIfFalse(u32_arg()),
+            OpCode::JumpIfTrue => Instr::JumpIfTrue(u32_arg()),
+            OpCode::Equal => Instr::Equal,
+            OpCode::NotEqual => Instr::NotEqual,
+            OpCode::LessThan => Instr::LessThan,
+            OpCode::GreaterThan => Instr::GreaterThan,
+            OpCode::GreaterEqual => Instr::GreaterEqual,
+            OpCode::LessEqual => Instr::LessEqual,
+            OpCode::Not => Instr::Not,
+            OpCode::LogicalAnd => Instr::LogicalAnd,
+            OpCode::LogicalOr => Instr::LogicalOr,
+            OpCode::Identical => Instr::Identical,
+            OpCode::Math => Instr::Math(MathFn::try_from(u8_arg())?),
+            OpCode::ToInt => Instr::ToInt(Rounding::try_from(u8_arg())?),
+            OpCode::ToFloat => Instr::ToFloat,
+            OpCode::Symbol => Instr::Symbol,
+            OpCode::Substitute => Instr::Substitute,
+            OpCode::Evaluate => Instr::Evaluate,
+            OpCode::ExprEqual => Instr::ExprEqual,
+            OpCode::Simplify => Instr::Simplify,
+            OpCode::Differentiate => Instr::Differentiate(u16_arg()),
+            OpCode::CallFunction => Instr::CallFunction(u16_arg()),
+            OpCode::Return => Instr::Return,
+            OpCode::BitAnd => Instr::BitAnd,
+            OpCode::BitOr => Instr::BitOr,
+            OpCode::BitXor => Instr::BitXor,
+            OpCode::BitNot => Instr::BitNot,
+            OpCode::Shl => Instr::Shl,
+            OpCode::Shr => Instr::Shr,
+            OpCode::UShr => Instr::UShr,
+            OpCode::RotateLeft => Instr::RotateLeft,
+            OpCode::RotateRight => Instr::RotateRight,
+            OpCode::PopCount => Instr::PopCount,
+            OpCode::LeadingZeros => Instr::LeadingZeros,
+            OpCode::TrailingZeros => Instr::TrailingZeros,
+            OpCode::Print => Instr::Print,
+            OpCode::NoOp => Instr::NoOp,
+            OpCode::AddLocalConst => Instr::AddLocalConst(u8_arg(), u16_at(2)),
+            OpCode::IncLocal => Instr::IncLocal(u8_arg(), u16_at(2) as i16),
+            OpCode::CompareLocalConstJump => {
+                let cond = code[offset + 4];
+                if Instr::split_condition(cond).is_none() {
+                    return Err(VMError::InvalidCondition(cond));
+                }
+                Instr::CompareLocalConstJump(u8_arg(), u16_at(2), cond, u32_at(5))
+            }
+            OpCode::PushLocalPair => Instr::PushLocalPair(u8_arg(), code[offset + 2]),
+        };
+        Ok(instr)
+    }
+
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            Instr::Add => OpCode::Add,
+            Instr::Sub => OpCode::Sub,
+            Instr::Mul => OpCode::Mul,
+            Instr::Div => OpCode::Div,
+            Instr::DivInt => OpCode::DivInt,
+            Instr::Mod => OpCode::Mod,
+            Instr::DivExact => OpCode::DivExact,
+            Instr::AddWrap => OpCode::AddWrap,
+            Instr::SubWrap => OpCode::SubWrap,
+            Instr::MulWrap => OpCode::MulWrap,
+            Instr::AddChecked => OpCode::AddChecked,
+            Instr::SubChecked => OpCode::SubChecked,
+            Instr::MulChecked => OpCode::MulChecked,
+            Instr::AddSat => OpCode::AddSat,
+            Instr::SubSat => OpCode::SubSat,
+            Instr::MulSat => OpCode::MulSat,
+            Instr::PushConst(_) => OpCode::PushConst,
+            Instr::PushLocal(_) => OpCode::PushLocal,
+            Instr::StoreLocal(_) => OpCode::StoreLocal,
+            Instr::PushGlobal(_) => OpCode::PushGlobal,
+            Instr::StoreGlobal(_) => OpCode::StoreGlobal,
+            Instr::Pop => OpCode::Pop,
+            Instr::PushImmediate(_) => OpCode::PushImmediate,
+            Instr::Box => OpCode::Box,
+            Instr::Unbox => OpCode::Unbox,
+            Instr::SetBox => OpCode::SetBox,
+            Instr::Array(_) => OpCode::Array,
+            Instr::ArraySet => OpCode::ArraySet,
+            Instr::ArrayGet => OpCode::ArrayGet,
+            Instr::ArrayPush => OpCode::ArrayPush,
+            Instr::ArrayPop => OpCode::ArrayPop,
+            Instr::ArrayLen => OpCode::ArrayLen,
+            Instr::StoreLocalKeep(_) => OpCode::StoreLocalKeep,
+            Instr::Jump(_) => OpCode::Jump,
+            Instr::JumpIfFalse(_) => OpCode::JumpIfFalse,
+            Instr::JumpIfTrue(_) => OpCode::JumpIfTrue,
+            Instr::Equal => OpCode::Equal,
+            Instr::NotEqual => OpCode::NotEqual,
+            Instr::LessThan => OpCode::LessThan,
+            Instr::GreaterThan => OpCode::GreaterThan,
+            Instr::GreaterEqual => OpCode::GreaterEqual,
+            Instr::LessEqual => OpCode::LessEqual,
+            Instr::Not => OpCode::Not,
+            Instr::LogicalAnd => OpCode::LogicalAnd,
+            Instr::LogicalOr => OpCode::LogicalOr,
+            Instr::Identical => OpCode::Identical,
+            Instr::Math(_) => OpCode::Math,
+            Instr::ToInt(_) => OpCode::ToInt,
+            Instr::ToFloat => OpCode::ToFloat,
+            Instr::Symbol => OpCode::Symbol,
+            Instr::Substitute => OpCode::Substitute,
+            Instr::Evaluate => OpCode::Evaluate,
+            Instr::ExprEqual => OpCode::ExprEqual,
+            Instr::Simplify => OpCode::Simplify,
+            Instr::Differentiate(_) => OpCode::Differentiate,
+            Instr::CallFunction(_) => OpCode::CallFunction,
+            Instr::Return => OpCode::Return,
+            Instr::BitAnd => OpCode::BitAnd,
+            Instr::BitOr => OpCode::BitOr,
+            Instr::BitXor => OpCode::BitXor,
+            Instr::BitNot => OpCode::BitNot,
+            Instr::Shl => OpCode::Shl,
+            Instr::Shr => OpCode::Shr,
+            Instr::UShr => OpCode::UShr,
+            Instr::RotateLeft => OpCode::RotateLeft,
+            Instr::RotateRight => OpCode::RotateRight,
+            Instr::PopCount => OpCode::PopCount,
+            Instr::LeadingZeros => OpCode::LeadingZeros,
+            Instr::TrailingZeros => OpCode::TrailingZeros,
+            Instr::Print => OpCode::Print,
+            Instr::NoOp => OpCode::NoOp,
+            Instr::AddLocalConst(..) => OpCode::AddLocalConst,
+            Instr::IncLocal(..) => OpCode::IncLocal,
+            Instr::CompareLocalConstJump(..) => OpCode::CompareLocalConstJump,
+            Instr::PushLocalPair(..) => OpCode::PushLocalPair,
+            Instr::AddInt => OpCode::Add,
+            Instr::SubInt => OpCode::Sub,
+            Instr::MulInt => OpCode::Mul,
+            Instr::EqualInt => OpCode::Equal,
+            Instr::NotEqualInt => OpCode::NotEqual,
+            Instr::LessThanInt => OpCode::LessThan,
+            Instr::GreaterThanInt => OpCode::GreaterThan,
+            Instr::GreaterEqualInt => OpCode::GreaterEqual,
+            Instr::LessEqualInt => OpCode::LessEqual,
+        }
+    }
+
+    // The int-specialized form of a generic op
+    pub fn quickened(&self) -> Option<Instr> {
+        match self {
+            Instr::Add => Some(Instr::AddInt),
+            Instr::Sub => Some(Instr::SubInt),
+            Instr::Mul => Some(Instr::MulInt),
+            Instr::Equal => Some(Instr::EqualInt),
+            Instr::NotEqual => Some(Instr::NotEqualInt),
+            Instr::LessThan => Some(Instr::LessThanInt),
+            Instr::GreaterThan => Some(Instr::GreaterThanInt),
+            Instr::GreaterEqual => Some(Instr::GreaterEqualInt),
+            Instr::LessEqual => Some(Instr::LessEqualInt),
+            _ => None,
+        }
+    }
+
+    // The generic op a quickened instruction falls back to
+    pub fn generic(&self) -> Instr {
+        match self {
+            Instr::AddInt => Instr::Add,
+            Instr::SubInt => Instr::Sub,
+            Instr::MulInt => Instr::Mul,
+            Instr::EqualInt => Instr::Equal,
+            Instr::NotEqualInt => Instr::NotEqual,
+            Instr::LessThanInt => Instr::LessThan,
+            Instr::GreaterThanInt => Instr::GreaterThan,
+            Instr::GreaterEqualInt => Instr::GreaterEqual,
+            Instr::LessEqualInt => Instr::LessEqual,
+            _ => *self,
+        }
+    }
+
+    // The comparison and jump sense a CompareLocalConstJump condition stands for. The bool is
+    // true when it jumps if the comparison holds.
+    pub fn split_condition(cond: u8) -> Option<(Instr, bool)> {
+        let cmp = match OpCode::try_from(cond & !JUMP_IF_FALSE).ok()? {
+            OpCode::Equal => Instr::Equal,
+            OpCode::NotEqual => Instr::NotEqual,
+            OpCode::LessThan => Instr::LessThan,
+            OpCode::GreaterThan => Instr::GreaterThan,
+            OpCode::GreaterEqual => Instr::GreaterEqual,
+            OpCode::LessEqual => Instr::LessEqual,
+            _ => return None,
+        };
+        Some((cmp, cond & JUMP_IF_FALSE == 0))
+    }
+
+    // Pack a comparison and jump sense into a CompareLocalConstJump condition
+    pub fn condition(cmp: Instr, jump_if: bool) -> Option<u8> {
+        cmp.comparison()?;
+        let sense = if jump_if { 0 } else { JUMP_IF_FALSE };
+        Some(cmp.opcode() as u8 | sense)
+    }
+
+    // The operator behind a generic comparison
+    pub fn comparison(&self) -> Option<ops::BinaryOp> {
+        match self {
+            Instr::Equal => Some(ops::equal),
+            Instr::NotEqual => Some(ops::not_equal),
+            Instr::LessThan => Some(ops::less_than),
+            Instr::GreaterThan => Some(ops::greater_than),
+            Instr::GreaterEqual => Some(ops::greater_equal),
+            Instr::LessEqual => Some(ops::less_equal),
+            _ => None,
+        }
+    }
+
+    // The overflow mode and operator behind an Int op with a fixed mode
+    pub fn overflow(&self) -> Option<(Overflow, ops::BinaryOp)> {
+        match self {
+            Instr::AddWrap => Some((Overflow::Wrapping, ops::add)),
+            Instr::SubWrap => Some((Overflow::Wrapping, ops::sub)),
+            Instr::MulWrap => Some((Overflow::Wrapping, ops::mul)),
+            Instr::AddChecked => Some((Overflow::Checked, ops::add)),
+            Instr::SubChecked => Some((Overflow::Checked, ops::sub)),
+            Instr::MulChecked => Some((Overflow::Checked, ops::mul)),
+            Instr::AddSat => Some((Overflow::Saturating, ops::add)),
+            Instr::SubSat => Some((Overflow::Saturating, ops::sub)),
+            Instr::MulSat => Some((Overflow::Saturating, ops::mul)),
+            _ => None,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.opcode().size()
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.opcode() as u8);
+        match *self {
+            Instr::PushLocal(arg)
+            | Instr::StoreLocal(arg)
+            | Instr::StoreLocalKeep(arg)
+            | Instr::Array(arg) => out.push(arg),
+            Instr::Math(f) => out.push(f as u8),
+            Instr::ToInt(mode) => out.push(mode as u8),
+            Instr::PushConst(arg)
+            | Instr::PushGlobal(arg)
+            | Instr::StoreGlobal(arg)
+            | Instr::CallFunction(arg)
+            | Instr::Differentiate(arg) => out.extend_from_slice(&arg.to_le_bytes()),
+            Instr::PushImmediate(arg) => out.extend_from_slice(&arg.to_le_bytes()),
+            Instr::Jump(arg) | Instr::JumpIfFalse(arg) | Instr::JumpIfTrue(arg) => {
+                out.extend_from_slice(&arg.to_le_bytes())
+            }
+            Instr::AddLocalConst(local, idx) => {
+                out.push(local);
+                out.extend_from_slice(&idx.to_le_bytes());
+            }
+            Instr::IncLocal(local, delta) => {
+                out.push(local);
+                out.extend_from_slice(&delta.to_le_bytes());
+            }
+            Instr::CompareLocalConstJump(local, idx, cond, target) => {
+                out.push(local);
+                out.extend_from_slice(&idx.to_le_bytes());
+                out.push(cond);
+                out.extend_from_slice(&target.to_le_bytes());
+            }
+            Instr::PushLocalPair(first, second) => {
+                out.push(first);
+                out.push(second);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn jump_target(&self) -> Option<u32> {
+        match *self {
+            Instr::Jump(target)
+            | Instr::JumpIfFalse(target)
+            | Instr::JumpIfTrue(target)
+            | Instr::CompareLocalConstJump(_, _, _, target) => Some(target),
+            _ => None,
+        }
+    }
+
+    pub fn with_jump_target(&self, target: u32) -> Instr {
+        match self {
+            Instr::Jump(_) => Instr::Jump(target),
+            Instr::JumpIfFalse(_) => Instr::JumpIfFalse(target),
+            Instr::JumpIfTrue(_) => Instr::JumpIfTrue(target),
+            Instr::CompareLocalConstJump(local, idx, cond, _) => {
+                Instr::CompareLocalConstJump(*local, *idx, *cond, target)
+            }
+            _ => *self,
+        }
+    }
+}
+
+// Instructions with jump targets as instruction indices, alongside the byte offset each was
+// decoded from. A target equal to the instruction count means the end of the code.
+#[derive(Debug, Clone)]
+pub struct IndexedCode {
+    pub instrs: Vec<Instr>,
+    pub offsets: Vec<usize>,
+}
+
+impl IndexedCode {
+    pub fn decode(code: &[u8]) -> Result<IndexedCode, VMError> {
+        let mut instrs: Vec<Instr> = Vec::new();
+        let mut offsets: Vec<usize> = Vec::new();
+        let mut offset = 0;
+        while offset < code.len() {
+            let instr = Instr::decode_at(code, offset)?;
+            instrs.push(instr);
+            offsets.push(offset);
+            offset += instr.size();
+        }
+        let mut indexed = IndexedCode { instrs, offsets };
+        for idx in 0..indexed.instrs.len() {
+            let instr = indexed.instrs[idx];
+            if let Some(target) = instr.jump_target() {
+                let Some(target_idx) = indexed.index_of(target as usize, code.len()) else {
+                    return Err(VMError::InvalidJumpTarget(target as usize));
+                };
+                indexed.instrs[idx] = instr.with_jump_target(target_idx as u32);
+            }
+        }
+        Ok(indexed)
+    }
+
+    // Index of the instruction starting at a byte address
+    pub fn index_of(&self, address: usize, code_len: usize) -> Option<usize> {
+        if address == code_len {
+            return Some(self.instrs.len());
+        }
+        self.offsets.binary_search(&address).ok()
+    }
+
+    // Encode back to bytes with byte address targets. Returns the code and the offset of every
+    // instruction, plus a final entry for the end of the code.
+    pub fn encode(instrs: &[Instr]) -> (Vec<u8>, Vec<usize>) {
+        let mut offsets: Vec<usize> = Vec::with_capacity(instrs.len() + 1);
+        let mut offset = 0;
+        for instr in instrs {
+            offsets.push(offset);
+            offset += instr.size();
+        }
+        offsets.push(offset);
+        let mut code: Vec<u8> = Vec::with_capacity(offset);
+        for instr in instrs {
+            match instr.jump_target() {
+                Some(target) => instr
+                    .with_jump_target(offsets[target as usize] as u32)
+                    .encode(&mut code),
+                None => instr.encode(&mut code),
+            }
+        }
+        (code, offsets)
+    }
+}
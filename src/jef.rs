@@ -4,20 +4,67 @@ use crate::{
     bytecode::Bytecode,
     error::JEFError,
     function::Function,
+    math::{MathFn, Rounding},
     opcode::OpCode,
     value::{HeapString, Value},
 };
 
+use num_bigint::BigInt;
+use num_complex::Complex64;
+use num_rational::BigRational;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum JEFValue {
     Int(i64),
+    // Decimal digits, since JSON numbers can't hold it
+    BigInt(String),
+    // Numerator and denominator as digits, like "3/4"
+    Rational(String),
     Float(f64),
+    // Real and imaginary parts
+    Complex(f64, f64),
     String(String),
     Bool(bool),
 }
 
+impl JEFValue {
+    // A const pool value as JEF. None for values that can't be consts.
+    pub fn from_value(val: &Value) -> Option<JEFValue> {
+        match val {
+            Value::Int(v) => Some(JEFValue::Int(*v)),
+            Value::BigInt(v) => Some(JEFValue::BigInt(v.to_string())),
+            Value::Rational(v) => Some(JEFValue::Rational(v.to_string())),
+            Value::Float(v) => Some(JEFValue::Float(*v)),
+            Value::Complex(v) => Some(JEFValue::Complex(v.re, v.im)),
+            Value::String(v) => Some(JEFValue::String(v.to_string())),
+            Value::Bool(v) => Some(JEFValue::Bool(*v)),
+            _ => None,
+        }
+    }
+    pub fn to_value(self) -> Result<Value, JEFError> {
+        match self {
+            JEFValue::Int(v) => Ok(Value::Int(v)),
+            JEFValue::BigInt(v) => match v.parse::<BigInt>() {
+                Ok(big) => Ok(Value::from_bigint(big)),
+                Err(_) => Err(JEFError::InvalidArgument(format!("Invalid BigInt: {}", v))),
+            },
+            JEFValue::Rational(v) => match v.parse::<BigRational>() {
+                Ok(rational) => Ok(Value::from_rational(rational)),
+                Err(_) => Err(JEFError::InvalidArgument(format!(
+                    "Invalid Rational: {}",
+                    v
+                ))),
+            },
+            JEFValue::Float(v) => Ok(Value::Float(v)),
+            JEFValue::Complex(re, im) => Ok(Value::Complex(Complex64::new(re, im))),
+            JEFValue::Bool(v) => Ok(Value::Bool(v)),
+            JEFValue::String(v) => Ok(Value::String(HeapString::new(v))),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct JEF {
     pub consts: Vec<JEFValue>,
@@ -47,10 +94,11 @@ pub fn test_json() {
     };
     let json_text = serde_json::to_string_pretty(&test_jef).unwrap();
     println!("test_json {}", &json_text);
-    let json_obj: JEF = serde_json::from_str(&json_text.as_str()).unwrap();
+    let json_obj: JEF = serde_json::from_str(json_text.as_str()).unwrap();
     println!("{:?}", json_obj);
 }
 
+#[allow(clippy::needless_return)]
 pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
     let mut fix_labels: Vec<FixLabel> = Vec::new();
     let mut labels: HashMap<String, u32> = HashMap::new();
@@ -66,20 +114,13 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
 
     // Convert and push const pool
     for val in jef.consts {
-        let value: Value = match val {
-            JEFValue::Int(v) => Value::Int(v),
-            JEFValue::Float(v) => Value::Float(v),
-            JEFValue::Bool(v) => Value::Bool(v),
-            JEFValue::String(v) => Value::String(HeapString::new(v)),
-        };
-        bytecode.consts.push(value);
+        bytecode.consts.push(val.to_value()?);
     }
 
     // Clone JEF function pool into bytecode, to be modified later with function addresses
     bytecode.functions = jef.functions.clone();
 
-    let mut code_idx = 0;
-    for code in jef.code {
+    for (code_idx, code) in jef.code.into_iter().enumerate() {
         match code.0.as_str() {
             "Add" => {
                 check_arg_count(&code, 0, code_idx)?;
@@ -105,6 +146,46 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                 check_arg_count(&code, 0, code_idx)?;
                 bytecode.code.push(OpCode::Mod as u8);
             }
+            "DivExact" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::DivExact as u8);
+            }
+            "AddWrap" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::AddWrap as u8);
+            }
+            "SubWrap" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::SubWrap as u8);
+            }
+            "MulWrap" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::MulWrap as u8);
+            }
+            "AddChecked" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::AddChecked as u8);
+            }
+            "SubChecked" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::SubChecked as u8);
+            }
+            "MulChecked" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::MulChecked as u8);
+            }
+            "AddSat" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::AddSat as u8);
+            }
+            "SubSat" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::SubSat as u8);
+            }
+            "MulSat" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::MulSat as u8);
+            }
 
             // Memory/Stack Manipulation
             "PushConst" => {
@@ -120,7 +201,7 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                         return Err(JEFError::InvalidArgument(format!(
                             "Expected 16bit Integer at position: {}",
                             code_idx
-                        )))
+                        )));
                     }
                 }
             }
@@ -129,15 +210,13 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                 bytecode.code.push(OpCode::PushLocal as u8);
                 match code.1[0] {
                     JEFValue::Int(idx) => {
-                        let arg = u16::to_le_bytes(idx as u16);
-                        bytecode.code.push(arg[0]);
-                        bytecode.code.push(arg[1]);
+                        bytecode.code.push(idx as u8);
                     }
                     _ => {
                         return Err(JEFError::InvalidArgument(format!(
-                            "Expected 16bit Integer at position: {}",
+                            "Expected 8bit Integer at position: {}",
                             code_idx
-                        )))
+                        )));
                     }
                 }
             }
@@ -146,15 +225,28 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                 bytecode.code.push(OpCode::StoreLocal as u8);
                 match code.1[0] {
                     JEFValue::Int(idx) => {
-                        let arg = u16::to_le_bytes(idx as u16);
-                        bytecode.code.push(arg[0]);
-                        bytecode.code.push(arg[1]);
+                        bytecode.code.push(idx as u8);
                     }
                     _ => {
                         return Err(JEFError::InvalidArgument(format!(
-                            "Expected 16bit Integer at position: {}",
+                            "Expected 8bit Integer at position: {}",
                             code_idx
-                        )))
+                        )));
+                    }
+                }
+            }
+            "StoreLocalKeep" => {
+                check_arg_count(&code, 1, code_idx)?;
+                bytecode.code.push(OpCode::StoreLocalKeep as u8);
+                match code.1[0] {
+                    JEFValue::Int(idx) => {
+                        bytecode.code.push(idx as u8);
+                    }
+                    _ => {
+                        return Err(JEFError::InvalidArgument(format!(
+                            "Expected 8bit Integer at position: {}",
+                            code_idx
+                        )));
                     }
                 }
             }
@@ -171,7 +263,7 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                         return Err(JEFError::InvalidArgument(format!(
                             "Expected 16bit Integer at position: {}",
                             code_idx
-                        )))
+                        )));
                     }
                 }
             }
@@ -188,7 +280,7 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                         return Err(JEFError::InvalidArgument(format!(
                             "Expected 16bit Integer at position: {}",
                             code_idx
-                        )))
+                        )));
                     }
                 }
             }
@@ -209,7 +301,7 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                         return Err(JEFError::InvalidArgument(format!(
                             "Expected 16bit Integer at position: {}",
                             code_idx
-                        )))
+                        )));
                     }
                 }
             }
@@ -230,15 +322,13 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                 bytecode.code.push(OpCode::Array as u8);
                 match code.1[0] {
                     JEFValue::Int(val) => {
-                        let arg = u16::to_le_bytes(val as u16);
-                        bytecode.code.push(arg[0]);
-                        bytecode.code.push(arg[1]);
+                        bytecode.code.push(val as u8);
                     }
                     _ => {
                         return Err(JEFError::InvalidArgument(format!(
-                            "Expected 16bit Integer at position: {}",
+                            "Expected 8bit Integer at position: {}",
                             code_idx
-                        )))
+                        )));
                     }
                 }
             }
@@ -278,7 +368,7 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                         } else {
                             fix_labels.push(FixLabel {
                                 offset: bytecode.code.len(),
-                                label: label,
+                                label,
                             });
 
                             let location = u32::to_le_bytes(0);
@@ -310,7 +400,7 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                         } else {
                             fix_labels.push(FixLabel {
                                 offset: bytecode.code.len(),
-                                label: label,
+                                label,
                             });
 
                             let location = u32::to_le_bytes(0);
@@ -342,7 +432,7 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                         } else {
                             fix_labels.push(FixLabel {
                                 offset: bytecode.code.len(),
-                                label: label,
+                                label,
                             });
 
                             let location = u32::to_le_bytes(0);
@@ -398,6 +488,125 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                 check_arg_count(&code, 0, code_idx)?;
                 bytecode.code.push(OpCode::LogicalOr as u8);
             }
+            "Identical" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::Identical as u8);
+            }
+            "BitAnd" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::BitAnd as u8);
+            }
+            "BitOr" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::BitOr as u8);
+            }
+            "BitXor" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::BitXor as u8);
+            }
+            "BitNot" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::BitNot as u8);
+            }
+            "Shl" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::Shl as u8);
+            }
+            "Shr" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::Shr as u8);
+            }
+            "UShr" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::UShr as u8);
+            }
+            "RotateLeft" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::RotateLeft as u8);
+            }
+            "RotateRight" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::RotateRight as u8);
+            }
+            "PopCount" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::PopCount as u8);
+            }
+            "LeadingZeros" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::LeadingZeros as u8);
+            }
+            "TrailingZeros" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::TrailingZeros as u8);
+            }
+
+            // Symbolic
+            "Symbol" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::Symbol as u8);
+            }
+            "Substitute" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::Substitute as u8);
+            }
+            "Evaluate" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::Evaluate as u8);
+            }
+            "ExprEqual" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::ExprEqual as u8);
+            }
+            "Simplify" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::Simplify as u8);
+            }
+            "Differentiate" => {
+                check_arg_count(&code, 1, code_idx)?;
+                bytecode.code.push(OpCode::Differentiate as u8);
+                match code.1[0] {
+                    JEFValue::Int(idx) => {
+                        let arg = u16::to_le_bytes(idx as u16);
+                        bytecode.code.push(arg[0]);
+                        bytecode.code.push(arg[1]);
+                    }
+                    _ => {
+                        return Err(JEFError::InvalidArgument(format!(
+                            "Expected 16bit Integer at position: {}",
+                            code_idx
+                        )));
+                    }
+                }
+            }
+
+            // Conversions
+            "ToInt" => {
+                check_arg_count(&code, 1, code_idx)?;
+                bytecode.code.push(OpCode::ToInt as u8);
+                match code.1[0] {
+                    JEFValue::Int(mode) if Rounding::try_from(mode as u8).is_ok() => {
+                        bytecode.code.push(mode as u8);
+                    }
+                    _ => {
+                        return Err(JEFError::InvalidArgument(format!(
+                            "Expected rounding mode at position: {}",
+                            code_idx
+                        )));
+                    }
+                }
+            }
+            "ToFloat" => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::ToFloat as u8);
+            }
+
+            // Math, named by function
+            name if let Some(f) = MathFn::from_name(name) => {
+                check_arg_count(&code, 0, code_idx)?;
+                bytecode.code.push(OpCode::Math as u8);
+                bytecode.code.push(f as u8);
+            }
 
             // Functions
             "CallFunction" => {
@@ -415,7 +624,7 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                         return Err(JEFError::InvalidArgument(format!(
                             "Expected 16bit Integer at position: {}",
                             code_idx
-                        )))
+                        )));
                     }
                 }
             }
@@ -444,14 +653,12 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                 check_arg_count(&code, 1, code_idx)?;
                 match code.1[0].clone() {
                     JEFValue::String(label) => {
-                        if let Some(_) = labels.get(&label) {
+                        let location = bytecode.code.len();
+                        if labels.insert(label.clone(), location as u32).is_some() {
                             return Err(JEFError::DuplicateLabel(format!(
                                 "Duplicate label: {}, found at position: {}",
                                 &label, code_idx
                             )));
-                        } else {
-                            let location = bytecode.code.len();
-                            labels.insert(label, location as u32);
                         }
                     }
                     _ => {
@@ -470,7 +677,6 @@ pub fn assemble_json(file_name: &str) -> Result<Bytecode, JEFError> {
                 )));
             }
         }
-        code_idx += 1;
     }
 
     for label in fix_labels {
@@ -503,3 +709,61 @@ fn check_arg_count(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn big_consts_round_trip() {
+        let big = Value::from_bigint("-98765432109876543210".parse().unwrap());
+        let consts = vec![Value::Int(i64::MIN), big.clone()];
+        let jef: Vec<JEFValue> = consts.iter().filter_map(JEFValue::from_value).collect();
+        let json = serde_json::to_string(&jef).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"Int":-9223372036854775808},{"BigInt":"-98765432109876543210"}]"#
+        );
+        let parsed: Vec<JEFValue> = serde_json::from_str(&json).unwrap();
+        let values: Vec<Value> = parsed.into_iter().map(|v| v.to_value().unwrap()).collect();
+        assert_eq!(values, consts);
+        // Small enough BigInt consts are loaded as Ints
+        let small = JEFValue::BigInt("42".to_string()).to_value().unwrap();
+        assert_eq!(small, Value::Int(42));
+        assert!(JEFValue::BigInt("4x2".to_string()).to_value().is_err());
+    }
+
+    #[test]
+    fn rational_consts_round_trip() {
+        let half = Value::from_rational("2/4".parse().unwrap());
+        let jef = JEFValue::from_value(&half).unwrap();
+        assert_eq!(
+            serde_json::to_string(&jef).unwrap(),
+            r#"{"Rational":"1/2"}"#
+        );
+        assert_eq!(jef.to_value().unwrap(), half);
+        let whole = JEFValue::Rational("6/3".to_string()).to_value().unwrap();
+        assert_eq!(whole, Value::Int(2));
+        assert!(JEFValue::Rational("1/0".to_string()).to_value().is_err());
+    }
+
+    #[test]
+    fn complex_consts_and_math_ops() {
+        let z = Value::Complex(Complex64::new(2.0, -3.5));
+        let jef = JEFValue::from_value(&z).unwrap();
+        assert_eq!(
+            serde_json::to_string(&jef).unwrap(),
+            r#"{"Complex":[2.0,-3.5]}"#
+        );
+        assert_eq!(jef.to_value().unwrap(), z);
+        let json = r#"{"consts":[{"Complex":[0.0,1.0]}],"functions":[],
+            "code":[["PushConst",[{"Int":0}]],["Conj",[]],["Print",[]]]}"#;
+        let path = std::env::temp_dir().join("jef_complex_consts_and_math_ops.json");
+        fs::write(&path, json).unwrap();
+        let bytecode = assemble_json(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        let conj = [OpCode::Math as u8, MathFn::Conj as u8];
+        assert_eq!(&bytecode.code[3..5], &conj);
+    }
+}
@@ -0,0 +1,761 @@
+// Template JIT for Linux on x86-64. A function called often enough is compiled for the types of
+// the arguments it's called with, if everything it does is int, float or bool arithmetic,
+// comparisons, local loads and stores and jumps. Types are inferred from the arguments, so the
+// only guard is on the argument types at the call.
+//
+// Compiled code has no side effects beyond its own locals, so when it hits something it can't
+// handle (a zero divisor, or an Int overflowing) it gives up and the interpreter
+// runs the call from the start, doing whatever it always would.
+
+use crate::function::Function;
+use crate::instr::Instr;
+use crate::memory::Stack;
+use crate::value::Value;
+
+// Calls to a function before it's compiled
+pub const JIT_THRESHOLD: u32 = 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Ty {
+    Int,
+    Float,
+    Bool,
+    // A local not stored yet, or with different types on different paths
+    Undef,
+}
+
+impl Ty {
+    fn of(val: &Value) -> Option<Ty> {
+        match val {
+            Value::Int(_) => Some(Ty::Int),
+            Value::Float(_) => Some(Ty::Float),
+            Value::Bool(_) => Some(Ty::Bool),
+            _ => None,
+        }
+    }
+}
+
+// Values are passed in and out of compiled code as raw 64 bit slots
+fn to_raw(val: &Value) -> u64 {
+    match val {
+        Value::Int(v) => *v as u64,
+        Value::Float(v) => v.to_bits(),
+        Value::Bool(v) => *v as u64,
+        _ => 0,
+    }
+}
+
+fn from_raw(raw: u64, ty: Ty) -> Value {
+    match ty {
+        Ty::Int => Value::Int(raw as i64),
+        Ty::Float => Value::Float(f64::from_bits(raw)),
+        Ty::Bool => Value::Bool(raw != 0),
+        Ty::Undef => Value::NULL,
+    }
+}
+
+// Types of the stack and locals before an instruction
+#[derive(Debug, Clone, PartialEq)]
+struct State {
+    stack: Vec<Ty>,
+    locals: Vec<Ty>,
+}
+
+impl State {
+    fn pop(&mut self) -> Option<Ty> {
+        self.stack.pop()
+    }
+    fn local(&self, idx: u8) -> Option<Ty> {
+        match self.locals.get(idx as usize) {
+            Some(Ty::Undef) | None => None,
+            Some(ty) => Some(*ty),
+        }
+    }
+    // Combine with the state another path arrives in. None if the stacks disagree.
+    fn merge(&self, other: &State) -> Option<State> {
+        if self.stack != other.stack {
+            return None;
+        }
+        let locals = self
+            .locals
+            .iter()
+            .zip(&other.locals)
+            .map(|(a, b)| if a == b { *a } else { Ty::Undef })
+            .collect();
+        Some(State {
+            stack: self.stack.clone(),
+            locals,
+        })
+    }
+}
+
+fn const_ty(consts: &[Value], idx: u16) -> Option<Ty> {
+    consts.get(idx as usize).and_then(Ty::of)
+}
+
+// Result type of a binary operator, or None if it would fail or isn't compiled
+fn binary_ty(instr: Instr, lhs: Ty, rhs: Ty) -> Option<Ty> {
+    match (instr, lhs, rhs) {
+        (Instr::Add | Instr::Sub | Instr::Mul | Instr::Div, Ty::Int, Ty::Int) => Some(Ty::Int),
+        (Instr::Add | Instr::Sub | Instr::Mul | Instr::Div, Ty::Float, Ty::Float) => {
+            Some(Ty::Float)
+        }
+        (Instr::DivInt | Instr::Mod, Ty::Int, Ty::Int) => Some(Ty::Int),
+        (Instr::Equal | Instr::NotEqual, Ty::Int, Ty::Int)
+        | (Instr::Equal | Instr::NotEqual, Ty::Bool, Ty::Bool) => Some(Ty::Bool),
+        (
+            Instr::LessThan | Instr::GreaterThan | Instr::GreaterEqual | Instr::LessEqual,
+            Ty::Int | Ty::Float,
+            _,
+        ) if lhs == rhs => Some(Ty::Bool),
+        (Instr::LogicalAnd | Instr::LogicalOr, Ty::Bool, Ty::Bool) => Some(Ty::Bool),
+        _ => None,
+    }
+}
+
+// Apply an instruction's effect on types. None if it isn't supported with these types.
+fn transfer(instr: Instr, state: &mut State, consts: &[Value]) -> Option<()> {
+    match instr {
+        Instr::Add
+        | Instr::Sub
+        | Instr::Mul
+        | Instr::Div
+        | Instr::DivInt
+        | Instr::Mod
+        | Instr::Equal
+        | Instr::NotEqual
+        | Instr::LessThan
+        | Instr::GreaterThan
+        | Instr::GreaterEqual
+        | Instr::LessEqual
+        | Instr::LogicalAnd
+        | Instr::LogicalOr => {
+            let rhs = state.pop()?;
+            let lhs = state.pop()?;
+            state.stack.push(binary_ty(instr, lhs, rhs)?);
+        }
+        Instr::Not => {
+            if state.pop()? != Ty::Bool {
+                return None;
+            }
+            state.stack.push(Ty::Bool);
+        }
+        Instr::PushConst(idx) => state.stack.push(const_ty(consts, idx)?),
+        Instr::PushImmediate(_) => state.stack.push(Ty::Int),
+        Instr::PushLocal(idx) => state.stack.push(state.local(idx)?),
+        Instr::StoreLocal(idx) => {
+            let ty = state.pop()?;
+            *state.locals.get_mut(idx as usize)? = ty;
+        }
+        Instr::StoreLocalKeep(idx) => {
+            let ty = *state.stack.last()?;
+            *state.locals.get_mut(idx as usize)? = ty;
+        }
+        Instr::Pop => {
+            state.pop()?;
+        }
+        Instr::JumpIfFalse(_) | Instr::JumpIfTrue(_) => {
+            if state.pop()? != Ty::Bool {
+                return None;
+            }
+        }
+        Instr::Return => {
+            state.pop()?;
+        }
+        Instr::Jump(_) | Instr::NoOp => {}
+        Instr::AddLocalConst(local, idx) => {
+            let ty = binary_ty(Instr::Add, state.local(local)?, const_ty(consts, idx)?)?;
+            state.stack.push(ty);
+        }
+        Instr::IncLocal(local, _) => {
+            if state.local(local)? != Ty::Int {
+                return None;
+            }
+        }
+        Instr::CompareLocalConstJump(local, idx, cond, _) => {
+            let (cmp, _) = Instr::split_condition(cond)?;
+            binary_ty(cmp, state.local(local)?, const_ty(consts, idx)?)?;
+        }
+        Instr::PushLocalPair(first, second) => {
+            state.stack.push(state.local(first)?);
+            state.stack.push(state.local(second)?);
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+// Types before every instruction reachable from the function's entry, and the return type
+fn infer(
+    instrs: &[Instr],
+    func: Function,
+    args: &[Ty],
+    consts: &[Value],
+) -> Option<(Vec<Option<State>>, Ty)> {
+    if func.address >= instrs.len() {
+        return None;
+    }
+    let mut states: Vec<Option<State>> = vec![None; instrs.len()];
+    let mut locals = vec![Ty::Undef; func.locals as usize];
+    locals.get_mut(..args.len())?.copy_from_slice(args);
+    states[func.address] = Some(State {
+        stack: Vec::new(),
+        locals,
+    });
+    let mut ret: Option<Ty> = None;
+    let mut work = vec![func.address];
+    while let Some(idx) = work.pop() {
+        let instr = instrs[idx].generic();
+        let mut state = states[idx].clone()?;
+        if let Instr::Return = instr {
+            let ty = *state.stack.last()?;
+            if ret.is_some_and(|r| r != ty) {
+                return None;
+            }
+            ret = Some(ty);
+        }
+        transfer(instr, &mut state, consts)?;
+        let successors: Vec<usize> = match instr {
+            Instr::Return => Vec::new(),
+            Instr::Jump(target) => vec![target as usize],
+            _ => match instr.jump_target() {
+                Some(target) => vec![target as usize, idx + 1],
+                None => vec![idx + 1],
+            },
+        };
+        for next in successors {
+            // Running off the end of the code isn't something compiled code can do
+            if next >= instrs.len() {
+                return None;
+            }
+            let merged = match &states[next] {
+                Some(existing) => existing.merge(&state)?,
+                None => state.clone(),
+            };
+            if states[next].as_ref() != Some(&merged) {
+                states[next] = Some(merged);
+                work.push(next);
+            }
+        }
+    }
+    Some((states, ret?))
+}
+
+// Machine code emitter. Compiled functions take a pointer to their slots in rdi: the locals
+// followed by one slot per stack depth. They return 0 with the result in slot 0, or 1 to have
+// the interpreter run the call instead.
+struct Asm {
+    code: Vec<u8>,
+    // Offsets of rel32 operands to patch, and the instruction index they jump to
+    jumps: Vec<(usize, usize)>,
+    // Offsets of rel32 operands that jump to the bail out
+    bails: Vec<usize>,
+}
+
+impl Asm {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+    // An instruction addressing [rdi + slot * 8]
+    fn slot_op(&mut self, opcode: &[u8], reg: u8, slot: usize) {
+        self.bytes(opcode);
+        self.code.push(0x87 | (reg << 3));
+        self.bytes(&((slot * 8) as i32).to_le_bytes());
+    }
+    fn load_rax(&mut self, slot: usize) {
+        self.slot_op(&[0x48, 0x8B], 0, slot);
+    }
+    fn load_rcx(&mut self, slot: usize) {
+        self.slot_op(&[0x48, 0x8B], 1, slot);
+    }
+    fn store_rax(&mut self, slot: usize) {
+        self.slot_op(&[0x48, 0x89], 0, slot);
+    }
+    fn load_xmm0(&mut self, slot: usize) {
+        self.slot_op(&[0xF2, 0x0F, 0x10], 0, slot);
+    }
+    fn load_xmm1(&mut self, slot: usize) {
+        self.slot_op(&[0xF2, 0x0F, 0x10], 1, slot);
+    }
+    fn store_xmm0(&mut self, slot: usize) {
+        self.slot_op(&[0xF2, 0x0F, 0x11], 0, slot);
+    }
+    fn mov_rax_imm(&mut self, imm: u64) {
+        self.bytes(&[0x48, 0xB8]);
+        self.bytes(&imm.to_le_bytes());
+    }
+    fn mov_rcx_imm(&mut self, imm: u64) {
+        self.bytes(&[0x48, 0xB9]);
+        self.bytes(&imm.to_le_bytes());
+    }
+    // movq xmm1, rcx
+    fn rcx_to_xmm1(&mut self) {
+        self.bytes(&[0x66, 0x48, 0x0F, 0x6E, 0xC9]);
+    }
+    // Jump with a rel32 operand to instruction target
+    fn jump(&mut self, opcode: &[u8], target: usize) {
+        self.bytes(opcode);
+        self.jumps.push((self.code.len(), target));
+        self.bytes(&[0; 4]);
+    }
+    fn bail_if(&mut self, opcode: &[u8]) {
+        self.bytes(opcode);
+        self.bails.push(self.code.len());
+        self.bytes(&[0; 4]);
+    }
+
+    // rax = rax op rcx for ints, xmm0 = xmm0 op xmm1 for floats, rax = 0 or 1 for comparisons
+    fn binary(&mut self, instr: Instr, ty: Ty) {
+        match (instr, ty) {
+            // Overflow bails, for the interpreter to handle in the VM's overflow mode
+            (Instr::Add, Ty::Int) => {
+                self.bytes(&[0x48, 0x01, 0xC8]);
+                self.bail_if(&[0x0F, 0x80]);
+            }
+            (Instr::Sub, Ty::Int) => {
+                self.bytes(&[0x48, 0x29, 0xC8]);
+                self.bail_if(&[0x0F, 0x80]);
+            }
+            (Instr::Mul, Ty::Int) => {
+                self.bytes(&[0x48, 0x0F, 0xAF, 0xC1]);
+                self.bail_if(&[0x0F, 0x80]);
+            }
+            (Instr::Div | Instr::DivInt | Instr::Mod, Ty::Int) => self.int_divide(instr),
+            (Instr::Add, Ty::Float) => self.bytes(&[0xF2, 0x0F, 0x58, 0xC1]),
+            (Instr::Sub, Ty::Float) => self.bytes(&[0xF2, 0x0F, 0x5C, 0xC1]),
+            (Instr::Mul, Ty::Float) => self.bytes(&[0xF2, 0x0F, 0x59, 0xC1]),
+            (Instr::Div, Ty::Float) => {
+                // xorpd xmm2, xmm2; ucomisd xmm1, xmm2; a NaN divisor is fine, zero isn't
+                self.bytes(&[0x66, 0x0F, 0x57, 0xD2, 0x66, 0x0F, 0x2E, 0xCA]);
+                self.bytes(&[0x7A, 0x06]);
+                self.bail_if(&[0x0F, 0x84]);
+                self.bytes(&[0xF2, 0x0F, 0x5E, 0xC1]);
+            }
+            (Instr::LogicalAnd, _) => self.bytes(&[0x48, 0x21, 0xC8]),
+            (Instr::LogicalOr, _) => self.bytes(&[0x48, 0x09, 0xC8]),
+            (_, Ty::Float) => {
+                // ucomisd leaves a NaN comparison unordered, which seta/setae treat as false.
+                // Less-than forms compare the other way round.
+                let (swap, setcc) = match instr {
+                    Instr::GreaterThan => (false, 0x97),
+                    Instr::GreaterEqual => (false, 0x93),
+                    Instr::LessThan => (true, 0x97),
+                    _ => (true, 0x93),
+                };
+                let modrm = if swap { 0xC8 } else { 0xC1 };
+                self.bytes(&[0x66, 0x0F, 0x2E, modrm]);
+                self.bytes(&[0x0F, setcc, 0xC0, 0x0F, 0xB6, 0xC0]);
+            }
+            _ => {
+                let setcc = match instr {
+                    Instr::Equal => 0x94,
+                    Instr::NotEqual => 0x95,
+                    Instr::LessThan => 0x9C,
+                    Instr::GreaterThan => 0x9F,
+                    Instr::GreaterEqual => 0x9D,
+                    _ => 0x9E,
+                };
+                // cmp rax, rcx; setcc al; movzx eax, al
+                self.bytes(&[0x48, 0x39, 0xC8, 0x0F, setcc, 0xC0, 0x0F, 0xB6, 0xC0]);
+            }
+        }
+    }
+
+    // Truncating division and remainder. idiv faults on a zero divisor and on i64::MIN / -1,
+    // so zero bails and -1 is handled without it: negating i64::MIN overflows and bails too.
+    fn int_divide(&mut self, instr: Instr) {
+        // test rcx, rcx; jz bail
+        self.bytes(&[0x48, 0x85, 0xC9]);
+        self.bail_if(&[0x0F, 0x84]);
+        match instr {
+            Instr::Mod => {
+                // cmp rcx, -1; jne divide; xor eax, eax; jmp done
+                self.bytes(&[0x48, 0x83, 0xF9, 0xFF, 0x75, 0x04, 0x31, 0xC0, 0xEB, 0x08]);
+                // divide: cqo; idiv rcx; mov rax, rdx; done:
+                self.bytes(&[0x48, 0x99, 0x48, 0xF7, 0xF9, 0x48, 0x89, 0xD0]);
+            }
+            _ => {
+                // cmp rcx, -1; jne divide; neg rax; jo bail; jmp done
+                self.bytes(&[0x48, 0x83, 0xF9, 0xFF, 0x75, 0x0B, 0x48, 0xF7, 0xD8]);
+                self.bail_if(&[0x0F, 0x80]);
+                self.bytes(&[0xEB, 0x05]);
+                // divide: cqo; idiv rcx; done:
+                self.bytes(&[0x48, 0x99, 0x48, 0xF7, 0xF9]);
+            }
+        }
+    }
+}
+
+fn compile(instrs: &[Instr], states: &[Option<State>], locals: usize, consts: &[Value]) -> Asm {
+    let mut asm = Asm {
+        code: Vec::new(),
+        jumps: Vec::new(),
+        bails: Vec::new(),
+    };
+    let mut offsets = vec![0; instrs.len()];
+    let slot = |depth: usize| locals + depth;
+    let raw_const = |idx: u16| to_raw(&consts[idx as usize]);
+    for (idx, state) in states.iter().enumerate() {
+        offsets[idx] = asm.code.len();
+        let Some(state) = state else {
+            continue;
+        };
+        let depth = state.stack.len();
+        let top = || slot(depth - 1);
+        match instrs[idx].generic() {
+            Instr::Not => {
+                // xor rax, 1
+                asm.load_rax(top());
+                asm.bytes(&[0x48, 0x83, 0xF0, 0x01]);
+                asm.store_rax(top());
+            }
+            Instr::PushConst(k) => {
+                asm.mov_rax_imm(raw_const(k));
+                asm.store_rax(slot(depth));
+            }
+            Instr::PushImmediate(val) => {
+                asm.mov_rax_imm(val as i64 as u64);
+                asm.store_rax(slot(depth));
+            }
+            Instr::PushLocal(local) => {
+                asm.load_rax(local as usize);
+                asm.store_rax(slot(depth));
+            }
+            Instr::StoreLocal(local) | Instr::StoreLocalKeep(local) => {
+                asm.load_rax(top());
+                asm.store_rax(local as usize);
+            }
+            Instr::Pop | Instr::NoOp => {}
+            Instr::Jump(target) => asm.jump(&[0xE9], target as usize),
+            Instr::JumpIfFalse(target) | Instr::JumpIfTrue(target) => {
+                // test rax, rax; jz/jnz
+                asm.load_rax(top());
+                asm.bytes(&[0x48, 0x85, 0xC0]);
+                let jcc = match instrs[idx].generic() {
+                    Instr::JumpIfTrue(_) => 0x85,
+                    _ => 0x84,
+                };
+                asm.jump(&[0x0F, jcc], target as usize);
+            }
+            Instr::Return => {
+                // mov [rdi], rax; xor eax, eax; ret
+                asm.load_rax(top());
+                asm.bytes(&[0x48, 0x89, 0x07, 0x31, 0xC0, 0xC3]);
+            }
+            Instr::IncLocal(local, delta) => {
+                asm.load_rax(local as usize);
+                asm.mov_rcx_imm(delta as i64 as u64);
+                asm.binary(Instr::Add, Ty::Int);
+                asm.store_rax(local as usize);
+            }
+            Instr::AddLocalConst(local, k) => {
+                let ty = state.locals[local as usize];
+                load_operands(&mut asm, ty, local as usize, Operand::Const(raw_const(k)));
+                asm.binary(Instr::Add, ty);
+                store_result(&mut asm, ty, slot(depth));
+            }
+            Instr::CompareLocalConstJump(local, k, cond, target) => {
+                let (cmp, jump_if) = Instr::split_condition(cond).expect("checked by infer");
+                let ty = state.locals[local as usize];
+                load_operands(&mut asm, ty, local as usize, Operand::Const(raw_const(k)));
+                asm.binary(cmp, ty);
+                asm.bytes(&[0x48, 0x85, 0xC0]);
+                asm.jump(&[0x0F, if jump_if { 0x85 } else { 0x84 }], target as usize);
+            }
+            Instr::PushLocalPair(first, second) => {
+                asm.load_rax(first as usize);
+                asm.store_rax(slot(depth));
+                asm.load_rax(second as usize);
+                asm.store_rax(slot(depth + 1));
+            }
+            instr => {
+                // Binary operators
+                let ty = state.stack[depth - 2];
+                load_operands(&mut asm, ty, slot(depth - 2), Operand::Slot(top()));
+                asm.binary(instr, ty);
+                let result = binary_ty(instr, ty, ty).expect("checked by infer");
+                store_result(&mut asm, result, slot(depth - 2));
+            }
+        }
+    }
+    let bail = asm.code.len();
+    // mov eax, 1; ret
+    asm.bytes(&[0xB8, 0x01, 0x00, 0x00, 0x00, 0xC3]);
+    let patch = |code: &mut Vec<u8>, at: usize, to: usize| {
+        let rel = to as i64 - (at as i64 + 4);
+        code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    };
+    for (at, target) in std::mem::take(&mut asm.jumps) {
+        patch(&mut asm.code, at, offsets[target]);
+    }
+    for at in std::mem::take(&mut asm.bails) {
+        patch(&mut asm.code, at, bail);
+    }
+    asm
+}
+
+enum Operand {
+    Slot(usize),
+    Const(u64),
+}
+
+// Load a left operand slot and a right operand into rax and rcx, or xmm0 and xmm1 for floats
+fn load_operands(asm: &mut Asm, ty: Ty, lhs: usize, rhs: Operand) {
+    match (ty, rhs) {
+        (Ty::Float, Operand::Slot(rhs)) => {
+            asm.load_xmm0(lhs);
+            asm.load_xmm1(rhs);
+        }
+        (Ty::Float, Operand::Const(raw)) => {
+            asm.load_xmm0(lhs);
+            asm.mov_rcx_imm(raw);
+            asm.rcx_to_xmm1();
+        }
+        (_, Operand::Slot(rhs)) => {
+            asm.load_rax(lhs);
+            asm.load_rcx(rhs);
+        }
+        (_, Operand::Const(raw)) => {
+            asm.load_rax(lhs);
+            asm.mov_rcx_imm(raw);
+        }
+    }
+}
+
+fn store_result(asm: &mut Asm, ty: Ty, slot: usize) {
+    match ty {
+        Ty::Float => asm.store_xmm0(slot),
+        _ => asm.store_rax(slot),
+    }
+}
+
+// Compiled code in its own executable mapping
+struct ExecBuffer {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl ExecBuffer {
+    fn new(code: &[u8]) -> Option<ExecBuffer> {
+        let len = code.len().max(1);
+        // SAFETY: a fresh private anonymous mapping, checked before use
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return None;
+        }
+        let buffer = ExecBuffer { ptr, len };
+        // SAFETY: the mapping is len bytes and writable; it only becomes executable after the
+        // copy, and is never writable and executable at once
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if libc::mprotect(ptr, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return None;
+            }
+        }
+        Some(buffer)
+    }
+}
+
+impl Drop for ExecBuffer {
+    fn drop(&mut self) {
+        // SAFETY: unmaps exactly the mapping made in new
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+type Native = unsafe extern "sysv64" fn(*mut u64) -> u64;
+
+struct Compiled {
+    code: ExecBuffer,
+    slots: usize,
+    ret: Ty,
+}
+
+// A function compiled, or found not compilable, for one set of argument types
+struct Specialization {
+    args: Vec<Ty>,
+    compiled: Option<Compiled>,
+}
+
+#[derive(Default)]
+struct JitFunction {
+    calls: u32,
+    specializations: Vec<Specialization>,
+}
+
+pub struct Jit {
+    threshold: u32,
+    functions: Vec<JitFunction>,
+    // Reused for every call
+    args: Vec<Ty>,
+    slots: Vec<u64>,
+}
+
+impl Jit {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            functions: Vec::new(),
+            args: Vec::new(),
+            slots: Vec::new(),
+        }
+    }
+
+    // Run a call to function fidx natively if it's hot and compiles for the arguments on top
+    // of the stack. Returns the result, leaving the stack for the caller to pop, or None to
+    // have the interpreter make the call.
+    pub fn call(
+        &mut self,
+        fidx: u16,
+        func: Function,
+        instrs: &[Instr],
+        consts: &[Value],
+        stack: &Stack,
+    ) -> Option<Value> {
+        let fidx = fidx as usize;
+        if fidx >= self.functions.len() {
+            self.functions.resize_with(fidx + 1, JitFunction::default);
+        }
+        let function = &mut self.functions[fidx];
+        function.calls = function.calls.saturating_add(1);
+        if function.calls < self.threshold {
+            return None;
+        }
+        let arity = func.arity as usize;
+        self.args.clear();
+        self.slots.clear();
+        for depth in (0..arity).rev() {
+            let val = stack.peek_at(depth).ok()?;
+            self.args.push(Ty::of(&val)?);
+            self.slots.push(to_raw(&val));
+        }
+        let found = function
+            .specializations
+            .iter()
+            .position(|spec| spec.args == self.args);
+        let spec = match found {
+            Some(idx) => &function.specializations[idx],
+            None => {
+                let compiled = infer(instrs, func, &self.args, consts).and_then(|(states, ret)| {
+                    let max_depth = states
+                        .iter()
+                        .flatten()
+                        .map(|s| s.stack.len() + 2)
+                        .max()
+                        .unwrap_or(0);
+                    let asm = compile(instrs, &states, func.locals as usize, consts);
+                    Some(Compiled {
+                        code: ExecBuffer::new(&asm.code)?,
+                        slots: (func.locals as usize + max_depth).max(1),
+                        ret,
+                    })
+                });
+                function.specializations.push(Specialization {
+                    args: self.args.clone(),
+                    compiled,
+                });
+                function.specializations.last().expect("just pushed")
+            }
+        };
+        let compiled = spec.compiled.as_ref()?;
+        self.slots.resize(compiled.slots, 0);
+        // SAFETY: the buffer holds code compiled for exactly these argument types, which only
+        // touches the slots it was compiled with
+        let status = unsafe {
+            let native: Native = std::mem::transmute(compiled.code.ptr);
+            native(self.slots.as_mut_ptr())
+        };
+        if status != 0 {
+            return None;
+        }
+        Some(from_raw(self.slots[0], compiled.ret))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::assembler::assemble_source;
+    use crate::instr::IndexedCode;
+
+    // Compile function 0 of source for args and run it, or None if it bails or won't compile
+    fn run(source: &str, args: &[Value]) -> Option<Value> {
+        let bytecode = assemble_source(source).unwrap();
+        let indexed = IndexedCode::decode(&bytecode.code).unwrap();
+        let mut func = bytecode.functions[0];
+        func.address = indexed.index_of(func.address, bytecode.code.len()).unwrap();
+        let mut stack = Stack::new(16, usize::MAX);
+        for arg in args {
+            stack.push(arg.clone()).unwrap();
+        }
+        let mut jit = Jit::new(0);
+        jit.call(0, func, &indexed.instrs, &bytecode.consts, &stack)
+    }
+
+    #[test]
+    fn int_loops_compile() {
+        let source = "func sum 1\npshi 0\nstrl total\nlabel loop\npshl arg0\npshi 0\nlteq\n\
+                      jmpt end\npshl total\npshl arg0\nadd\nstrl total\npshl arg0\npshi 1\nsub\n\
+                      strl arg0\njump loop\nlabel end\npshl total\nendf\nmain";
+        assert_eq!(run(source, &[Value::Int(100)]), Some(Value::Int(5050)));
+    }
+
+    #[test]
+    fn float_arithmetic_and_comparisons() {
+        let source = "func f 2\npshl arg0\npshl arg1\nlsth\njmpf other\npshl arg0\npshl arg1\n\
+                      div\nstrl result\njump end\nlabel other\npshl arg0\npshl arg1\nmul\n\
+                      pshc 0.5\nsub\nstrl result\nlabel end\npshl result\nendf\nmain";
+        let float = |l: f64, r: f64| run(source, &[Value::Float(l), Value::Float(r)]);
+        assert_eq!(float(1.0, 4.0), Some(Value::Float(0.25)));
+        assert_eq!(float(3.0, 2.0), Some(Value::Float(5.5)));
+        // NaN compares false, so takes the other branch
+        assert!(matches!(float(f64::NAN, 1.0), Some(Value::Float(v)) if v.is_nan()));
+    }
+
+    #[test]
+    fn integer_arithmetic_matches_ops() {
+        let div = "func f 2\npshl arg0\npshl arg1\ndiv\nendf\nmain";
+        let rem = "func f 2\npshl arg0\npshl arg1\nmod\nendf\nmain";
+        for (l, r) in [(7, 2), (-7, 2), (5, -1), (-9, -4)] {
+            let args = [Value::Int(l), Value::Int(r)];
+            assert_eq!(run(div, &args), Some(Value::Int(l / r)));
+            assert_eq!(run(rem, &args), Some(Value::Int(l % r)));
+        }
+        let min = [Value::Int(i64::MIN), Value::Int(-1)];
+        assert_eq!(run(rem, &min), Some(Value::Int(0)));
+        // Overflow leaves the call to the interpreter, which gives a BigInt
+        assert_eq!(run(div, &min), None);
+        let add = "func f 2\npshl arg0\npshl arg1\nadd\nendf\nmain";
+        assert_eq!(run(add, &[Value::Int(i64::MAX), Value::Int(1)]), None);
+        assert_eq!(
+            run(add, &[Value::Int(-1), Value::Int(1)]),
+            Some(Value::Int(0))
+        );
+        // A zero divisor leaves the call to the interpreter
+        assert_eq!(run(div, &[Value::Int(1), Value::Int(0)]), None);
+    }
+
+    #[test]
+    fn unsupported_code_falls_back() {
+        // Mixed types, a call and an unset local all stay in the interpreter
+        let mixed = "func f 2\npshl arg0\npshl arg1\nadd\nendf\nmain";
+        assert_eq!(run(mixed, &[Value::Int(1), Value::Float(1.0)]), None);
+        let call = "func f 1\npshl arg0\ncallf f\nendf\nmain";
+        assert_eq!(run(call, &[Value::Int(1)]), None);
+        let unset = "func f 1\npshl arg0\njmpf skip\npshi 1\nstrl x\nlabel skip\npshl x\n\
+                     endf\nmain";
+        assert_eq!(run(unset, &[Value::Bool(true)]), None);
+    }
+}
@@ -1,10 +1,30 @@
+pub mod algebra;
 pub mod assembler;
+pub mod builder;
 pub mod bytecode;
+pub mod constexpr;
+pub mod disassembler;
 pub mod error;
+pub mod expr;
 pub mod function;
+pub mod instr;
 pub mod jef;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod math;
 pub mod memory;
 pub mod opcode;
+pub mod ops;
+pub mod optimizer;
+pub mod packed;
+pub mod preprocessor;
+pub mod regcode;
+pub mod regvm;
+pub mod threaded;
 pub mod utils;
 pub mod value;
+pub mod verifier;
 pub mod vm;
+
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", target_os = "linux"))))]
+compile_error!("the jit feature is only available on Linux on x86-64");
@@ -1,27 +1,22 @@
-use fvm::bytecode::Bytecode;
-use fvm::jef::{assemble_json, test_json};
+use fvm::assembler::{AssemblerOptions, assemble_file};
+use fvm::disassembler::disassemble;
+use fvm::jef::assemble_json;
+use fvm::memory::Slot;
+use fvm::optimizer::optimize;
+use fvm::value::Value;
 use fvm::vm::VM;
-use fvm::{assembler::assemble, value::Value};
+use std::env;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
 fn main() {
     let start: Instant;
     let end: Duration;
     let mut vm = VM::new(256);
-    // let assembled = assemble();
+    // let assembled = fvm::assembler::assemble();
     // match assembled {
-    //     Ok(data) => {
-    //         println!("{:?}", data);
-    //         let entry = data.0;
-    //         let consts = data.1;
-    //         let functions = data.2;
-    //         let code = data.3;
-    //         let bytecode = Bytecode {
-    //             entry,
-    //             consts,
-    //             functions,
-    //             code,
-    //         };
+    //     Ok(bytecode) => {
+    //         println!("{:?}", bytecode);
     //         vm.load_code(bytecode);
     //         start = Instant::now();
     //         let result = vm.execute();
@@ -43,13 +38,51 @@ fn main() {
     //     }
     // }
 
-    // test_json();
-    let assembled = assemble_json("program.jef");
+    // fvm::jef::test_json();
+    let mut options = AssemblerOptions::default();
+    let mut path = PathBuf::from("program.jef");
+    let mut listing = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-O" => options.optimize = true,
+            "-d" => listing = true,
+            "-I" => match args.next() {
+                Some(dir) => options.include_paths.push(PathBuf::from(dir)),
+                None => {
+                    println!("Error: -I needs a directory");
+                    return;
+                }
+            },
+            _ => path = PathBuf::from(arg),
+        }
+    }
+
+    let assembled = if path.extension().is_some_and(|ext| ext == "fasm") {
+        assemble_file(&path, &options).map_err(|e| format!("{:?}", e))
+    } else {
+        let optimize_jef = |bytecode| match options.optimize {
+            true => optimize(bytecode).map_err(|e| format!("{:?}", e)),
+            false => Ok(bytecode),
+        };
+        assemble_json(&path.to_string_lossy())
+            .map_err(|e| format!("{:?}", e))
+            .and_then(optimize_jef)
+    };
     match assembled {
         Ok(data) => {
-            println!("{:?}", data);
+            match listing {
+                true => match disassemble(&data) {
+                    Ok(text) => print!("{}", text),
+                    Err(e) => println!("Disassembly Error: {:?}", e),
+                },
+                false => println!("{:?}", data),
+            }
             let bytecode = data;
-            vm.load_code(bytecode);
+            if let Err(e) = vm.load_code(bytecode) {
+                println!("VM Load Error: {:?}", e);
+                return;
+            }
             start = Instant::now();
             let result = vm.execute();
             end = start.elapsed();
@@ -58,7 +91,8 @@ fn main() {
                 Ok(_) => {
                     println!("VM Returned OK.");
                     println!("Runtime: {:.8?}", end);
-                    println!("enum size: {}bytes", size_of::<Value>())
+                    println!("enum size: {}bytes", size_of::<Value>());
+                    println!("stack slot size: {}bytes", size_of::<Slot>())
                 }
                 Err(e) => {
                     println!("VM Returned Error: {:?}", e);
@@ -66,7 +100,7 @@ fn main() {
             }
         }
         Err(er) => {
-            println!("Error {:?}", er);
+            println!("Error {}", er);
         }
     }
 }
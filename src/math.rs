@@ -0,0 +1,510 @@
+// The functions behind the Math opcode, picked by its operand byte. A function takes its
+// arguments off the stack, the last one on top, and pushes its result.
+//
+// Real arguments can be any number, and an Int gives the same Float as the equal Float would.
+// Rounding keeps its argument's type, except that a Rational rounds to an integer. Where a
+// real function has no real result (the square root or log of a negative number) the result
+// is Complex. Arguments where a function isn't defined at all, such as the log of zero or the
+// arcsine of 2, are an OutsideDomain error.
+
+use crate::error::VMError;
+use crate::expr;
+use crate::ops;
+use crate::value::Value;
+use num_bigint::BigInt;
+use num_complex::Complex64;
+use num_rational::BigRational;
+use num_traits::Signed;
+use std::f64::consts::{E, PI, TAU};
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MathFn {
+    // Complex numbers
+    Abs = 0x00,   // -- abs
+    Arg = 0x01,   // -- arg
+    Conj = 0x02,  // -- conj
+    Re = 0x03,    // -- re
+    Im = 0x04,    // -- im
+    Polar = 0x05, // -- polar  (r, theta -> r * e^(i theta))
+
+    // Roots, exponentials and logarithms
+    Sqrt = 0x10,  // -- sqrt
+    Exp = 0x11,   // -- exp
+    Ln = 0x12,    // -- ln
+    Pow = 0x13,   // -- pow  (base, exponent)
+    Log10 = 0x14, // -- log10
+    Log2 = 0x15,  // -- log2
+    Cbrt = 0x16,  // -- cbrt
+
+    // Trigonometry, in radians
+    Sin = 0x20,   // -- sin
+    Cos = 0x21,   // -- cos
+    Tan = 0x22,   // -- tan
+    Asin = 0x23,  // -- asin
+    Acos = 0x24,  // -- acos
+    Atan = 0x25,  // -- atan
+    Atan2 = 0x26, // -- atan2  (y, x)
+    Sinh = 0x28,  // -- sinh
+    Cosh = 0x29,  // -- cosh
+    Tanh = 0x2A,  // -- tanh
+
+    // Rounding and comparison
+    Floor = 0x30, // -- floor
+    Ceil = 0x31,  // -- ceil
+    Round = 0x32, // -- round  (half away from zero)
+    Trunc = 0x33, // -- trunc
+    Min = 0x38,   // -- min
+    Max = 0x39,   // -- max
+    Hypot = 0x3A, // -- hypot
+
+    // Constants, taking no arguments
+    Pi = 0x40,  // -- pi
+    E = 0x41,   // -- e
+    Tau = 0x42, // -- tau
+}
+
+const FUNCTIONS: [MathFn; 33] = [
+    MathFn::Abs,
+    MathFn::Arg,
+    MathFn::Conj,
+    MathFn::Re,
+    MathFn::Im,
+    MathFn::Polar,
+    MathFn::Sqrt,
+    MathFn::Exp,
+    MathFn::Ln,
+    MathFn::Pow,
+    MathFn::Log10,
+    MathFn::Log2,
+    MathFn::Cbrt,
+    MathFn::Sin,
+    MathFn::Cos,
+    MathFn::Tan,
+    MathFn::Asin,
+    MathFn::Acos,
+    MathFn::Atan,
+    MathFn::Atan2,
+    MathFn::Sinh,
+    MathFn::Cosh,
+    MathFn::Tanh,
+    MathFn::Floor,
+    MathFn::Ceil,
+    MathFn::Round,
+    MathFn::Trunc,
+    MathFn::Min,
+    MathFn::Max,
+    MathFn::Hypot,
+    MathFn::Pi,
+    MathFn::E,
+    MathFn::Tau,
+];
+
+impl TryFrom<u8> for MathFn {
+    type Error = VMError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        FUNCTIONS
+            .into_iter()
+            .find(|f| *f as u8 == value)
+            .ok_or(VMError::InvalidMathFunction(value))
+    }
+}
+
+impl MathFn {
+    pub fn arity(&self) -> usize {
+        match self {
+            MathFn::Pi | MathFn::E | MathFn::Tau => 0,
+            MathFn::Polar
+            | MathFn::Pow
+            | MathFn::Atan2
+            | MathFn::Min
+            | MathFn::Max
+            | MathFn::Hypot => 2,
+            _ => 1,
+        }
+    }
+
+    // Assembler spelling
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            MathFn::Abs => "abs",
+            MathFn::Arg => "arg",
+            MathFn::Conj => "conj",
+            MathFn::Re => "re",
+            MathFn::Im => "im",
+            MathFn::Polar => "polar",
+            MathFn::Sqrt => "sqrt",
+            MathFn::Exp => "exp",
+            MathFn::Ln => "ln",
+            MathFn::Pow => "pow",
+            MathFn::Log10 => "log10",
+            MathFn::Log2 => "log2",
+            MathFn::Cbrt => "cbrt",
+            MathFn::Sin => "sin",
+            MathFn::Cos => "cos",
+            MathFn::Tan => "tan",
+            MathFn::Asin => "asin",
+            MathFn::Acos => "acos",
+            MathFn::Atan => "atan",
+            MathFn::Atan2 => "atan2",
+            MathFn::Sinh => "sinh",
+            MathFn::Cosh => "cosh",
+            MathFn::Tanh => "tanh",
+            MathFn::Floor => "floor",
+            MathFn::Ceil => "ceil",
+            MathFn::Round => "round",
+            MathFn::Trunc => "trunc",
+            MathFn::Min => "min",
+            MathFn::Max => "max",
+            MathFn::Hypot => "hypot",
+            MathFn::Pi => "pi",
+            MathFn::E => "e",
+            MathFn::Tau => "tau",
+        }
+    }
+    pub fn from_mnemonic(name: &str) -> Option<MathFn> {
+        FUNCTIONS.into_iter().find(|f| f.mnemonic() == name)
+    }
+    // JEF spelling, the variant name
+    pub fn from_name(name: &str) -> Option<MathFn> {
+        FUNCTIONS.into_iter().find(|f| format!("{:?}", f) == name)
+    }
+
+    // Apply to arguments, which must number arity. Symbolic arguments give an Expr.
+    pub fn apply(&self, args: &[Value]) -> Result<Value, VMError> {
+        if let Some(val) = expr::apply(*self, args) {
+            return Ok(val);
+        }
+        match (self, args) {
+            (MathFn::Pi, []) => Ok(Value::Float(PI)),
+            (MathFn::E, []) => Ok(Value::Float(E)),
+            (MathFn::Tau, []) => Ok(Value::Float(TAU)),
+            (MathFn::Pow, [base, exp]) => ops::pow(base.clone(), exp.clone()),
+            (MathFn::Polar, [r, theta]) => match (r.as_f64(), theta.as_f64()) {
+                (Some(r), Some(theta)) => Ok(Value::Complex(Complex64::from_polar(r, theta))),
+                _ => Err(VMError::InvalidOperandType(r.clone(), theta.clone())),
+            },
+            (MathFn::Atan2, [y, x]) => reals(y, x, f64::atan2),
+            (MathFn::Hypot, [x, y]) => reals(x, y, f64::hypot),
+            // The first of equals, and NaN only when it's the first
+            (MathFn::Min, [l, r]) => Ok(if less(r, l)? { r } else { l }.clone()),
+            (MathFn::Max, [l, r]) => Ok(if less(l, r)? { r } else { l }.clone()),
+            (_, [val]) if self.arity() == 1 => unary(*self, val),
+            _ => Err(VMError::StackUnderflow),
+        }
+    }
+}
+
+// How ToInt picks an integer for a number between two, and how the rounding functions round
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Rounding {
+    Trunc = 0x00, // toward zero
+    Floor = 0x01, // down
+    Ceil = 0x02,  // up
+    Round = 0x03, // to the nearest, half away from zero
+}
+
+const ROUNDINGS: [Rounding; 4] = [
+    Rounding::Trunc,
+    Rounding::Floor,
+    Rounding::Ceil,
+    Rounding::Round,
+];
+
+impl TryFrom<u8> for Rounding {
+    type Error = VMError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        ROUNDINGS
+            .into_iter()
+            .find(|mode| *mode as u8 == value)
+            .ok_or(VMError::InvalidRoundingMode(value))
+    }
+}
+
+impl Rounding {
+    // Assembler spelling
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Rounding::Trunc => "trunc",
+            Rounding::Floor => "floor",
+            Rounding::Ceil => "ceil",
+            Rounding::Round => "round",
+        }
+    }
+    pub fn from_mnemonic(name: &str) -> Option<Rounding> {
+        ROUNDINGS.into_iter().find(|mode| mode.mnemonic() == name)
+    }
+
+    pub fn float(&self, v: f64) -> f64 {
+        match self {
+            Rounding::Trunc => v.trunc(),
+            Rounding::Floor => v.floor(),
+            Rounding::Ceil => v.ceil(),
+            Rounding::Round => v.round(),
+        }
+    }
+    pub fn rational(&self, v: &BigRational) -> BigInt {
+        match self {
+            Rounding::Trunc => v.trunc(),
+            Rounding::Floor => v.floor(),
+            Rounding::Ceil => v.ceil(),
+            Rounding::Round => v.round(),
+        }
+        .to_integer()
+    }
+}
+
+fn less(l: &Value, r: &Value) -> Result<bool, VMError> {
+    Ok(ops::less_than(l.clone(), r.clone())? == Value::Bool(true))
+}
+
+fn reals(l: &Value, r: &Value, f: fn(f64, f64) -> f64) -> Result<Value, VMError> {
+    match (l.as_f64(), r.as_f64()) {
+        (Some(l), Some(r)) => Ok(Value::Float(f(l, r))),
+        _ => Err(VMError::InvalidOperandType(l.clone(), r.clone())),
+    }
+}
+
+fn unary(f: MathFn, val: &Value) -> Result<Value, VMError> {
+    if let Value::Complex(z) = val {
+        return Ok(match f {
+            MathFn::Abs => Value::Float(z.norm()),
+            MathFn::Arg => Value::Float(z.arg()),
+            MathFn::Conj => Value::Complex(z.conj()),
+            MathFn::Re => Value::Float(z.re),
+            MathFn::Im => Value::Float(z.im),
+            MathFn::Sqrt => Value::Complex(z.sqrt()),
+            MathFn::Exp => Value::Complex(z.exp()),
+            MathFn::Ln => Value::Complex(z.ln()),
+            MathFn::Log10 => Value::Complex(z.log10()),
+            MathFn::Log2 => Value::Complex(z.log2()),
+            MathFn::Cbrt => Value::Complex(z.cbrt()),
+            MathFn::Sin => Value::Complex(z.sin()),
+            MathFn::Cos => Value::Complex(z.cos()),
+            MathFn::Tan => Value::Complex(z.tan()),
+            MathFn::Asin => Value::Complex(z.asin()),
+            MathFn::Acos => Value::Complex(z.acos()),
+            MathFn::Atan => Value::Complex(z.atan()),
+            MathFn::Sinh => Value::Complex(z.sinh()),
+            MathFn::Cosh => Value::Complex(z.cosh()),
+            MathFn::Tanh => Value::Complex(z.tanh()),
+            _ => return Err(VMError::InvalidUnaryOperandType(val.clone())),
+        });
+    }
+    let Some(x) = val.as_f64() else {
+        return Err(VMError::InvalidUnaryOperandType(val.clone()));
+    };
+    let outside_domain = || Err(VMError::OutsideDomain(f, val.clone()));
+    Ok(match f {
+        MathFn::Abs => abs(val)?,
+        MathFn::Arg => Value::Float(if x < 0.0 { PI } else { 0.0 }),
+        MathFn::Conj | MathFn::Re => val.clone(),
+        MathFn::Im => Value::Int(0),
+        MathFn::Sqrt if x < 0.0 => Value::Complex(Complex64::new(0.0, (-x).sqrt())),
+        MathFn::Sqrt => Value::Float(x.sqrt()),
+        MathFn::Exp => Value::Float(x.exp()),
+        MathFn::Ln | MathFn::Log10 | MathFn::Log2 if x == 0.0 => return outside_domain(),
+        MathFn::Ln | MathFn::Log10 | MathFn::Log2 if x < 0.0 => {
+            let z = Complex64::new(x, 0.0);
+            Value::Complex(match f {
+                MathFn::Ln => z.ln(),
+                MathFn::Log10 => z.log10(),
+                _ => z.log2(),
+            })
+        }
+        MathFn::Ln => Value::Float(x.ln()),
+        MathFn::Log10 => Value::Float(x.log10()),
+        MathFn::Log2 => Value::Float(x.log2()),
+        MathFn::Cbrt => Value::Float(x.cbrt()),
+        MathFn::Sin => Value::Float(x.sin()),
+        MathFn::Cos => Value::Float(x.cos()),
+        MathFn::Tan => Value::Float(x.tan()),
+        MathFn::Asin | MathFn::Acos if !(-1.0..=1.0).contains(&x) => return outside_domain(),
+        MathFn::Asin => Value::Float(x.asin()),
+        MathFn::Acos => Value::Float(x.acos()),
+        MathFn::Atan => Value::Float(x.atan()),
+        MathFn::Sinh => Value::Float(x.sinh()),
+        MathFn::Cosh => Value::Float(x.cosh()),
+        MathFn::Tanh => Value::Float(x.tanh()),
+        MathFn::Floor | MathFn::Ceil | MathFn::Round | MathFn::Trunc => round(f, val)?,
+        _ => return Err(VMError::InvalidUnaryOperandType(val.clone())),
+    })
+}
+
+// Exact numbers stay exact
+fn abs(val: &Value) -> Result<Value, VMError> {
+    match val {
+        Value::Int(v) => Ok(match v.checked_abs() {
+            Some(v) => Value::Int(v),
+            None => Value::from_bigint(BigInt::from(*v).abs()),
+        }),
+        Value::BigInt(v) => Ok(Value::from_bigint(v.abs())),
+        Value::Rational(v) => Ok(Value::from_rational(v.abs())),
+        Value::Float(v) => Ok(Value::Float(v.abs())),
+        _ => Err(VMError::InvalidUnaryOperandType(val.clone())),
+    }
+}
+
+// Integers are already rounded, a Rational rounds to an integer and a Float to a whole Float
+fn round(f: MathFn, val: &Value) -> Result<Value, VMError> {
+    let mode = match f {
+        MathFn::Floor => Rounding::Floor,
+        MathFn::Ceil => Rounding::Ceil,
+        MathFn::Round => Rounding::Round,
+        _ => Rounding::Trunc,
+    };
+    match val {
+        Value::Float(v) => Ok(Value::Float(mode.float(*v))),
+        _ => ops::to_int(val.clone(), mode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn apply(f: MathFn, args: &[Value]) -> Value {
+        f.apply(args).unwrap()
+    }
+
+    #[test]
+    fn negative_roots_and_logs_are_complex() {
+        assert_eq!(
+            apply(MathFn::Sqrt, &[Value::Int(-4)]),
+            Value::Complex(Complex64::new(0.0, 2.0))
+        );
+        assert_eq!(
+            apply(MathFn::Sqrt, &[Value::Float(2.25)]),
+            Value::Float(1.5)
+        );
+        assert_eq!(
+            apply(MathFn::Ln, &[Value::Int(-1)]),
+            Value::Complex(Complex64::new(0.0, PI))
+        );
+        let i = Value::Complex(Complex64::i());
+        let Value::Complex(z) = apply(MathFn::Exp, &[Value::Complex(Complex64::new(0.0, PI))])
+        else {
+            panic!("exp of a Complex is Complex");
+        };
+        assert!((z.re + 1.0).abs() < 1e-12 && z.im.abs() < 1e-12);
+        assert_eq!(
+            apply(MathFn::Conj, &[i]),
+            Value::Complex(Complex64::new(0.0, -1.0))
+        );
+    }
+
+    #[test]
+    fn polar_form() {
+        let z = || Value::Complex(Complex64::new(3.0, 4.0));
+        assert_eq!(apply(MathFn::Abs, &[z()]), Value::Float(5.0));
+        assert_eq!(apply(MathFn::Re, &[z()]), Value::Float(3.0));
+        assert_eq!(apply(MathFn::Im, &[z()]), Value::Float(4.0));
+        let arg = apply(MathFn::Arg, &[z()]);
+        let Value::Complex(back) = apply(MathFn::Polar, &[Value::Int(5), arg]) else {
+            panic!("polar gives a Complex");
+        };
+        assert!((back.re - 3.0).abs() < 1e-12 && (back.im - 4.0).abs() < 1e-12);
+        assert_eq!(apply(MathFn::Arg, &[Value::Int(-2)]), Value::Float(PI));
+    }
+
+    #[test]
+    fn abs_stays_exact() {
+        assert_eq!(apply(MathFn::Abs, &[Value::Int(-3)]), Value::Int(3));
+        assert_eq!(
+            apply(MathFn::Abs, &[Value::Int(i64::MIN)]),
+            Value::from_bigint(BigInt::from(i64::MIN) * -1)
+        );
+        let half = Value::from_rational("-1/2".parse().unwrap());
+        assert_eq!(
+            apply(MathFn::Abs, &[half]),
+            Value::from_rational("1/2".parse().unwrap())
+        );
+        assert!(MathFn::Abs.apply(&[Value::Bool(true)]).is_err());
+    }
+
+    #[test]
+    fn ints_and_floats_agree() {
+        for f in [
+            MathFn::Sin,
+            MathFn::Exp,
+            MathFn::Atan,
+            MathFn::Cbrt,
+            MathFn::Log2,
+        ] {
+            assert_eq!(
+                apply(f, &[Value::Int(8)]),
+                apply(f, &[Value::Float(8.0)]),
+                "{:?}",
+                f
+            );
+        }
+        assert_eq!(apply(MathFn::Log10, &[Value::Int(1000)]), Value::Float(3.0));
+        assert_eq!(apply(MathFn::Cbrt, &[Value::Int(-27)]), Value::Float(-3.0));
+        let (three, four) = (Value::Int(3), Value::Float(4.0));
+        assert_eq!(apply(MathFn::Hypot, &[three, four]), Value::Float(5.0));
+        let angle = apply(MathFn::Atan2, &[Value::Int(1), Value::Int(-1)]);
+        assert_eq!(angle, Value::Float(3.0 * PI / 4.0));
+        assert_eq!(apply(MathFn::Tau, &[]), Value::Float(2.0 * PI));
+        assert_eq!(
+            apply(MathFn::Ln, &[apply(MathFn::E, &[])]),
+            Value::Float(1.0)
+        );
+    }
+
+    #[test]
+    fn rounding_keeps_type() {
+        let rational = |s: &str| Value::from_rational(s.parse().unwrap());
+        assert_eq!(
+            apply(MathFn::Floor, &[Value::Float(-2.5)]),
+            Value::Float(-3.0)
+        );
+        assert_eq!(
+            apply(MathFn::Round, &[Value::Float(-2.5)]),
+            Value::Float(-3.0)
+        );
+        assert_eq!(
+            apply(MathFn::Trunc, &[Value::Float(-2.5)]),
+            Value::Float(-2.0)
+        );
+        assert_eq!(apply(MathFn::Ceil, &[Value::Int(7)]), Value::Int(7));
+        assert_eq!(apply(MathFn::Ceil, &[rational("5/2")]), Value::Int(3));
+        assert_eq!(apply(MathFn::Round, &[rational("-5/2")]), Value::Int(-3));
+        assert_eq!(apply(MathFn::Trunc, &[rational("-7/2")]), Value::Int(-3));
+        let z = Value::Complex(Complex64::new(1.5, 0.0));
+        assert!(MathFn::Floor.apply(&[z]).is_err());
+    }
+
+    #[test]
+    fn min_and_max_keep_the_winner() {
+        let (two, half) = (|| Value::Int(2), || Value::Float(1.5));
+        assert_eq!(apply(MathFn::Min, &[two(), half()]), half());
+        assert_eq!(apply(MathFn::Max, &[two(), half()]), two());
+        assert_eq!(
+            apply(MathFn::Max, &[Value::Int(2), Value::Float(2.0)]),
+            Value::Int(2)
+        );
+        assert!(MathFn::Min.apply(&[two(), Value::Bool(false)]).is_err());
+    }
+
+    #[test]
+    fn domain_errors() {
+        assert!(matches!(
+            MathFn::Asin.apply(&[Value::Int(2)]),
+            Err(VMError::OutsideDomain(MathFn::Asin, Value::Int(2)))
+        ));
+        assert!(matches!(
+            MathFn::Log2.apply(&[Value::Float(0.0)]),
+            Err(VMError::OutsideDomain(MathFn::Log2, _))
+        ));
+        assert_eq!(apply(MathFn::Acos, &[Value::Int(1)]), Value::Float(0.0));
+        // A negative still has a Complex log
+        let Value::Complex(z) = apply(MathFn::Log10, &[Value::Int(-100)]) else {
+            panic!("log of a negative is Complex");
+        };
+        assert!((z.re - 2.0).abs() < 1e-12 && (z.im - PI / 10f64.ln()).abs() < 1e-12);
+    }
+}
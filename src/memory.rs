@@ -1,14 +1,28 @@
 use crate::{error::VMError, value::Value};
 
+// What the stack stores values as. With the nanbox feature that's a 64 bit PackedValue, and
+// values are packed and unpacked as they go on and off the stack.
+#[cfg(not(feature = "nanbox"))]
+pub type Slot = Value;
+#[cfg(feature = "nanbox")]
+pub type Slot = crate::packed::PackedValue;
+
+// Just a move when Slot is Value
+#[allow(clippy::useless_conversion)]
+fn unpack(slot: Slot) -> Value {
+    Value::from(slot)
+}
+
 #[derive(Debug)]
 pub struct Stack {
-    values: Vec<Value>,
+    values: Vec<Slot>,
     max_size: usize,
     pointer: usize,
     pub frames: Vec<StackFrame>,
 }
 
 impl Stack {
+    #[allow(clippy::needless_return)]
     pub fn new(init_capacity: usize, max_size: usize) -> Self {
         let mut stack = Self {
             values: Vec::new(),
@@ -17,16 +31,16 @@ impl Stack {
             frames: Vec::new(),
         };
 
-        stack.values.resize(init_capacity, Value::default());
+        stack.values.resize(init_capacity, Slot::default());
 
         return stack;
     }
     pub fn push(&mut self, val: Value) -> Result<(), VMError> {
         if self.pointer < self.max_size {
             if self.pointer >= self.values.len() {
-                self.values.resize(self.values.len() * 2, Value::default());
+                self.values.resize(self.values.len() * 2, Slot::default());
             }
-            self.values[self.pointer] = val;
+            self.values[self.pointer] = Slot::from(val);
             self.pointer += 1;
             Ok(())
         } else {
@@ -34,16 +48,47 @@ impl Stack {
         }
     }
     pub fn pop(&mut self) -> Result<Value, VMError> {
-        if self.pointer <= 0 {
+        if self.pointer == 0 {
             return Err(VMError::StackUnderflow);
         }
         self.pointer -= 1;
         // return Ok(self.values[self.pointer].clone());
-        return Ok(std::mem::replace(
-            &mut self.values[self.pointer],
-            Value::default(),
-        ));
+        Ok(unpack(std::mem::take(&mut self.values[self.pointer])))
+    }
+    pub fn peek(&self) -> Result<Value, VMError> {
+        if self.pointer == 0 {
+            return Err(VMError::StackUnderflow);
+        }
+        Ok(unpack(self.values[self.pointer - 1].clone()))
+    }
+    // The value depth places below the top, without popping
+    pub fn peek_at(&self, depth: usize) -> Result<Value, VMError> {
+        if depth >= self.pointer {
+            return Err(VMError::StackUnderflow);
+        }
+        Ok(unpack(self.values[self.pointer - 1 - depth].clone()))
+    }
+    // Whether the top two values are both Int
+    pub fn top_ints(&self) -> bool {
+        self.pointer >= 2
+            && self.values[self.pointer - 2].as_int().is_some()
+            && self.values[self.pointer - 1].as_int().is_some()
+    }
+    // Replace the top two values with op applied to them, if both are Int and op has a result
+    // (it has none on overflow). Leaves the stack untouched and returns false otherwise.
+    pub fn binary_int(&mut self, op: impl FnOnce(i64, i64) -> Option<Value>) -> bool {
+        if self.pointer >= 2
+            && let Some(l) = self.values[self.pointer - 2].as_int()
+            && let Some(r) = self.values[self.pointer - 1].as_int()
+            && let Some(val) = op(l, r)
+        {
+            self.values[self.pointer - 2] = Slot::from(val);
+            self.pointer -= 1;
+            return true;
+        }
+        false
     }
+    #[allow(clippy::needless_return)]
     pub fn push_frame(
         &mut self,
         args: Vec<Value>,
@@ -61,10 +106,11 @@ impl Stack {
         self.frames.push(frame);
         self.pointer += locals;
         if self.pointer >= self.values.len() {
-            self.values.resize(self.values.len() * 2, Value::default());
+            self.values.resize(self.values.len() * 2, Slot::default());
         }
         return Ok(());
     }
+    #[allow(clippy::needless_return)]
     pub fn pop_frame(&mut self) -> Result<usize, VMError> {
         if let Some(frame) = self.frames.pop() {
             self.pointer = frame.previous_frame_pointer;
@@ -74,9 +120,10 @@ impl Stack {
             return Err(VMError::StackUnderflow);
         }
     }
+    #[allow(clippy::needless_return)]
     pub fn peek_local(&mut self, idx: u8) -> Result<Value, VMError> {
         if let Some(frame_ptr) = self.frames.last() {
-            let val = self.values[frame_ptr.previous_frame_pointer + idx as usize].clone();
+            let val = unpack(self.values[frame_ptr.previous_frame_pointer + idx as usize].clone());
 
             return Ok(val);
         } else {
@@ -85,7 +132,7 @@ impl Stack {
     }
     pub fn set_local(&mut self, val: Value, idx: u8) {
         if let Some(frame_ptr) = self.frames.last() {
-            self.values[frame_ptr.previous_frame_pointer + idx as usize] = val;
+            self.values[frame_ptr.previous_frame_pointer + idx as usize] = Slot::from(val);
         }
     }
 }
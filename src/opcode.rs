@@ -4,30 +4,43 @@ use crate::error::VMError;
 #[derive(Debug, Copy, Clone)]
 pub enum OpCode {
     // Arithmetic 0x00 - 0x0F
-    Add = 0x00,    // -- adds
-    Sub = 0x01,    // -- subs
-    Mul = 0x02,    // -- muls
-    Div = 0x03,    // -- divs
-    DivInt = 0x04, // -- divi
-    Mod = 0x05,
+    Add = 0x00,      // -- adds
+    Sub = 0x01,      // -- subs
+    Mul = 0x02,      // -- muls
+    Div = 0x03,      // -- divs
+    DivInt = 0x04,   // -- divi
+    Mod = 0x05,      // -- mod
+    DivExact = 0x06, // -- divx
+
+    // Int arithmetic with a fixed overflow mode, whatever the VM's (see ops::Overflow)
+    AddWrap = 0x07,    // -- wadd
+    SubWrap = 0x08,    // -- wsub
+    MulWrap = 0x09,    // -- wmul
+    AddChecked = 0x0A, // -- cadd
+    SubChecked = 0x0B, // -- csub
+    MulChecked = 0x0C, // -- cmul
+    AddSat = 0x0D,     // -- sadd
+    SubSat = 0x0E,     // -- ssub
+    MulSat = 0x0F,     // -- smul
 
     // Memory/Stack Manipulation 0x10 - 0x25
     PushConst = 0x10,     // u16 -- pshc <literal>
-    PushLocal = 0x11,     // u16 -- pshl <ident>
-    StoreLocal = 0x12,    // u16 -- strl <ident>
+    PushLocal = 0x11,     // u8  -- pshl <ident>
+    StoreLocal = 0x12,    // u8  -- strl <ident>
     PushGlobal = 0x13,    // u16 -- pshg <ident>
     StoreGlobal = 0x14,   // u16 -- strg <ident>
     Pop = 0x15,           //     -- pops
-    PushImmediate = 0x16, // i16 --
+    PushImmediate = 0x16, // i16 -- pshi <int>
     Box = 0x17,           //     -- box
     Unbox = 0x18,         //     -- unbox
     SetBox = 0x19,        //     -- setbox
-    Array = 0x1A,         // u16 -- array <int>
+    Array = 0x1A,         // u8  -- array <int>
     ArraySet = 0x1B,
     ArrayGet = 0x1C,
     ArrayPush = 0x1D,
     ArrayPop = 0x1E,
     ArrayLen = 0x1F,
+    StoreLocalKeep = 0x20, // u8  -- strk <ident>
 
     // Control Flow 0x26 - 0x3F
     Jump = 0x26,        // u32 -- jump <label>
@@ -41,14 +54,48 @@ pub enum OpCode {
     GreaterThan = 0x43,  // -- grth
     GreaterEqual = 0x44, // -- gteq
     LessEqual = 0x45,    // -- lteq
-    Not = 0x46,          // -- bnot
+    Not = 0x46,          // -- not
     LogicalAnd = 0x47,   // -- land
     LogicalOr = 0x48,    // -- lgor
+    Identical = 0x49,    // -- ideq
+
+    // Math 0x50 - 0x57, the function picked by the operand (see math.rs), and conversions
+    Math = 0x50,    // u8 -- abs, sqrt, ...
+    ToInt = 0x51,   // u8 (rounding) -- toint <mode>
+    ToFloat = 0x52, //    -- toflt
+
+    // Symbolic 0x58 - 0x5F, on expression trees (see expr.rs and algebra.rs)
+    Symbol = 0x58,        // -- sym
+    Substitute = 0x59,    // -- subs
+    Evaluate = 0x5A,      // -- eval
+    ExprEqual = 0x5B,     // -- exeq
+    Simplify = 0x5C,      // -- simp
+    Differentiate = 0x5D, // u16 (const name) -- diff <var>
 
     // Functions
     CallFunction = 0x61, //  u16(func id) -- call <ident>
     Return = 0x62,
 
+    // Bitwise 0x68 - 0x7F, on Int words (see ops.rs)
+    BitAnd = 0x68,        // -- band
+    BitOr = 0x69,         // -- bor
+    BitXor = 0x6A,        // -- bxor
+    BitNot = 0x6B,        // -- bnot
+    Shl = 0x6C,           // -- shl
+    Shr = 0x6D,           // -- shr
+    UShr = 0x6E,          // -- ushr
+    RotateLeft = 0x6F,    // -- rotl
+    RotateRight = 0x70,   // -- rotr
+    PopCount = 0x71,      // -- popc
+    LeadingZeros = 0x72,  // -- clz
+    TrailingZeros = 0x73, // -- ctz
+
+    // Superinstructions 0x80 - 0x8F, emitted by the optimizer for common sequences
+    AddLocalConst = 0x80,         // u8 u16         -- pshl x; pshc k; add
+    IncLocal = 0x81,              // u8 i16         -- pshl x; pshi k; add; strl x
+    CompareLocalConstJump = 0x82, // u8 u16 u8 u32  -- pshl x; pshc k; <cmp>; jmpt/jmpf <label>
+    PushLocalPair = 0x83,         // u8 u8          -- pshl x; pshl y
+
     // Testing ops
     Print = 0xF5, // prnt
 
@@ -63,9 +110,48 @@ impl OpCode {
             OpCode::PushConst => vec![2],
             OpCode::PushImmediate => vec![2],
             OpCode::PushGlobal => vec![2],
+            OpCode::StoreGlobal => vec![2],
+            OpCode::PushLocal => vec![1],
+            OpCode::StoreLocal => vec![1],
+            OpCode::StoreLocalKeep => vec![1],
+            OpCode::Array => vec![1],
+            OpCode::Math => vec![1],
+            OpCode::ToInt => vec![1],
+            OpCode::Jump => vec![4],
+            OpCode::JumpIfFalse => vec![4],
+            OpCode::JumpIfTrue => vec![4],
+            OpCode::CallFunction => vec![2],
+            OpCode::Differentiate => vec![2],
+            OpCode::AddLocalConst => vec![1, 2],
+            OpCode::IncLocal => vec![1, 2],
+            OpCode::CompareLocalConstJump => vec![1, 2, 1, 4],
+            OpCode::PushLocalPair => vec![1, 1],
             _ => vec![0],
         }
     }
+    // Total size in bytes of the instruction, opcode included. Matches arg_sizecount without
+    // allocating, since the VM calls this for every instruction it decodes.
+    pub fn size(&self) -> usize {
+        match self {
+            OpCode::PushLocal
+            | OpCode::StoreLocal
+            | OpCode::StoreLocalKeep
+            | OpCode::Array
+            | OpCode::Math
+            | OpCode::ToInt => 2,
+            OpCode::PushConst
+            | OpCode::PushImmediate
+            | OpCode::PushGlobal
+            | OpCode::StoreGlobal
+            | OpCode::CallFunction
+            | OpCode::Differentiate
+            | OpCode::PushLocalPair => 3,
+            OpCode::AddLocalConst | OpCode::IncLocal => 4,
+            OpCode::CompareLocalConstJump => 9,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue => 5,
+            _ => 1,
+        }
+    }
 }
 
 // Safe conversion from u8
@@ -80,6 +166,17 @@ impl TryFrom<u8> for OpCode {
             0x02 => Ok(OpCode::Mul),
             0x03 => Ok(OpCode::Div),
             0x04 => Ok(OpCode::DivInt),
+            0x05 => Ok(OpCode::Mod),
+            0x06 => Ok(OpCode::DivExact),
+            0x07 => Ok(OpCode::AddWrap),
+            0x08 => Ok(OpCode::SubWrap),
+            0x09 => Ok(OpCode::MulWrap),
+            0x0A => Ok(OpCode::AddChecked),
+            0x0B => Ok(OpCode::SubChecked),
+            0x0C => Ok(OpCode::MulChecked),
+            0x0D => Ok(OpCode::AddSat),
+            0x0E => Ok(OpCode::SubSat),
+            0x0F => Ok(OpCode::MulSat),
 
             // Memory/Stack Manipulation
             0x10 => Ok(OpCode::PushConst),
@@ -98,6 +195,7 @@ impl TryFrom<u8> for OpCode {
             0x1D => Ok(OpCode::ArrayPush),
             0x1E => Ok(OpCode::ArrayPop),
             0x1F => Ok(OpCode::ArrayLen),
+            0x20 => Ok(OpCode::StoreLocalKeep),
 
             // Control Flow
             0x26 => Ok(OpCode::Jump),
@@ -114,11 +212,45 @@ impl TryFrom<u8> for OpCode {
             0x46 => Ok(OpCode::Not),
             0x47 => Ok(OpCode::LogicalAnd),
             0x48 => Ok(OpCode::LogicalOr),
+            0x49 => Ok(OpCode::Identical),
+
+            // Math
+            0x50 => Ok(OpCode::Math),
+            0x51 => Ok(OpCode::ToInt),
+            0x52 => Ok(OpCode::ToFloat),
+
+            // Symbolic
+            0x58 => Ok(OpCode::Symbol),
+            0x59 => Ok(OpCode::Substitute),
+            0x5A => Ok(OpCode::Evaluate),
+            0x5B => Ok(OpCode::ExprEqual),
+            0x5C => Ok(OpCode::Simplify),
+            0x5D => Ok(OpCode::Differentiate),
 
             // Functions
             0x61 => Ok(OpCode::CallFunction),
             0x62 => Ok(OpCode::Return),
 
+            // Bitwise
+            0x68 => Ok(OpCode::BitAnd),
+            0x69 => Ok(OpCode::BitOr),
+            0x6A => Ok(OpCode::BitXor),
+            0x6B => Ok(OpCode::BitNot),
+            0x6C => Ok(OpCode::Shl),
+            0x6D => Ok(OpCode::Shr),
+            0x6E => Ok(OpCode::UShr),
+            0x6F => Ok(OpCode::RotateLeft),
+            0x70 => Ok(OpCode::RotateRight),
+            0x71 => Ok(OpCode::PopCount),
+            0x72 => Ok(OpCode::LeadingZeros),
+            0x73 => Ok(OpCode::TrailingZeros),
+
+            // Superinstructions
+            0x80 => Ok(OpCode::AddLocalConst),
+            0x81 => Ok(OpCode::IncLocal),
+            0x82 => Ok(OpCode::CompareLocalConstJump),
+            0x83 => Ok(OpCode::PushLocalPair),
+
             // Testing
             0xF5 => Ok(OpCode::Print),
             0xFF => Ok(OpCode::NoOp),
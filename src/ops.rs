@@ -0,0 +1,886 @@
+// Arithmetic, comparison, logic and bitwise semantics shared by VM::execute and the optimizer's
+// constant folding. Mixed numbers are promoted along Int -> BigInt -> Rational -> Float ->
+// Complex, the result taking the higher of the two types. Integer arithmetic that overflows an
+// Int gives a BigInt, and BigInt results small enough to be an Int are turned back into one. A
+// Rational with an integer or another Rational stays exact, and any exact number with a Float
+// gives a Float. Any number with a Complex gives a Complex; complex numbers have no ordering.
+// Arithmetic with a symbolic Expr builds a tree.
+
+use crate::error::VMError;
+use crate::expr::{self, BinOp};
+use crate::math::Rounding;
+use crate::value::Value;
+use num_bigint::{BigInt, Sign};
+use num_complex::Complex64;
+use num_rational::BigRational;
+use num_traits::{FromPrimitive, ToPrimitive, Zero};
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+pub type BinaryOp = fn(Value, Value) -> Result<Value, VMError>;
+
+// Integer arithmetic on Int and BigInt operands. Two Ints use checked, and only if that
+// overflows, or either side is a BigInt, is it done in BigInt. None if an operand isn't an
+// integer.
+fn integer(
+    lop: &Value,
+    rop: &Value,
+    checked: fn(i64, i64) -> Option<i64>,
+    big: fn(BigInt, BigInt) -> BigInt,
+) -> Option<Value> {
+    if let (Value::Int(l), Value::Int(r)) = (lop, rop)
+        && let Some(v) = checked(*l, *r)
+    {
+        return Some(Value::Int(v));
+    }
+    Some(Value::from_bigint(big(lop.as_bigint()?, rop.as_bigint()?)))
+}
+
+// Both operands as BigInt, when at least one of them is a BigInt and the other an integer
+fn bigints(lop: &Value, rop: &Value) -> Option<(BigInt, BigInt)> {
+    match (lop, rop) {
+        (Value::BigInt(_), _) | (_, Value::BigInt(_)) => Some((lop.as_bigint()?, rop.as_bigint()?)),
+        _ => None,
+    }
+}
+
+// Both operands as BigRational, when at least one is a Rational and the other exact too
+fn rationals(lop: &Value, rop: &Value) -> Option<(BigRational, BigRational)> {
+    match (lop, rop) {
+        (Value::Rational(_), _) | (_, Value::Rational(_)) => {
+            Some((lop.as_rational()?, rop.as_rational()?))
+        }
+        _ => None,
+    }
+}
+
+// Both operands as f64, when at least one is a Float and the other a real number
+fn floats(lop: &Value, rop: &Value) -> Option<(f64, f64)> {
+    match (lop, rop) {
+        (Value::Float(_), _) | (_, Value::Float(_)) => Some((lop.as_f64()?, rop.as_f64()?)),
+        _ => None,
+    }
+}
+
+// Both operands as Complex64, when at least one is a Complex and the other a number
+fn complexes(lop: &Value, rop: &Value) -> Option<(Complex64, Complex64)> {
+    match (lop, rop) {
+        (Value::Complex(_), _) | (_, Value::Complex(_)) => {
+            Some((lop.as_complex()?, rop.as_complex()?))
+        }
+        _ => None,
+    }
+}
+
+pub fn add(lop: Value, rop: Value) -> Result<Value, VMError> {
+    if let Some(val) = integer(&lop, &rop, i64::checked_add, |l, r| l + r) {
+        return Ok(val);
+    }
+    match (&lop, &rop) {
+        (Value::Float(l), Value::Float(r)) => Ok(Value::Float(l + r)),
+        _ if let Some((l, r)) = rationals(&lop, &rop) => Ok(Value::from_rational(l + r)),
+        _ if let Some((l, r)) = floats(&lop, &rop) => Ok(Value::Float(l + r)),
+        _ if let Some((l, r)) = complexes(&lop, &rop) => Ok(Value::Complex(l + r)),
+        _ if let Some(val) = expr::binary(BinOp::Add, &lop, &rop) => Ok(val),
+        _ => Err(VMError::InvalidOperandType(lop, rop)),
+    }
+}
+
+pub fn sub(lop: Value, rop: Value) -> Result<Value, VMError> {
+    if let Some(val) = integer(&lop, &rop, i64::checked_sub, |l, r| l - r) {
+        return Ok(val);
+    }
+    match (&lop, &rop) {
+        (Value::Float(l), Value::Float(r)) => Ok(Value::Float(l - r)),
+        _ if let Some((l, r)) = rationals(&lop, &rop) => Ok(Value::from_rational(l - r)),
+        _ if let Some((l, r)) = floats(&lop, &rop) => Ok(Value::Float(l - r)),
+        _ if let Some((l, r)) = complexes(&lop, &rop) => Ok(Value::Complex(l - r)),
+        _ if let Some(val) = expr::binary(BinOp::Sub, &lop, &rop) => Ok(val),
+        _ => Err(VMError::InvalidOperandType(lop, rop)),
+    }
+}
+
+pub fn mul(lop: Value, rop: Value) -> Result<Value, VMError> {
+    if let Some(val) = integer(&lop, &rop, i64::checked_mul, |l, r| l * r) {
+        return Ok(val);
+    }
+    match (&lop, &rop) {
+        (Value::Float(l), Value::Float(r)) => Ok(Value::Float(l * r)),
+        _ if let Some((l, r)) = rationals(&lop, &rop) => Ok(Value::from_rational(l * r)),
+        _ if let Some((l, r)) = floats(&lop, &rop) => Ok(Value::Float(l * r)),
+        _ if let Some((l, r)) = complexes(&lop, &rop) => Ok(Value::Complex(l * r)),
+        _ if let Some(val) = expr::binary(BinOp::Mul, &lop, &rop) => Ok(val),
+        _ => Err(VMError::InvalidOperandType(lop, rop)),
+    }
+}
+
+pub fn div(lop: Value, rop: Value) -> Result<Value, VMError> {
+    match (&lop, &rop) {
+        (_, Value::Float(0.0) | Value::Int(0)) => Err(VMError::DivisionByZero),
+        (Value::Float(l), Value::Float(r)) => Ok(Value::Float(l / r)),
+        _ if let Some(val) = integer(&lop, &rop, i64::checked_div, |l, r| l / r) => Ok(val),
+        _ if let Some((l, r)) = rationals(&lop, &rop) => Ok(Value::from_rational(l / r)),
+        _ if let Some((l, r)) = floats(&lop, &rop) => Ok(Value::Float(l / r)),
+        _ if let Some((l, r)) = complexes(&lop, &rop) => match r == Complex64::ZERO {
+            true => Err(VMError::DivisionByZero),
+            false => Ok(Value::Complex(l / r)),
+        },
+        _ if let Some(val) = expr::binary(BinOp::Div, &lop, &rop) => Ok(val),
+        _ => Err(VMError::InvalidOperandType(lop, rop)),
+    }
+}
+
+// Division that doesn't truncate: integers give a Rational unless they divide exactly
+pub fn div_exact(lop: Value, rop: Value) -> Result<Value, VMError> {
+    match (&lop, &rop) {
+        (_, Value::Float(0.0) | Value::Int(0)) => Err(VMError::DivisionByZero),
+        _ if let (Some(l), Some(r)) = (lop.as_rational(), rop.as_rational()) => {
+            Ok(Value::from_rational(l / r))
+        }
+        _ => div(lop, rop),
+    }
+}
+
+pub fn div_int(lop: Value, rop: Value) -> Result<Value, VMError> {
+    match (&lop, &rop) {
+        (_, Value::Float(0.0) | Value::Int(0)) => Err(VMError::DivisionByZero),
+        _ if let Some(val) = integer(&lop, &rop, i64::checked_div, |l, r| l / r) => Ok(val),
+        _ if let Some((l, r)) = rationals(&lop, &rop) => {
+            Ok(Value::from_bigint((l / r).floor().to_integer()))
+        }
+        _ if let Some((l, r)) = floats(&lop, &rop) => to_int(Value::Float(l / r), Rounding::Floor),
+        _ => Err(VMError::InvalidOperandType(lop, rop)),
+    }
+}
+
+pub fn modulo(lop: Value, rop: Value) -> Result<Value, VMError> {
+    match (&lop, &rop) {
+        (_, Value::Float(0.0) | Value::Int(0)) => Err(VMError::DivisionByZero),
+        _ if let Some(val) = integer(&lop, &rop, i64::checked_rem, |l, r| l % r) => Ok(val),
+        // Takes the sign of the dividend, like the integer remainder
+        _ if let Some((l, r)) = rationals(&lop, &rop) => {
+            let quotient = (&l / &r).trunc();
+            Ok(Value::from_rational(l - r * quotient))
+        }
+        _ if let Some((l, r)) = floats(&lop, &rop) => Ok(Value::Float(l % r)),
+        _ => Err(VMError::InvalidOperandType(lop, rop)),
+    }
+}
+
+// Results of exact powers are kept below about this many bits, which takes a fraction of a
+// second to compute
+const EXACT_POW_BITS: u64 = 1 << 20;
+
+// Exact for an exact base and an integer exponent, unless the result would be too big. Otherwise
+// in f64, or Complex where there is no real result, as with (-8)^(1/3).
+pub fn pow(base: Value, exp: Value) -> Result<Value, VMError> {
+    if let (Some(b), Some(e)) = (base.as_rational(), exp.as_bigint().and_then(|e| e.to_i32()))
+        // No more than the result's size in bits, and at least half of it
+        && b.numer().bits().max(b.denom().bits()).saturating_sub(1) * e.unsigned_abs() as u64
+            <= EXACT_POW_BITS
+    {
+        if b.is_zero() && e < 0 {
+            return Err(VMError::DivisionByZero);
+        }
+        return Ok(Value::from_rational(b.pow(e)));
+    }
+    match (&base, &exp) {
+        _ if let Some((b, e)) = complexes(&base, &exp) => Ok(Value::Complex(b.powc(e))),
+        _ if let (Some(b), Some(e)) = (base.as_f64(), exp.as_f64()) => {
+            match b < 0.0 && e.fract() != 0.0 {
+                true => Ok(Value::Complex(Complex64::new(b, 0.0).powf(e))),
+                false => Ok(Value::Float(b.powf(e))),
+            }
+        }
+        _ => Err(VMError::InvalidOperandType(base, exp)),
+    }
+}
+
+// What an Int op does with a result that doesn't fit an Int. Promote, the default, gives a
+// BigInt; the others keep the result an Int so programs don't depend on BigInt support.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Overflow {
+    #[default]
+    Promote,
+    Checked,
+    Wrapping,
+    Saturating,
+}
+
+// An arithmetic op under an overflow mode. Only two Int operands can overflow; anything else,
+// BigInts included, is promoted as usual.
+pub fn overflowing(mode: Overflow, op: BinaryOp, lop: Value, rop: Value) -> Result<Value, VMError> {
+    let ints = matches!((&lop, &rop), (Value::Int(_), Value::Int(_)));
+    match (mode, op(lop, rop)?) {
+        (Overflow::Promote, val) => Ok(val),
+        (Overflow::Checked, Value::BigInt(_)) if ints => Err(VMError::IntegerOverflow),
+        // The low 64 bits, BigInt's & working on two's complement like the hardware would
+        (Overflow::Wrapping, Value::BigInt(v)) if ints => {
+            let low = (&*v & &BigInt::from(u64::MAX)).to_u64();
+            Ok(Value::Int(low.expect("masked to 64 bits") as i64))
+        }
+        (Overflow::Saturating, Value::BigInt(v)) if ints => match v.sign() {
+            Sign::Minus => Ok(Value::Int(i64::MIN)),
+            _ => Ok(Value::Int(i64::MAX)),
+        },
+        (_, val) => Ok(val),
+    }
+}
+
+// An integer picked by mode. Every finite Float has one, as a BigInt if it's beyond an Int.
+pub fn to_int(val: Value, mode: Rounding) -> Result<Value, VMError> {
+    match &val {
+        Value::Int(_) | Value::BigInt(_) => Ok(val),
+        Value::Rational(v) => Ok(Value::from_bigint(mode.rational(v))),
+        Value::Float(v) if v.is_nan() => Err(VMError::NaNConversion),
+        Value::Float(v) => match BigInt::from_f64(mode.float(*v)) {
+            Some(v) => Ok(Value::from_bigint(v)),
+            None => Err(VMError::OutOfRange(val)),
+        },
+        _ => Err(VMError::InvalidUnaryOperandType(val)),
+    }
+}
+
+// The nearest Float, out of range for exact numbers too big for a finite one
+pub fn to_float(val: Value) -> Result<Value, VMError> {
+    match (&val, val.as_f64()) {
+        (Value::Float(_), Some(v)) => Ok(Value::Float(v)),
+        (_, Some(v)) if v.is_finite() => Ok(Value::Float(v)),
+        (Value::BigInt(_) | Value::Rational(_), _) => Err(VMError::OutOfRange(val)),
+        _ => Err(VMError::InvalidUnaryOperandType(val)),
+    }
+}
+
+// Pairs of arrays or boxes already met in one comparison. Meeting a pair again means both
+// values contain themselves with nothing differing on the way back round, so the pair is taken
+// to match rather than recursing forever.
+type Seen = HashSet<(*const (), *const ())>;
+
+fn revisits<T>(seen: &mut Seen, l: &Rc<T>, r: &Rc<T>) -> bool {
+    !seen.insert((Rc::as_ptr(l).cast(), Rc::as_ptr(r).cast()))
+}
+
+// Structural equality. Numbers are equal by value whatever their types, Floats as IEEE has it
+// so NaN equals nothing, and strings, arrays and boxes by their contents. Values of different
+// kinds are never equal. An array is compared by its contents even against itself, so one
+// holding a NaN isn't equal to itself. There is no map value yet, HeapMap being unused, so maps
+// have no equality to define.
+fn same(lop: &Value, rop: &Value) -> bool {
+    same_in(lop, rop, &mut Seen::new())
+}
+
+fn same_in(lop: &Value, rop: &Value, seen: &mut Seen) -> bool {
+    match (lop, rop) {
+        (Value::Int(l), Value::Int(r)) => l == r,
+        (Value::Bool(l), Value::Bool(r)) => l == r,
+        (Value::NULL, Value::NULL) => true,
+        (Value::String(l), Value::String(r)) => l == r,
+        (Value::Ident(l), Value::Ident(r)) => l == r,
+        (Value::Function(l), Value::Function(r)) => l == r,
+        (Value::Expr(l), Value::Expr(r)) => l == r,
+        (Value::Array(l), Value::Array(r)) => {
+            revisits(seen, l, r) || {
+                let (l, r) = (l.borrow(), r.borrow());
+                l.len() == r.len() && l.iter().zip(r.iter()).all(|(l, r)| same_in(l, r, seen))
+            }
+        }
+        (Value::HeapValue(l), Value::HeapValue(r)) => {
+            revisits(seen, l, r) || same_in(&l.borrow(), &r.borrow(), seen)
+        }
+        _ if let Some((l, r)) = bigints(lop, rop) => l == r,
+        _ if let Some((l, r)) = rationals(lop, rop) => l == r,
+        _ if let Some((l, r)) = floats(lop, rop) => l == r,
+        _ if let Some((l, r)) = complexes(lop, rop) => l == r,
+        _ => false,
+    }
+}
+
+pub fn equal(lop: Value, rop: Value) -> Result<Value, VMError> {
+    Ok(Value::Bool(same(&lop, &rop)))
+}
+
+pub fn not_equal(lop: Value, rop: Value) -> Result<Value, VMError> {
+    Ok(Value::Bool(!same(&lop, &rop)))
+}
+
+// Identity for arrays and boxes, which are only identical to the same reference. Other values,
+// strings included, can't be changed through a reference, so whether two share one is unseen
+// and they are compared as equal does.
+pub fn identical(lop: Value, rop: Value) -> Result<Value, VMError> {
+    match (&lop, &rop) {
+        (Value::Array(l), Value::Array(r)) => Ok(Value::Bool(Rc::ptr_eq(l, r))),
+        (Value::HeapValue(l), Value::HeapValue(r)) => Ok(Value::Bool(Rc::ptr_eq(l, r))),
+        _ => equal(lop, rop),
+    }
+}
+
+// The order of two real numbers, strings or arrays, None where a NaN leaves it unordered.
+// Strings compare by code point and arrays element by element, a prefix coming first. Other
+// values, complex numbers included, have no order. As with equality an array is compared by
+// its contents even against itself, and a pair of arrays met again inside themselves is taken
+// as equal.
+fn compare(lop: &Value, rop: &Value) -> Result<Option<Ordering>, VMError> {
+    compare_in(lop, rop, &mut Seen::new())
+}
+
+fn compare_in(lop: &Value, rop: &Value, seen: &mut Seen) -> Result<Option<Ordering>, VMError> {
+    match (lop, rop) {
+        (Value::Int(l), Value::Int(r)) => Ok(Some(l.cmp(r))),
+        (Value::Float(l), Value::Float(r)) => Ok(l.partial_cmp(r)),
+        (Value::String(l), Value::String(r)) => Ok(Some(l.cmp(r))),
+        (Value::Array(l), Value::Array(r)) => {
+            if revisits(seen, l, r) {
+                return Ok(Some(Ordering::Equal));
+            }
+            let (l, r) = (l.borrow(), r.borrow());
+            for (l, r) in l.iter().zip(r.iter()) {
+                match compare_in(l, r, seen)? {
+                    Some(Ordering::Equal) => {}
+                    ordering => return Ok(ordering),
+                }
+            }
+            Ok(Some(l.len().cmp(&r.len())))
+        }
+        _ if let Some((l, r)) = bigints(lop, rop) => Ok(Some(l.cmp(&r))),
+        _ if let Some((l, r)) = rationals(lop, rop) => Ok(Some(l.cmp(&r))),
+        _ if let Some((l, r)) = floats(lop, rop) => Ok(l.partial_cmp(&r)),
+        _ => Err(VMError::InvalidOperandType(lop.clone(), rop.clone())),
+    }
+}
+
+fn order(lop: Value, rop: Value, holds: fn(Ordering) -> bool) -> Result<Value, VMError> {
+    Ok(Value::Bool(compare(&lop, &rop)?.is_some_and(holds)))
+}
+
+pub fn less_than(lop: Value, rop: Value) -> Result<Value, VMError> {
+    order(lop, rop, Ordering::is_lt)
+}
+
+pub fn greater_than(lop: Value, rop: Value) -> Result<Value, VMError> {
+    order(lop, rop, Ordering::is_gt)
+}
+
+pub fn greater_equal(lop: Value, rop: Value) -> Result<Value, VMError> {
+    order(lop, rop, Ordering::is_ge)
+}
+
+pub fn less_equal(lop: Value, rop: Value) -> Result<Value, VMError> {
+    order(lop, rop, Ordering::is_le)
+}
+
+pub fn not(val: Value) -> Result<Value, VMError> {
+    match val {
+        Value::Bool(v) => Ok(Value::Bool(!v)),
+        _ => Err(VMError::InvalidUnaryOperandType(val)),
+    }
+}
+
+pub fn logical_and(lop: Value, rop: Value) -> Result<Value, VMError> {
+    match (&lop, &rop) {
+        (Value::Bool(l), Value::Bool(r)) => Ok(Value::Bool(*l && *r)),
+        _ => Err(VMError::InvalidOperandType(lop, rop)),
+    }
+}
+
+pub fn logical_or(lop: Value, rop: Value) -> Result<Value, VMError> {
+    match (&lop, &rop) {
+        (Value::Bool(l), Value::Bool(r)) => Ok(Value::Bool(*l || *r)),
+        _ => Err(VMError::InvalidOperandType(lop, rop)),
+    }
+}
+
+// Bitwise ops, on the 64 bits of an Int. And, or, xor and not take BigInts as well, as if
+// they were two's complement with the sign extended forever, which agrees with an Int's bits.
+fn bitwise(
+    lop: Value,
+    rop: Value,
+    int: fn(i64, i64) -> i64,
+    big: fn(&BigInt, &BigInt) -> BigInt,
+) -> Result<Value, VMError> {
+    match (&lop, &rop) {
+        (Value::Int(l), Value::Int(r)) => Ok(Value::Int(int(*l, *r))),
+        _ if let Some((l, r)) = bigints(&lop, &rop) => Ok(Value::from_bigint(big(&l, &r))),
+        _ => Err(VMError::InvalidOperandType(lop, rop)),
+    }
+}
+
+pub fn bit_and(lop: Value, rop: Value) -> Result<Value, VMError> {
+    bitwise(lop, rop, |l, r| l & r, |l, r| l & r)
+}
+
+pub fn bit_or(lop: Value, rop: Value) -> Result<Value, VMError> {
+    bitwise(lop, rop, |l, r| l | r, |l, r| l | r)
+}
+
+pub fn bit_xor(lop: Value, rop: Value) -> Result<Value, VMError> {
+    bitwise(lop, rop, |l, r| l ^ r, |l, r| l ^ r)
+}
+
+pub fn bit_not(val: Value) -> Result<Value, VMError> {
+    match &val {
+        Value::Int(v) => Ok(Value::Int(!v)),
+        Value::BigInt(v) => Ok(Value::from_bigint(!v.as_ref())),
+        _ => Err(VMError::InvalidUnaryOperandType(val)),
+    }
+}
+
+// Shifts and rotates of an Int by 0 to 63 bits. Bits shifted out of the 64 are lost.
+fn shift(lop: Value, rop: Value, f: fn(i64, u32) -> i64) -> Result<Value, VMError> {
+    match (&lop, &rop) {
+        (Value::Int(l), Value::Int(n @ 0..64)) => Ok(Value::Int(f(*l, *n as u32))),
+        (Value::Int(_), Value::Int(_) | Value::BigInt(_)) => Err(VMError::ShiftOutOfRange(rop)),
+        _ => Err(VMError::InvalidOperandType(lop, rop)),
+    }
+}
+
+pub fn shl(lop: Value, rop: Value) -> Result<Value, VMError> {
+    shift(lop, rop, |l, n| l << n)
+}
+
+// Arithmetic, copying the sign bit in
+pub fn shr(lop: Value, rop: Value) -> Result<Value, VMError> {
+    shift(lop, rop, |l, n| l >> n)
+}
+
+// Logical, shifting zeros in
+pub fn ushr(lop: Value, rop: Value) -> Result<Value, VMError> {
+    shift(lop, rop, |l, n| ((l as u64) >> n) as i64)
+}
+
+pub fn rotate_left(lop: Value, rop: Value) -> Result<Value, VMError> {
+    shift(lop, rop, i64::rotate_left)
+}
+
+pub fn rotate_right(lop: Value, rop: Value) -> Result<Value, VMError> {
+    shift(lop, rop, i64::rotate_right)
+}
+
+// A count of an Int's bits
+fn bit_count(val: Value, f: fn(i64) -> u32) -> Result<Value, VMError> {
+    match val {
+        Value::Int(v) => Ok(Value::Int(f(v) as i64)),
+        _ => Err(VMError::InvalidUnaryOperandType(val)),
+    }
+}
+
+pub fn pop_count(val: Value) -> Result<Value, VMError> {
+    bit_count(val, i64::count_ones)
+}
+
+pub fn leading_zeros(val: Value) -> Result<Value, VMError> {
+    bit_count(val, i64::leading_zeros)
+}
+
+pub fn trailing_zeros(val: Value) -> Result<Value, VMError> {
+    bit_count(val, i64::trailing_zeros)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn rational(s: &str) -> Value {
+        Value::from_rational(s.parse().unwrap())
+    }
+
+    #[test]
+    fn mixed_numbers_promote() {
+        let big = || Value::from_bigint(BigInt::from(i64::MAX) * 4);
+        let (one, half) = (|| Value::Int(1), || Value::Float(0.5));
+        assert_eq!(add(one(), half()).unwrap(), Value::Float(1.5));
+        let doubled = mul(half(), big()).unwrap();
+        assert_eq!(doubled, Value::Float(i64::MAX as f64 * 2.0));
+        assert_eq!(sub(rational("3/4"), half()).unwrap(), Value::Float(0.25));
+        assert_eq!(div(one(), Value::Float(4.0)).unwrap(), Value::Float(0.25));
+        assert_eq!(
+            modulo(Value::Float(7.5), Value::Int(2)).unwrap(),
+            Value::Float(1.5)
+        );
+        assert_eq!(
+            div_int(Value::Int(7), Value::Float(-2.0)).unwrap(),
+            Value::Int(-4)
+        );
+        assert_eq!(
+            equal(Value::Int(2), Value::Float(2.0)).unwrap(),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            not_equal(half(), rational("1/2")).unwrap(),
+            Value::Bool(false)
+        );
+        assert_eq!(
+            less_than(big(), Value::Float(1e300)).unwrap(),
+            Value::Bool(true)
+        );
+        let nan = || Value::Float(f64::NAN);
+        assert_eq!(equal(nan(), nan()).unwrap(), Value::Bool(false));
+        assert!(add(one(), Value::Bool(true)).is_err());
+        assert!(matches!(
+            div(one(), Value::Float(0.0)),
+            Err(VMError::DivisionByZero)
+        ));
+    }
+
+    #[test]
+    fn huge_powers_are_not_exact() {
+        let int = Value::Int;
+        let two_to = |e: i64| Value::from_bigint(BigInt::from(1) << e);
+        assert_eq!(pow(int(2), int(1_000_000)).unwrap(), two_to(1_000_000));
+        assert_eq!(
+            pow(int(10), int(2_000_000_000)).unwrap(),
+            Value::Float(f64::INFINITY)
+        );
+        assert_eq!(
+            pow(rational("1/3"), int(-2_000_000_000)).unwrap(),
+            Value::Float(f64::INFINITY)
+        );
+        assert_eq!(
+            pow(rational("2/3"), int(2_000_000_000)).unwrap(),
+            Value::Float(0.0)
+        );
+        // Bases whose powers stay small are still exact
+        assert_eq!(pow(int(-1), int(2_000_000_001)).unwrap(), int(-1));
+        assert_eq!(pow(int(0), int(2_000_000_000)).unwrap(), int(0));
+        assert!(matches!(
+            pow(int(0), int(-2_000_000_000)),
+            Err(VMError::DivisionByZero)
+        ));
+    }
+
+    #[test]
+    fn conversions() {
+        let float = |v: f64, mode: Rounding| to_int(Value::Float(v), mode);
+        assert_eq!(float(-2.5, Rounding::Trunc).unwrap(), Value::Int(-2));
+        assert_eq!(float(-2.5, Rounding::Floor).unwrap(), Value::Int(-3));
+        assert_eq!(float(2.1, Rounding::Ceil).unwrap(), Value::Int(3));
+        assert_eq!(float(2.5, Rounding::Round).unwrap(), Value::Int(3));
+        let rounded = to_int(rational("-7/2"), Rounding::Round).unwrap();
+        assert_eq!(rounded, Value::Int(-4));
+        let beyond_int = float(1e20, Rounding::Trunc).unwrap();
+        assert_eq!(beyond_int, Value::from_bigint(BigInt::from(10).pow(20)));
+        assert!(matches!(
+            float(f64::NAN, Rounding::Trunc),
+            Err(VMError::NaNConversion)
+        ));
+        assert!(matches!(
+            float(f64::INFINITY, Rounding::Floor),
+            Err(VMError::OutOfRange(_))
+        ));
+        assert_eq!(to_float(Value::Int(3)).unwrap(), Value::Float(3.0));
+        assert_eq!(to_float(rational("1/4")).unwrap(), Value::Float(0.25));
+        let huge = Value::from_bigint(BigInt::from(10).pow(400));
+        assert!(matches!(to_float(huge), Err(VMError::OutOfRange(_))));
+        assert!(to_float(Value::Bool(true)).is_err());
+    }
+
+    #[test]
+    fn overflow_modes() {
+        let (max, min) = (|| Value::Int(i64::MAX), || Value::Int(i64::MIN));
+        let add_one = |mode| overflowing(mode, add, max(), Value::Int(1));
+        let promoted = Value::from_bigint(BigInt::from(i64::MAX) + 1);
+        assert_eq!(add_one(Overflow::Promote).unwrap(), promoted);
+        assert!(matches!(
+            add_one(Overflow::Checked),
+            Err(VMError::IntegerOverflow)
+        ));
+        assert_eq!(add_one(Overflow::Wrapping).unwrap(), min());
+        assert_eq!(add_one(Overflow::Saturating).unwrap(), max());
+        let squared = overflowing(Overflow::Wrapping, mul, max(), max()).unwrap();
+        assert_eq!(squared, Value::Int(1));
+        let negated = overflowing(Overflow::Wrapping, div, min(), Value::Int(-1)).unwrap();
+        assert_eq!(negated, min());
+        let below = overflowing(Overflow::Saturating, sub, min(), Value::Int(1)).unwrap();
+        assert_eq!(below, min());
+        // Results that fit, and BigInt operands, are the same in every mode
+        let fits = overflowing(Overflow::Checked, sub, max(), max()).unwrap();
+        assert_eq!(fits, Value::Int(0));
+        let big = overflowing(Overflow::Checked, add, promoted.clone(), Value::Int(1)).unwrap();
+        assert_eq!(big, Value::from_bigint(BigInt::from(i64::MAX) + 2));
+    }
+
+    #[test]
+    fn bitwise_and_shifts() {
+        let int = Value::Int;
+        assert_eq!(bit_and(int(0b1100), int(0b1010)).unwrap(), int(0b1000));
+        assert_eq!(bit_or(int(0b1100), int(0b1010)).unwrap(), int(0b1110));
+        assert_eq!(bit_xor(int(0b1100), int(0b1010)).unwrap(), int(0b0110));
+        assert_eq!(bit_not(int(0)).unwrap(), int(-1));
+        // A BigInt mask keeps the low 64 bits of a negative Int as a positive number
+        let mask = Value::from_bigint(BigInt::from(u64::MAX));
+        let low = bit_and(int(-1), mask.clone()).unwrap();
+        assert_eq!(low, mask);
+        assert_eq!(bit_xor(low, mask).unwrap(), int(0));
+        assert_eq!(shl(int(1), int(63)).unwrap(), int(i64::MIN));
+        assert_eq!(shr(int(-16), int(2)).unwrap(), int(-4));
+        assert_eq!(ushr(int(-16), int(60)).unwrap(), int(15));
+        assert_eq!(rotate_left(int(i64::MIN), int(1)).unwrap(), int(1));
+        assert_eq!(rotate_right(int(1), int(1)).unwrap(), int(i64::MIN));
+        assert!(matches!(
+            shl(int(1), int(64)),
+            Err(VMError::ShiftOutOfRange(Value::Int(64)))
+        ));
+        assert!(matches!(
+            shr(int(1), int(-1)),
+            Err(VMError::ShiftOutOfRange(_))
+        ));
+        assert!(shl(Value::Float(1.0), int(1)).is_err());
+        assert_eq!(pop_count(int(-1)).unwrap(), int(64));
+        assert_eq!(leading_zeros(int(1)).unwrap(), int(63));
+        assert_eq!(trailing_zeros(int(0)).unwrap(), int(64));
+        let wide = Value::from_bigint(BigInt::from(1) << 70);
+        assert!(pop_count(wide).is_err());
+    }
+
+    const SHIFTS: [BinaryOp; 5] = [shl, shr, ushr, rotate_left, rotate_right];
+
+    #[test]
+    fn shift_counts_out_of_range() {
+        let int = Value::Int;
+        let huge = Value::from_bigint(BigInt::from(1) << 70);
+        for op in SHIFTS {
+            assert!(op(int(-1), int(63)).is_ok());
+            for count in [int(64), int(-1), int(i64::MIN), huge.clone()] {
+                match op(int(1), count.clone()) {
+                    Err(VMError::ShiftOutOfRange(val)) => assert_eq!(val, count),
+                    other => panic!("Expected ShiftOutOfRange, got {:?}", other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shifts_by_zero_and_63() {
+        let int = Value::Int;
+        let bits = int(0x0123_4567_89ab_cdef);
+        for op in SHIFTS {
+            assert_eq!(op(bits.clone(), int(0)).unwrap(), bits);
+        }
+        assert_eq!(
+            rotate_left(bits.clone(), int(63)).unwrap(),
+            rotate_right(bits.clone(), int(1)).unwrap()
+        );
+        assert_eq!(
+            rotate_right(bits.clone(), int(63)).unwrap(),
+            rotate_left(bits, int(1)).unwrap()
+        );
+        assert_eq!(shl(int(3), int(63)).unwrap(), int(i64::MIN));
+        assert_eq!(shr(int(i64::MIN), int(63)).unwrap(), int(-1));
+        assert_eq!(ushr(int(i64::MIN), int(63)).unwrap(), int(1));
+    }
+
+    #[test]
+    fn bigint_and_negative_operands() {
+        let int = Value::Int;
+        let big = |v: BigInt| Value::from_bigint(v);
+        let wide = || BigInt::from(1) << 70;
+        // Only Ints shift; a BigInt is a type error rather than losing its high bits
+        assert!(matches!(
+            shl(big(wide()), int(1)),
+            Err(VMError::InvalidOperandType(Value::BigInt(_), Value::Int(1)))
+        ));
+        assert_eq!(shl(int(-1), int(4)).unwrap(), int(-16));
+        assert_eq!(shr(int(-1), int(40)).unwrap(), int(-1));
+        assert_eq!(ushr(int(-1), int(1)).unwrap(), int(i64::MAX));
+        assert_eq!(rotate_left(int(-2), int(1)).unwrap(), int(-3));
+        // Negative BigInts act as sign extended two's complement
+        assert_eq!(bit_and(big(-wide()), int(-1)).unwrap(), big(-wide()));
+        assert_eq!(bit_or(big(wide()), int(-1)).unwrap(), int(-1));
+        assert_eq!(bit_xor(big(-wide()), big(-wide())).unwrap(), int(0));
+        assert_eq!(bit_not(big(wide())).unwrap(), big(-wide() - 1));
+        assert_eq!(bit_not(int(i64::MIN)).unwrap(), int(i64::MAX));
+    }
+
+    #[test]
+    fn bit_counts_of_negatives() {
+        let int = Value::Int;
+        assert_eq!(pop_count(int(-2)).unwrap(), int(63));
+        assert_eq!(pop_count(int(i64::MIN)).unwrap(), int(1));
+        assert_eq!(leading_zeros(int(-1)).unwrap(), int(0));
+        assert_eq!(trailing_zeros(int(i64::MIN)).unwrap(), int(63));
+        assert_eq!(trailing_zeros(int(-4)).unwrap(), int(2));
+    }
+
+    #[test]
+    fn floats_and_bools_are_type_errors() {
+        let int = Value::Int;
+        for val in [Value::Float(1.0), Value::Bool(true)] {
+            assert!(matches!(
+                bit_and(val.clone(), int(1)),
+                Err(VMError::InvalidOperandType(..))
+            ));
+            assert!(matches!(
+                bit_xor(int(1), val.clone()),
+                Err(VMError::InvalidOperandType(..))
+            ));
+            assert!(matches!(
+                bit_not(val.clone()),
+                Err(VMError::InvalidUnaryOperandType(_))
+            ));
+            for op in SHIFTS {
+                assert!(matches!(
+                    op(val.clone(), int(1)),
+                    Err(VMError::InvalidOperandType(..))
+                ));
+                assert!(matches!(
+                    op(int(1), val.clone()),
+                    Err(VMError::InvalidOperandType(..))
+                ));
+            }
+            for op in [pop_count, leading_zeros, trailing_zeros] {
+                assert!(matches!(
+                    op(val.clone()),
+                    Err(VMError::InvalidUnaryOperandType(_))
+                ));
+            }
+        }
+    }
+
+    fn string(s: &str) -> Value {
+        Value::String(Rc::new(s.to_string()))
+    }
+
+    fn is(val: Result<Value, VMError>, expected: bool) {
+        assert_eq!(val.unwrap(), Value::Bool(expected));
+    }
+
+    // An array holding only itself
+    fn cycle() -> Value {
+        let array = Value::new_array(vec![]);
+        Value::push_to_array(array.clone(), array.clone()).unwrap();
+        array
+    }
+
+    #[test]
+    fn scalar_equality() {
+        let int = Value::Int;
+        is(equal(string("abc"), string("abc")), true);
+        is(equal(Value::NULL, Value::NULL), true);
+        is(equal(int(2), Value::Float(2.0)), true);
+        is(equal(Value::Float(f64::NAN), Value::Float(f64::NAN)), false);
+        is(
+            not_equal(Value::Float(f64::NAN), Value::Float(f64::NAN)),
+            true,
+        );
+        is(equal(int(1), string("1")), false);
+        is(equal(Value::NULL, Value::Bool(false)), false);
+        is(identical(int(2), Value::Float(2.0)), true);
+    }
+
+    #[test]
+    fn nested_array_equality() {
+        let (int, array) = (Value::Int, Value::new_array);
+        let nested = || array(vec![int(1), array(vec![string("x"), array(vec![])])]);
+        let (a, b) = (nested(), nested());
+        is(equal(a.clone(), b.clone()), true);
+        is(identical(a.clone(), b.clone()), false);
+        is(identical(a.clone(), a.clone()), true);
+        let deeper = array(vec![int(1), array(vec![string("y"), array(vec![])])]);
+        is(equal(a.clone(), deeper), false);
+        let shorter = array(vec![int(1), array(vec![string("x")])]);
+        is(equal(a.clone(), shorter), false);
+        let nan = || array(vec![array(vec![Value::Float(f64::NAN)])]);
+        is(equal(nan(), nan()), false);
+        is(equal(Value::new_box(a), Value::new_box(b)), true);
+    }
+
+    #[test]
+    fn arrays_holding_nan_are_not_equal_to_themselves() {
+        let nan = Value::new_array(vec![Value::Int(1), Value::Float(f64::NAN)]);
+        is(equal(nan.clone(), nan.clone()), false);
+        is(not_equal(nan.clone(), nan.clone()), true);
+        is(less_equal(nan.clone(), nan.clone()), false);
+        // Identity is still identity
+        is(identical(nan.clone(), nan.clone()), true);
+        let boxed = Value::new_box(Value::Float(f64::NAN));
+        is(equal(boxed.clone(), boxed.clone()), false);
+        is(identical(boxed.clone(), boxed), true);
+        let cycle = cycle();
+        Value::push_to_array(Value::Float(f64::NAN), cycle.clone()).unwrap();
+        is(equal(cycle.clone(), cycle), false);
+    }
+
+    #[test]
+    fn strings_are_identical_by_value() {
+        let (a, b) = (string("abc"), string("abc"));
+        assert!(!matches!((&a, &b), (Value::String(l), Value::String(r)) if Rc::ptr_eq(l, r)));
+        is(identical(a.clone(), b), true);
+        is(identical(a.clone(), a.clone()), true);
+        is(identical(a, string("abd")), false);
+        // Unlike arrays of the same strings
+        let array = || Value::new_array(vec![string("abc")]);
+        is(identical(array(), array()), false);
+    }
+
+    #[test]
+    fn cyclic_values_are_compared() {
+        let (a, b) = (cycle(), cycle());
+        is(equal(a.clone(), a.clone()), true);
+        is(equal(a.clone(), b.clone()), true);
+        is(less_than(a.clone(), a.clone()), false);
+        is(less_than(a.clone(), b.clone()), false);
+        is(less_equal(a.clone(), b.clone()), true);
+        // Differing after the cycle still tells them apart
+        Value::push_to_array(Value::Int(1), a.clone()).unwrap();
+        Value::push_to_array(Value::Int(2), b.clone()).unwrap();
+        is(equal(a.clone(), b.clone()), false);
+        is(less_than(a, b), true);
+        let boxed = || {
+            let val = Value::new_box(Value::NULL);
+            if let Value::HeapValue(rc) = &val {
+                *rc.borrow_mut() = val.clone();
+            }
+            val
+        };
+        is(equal(boxed(), boxed()), true);
+    }
+
+    #[test]
+    fn string_and_array_ordering() {
+        let (int, array) = (Value::Int, Value::new_array);
+        is(less_than(string("abc"), string("abd")), true);
+        is(less_than(string("ab"), string("abc")), true);
+        is(greater_equal(string("b"), string("abc")), true);
+        let short = array(vec![int(1), int(2)]);
+        is(
+            less_than(short.clone(), array(vec![int(1), int(2), int(0)])),
+            true,
+        );
+        is(greater_than(array(vec![int(1), int(3)]), short), true);
+        let nested = |v| array(vec![array(vec![int(v)])]);
+        is(less_than(nested(1), nested(2)), true);
+        is(less_than(nested(2), nested(1)), false);
+    }
+
+    #[test]
+    fn nan_ordering() {
+        let nan = || Value::Float(f64::NAN);
+        for op in [less_than, greater_than, less_equal, greater_equal] {
+            is(op(nan(), Value::Int(1)), false);
+            is(op(Value::Float(1.0), nan()), false);
+            is(op(nan(), nan()), false);
+            let array = |v| Value::new_array(vec![v]);
+            is(op(array(nan()), array(Value::Float(0.0))), false);
+        }
+    }
+
+    #[test]
+    fn mixed_type_ordering_errors() {
+        let int = Value::Int;
+        for (lop, rop) in [
+            (string("a"), int(1)),
+            (Value::Complex(Complex64::ONE), int(1)),
+            (Value::Bool(false), Value::Bool(true)),
+            (Value::NULL, Value::NULL),
+            (Value::new_array(vec![]), string("")),
+        ] {
+            assert!(matches!(
+                less_than(lop, rop),
+                Err(VMError::InvalidOperandType(..))
+            ));
+        }
+        let mixed = |v| Value::new_array(vec![int(1), v]);
+        assert!(less_than(mixed(int(2)), mixed(string("2"))).is_err());
+    }
+}
@@ -0,0 +1,620 @@
+use crate::bytecode::Bytecode;
+use crate::error::VMError;
+use crate::error::VerifyError;
+use crate::instr::{IndexedCode, Instr};
+use crate::ops::{self, Overflow};
+use crate::value::Value;
+use crate::verifier::verify;
+
+// Bytecode with jump targets, function addresses and the entry point as instruction indices,
+// so passes can delete and rewrite instructions without tracking byte offsets
+struct Program {
+    instrs: Vec<Instr>,
+    consts: Vec<Value>,
+    functions: Vec<usize>,
+    entry: usize,
+}
+
+impl Program {
+    // Instructions control can reach other than by falling through
+    fn block_heads(&self) -> Vec<bool> {
+        let mut heads = vec![false; self.instrs.len() + 1];
+        for instr in &self.instrs {
+            if let Some(target) = instr.jump_target() {
+                heads[target as usize] = true;
+            }
+        }
+        for func in &self.functions {
+            heads[*func] = true;
+        }
+        heads[self.entry] = true;
+        heads
+    }
+
+    // The value pushed by a constant push
+    fn constant(&self, instr: Instr) -> Option<Value> {
+        match instr {
+            Instr::PushConst(idx) => Some(self.consts[idx as usize].clone()),
+            Instr::PushImmediate(val) => Some(Value::Int(val as i64)),
+            _ => None,
+        }
+    }
+
+    // An instruction pushing val, reusing an existing const where possible
+    fn push_value(&mut self, val: Value) -> Option<Instr> {
+        if let Value::Int(int) = val
+            && let Ok(imm) = i16::try_from(int)
+        {
+            return Some(Instr::PushImmediate(imm));
+        }
+        self.const_index(val).map(Instr::PushConst)
+    }
+
+    // Index of a const holding val, adding one if there isn't one already
+    fn const_index(&mut self, val: Value) -> Option<u16> {
+        // Compare floats bitwise so -0.0 doesn't reuse a 0.0 const
+        let same = |c: &Value| match (c, &val) {
+            (Value::Float(c), Value::Float(v)) => c.to_bits() == v.to_bits(),
+            _ => *c == val,
+        };
+        if let Some(idx) = self.consts.iter().position(same) {
+            return Some(idx as u16);
+        }
+        if self.consts.len() > u16::MAX as usize {
+            return None;
+        }
+        self.consts.push(val);
+        Some((self.consts.len() - 1) as u16)
+    }
+
+    // Drop every instruction not marked keep. Anything that pointed at a dropped instruction
+    // now points at the next one kept.
+    fn retain(&mut self, keep: &[bool]) -> bool {
+        if keep.iter().all(|k| *k) {
+            return false;
+        }
+        let mut new_index = vec![0; self.instrs.len() + 1];
+        let mut next = keep.iter().filter(|k| **k).count();
+        new_index[self.instrs.len()] = next;
+        for idx in (0..self.instrs.len()).rev() {
+            if keep[idx] {
+                next -= 1;
+            }
+            new_index[idx] = next;
+        }
+        let instrs: Vec<Instr> = self
+            .instrs
+            .iter()
+            .zip(keep)
+            .filter(|(_, k)| **k)
+            .map(|(instr, _)| match instr.jump_target() {
+                Some(target) => instr.with_jump_target(new_index[target as usize] as u32),
+                None => *instr,
+            })
+            .collect();
+        self.instrs = instrs;
+        for func in self.functions.iter_mut() {
+            *func = new_index[*func];
+        }
+        self.entry = new_index[self.entry];
+        true
+    }
+}
+
+// Optimization passes, run until none of them changes anything:
+// - peephole: removes label/function NoOps, turns strl x; pshl x into strk x, drops constants
+//   that are pushed and immediately popped, threads jumps to jumps and removes jumps to the
+//   next instruction
+// - constant folding: evaluates arithmetic, comparisons and logic on constant operands with
+//   the VM's own semantics, and turns branches on constants into jumps or fallthroughs
+// - dead code: removes instructions unreachable from the entry point and every function, then
+//   drops constants nothing pushes anymore
+// Once nothing changes, common sequences are fused into superinstructions.
+// Function addresses, jump targets and the entry point are rewritten to match the shrunken code.
+pub fn optimize(bytecode: Bytecode) -> Result<Bytecode, VerifyError> {
+    verify(&bytecode)?;
+    let indexed = IndexedCode::decode(&bytecode.code).expect("verified code decodes");
+    let code_len = bytecode.code.len();
+    let index_of = |address: usize| {
+        indexed
+            .index_of(address, code_len)
+            .expect("verified address")
+    };
+    let functions = bytecode
+        .functions
+        .iter()
+        .map(|f| index_of(f.address))
+        .collect();
+    let entry = index_of(bytecode.entry);
+    let mut program = Program {
+        instrs: indexed.instrs,
+        consts: bytecode.consts,
+        functions,
+        entry,
+    };
+
+    loop {
+        let mut changed = thread_jumps(&mut program);
+        changed |= peephole(&mut program);
+        changed |= fold_constants(&mut program);
+        changed |= remove_unreachable(&mut program);
+        let keep: Vec<bool> = program.instrs.iter().map(|i| *i != Instr::NoOp).collect();
+        changed |= program.retain(&keep);
+        if !changed {
+            break;
+        }
+    }
+    fuse(&mut program);
+    remove_unused_consts(&mut program);
+
+    let (code, offsets) = IndexedCode::encode(&program.instrs);
+    let mut functions = bytecode.functions;
+    for (func, idx) in functions.iter_mut().zip(&program.functions) {
+        func.address = offsets[*idx];
+    }
+    Ok(Bytecode {
+        entry: offsets[program.entry],
+        consts: program.consts,
+        functions,
+        code,
+    })
+}
+
+// Point jumps that land on an unconditional jump (possibly through NoOps) at its final target
+fn thread_jumps(program: &mut Program) -> bool {
+    let mut changed = false;
+    for idx in 0..program.instrs.len() {
+        let Some(target) = program.instrs[idx].jump_target() else {
+            continue;
+        };
+        let mut final_target = target as usize;
+        // Bounded so a cycle of jumps can't loop forever
+        for _ in 0..program.instrs.len() {
+            match program.instrs.get(final_target) {
+                Some(Instr::NoOp) => final_target += 1,
+                Some(Instr::Jump(next)) if *next as usize != final_target => {
+                    final_target = *next as usize
+                }
+                _ => break,
+            }
+        }
+        if final_target != target as usize {
+            program.instrs[idx] = program.instrs[idx].with_jump_target(final_target as u32);
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn peephole(program: &mut Program) -> bool {
+    let heads = program.block_heads();
+    let mut keep = vec![true; program.instrs.len()];
+    let mut idx = 0;
+    while idx < program.instrs.len() {
+        let instr = program.instrs[idx];
+        let next = program.instrs.get(idx + 1).filter(|_| !heads[idx + 1]);
+        match (instr, next) {
+            (Instr::StoreLocal(store), Some(Instr::PushLocal(load))) if store == *load => {
+                program.instrs[idx] = Instr::StoreLocalKeep(store);
+                keep[idx + 1] = false;
+                idx += 2;
+            }
+            (Instr::PushConst(_) | Instr::PushImmediate(_), Some(Instr::Pop)) => {
+                keep[idx] = false;
+                keep[idx + 1] = false;
+                idx += 2;
+            }
+            (Instr::Jump(target), _) if target as usize == idx + 1 => {
+                keep[idx] = false;
+                idx += 1;
+            }
+            _ => idx += 1,
+        }
+    }
+    program.retain(&keep)
+}
+
+// Evaluate an operator whose operands are all constants. None when the VM would fail at
+// runtime, so the error is still raised there. Int arithmetic that overflows is left alone
+// too, since what it gives depends on the VM's overflow mode.
+fn evaluate(instr: Instr, operands: &[Value]) -> Option<Value> {
+    let result: Result<Value, VMError> = match (instr, operands) {
+        (Instr::Not, [val]) => ops::not(val.clone()),
+        (Instr::BitNot, [val]) => ops::bit_not(val.clone()),
+        (Instr::PopCount, [val]) => ops::pop_count(val.clone()),
+        (Instr::LeadingZeros, [val]) => ops::leading_zeros(val.clone()),
+        (Instr::TrailingZeros, [val]) => ops::trailing_zeros(val.clone()),
+        (Instr::Math(f), _) => f.apply(operands),
+        (Instr::ToInt(mode), [val]) => ops::to_int(val.clone(), mode),
+        (Instr::ToFloat, [val]) => ops::to_float(val.clone()),
+        (_, [lop, rop]) => {
+            let (lop, rop) = (lop.clone(), rop.clone());
+            let checked = |f| ops::overflowing(Overflow::Checked, f, lop.clone(), rop.clone());
+            match instr {
+                _ if let Some((mode, f)) = instr.overflow() => ops::overflowing(mode, f, lop, rop),
+                Instr::Add => checked(ops::add),
+                Instr::Sub => checked(ops::sub),
+                Instr::Mul => checked(ops::mul),
+                Instr::Div => checked(ops::div),
+                Instr::DivInt => checked(ops::div_int),
+                Instr::Mod => ops::modulo(lop, rop),
+                Instr::DivExact => ops::div_exact(lop, rop),
+                Instr::Equal => ops::equal(lop, rop),
+                Instr::NotEqual => ops::not_equal(lop, rop),
+                Instr::LessThan => ops::less_than(lop, rop),
+                Instr::GreaterThan => ops::greater_than(lop, rop),
+                Instr::GreaterEqual => ops::greater_equal(lop, rop),
+                Instr::LessEqual => ops::less_equal(lop, rop),
+                Instr::LogicalAnd => ops::logical_and(lop, rop),
+                Instr::LogicalOr => ops::logical_or(lop, rop),
+                Instr::Identical => ops::identical(lop, rop),
+                Instr::BitAnd => ops::bit_and(lop, rop),
+                Instr::BitOr => ops::bit_or(lop, rop),
+                Instr::BitXor => ops::bit_xor(lop, rop),
+                Instr::Shl => ops::shl(lop, rop),
+                Instr::Shr => ops::shr(lop, rop),
+                Instr::UShr => ops::ushr(lop, rop),
+                Instr::RotateLeft => ops::rotate_left(lop, rop),
+                Instr::RotateRight => ops::rotate_right(lop, rop),
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    result.ok()
+}
+
+fn operand_count(instr: Instr) -> usize {
+    match instr {
+        Instr::Not
+        | Instr::BitNot
+        | Instr::PopCount
+        | Instr::LeadingZeros
+        | Instr::TrailingZeros
+        | Instr::ToInt(_)
+        | Instr::ToFloat => 1,
+        Instr::Math(f) => f.arity(),
+        Instr::Add
+        | Instr::Sub
+        | Instr::Mul
+        | Instr::Div
+        | Instr::DivInt
+        | Instr::Mod
+        | Instr::DivExact
+        | Instr::AddWrap
+        | Instr::SubWrap
+        | Instr::MulWrap
+        | Instr::AddChecked
+        | Instr::SubChecked
+        | Instr::MulChecked
+        | Instr::AddSat
+        | Instr::SubSat
+        | Instr::MulSat
+        | Instr::Equal
+        | Instr::NotEqual
+        | Instr::LessThan
+        | Instr::GreaterThan
+        | Instr::GreaterEqual
+        | Instr::LessEqual
+        | Instr::LogicalAnd
+        | Instr::LogicalOr
+        | Instr::Identical
+        | Instr::BitAnd
+        | Instr::BitOr
+        | Instr::BitXor
+        | Instr::Shl
+        | Instr::Shr
+        | Instr::UShr
+        | Instr::RotateLeft
+        | Instr::RotateRight => 2,
+        _ => 0,
+    }
+}
+
+// Replace operators fed only by constant pushes in the same block with a push of the result,
+// and branches on a constant bool with a jump or nothing
+fn fold_constants(program: &mut Program) -> bool {
+    let heads = program.block_heads();
+    let mut keep = vec![true; program.instrs.len()];
+    let mut changed = false;
+    for idx in 0..program.instrs.len() {
+        let instr = program.instrs[idx];
+        let count = match instr {
+            Instr::JumpIfFalse(_) | Instr::JumpIfTrue(_) => 1,
+            _ => operand_count(instr),
+        };
+        if count == 0 || idx < count {
+            continue;
+        }
+        let first = idx - count;
+        // The operands and the operator must run together, with nothing jumping in between
+        if (first + 1..=idx).any(|i| heads[i]) || (first..idx).any(|i| !keep[i]) {
+            continue;
+        }
+        let Some(operands) = (first..idx)
+            .map(|i| program.constant(program.instrs[i]))
+            .collect::<Option<Vec<Value>>>()
+        else {
+            continue;
+        };
+        let folded = match (instr, &operands[..]) {
+            (Instr::JumpIfFalse(target), [Value::Bool(false)])
+            | (Instr::JumpIfTrue(target), [Value::Bool(true)]) => Instr::Jump(target),
+            (Instr::JumpIfFalse(_) | Instr::JumpIfTrue(_), [Value::Bool(_)]) => Instr::NoOp,
+            (Instr::JumpIfFalse(_) | Instr::JumpIfTrue(_), _) => continue,
+            _ => match evaluate(instr, &operands).and_then(|val| program.push_value(val)) {
+                Some(push) => push,
+                None => continue,
+            },
+        };
+        for dropped in keep.iter_mut().take(idx).skip(first) {
+            *dropped = false;
+        }
+        program.instrs[idx] = folded;
+        changed = true;
+    }
+    program.retain(&keep) || changed
+}
+
+// Replace common sequences with superinstructions. None of them may span a block head, since
+// control can't enter a superinstruction halfway through.
+fn fuse(program: &mut Program) {
+    let heads = program.block_heads();
+    let mut keep = vec![true; program.instrs.len()];
+    let mut idx = 0;
+    while idx < program.instrs.len() {
+        let end = (idx + 1..program.instrs.len())
+            .find(|i| heads[*i])
+            .unwrap_or(program.instrs.len())
+            .min(idx + 4);
+        let window = program.instrs[idx..end].to_vec();
+        let (fused, len) = match fuse_at(program, &window) {
+            Some(found) => found,
+            None => {
+                idx += 1;
+                continue;
+            }
+        };
+        program.instrs[idx] = fused;
+        for dropped in keep.iter_mut().skip(idx + 1).take(len - 1) {
+            *dropped = false;
+        }
+        idx += len;
+    }
+    program.retain(&keep);
+}
+
+// The superinstruction a block starting with these instructions begins with, and how many
+// instructions it replaces
+fn fuse_at(program: &mut Program, instrs: &[Instr]) -> Option<(Instr, usize)> {
+    let Some(Instr::PushLocal(local)) = instrs.first().copied() else {
+        return None;
+    };
+    let constant = instrs.get(1).and_then(|i| program.constant(*i));
+    // pshl x; pshc k; add; strl x, with k a small int. Not sub, as x + -k isn't always x - k:
+    // it builds a different expression for a symbol, and -0.0 - 0 is -0.0 but -0.0 + 0 is 0.0
+    if let (Some(Value::Int(k)), Some(Instr::Add), Some(Instr::StoreLocal(store))) =
+        (&constant, instrs.get(2), instrs.get(3))
+        && *store == local
+        && let Ok(delta) = i16::try_from(*k)
+    {
+        return Some((Instr::IncLocal(local, delta), 4));
+    }
+    // pshl x; pshc k; <cmp>; jmpt/jmpf
+    if let (Some(val), Some(cmp), Some(jump)) = (&constant, instrs.get(2), instrs.get(3)) {
+        let cond = match jump {
+            Instr::JumpIfTrue(_) => Instr::condition(*cmp, true),
+            Instr::JumpIfFalse(_) => Instr::condition(*cmp, false),
+            _ => None,
+        };
+        if let (Some(cond), Some(target)) = (cond, jump.jump_target())
+            && let Some(idx) = program.const_index(val.clone())
+        {
+            return Some((Instr::CompareLocalConstJump(local, idx, cond, target), 4));
+        }
+    }
+    // pshl x; pshc k; add
+    if let (Some(val), Some(Instr::Add)) = (&constant, instrs.get(2))
+        && let Some(idx) = program.const_index(val.clone())
+    {
+        return Some((Instr::AddLocalConst(local, idx), 3));
+    }
+    // pshl x; pshl y
+    if let Some(Instr::PushLocal(second)) = instrs.get(1) {
+        return Some((Instr::PushLocalPair(local, *second), 2));
+    }
+    None
+}
+
+// Drop every instruction that can't be reached from the entry point or a function
+fn remove_unreachable(program: &mut Program) -> bool {
+    let len = program.instrs.len();
+    let mut reachable = vec![false; len];
+    let mut work: Vec<usize> = program.functions.clone();
+    work.push(program.entry);
+    while let Some(idx) = work.pop() {
+        if idx >= len || reachable[idx] {
+            continue;
+        }
+        reachable[idx] = true;
+        let instr = program.instrs[idx];
+        match instr {
+            Instr::Jump(target) => work.push(target as usize),
+            Instr::JumpIfFalse(target) | Instr::JumpIfTrue(target) => {
+                work.push(target as usize);
+                work.push(idx + 1);
+            }
+            Instr::Return => {}
+            _ => work.push(idx + 1),
+        }
+    }
+    program.retain(&reachable)
+}
+
+// Drop constants no instruction pushes and renumber the rest
+fn remove_unused_consts(program: &mut Program) {
+    let mut used = vec![false; program.consts.len()];
+    for instr in program.instrs.iter_mut() {
+        if let Some(idx) = const_operand(instr) {
+            used[*idx as usize] = true;
+        }
+    }
+    let mut new_index = vec![0u16; program.consts.len()];
+    let mut consts: Vec<Value> = Vec::new();
+    for (idx, val) in program.consts.drain(..).enumerate() {
+        if used[idx] {
+            new_index[idx] = consts.len() as u16;
+            consts.push(val);
+        }
+    }
+    program.consts = consts;
+    for instr in program.instrs.iter_mut() {
+        if let Some(idx) = const_operand(instr) {
+            *idx = new_index[*idx as usize];
+        }
+    }
+}
+
+fn const_operand(instr: &mut Instr) -> Option<&mut u16> {
+    match instr {
+        Instr::PushConst(idx)
+        | Instr::AddLocalConst(_, idx)
+        | Instr::CompareLocalConstJump(_, idx, _, _)
+        | Instr::Differentiate(idx) => Some(idx),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::assembler::assemble_source;
+    use std::rc::Rc;
+
+    fn instrs(bytecode: &Bytecode) -> Vec<Instr> {
+        IndexedCode::decode(&bytecode.code).unwrap().instrs
+    }
+
+    #[test]
+    fn store_then_load_becomes_store_keep() {
+        let bytecode = assemble_source("func f 1\npshl arg0\nstrl x\npshl x\nendf").unwrap();
+        let optimized = optimize(bytecode).unwrap();
+        assert_eq!(
+            instrs(&optimized),
+            [Instr::PushLocal(0), Instr::StoreLocalKeep(1), Instr::Return]
+        );
+        assert_eq!(optimized.functions[0].address, 0);
+    }
+
+    #[test]
+    fn dead_pushes_and_noops_are_removed() {
+        let bytecode = assemble_source("main\npshc 1\npop\nlabel a\npshi 2\nprnt").unwrap();
+        let optimized = optimize(bytecode).unwrap();
+        assert_eq!(instrs(&optimized), [Instr::PushImmediate(2), Instr::Print]);
+        assert_eq!(optimized.entry, 0);
+    }
+
+    #[test]
+    fn jumps_are_threaded_and_retargeted() {
+        let source = "main\njump a\nlabel b\npshi 1\nprnt\nlabel a\njump c\nlabel c\njump b";
+        let optimized = optimize(assemble_source(source).unwrap()).unwrap();
+        assert_eq!(
+            instrs(&optimized),
+            [Instr::PushImmediate(1), Instr::Print, Instr::Jump(0)]
+        );
+    }
+
+    #[test]
+    fn function_addresses_follow_shrunken_code() {
+        let source =
+            "func f 0\npshc 1\npop\npshi 3\nendf\nfunc g 0\ncallf f\nendf\nmain\ncallf g\nprnt";
+        let bytecode = assemble_source(source).unwrap();
+        let optimized = optimize(bytecode).unwrap();
+        let code = instrs(&optimized);
+        let offsets = IndexedCode::decode(&optimized.code).unwrap().offsets;
+        assert_eq!(optimized.functions[0].address, offsets[0]);
+        assert_eq!(code[2], Instr::CallFunction(0));
+        assert_eq!(optimized.functions[1].address, offsets[2]);
+        assert_eq!(optimized.entry, offsets[4]);
+    }
+
+    #[test]
+    fn superinstructions_stop_at_jump_targets() {
+        let source = "func f 2\npshl arg0\npshl arg1\nlabel mid\npshi 1\nadd\njump mid\nendf";
+        let optimized = optimize(assemble_source(source).unwrap()).unwrap();
+        assert_eq!(
+            instrs(&optimized),
+            [
+                Instr::PushLocalPair(0, 1),
+                Instr::PushImmediate(1),
+                Instr::Add,
+                Instr::Jump(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn only_adding_to_a_local_is_fused() {
+        let source = "func f 1\npshl arg0\npshc 2\nadd\nstrl arg0\nendf\n\
+            func g 1\npshl arg0\npshc 2\nsub\nstrl arg0\nendf";
+        let optimized = optimize(assemble_source(source).unwrap()).unwrap();
+        assert_eq!(
+            instrs(&optimized),
+            [
+                Instr::IncLocal(0, 2),
+                Instr::Return,
+                Instr::PushLocal(0),
+                Instr::PushConst(0),
+                Instr::Sub,
+                Instr::StoreLocal(0),
+                Instr::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn constant_arithmetic_is_folded() {
+        let source = "main\npshc 2\npshc 3\nmul\npshc 4.0\npshc 0.5\ndiv\nprnt\nprnt";
+        let optimized = optimize(assemble_source(source).unwrap()).unwrap();
+        assert_eq!(
+            instrs(&optimized),
+            [
+                Instr::PushImmediate(6),
+                Instr::PushConst(0),
+                Instr::Print,
+                Instr::Print
+            ]
+        );
+        assert_eq!(optimized.consts, [Value::Float(8.0)]);
+    }
+
+    #[test]
+    fn runtime_errors_are_not_folded() {
+        let source = "main\npshc 1\npshc 0\ndiv\npshc 1\npshc true\nadd";
+        let optimized = optimize(assemble_source(source).unwrap()).unwrap();
+        assert_eq!(instrs(&optimized).len(), 6);
+    }
+
+    #[test]
+    fn constant_branches_become_jumps() {
+        let source = "main\npshc 1\npshc 2\nlsth\njmpf skip\npshc \"taken\"\nprnt\nlabel skip\n\
+                      pshc 1\npshc 2\ngrth\njmpf end\npshc \"dead\"\nprnt\nlabel end";
+        let optimized = optimize(assemble_source(source).unwrap()).unwrap();
+        assert_eq!(instrs(&optimized), [Instr::PushConst(0), Instr::Print]);
+        assert_eq!(
+            optimized.consts,
+            [Value::String(Rc::new("taken".to_string()))]
+        );
+    }
+
+    #[test]
+    fn unreachable_code_is_removed() {
+        let source =
+            "func f 0\npshi 1\nendf\npshi 2\nprnt\nmain\njump end\npshi 3\nprnt\nlabel end";
+        let optimized = optimize(assemble_source(source).unwrap()).unwrap();
+        assert_eq!(instrs(&optimized), [Instr::PushImmediate(1), Instr::Return]);
+        assert_eq!(optimized.functions[0].address, 0);
+        assert_eq!(optimized.entry, optimized.code.len());
+    }
+}
@@ -0,0 +1,314 @@
+use crate::value::Value;
+use std::cell::RefCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+// Floats are stored as their own bits, with NaNs canonicalised to a positive quiet NaN. That
+// leaves the negative quiet NaNs free: everything else is a tag in bits 48..51 of one of those
+// and a 48 bit payload below it.
+const TAG_BASE: u64 = 0xFFF8_0000_0000_0000;
+const TAG_SHIFT: u32 = 48;
+const TAG_MASK: u64 = 0x7;
+const PAYLOAD_MASK: u64 = (1 << TAG_SHIFT) - 1;
+const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+
+const TAG_NULL: u64 = 1;
+const TAG_BOOL: u64 = 2;
+const TAG_INT: u64 = 3;
+const TAG_FUNCTION: u64 = 4;
+// Payload is a pointer from Rc::into_raw, owning one strong count
+const TAG_HEAP: u64 = 5;
+// Payload is the index of a WIDE slot this value owns, for pointers that don't fit in 48 bits
+const TAG_WIDE: u64 = 6;
+
+// Ints inline in the payload as 48 bit two's complement; anything wider goes on the heap
+const INLINE_INT_MIN: i64 = -(1 << 47);
+const INLINE_INT_MAX: i64 = (1 << 47) - 1;
+
+// Heap values whose address is wider than the payload, as on hosts with 5-level paging. Each
+// packed value tagged wide owns one slot, holding its Rc.
+#[derive(Default)]
+struct WideSlots {
+    rcs: Vec<Option<Rc<Value>>>,
+    free: Vec<usize>,
+}
+
+impl WideSlots {
+    fn insert(&mut self, rc: Rc<Value>) -> u64 {
+        match self.free.pop() {
+            Some(idx) => {
+                self.rcs[idx] = Some(rc);
+                idx as u64
+            }
+            None => {
+                self.rcs.push(Some(rc));
+                (self.rcs.len() - 1) as u64
+            }
+        }
+    }
+    fn get(&self, idx: u64) -> &Rc<Value> {
+        self.rcs[idx as usize]
+            .as_ref()
+            .expect("wide slot owned by a live value")
+    }
+    fn take(&mut self, idx: u64) -> Rc<Value> {
+        self.free.push(idx as usize);
+        self.rcs[idx as usize]
+            .take()
+            .expect("wide slot owned by a live value")
+    }
+}
+
+thread_local! {
+    static WIDE: RefCell<WideSlots> = RefCell::default();
+}
+
+// A Value packed into 64 bits, used for stack slots with the nanbox feature. Heap values
+// (strings, idents, boxes, arrays and ints or function indices too wide for the payload) are
+// moved into an Rc<Value>.
+pub struct PackedValue {
+    bits: u64,
+    // Owns an Rc, so mustn't be Send or Sync
+    _rc: PhantomData<Rc<Value>>,
+}
+
+impl PackedValue {
+    #[inline]
+    fn from_bits(bits: u64) -> Self {
+        PackedValue {
+            bits,
+            _rc: PhantomData,
+        }
+    }
+    #[inline]
+    fn tagged(tag: u64, payload: u64) -> Self {
+        PackedValue::from_bits(TAG_BASE | (tag << TAG_SHIFT) | (payload & PAYLOAD_MASK))
+    }
+    fn boxed(val: Value) -> Self {
+        let rc = Rc::new(val);
+        if Rc::as_ptr(&rc) as u64 & !PAYLOAD_MASK != 0 {
+            return PackedValue::wide(rc);
+        }
+        PackedValue::tagged(TAG_HEAP, Rc::into_raw(rc) as u64)
+    }
+    fn wide(rc: Rc<Value>) -> Self {
+        let idx = WIDE.with(|wide| wide.borrow_mut().insert(rc));
+        PackedValue::tagged(TAG_WIDE, idx)
+    }
+    #[inline]
+    fn tag(&self) -> Option<u64> {
+        if self.bits < TAG_BASE {
+            return None;
+        }
+        Some((self.bits >> TAG_SHIFT) & TAG_MASK)
+    }
+    #[inline]
+    fn payload(&self) -> u64 {
+        self.bits & PAYLOAD_MASK
+    }
+    #[inline]
+    fn heap_ptr(&self) -> Option<*const Value> {
+        match self.tag() {
+            Some(TAG_HEAP) => Some(self.payload() as *const Value),
+            _ => None,
+        }
+    }
+    fn heap(&self) -> Option<&Value> {
+        match self.tag() {
+            // SAFETY: a heap tagged payload always comes from Rc::into_raw, and this value
+            // holds a strong count on it until dropped
+            Some(TAG_HEAP) => Some(unsafe { &*(self.payload() as *const Value) }),
+            // SAFETY: the slot's Rc lives until this value is dropped, and the Value it points
+            // to doesn't move when the slots do
+            Some(TAG_WIDE) => {
+                let ptr = WIDE.with(|wide| Rc::as_ptr(wide.borrow().get(self.payload())));
+                Some(unsafe { &*ptr })
+            }
+            _ => None,
+        }
+    }
+    #[inline]
+    pub fn as_int(&self) -> Option<i64> {
+        match self.tag() {
+            Some(TAG_INT) => Some(((self.payload() << 16) as i64) >> 16),
+            Some(TAG_HEAP | TAG_WIDE) => match self.heap() {
+                Some(Value::Int(v)) => Some(*v),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Default for PackedValue {
+    #[inline]
+    fn default() -> Self {
+        PackedValue::tagged(TAG_NULL, 0)
+    }
+}
+
+impl From<Value> for PackedValue {
+    #[inline]
+    fn from(val: Value) -> Self {
+        match val {
+            Value::NULL => PackedValue::default(),
+            Value::Int(v) if (INLINE_INT_MIN..=INLINE_INT_MAX).contains(&v) => {
+                PackedValue::tagged(TAG_INT, v as u64)
+            }
+            Value::Float(v) if v.is_nan() => PackedValue::from_bits(CANONICAL_NAN),
+            Value::Float(v) => PackedValue::from_bits(v.to_bits()),
+            Value::Bool(v) => PackedValue::tagged(TAG_BOOL, v as u64),
+            Value::Function(idx) if idx as u64 <= PAYLOAD_MASK => {
+                PackedValue::tagged(TAG_FUNCTION, idx as u64)
+            }
+            _ => PackedValue::boxed(val),
+        }
+    }
+}
+
+impl From<PackedValue> for Value {
+    #[inline]
+    fn from(packed: PackedValue) -> Self {
+        match packed.tag() {
+            None => Value::Float(f64::from_bits(packed.bits)),
+            Some(TAG_BOOL) => Value::Bool(packed.payload() != 0),
+            Some(TAG_INT) => Value::Int(((packed.payload() << 16) as i64) >> 16),
+            Some(TAG_FUNCTION) => Value::Function(packed.payload() as usize),
+            Some(TAG_HEAP) => {
+                let ptr = packed.payload() as *const Value;
+                // The strong count moves into the Rc, so packed mustn't release it as well
+                std::mem::forget(packed);
+                // SAFETY: ptr came from Rc::into_raw and we own its strong count
+                let rc = unsafe { Rc::from_raw(ptr) };
+                Rc::try_unwrap(rc).unwrap_or_else(|rc| (*rc).clone())
+            }
+            Some(TAG_WIDE) => {
+                let idx = packed.payload();
+                std::mem::forget(packed);
+                let rc = WIDE.with(|wide| wide.borrow_mut().take(idx));
+                Rc::try_unwrap(rc).unwrap_or_else(|rc| (*rc).clone())
+            }
+            _ => Value::NULL,
+        }
+    }
+}
+
+impl Clone for PackedValue {
+    #[inline]
+    fn clone(&self) -> Self {
+        if let Some(ptr) = self.heap_ptr() {
+            // SAFETY: ptr is live while self holds its strong count
+            unsafe { Rc::increment_strong_count(ptr) };
+        }
+        if self.tag() == Some(TAG_WIDE) {
+            let rc = WIDE.with(|wide| wide.borrow().get(self.payload()).clone());
+            return PackedValue::wide(rc);
+        }
+        PackedValue::from_bits(self.bits)
+    }
+}
+
+impl Drop for PackedValue {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some(ptr) = self.heap_ptr() {
+            // SAFETY: releases the strong count this value owns
+            unsafe { Rc::decrement_strong_count(ptr) };
+        }
+        if self.tag() == Some(TAG_WIDE) {
+            // Dropped once the slots are released, in case the Value's drop needs them
+            let rc = WIDE.with(|wide| wide.borrow_mut().take(self.payload()));
+            drop(rc);
+        }
+    }
+}
+
+impl fmt::Debug for PackedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", Value::from(self.clone()))
+    }
+}
+
+impl PartialEq for PackedValue {
+    fn eq(&self, other: &Self) -> bool {
+        Value::from(self.clone()) == Value::from(other.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::value::HeapString;
+
+    fn round_trip(val: Value) -> Value {
+        Value::from(PackedValue::from(val))
+    }
+
+    #[test]
+    fn scalars_round_trip() {
+        for val in [
+            Value::NULL,
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Int(0),
+            Value::Int(-1),
+            Value::Int(INLINE_INT_MIN),
+            Value::Int(INLINE_INT_MAX),
+            Value::Float(-0.0),
+            Value::Float(f64::NEG_INFINITY),
+            Value::Float(1.5e300),
+            Value::Function(7),
+        ] {
+            assert_eq!(round_trip(val.clone()), val);
+        }
+        assert!(matches!(round_trip(Value::Float(-f64::NAN)), Value::Float(v) if v.is_nan()));
+    }
+
+    #[test]
+    fn wide_ints_are_boxed() {
+        for v in [INLINE_INT_MAX + 1, INLINE_INT_MIN - 1, i64::MAX, i64::MIN] {
+            let packed = PackedValue::from(Value::Int(v));
+            assert_eq!(packed.tag(), Some(TAG_HEAP));
+            assert_eq!(packed.as_int(), Some(v));
+            assert_eq!(Value::from(packed), Value::Int(v));
+        }
+        assert_eq!(PackedValue::from(Value::Int(-5)).as_int(), Some(-5));
+    }
+
+    #[test]
+    fn heap_values_share_and_release() {
+        let arr = Value::new_array(vec![Value::Int(1)]);
+        let Value::Array(rc) = &arr else {
+            unreachable!()
+        };
+        let rc = rc.clone();
+        let packed = PackedValue::from(arr);
+        let copy = packed.clone();
+        assert_eq!(Value::from(copy), Value::new_array(vec![Value::Int(1)]));
+        assert_eq!(Rc::strong_count(&rc), 2);
+        drop(packed);
+        assert_eq!(Rc::strong_count(&rc), 1);
+
+        let text = Value::String(HeapString::new("text".to_string()));
+        assert_eq!(round_trip(text.clone()), text);
+        let ident = Value::Ident("name".to_string());
+        assert_eq!(round_trip(ident.clone()), ident);
+    }
+
+    #[test]
+    fn wide_pointers_use_slots() {
+        let slots = || WIDE.with(|wide| wide.borrow().rcs.iter().flatten().count());
+        let before = slots();
+        let packed = PackedValue::wide(Rc::new(Value::Int(i64::MAX)));
+        assert_eq!(packed.tag(), Some(TAG_WIDE));
+        assert_eq!(packed.as_int(), Some(i64::MAX));
+        let copy = packed.clone();
+        assert_eq!(slots(), before + 2);
+        assert_eq!(copy, packed);
+        drop(packed);
+        assert_eq!(Value::from(copy), Value::Int(i64::MAX));
+        assert_eq!(slots(), before);
+    }
+}
@@ -0,0 +1,524 @@
+use crate::assembler::is_keyword;
+use crate::error::AssemblerError;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MAX_MACRO_DEPTH: usize = 64;
+
+// Where an assembled line came from: file and line, the files that included it,
+// and the macro invocation it was expanded from
+#[derive(Debug, Clone, Default)]
+pub struct SourceLoc {
+    pub file: Option<String>,
+    pub line: usize,
+    pub included_from: Vec<(String, usize)>, // (file, include line), innermost first
+    pub expanded_from: Option<Box<(String, SourceLoc)>>, // (macro name, invocation)
+}
+
+impl SourceLoc {
+    fn expansion_depth(&self) -> usize {
+        match &self.expanded_from {
+            Some(expansion) => 1 + expansion.1.expansion_depth(),
+            None => 0,
+        }
+    }
+}
+
+impl fmt::Display for SourceLoc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line)?,
+            None => write!(f, "{}", self.line)?,
+        }
+        for (file, line) in &self.included_from {
+            write!(f, ", included from {}:{}", file, line)?;
+        }
+        if let Some(expansion) = &self.expanded_from {
+            write!(
+                f,
+                ", in macro {} expanded at line {}",
+                expansion.0, expansion.1
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub text: String,
+    pub loc: SourceLoc,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<SourceLine>,
+    labels: Vec<String>,
+}
+
+struct Preprocessor<'a> {
+    include_paths: &'a [PathBuf],
+    macros: HashMap<String, Macro>,
+    lines: Vec<SourceLine>,
+    included: HashSet<PathBuf>,
+    include_stack: Vec<PathBuf>,
+}
+
+// Collect macro definitions and expand every invocation, returning the lines left to assemble.
+// Includes in a source without a file are resolved against the working directory.
+pub fn preprocess(
+    source: &str,
+    include_paths: &[PathBuf],
+) -> Result<Vec<SourceLine>, AssemblerError> {
+    let mut pre = Preprocessor::new(include_paths);
+    pre.read_source(source, None, &[])?;
+    pre.finish()
+}
+
+pub fn preprocess_file(
+    path: &Path,
+    include_paths: &[PathBuf],
+) -> Result<Vec<SourceLine>, AssemblerError> {
+    let mut pre = Preprocessor::new(include_paths);
+    let source = fs::read_to_string(path)?;
+    if let Ok(canonical) = path.canonicalize() {
+        pre.included.insert(canonical.clone());
+        pre.include_stack.push(canonical);
+    }
+    pre.read_source(&source, Some(path), &[])?;
+    pre.finish()
+}
+
+impl<'a> Preprocessor<'a> {
+    fn new(include_paths: &'a [PathBuf]) -> Self {
+        Self {
+            include_paths,
+            macros: HashMap::new(),
+            lines: Vec::new(),
+            included: HashSet::new(),
+            include_stack: Vec::new(),
+        }
+    }
+
+    fn finish(self) -> Result<Vec<SourceLine>, AssemblerError> {
+        let mut expanded: Vec<SourceLine> = Vec::new();
+        let mut expansion_count = 0;
+        expand(
+            &self.lines,
+            &self.macros,
+            &mut expanded,
+            &mut expansion_count,
+        )?;
+        Ok(expanded)
+    }
+
+    fn read_source(
+        &mut self,
+        source: &str,
+        file: Option<&Path>,
+        included_from: &[(String, usize)],
+    ) -> Result<(), AssemblerError> {
+        let file_name = file.map(|f| f.display().to_string());
+        let mut current: Option<(String, Macro, SourceLoc)> = None;
+
+        for (idx, text) in source.lines().enumerate() {
+            let line = SourceLine {
+                text: text.trim().to_string(),
+                loc: SourceLoc {
+                    file: file_name.clone(),
+                    line: idx + 1,
+                    included_from: included_from.to_vec(),
+                    expanded_from: None,
+                },
+            };
+            let data: Vec<&str> = line.text.split(' ').collect();
+            match (data[0], current.as_mut()) {
+                ("macro" | "include", Some(_)) => {
+                    return Err(AssemblerError::InvalidMacro(format!(
+                        "Cannot {} inside macro at line: {}",
+                        data[0], line.loc
+                    )));
+                }
+                ("include", None) => {
+                    // The rest of the line, so a quoted path can contain spaces
+                    let quoted = line.text["include".len()..].trim();
+                    let Some(name) = quoted
+                        .strip_prefix('"')
+                        .and_then(|q| q.strip_suffix('"'))
+                        .filter(|name| !name.is_empty() && !name.contains('"'))
+                    else {
+                        return Err(AssemblerError::InvalidInclude(format!(
+                            "Expected quoted path at line: {}",
+                            line.loc
+                        )));
+                    };
+                    let mut chain = vec![(file_name.clone().unwrap_or_default(), idx + 1)];
+                    chain.extend(included_from.iter().cloned());
+                    self.include(name, file, chain, &line.loc)?;
+                }
+                ("macro", None) => {
+                    if data.len() < 2 || !data[1].starts_with(char::is_alphabetic) {
+                        return Err(AssemblerError::InvalidMacro(format!(
+                            "Expected macro name at line: {}",
+                            line.loc
+                        )));
+                    }
+                    // It would quietly replace the instruction everywhere it's used
+                    if is_keyword(data[1]) || matches!(data[1], "macro" | "endm" | "include") {
+                        return Err(AssemblerError::InvalidMacro(format!(
+                            "Macro {} has the name of an instruction or directive at line: {}",
+                            data[1], line.loc
+                        )));
+                    }
+                    let params: Vec<String> = data[2..].iter().map(|p| p.to_string()).collect();
+                    let mac = Macro {
+                        params,
+                        body: Vec::new(),
+                        labels: Vec::new(),
+                    };
+                    current = Some((data[1].to_string(), mac, line.loc));
+                }
+                ("endm", None) => {
+                    return Err(AssemblerError::InvalidMacro(format!(
+                        "Tried to end macro while not in macro at line: {}",
+                        line.loc
+                    )));
+                }
+                ("endm", Some(_)) => {
+                    if let Some((name, mac, start)) = current.take()
+                        && self.macros.insert(name.clone(), mac).is_some()
+                    {
+                        return Err(AssemblerError::InvalidMacro(format!(
+                            "Duplicate macro: {}, at line: {}",
+                            name, start
+                        )));
+                    }
+                }
+                // A global label keeps its name, so a second expansion would define it again
+                ("label", Some((name, _, _))) if data.get(1) == Some(&"global") => {
+                    return Err(AssemblerError::InvalidMacro(format!(
+                        "Cannot define global label in macro {} at line: {}",
+                        name, line.loc
+                    )));
+                }
+                (_, Some((_, mac, _))) => {
+                    // Labels defined in the body get a fresh name per expansion
+                    if data[0] == "label" && data.len() == 2 {
+                        mac.labels.push(data[1].trim_start_matches('.').to_string());
+                    }
+                    mac.body.push(line);
+                }
+                (_, None) => self.lines.push(line),
+            }
+        }
+        if let Some((name, _, start)) = current {
+            return Err(AssemblerError::InvalidMacro(format!(
+                "Macro {} starting at line: {} is missing endm",
+                name, start
+            )));
+        }
+        Ok(())
+    }
+
+    // Resolve against the including file's directory, then each include path in order.
+    // A file already included is skipped; one still being read is a cycle.
+    fn include(
+        &mut self,
+        name: &str,
+        includer: Option<&Path>,
+        chain: Vec<(String, usize)>,
+        loc: &SourceLoc,
+    ) -> Result<(), AssemblerError> {
+        let base = includer
+            .and_then(|f| f.parent())
+            .map(|dir| dir.join(name))
+            .unwrap_or_else(|| PathBuf::from(name));
+        let candidates =
+            std::iter::once(base).chain(self.include_paths.iter().map(|dir| dir.join(name)));
+        let Some(path) = candidates.into_iter().find(|p| p.is_file()) else {
+            return Err(AssemblerError::InvalidInclude(format!(
+                "Could not find include file: {}, at line: {}",
+                name, loc
+            )));
+        };
+        let canonical = path.canonicalize()?;
+        if self.include_stack.contains(&canonical) {
+            return Err(AssemblerError::InvalidInclude(format!(
+                "Include cycle through: {}, at line: {}",
+                name, loc
+            )));
+        }
+        if !self.included.insert(canonical.clone()) {
+            return Ok(());
+        }
+
+        let source = fs::read_to_string(&path)?;
+        self.include_stack.push(canonical);
+        self.read_source(&source, Some(&path), &chain)?;
+        self.include_stack.pop();
+        Ok(())
+    }
+}
+
+fn expand(
+    lines: &[SourceLine],
+    macros: &HashMap<String, Macro>,
+    out: &mut Vec<SourceLine>,
+    expansion_count: &mut usize,
+) -> Result<(), AssemblerError> {
+    for line in lines {
+        let data: Vec<&str> = line.text.split(' ').collect();
+        let Some(mac) = macros.get(data[0]) else {
+            out.push(line.clone());
+            continue;
+        };
+        let args = &data[1..];
+        if args.len() != mac.params.len() {
+            return Err(AssemblerError::InvalidMacro(format!(
+                "Macro {} expects {} arguments, got {} at line: {}",
+                data[0],
+                mac.params.len(),
+                args.len(),
+                line.loc
+            )));
+        }
+        if line.loc.expansion_depth() >= MAX_MACRO_DEPTH {
+            return Err(AssemblerError::MacroRecursionLimit(format!(
+                "Macro expansion deeper than {} at line: {}",
+                MAX_MACRO_DEPTH, line.loc
+            )));
+        }
+
+        *expansion_count += 1;
+        let expanded_from = Box::new((data[0].to_string(), line.loc.clone()));
+        let body: Vec<SourceLine> = mac
+            .body
+            .iter()
+            .map(|body_line| SourceLine {
+                text: substitute(&body_line.text, mac, args, *expansion_count),
+                loc: SourceLoc {
+                    expanded_from: Some(expanded_from.clone()),
+                    ..body_line.loc.clone()
+                },
+            })
+            .collect();
+        expand(&body, macros, out, expansion_count)?;
+    }
+    Ok(())
+}
+
+// Replace parameters in operands with their arguments and give body-local labels a
+// per-expansion suffix. The instruction itself is never a parameter.
+fn substitute(text: &str, mac: &Macro, args: &[&str], expansion: usize) -> String {
+    let tokens: Vec<&str> = text.split(' ').collect();
+    let names_label = matches!(tokens[0], "label" | "jump" | "jmpf" | "jmpt");
+    let substituted: Vec<String> = tokens
+        .iter()
+        .enumerate()
+        .map(|(idx, token)| {
+            if idx > 0
+                && let Some(param) = mac.params.iter().position(|p| p == token)
+            {
+                return args[param].to_string();
+            }
+            let (dot, name) = match token.strip_prefix('.') {
+                Some(name) => (".", name),
+                None => ("", *token),
+            };
+            if names_label && idx == 1 && mac.labels.iter().any(|l| l == name) {
+                return format!("{}{}@{}", dot, name, expansion);
+            }
+            token.to_string()
+        })
+        .collect();
+    substituted.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn texts(lines: &[SourceLine]) -> Vec<&str> {
+        lines.iter().map(|l| l.text.as_str()).collect()
+    }
+
+    #[test]
+    fn substitutes_parameters() {
+        let source = "macro addto x k\npshl x\npshc k\nadd\nstrl x\nendm\naddto count 2";
+        let lines = preprocess(source, &[]).unwrap();
+        assert_eq!(texts(&lines), ["pshl count", "pshc 2", "add", "strl count"]);
+    }
+
+    #[test]
+    fn only_operands_are_parameters() {
+        let source = "macro plus add\npshc add\nadd\nendm\nplus 2";
+        let lines = preprocess(source, &[]).unwrap();
+        assert_eq!(texts(&lines), ["pshc 2", "add"]);
+    }
+
+    #[test]
+    fn macros_cannot_take_instruction_names() {
+        for name in ["pshc", "label", "sqrt", "endm", "include"] {
+            let source = format!("macro {} x\npshi x\nendm", name);
+            match preprocess(&source, &[]) {
+                Err(AssemblerError::InvalidMacro(msg)) => assert_eq!(
+                    msg,
+                    format!(
+                        "Macro {} has the name of an instruction or directive at line: 1",
+                        name
+                    )
+                ),
+                other => panic!("Expected invalid macro, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn body_labels_are_hygienic() {
+        let source = "macro spin\nlabel top\njump .top\nendm\nspin\nspin";
+        let lines = preprocess(source, &[]).unwrap();
+        assert_eq!(
+            texts(&lines),
+            ["label top@1", "jump .top@1", "label top@2", "jump .top@2"]
+        );
+    }
+
+    #[test]
+    fn global_labels_are_rejected_in_macros() {
+        let source = "macro spin\nlabel global out\nendm\nspin\nspin";
+        match preprocess(source, &[]) {
+            Err(AssemblerError::InvalidMacro(msg)) => {
+                assert_eq!(msg, "Cannot define global label in macro spin at line: 2")
+            }
+            other => panic!("Expected invalid macro, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn nested_expansion_locations() {
+        let source = "macro inner\nbogus\nendm\nmacro outer\ninner\nendm\n\nouter";
+        let lines = preprocess(source, &[]).unwrap();
+        assert_eq!(
+            lines[1].loc.to_string(),
+            "2, in macro inner expanded at line 5, in macro outer expanded at line 8"
+        );
+    }
+
+    #[test]
+    fn recursion_depth_is_limited() {
+        let source = "macro forever\nforever\nendm\nforever";
+        assert!(matches!(
+            preprocess(source, &[]),
+            Err(AssemblerError::MacroRecursionLimit(_))
+        ));
+    }
+
+    #[test]
+    fn wrong_argument_count() {
+        let source = "macro two a b\nendm\ntwo 1";
+        assert!(matches!(
+            preprocess(source, &[]),
+            Err(AssemblerError::InvalidMacro(_))
+        ));
+    }
+
+    // A directory of its own under the system temp dir, removed when the test is done with it
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+            self.0.join(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // Write the given files into a fresh directory for the named test
+    fn write_files(test: &str, files: &[(&str, &str)]) -> TempDir {
+        let root = std::env::temp_dir().join(format!("fvm-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for (name, content) in files {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        TempDir(root)
+    }
+
+    #[test]
+    fn includes_relative_and_from_include_paths() {
+        let root = write_files(
+            "include",
+            &[
+                (
+                    "src/main.fasm",
+                    "include \"util.fasm\"\ninclude \"shared.fasm\"\nmain",
+                ),
+                ("src/util.fasm", "include \"shared.fasm\"\nprnt"),
+                ("lib/shared.fasm", "pop"),
+            ],
+        );
+        let lines = preprocess_file(&root.join("src/main.fasm"), &[root.join("lib")]).unwrap();
+        // shared.fasm is included twice but only read once
+        assert_eq!(texts(&lines), ["pop", "prnt", "main"]);
+        assert_eq!(
+            lines[0].loc.to_string(),
+            format!(
+                "{r}/lib/shared.fasm:1, included from {r}/src/util.fasm:1, included from {r}/src/main.fasm:1",
+                r = root.0.display()
+            )
+        );
+    }
+
+    #[test]
+    fn include_cycle_is_an_error() {
+        let root = write_files(
+            "cycle",
+            &[
+                ("a.fasm", "include \"b.fasm\""),
+                ("b.fasm", "include \"a.fasm\""),
+            ],
+        );
+        match preprocess_file(&root.join("a.fasm"), &[]) {
+            Err(AssemblerError::InvalidInclude(msg)) => {
+                assert!(msg.starts_with("Include cycle through: a.fasm"))
+            }
+            other => panic!("Expected include cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn macros_are_shared_across_includes() {
+        let root = write_files(
+            "macros",
+            &[
+                ("main.fasm", "include \"defs.fasm\"\ntwice"),
+                ("defs.fasm", "macro twice\nprnt\nprnt\nendm"),
+            ],
+        );
+        let lines = preprocess_file(&root.join("main.fasm"), &[]).unwrap();
+        assert_eq!(texts(&lines), ["prnt", "prnt"]);
+    }
+
+    #[test]
+    fn include_path_with_spaces() {
+        let root = write_files(
+            "spaces",
+            &[
+                ("main.fasm", "include  \"my lib/util.fasm\" \nmain"),
+                ("my lib/util.fasm", "prnt"),
+            ],
+        );
+        let lines = preprocess_file(&root.join("main.fasm"), &[]).unwrap();
+        assert_eq!(texts(&lines), ["prnt", "main"]);
+        let unquoted = preprocess("include my lib/util.fasm", &[]);
+        assert!(matches!(unquoted, Err(AssemblerError::InvalidInclude(_))));
+    }
+}
@@ -0,0 +1,843 @@
+use crate::bytecode::Bytecode;
+use crate::error::VMError;
+use crate::instr::{IndexedCode, Instr};
+use crate::math::{MathFn, Rounding};
+use crate::value::Value;
+use std::fmt;
+
+pub type Reg = u16;
+
+// Register form of the instruction set. Each function gets a window of registers: its locals
+// first, then one temporary per stack depth the stack code reaches. Jump targets are indices
+// into the register code.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RegInstr {
+    Move(Reg, Reg),        // dst, src
+    LoadConst(Reg, u16),   // dst, const index
+    LoadImm(Reg, i16),     // dst, value
+    LoadGlobal(Reg, u16),  // dst, global index
+    StoreGlobal(u16, Reg), // global index, src
+
+    // Arithmetic: dst, lhs, rhs
+    Add(Reg, Reg, Reg),
+    Sub(Reg, Reg, Reg),
+    Mul(Reg, Reg, Reg),
+    Div(Reg, Reg, Reg),
+    DivInt(Reg, Reg, Reg),
+    Mod(Reg, Reg, Reg),
+    DivExact(Reg, Reg, Reg),
+    AddWrap(Reg, Reg, Reg),
+    SubWrap(Reg, Reg, Reg),
+    MulWrap(Reg, Reg, Reg),
+    AddChecked(Reg, Reg, Reg),
+    SubChecked(Reg, Reg, Reg),
+    MulChecked(Reg, Reg, Reg),
+    AddSat(Reg, Reg, Reg),
+    SubSat(Reg, Reg, Reg),
+    MulSat(Reg, Reg, Reg),
+
+    // Comparisons and other operators: dst, lhs, rhs
+    Equal(Reg, Reg, Reg),
+    NotEqual(Reg, Reg, Reg),
+    LessThan(Reg, Reg, Reg),
+    GreaterThan(Reg, Reg, Reg),
+    GreaterEqual(Reg, Reg, Reg),
+    LessEqual(Reg, Reg, Reg),
+    LogicalAnd(Reg, Reg, Reg),
+    LogicalOr(Reg, Reg, Reg),
+    Identical(Reg, Reg, Reg),
+    Not(Reg, Reg), // dst, src
+
+    // Bitwise: dst, lhs, rhs or dst, src
+    BitAnd(Reg, Reg, Reg),
+    BitOr(Reg, Reg, Reg),
+    BitXor(Reg, Reg, Reg),
+    BitNot(Reg, Reg),
+    Shl(Reg, Reg, Reg),
+    Shr(Reg, Reg, Reg),
+    UShr(Reg, Reg, Reg),
+    RotateLeft(Reg, Reg, Reg),
+    RotateRight(Reg, Reg, Reg),
+    PopCount(Reg, Reg),
+    LeadingZeros(Reg, Reg),
+    TrailingZeros(Reg, Reg),
+
+    // Math: function, dst, first argument
+    Math(MathFn, Reg, Reg),
+    ToInt(Rounding, Reg, Reg), // mode, dst, src
+    ToFloat(Reg, Reg),         // dst, src
+
+    // Symbolic
+    Symbol(Reg, Reg),               // dst, name
+    Substitute(Reg, Reg, Reg, Reg), // dst, expr, symbol, value
+    Evaluate(Reg, Reg),             // dst, src
+    ExprEqual(Reg, Reg, Reg),       // dst, lhs, rhs
+    Simplify(Reg, Reg),             // dst, src
+    Differentiate(Reg, Reg, u16),   // dst, src, const name
+
+    // Boxes and arrays
+    Box(Reg, Reg),           // dst, src
+    Unbox(Reg, Reg),         // dst, box
+    SetBox(Reg, Reg),        // box, src
+    Array(Reg, Reg, u8),     // dst, first element, element count
+    ArraySet(Reg, Reg, Reg), // array, index, src
+    ArrayGet(Reg, Reg, Reg), // dst, array, index
+    ArrayPush(Reg, Reg),     // array, src
+    ArrayPop(Reg, Reg),      // dst, array
+    ArrayLen(Reg, Reg),      // dst, array
+
+    // Control Flow
+    Jump(u32),
+    JumpIfFalse(Reg, u32),
+    JumpIfTrue(Reg, u32),
+
+    // Functions
+    Call(u16, Reg, Reg), // function index, first argument, dst
+    Return(Reg),
+
+    // Testing ops
+    Print(Reg),
+}
+
+impl RegInstr {
+    // The register an instruction writes its result to, if any
+    fn dst_mut(&mut self) -> Option<&mut Reg> {
+        match self {
+            RegInstr::Move(dst, _)
+            | RegInstr::LoadConst(dst, _)
+            | RegInstr::LoadImm(dst, _)
+            | RegInstr::LoadGlobal(dst, _)
+            | RegInstr::Add(dst, _, _)
+            | RegInstr::Sub(dst, _, _)
+            | RegInstr::Mul(dst, _, _)
+            | RegInstr::Div(dst, _, _)
+            | RegInstr::DivInt(dst, _, _)
+            | RegInstr::Mod(dst, _, _)
+            | RegInstr::DivExact(dst, _, _)
+            | RegInstr::AddWrap(dst, _, _)
+            | RegInstr::SubWrap(dst, _, _)
+            | RegInstr::MulWrap(dst, _, _)
+            | RegInstr::AddChecked(dst, _, _)
+            | RegInstr::SubChecked(dst, _, _)
+            | RegInstr::MulChecked(dst, _, _)
+            | RegInstr::AddSat(dst, _, _)
+            | RegInstr::SubSat(dst, _, _)
+            | RegInstr::MulSat(dst, _, _)
+            | RegInstr::Equal(dst, _, _)
+            | RegInstr::NotEqual(dst, _, _)
+            | RegInstr::LessThan(dst, _, _)
+            | RegInstr::GreaterThan(dst, _, _)
+            | RegInstr::GreaterEqual(dst, _, _)
+            | RegInstr::LessEqual(dst, _, _)
+            | RegInstr::LogicalAnd(dst, _, _)
+            | RegInstr::LogicalOr(dst, _, _)
+            | RegInstr::Identical(dst, _, _)
+            | RegInstr::Not(dst, _)
+            | RegInstr::BitAnd(dst, _, _)
+            | RegInstr::BitOr(dst, _, _)
+            | RegInstr::BitXor(dst, _, _)
+            | RegInstr::BitNot(dst, _)
+            | RegInstr::Shl(dst, _, _)
+            | RegInstr::Shr(dst, _, _)
+            | RegInstr::UShr(dst, _, _)
+            | RegInstr::RotateLeft(dst, _, _)
+            | RegInstr::RotateRight(dst, _, _)
+            | RegInstr::PopCount(dst, _)
+            | RegInstr::LeadingZeros(dst, _)
+            | RegInstr::TrailingZeros(dst, _)
+            | RegInstr::Math(_, dst, _)
+            | RegInstr::ToInt(_, dst, _)
+            | RegInstr::ToFloat(dst, _)
+            | RegInstr::Symbol(dst, _)
+            | RegInstr::Substitute(dst, _, _, _)
+            | RegInstr::Evaluate(dst, _)
+            | RegInstr::ExprEqual(dst, _, _)
+            | RegInstr::Simplify(dst, _)
+            | RegInstr::Differentiate(dst, _, _)
+            | RegInstr::Box(dst, _)
+            | RegInstr::Unbox(dst, _)
+            | RegInstr::ArrayGet(dst, _, _)
+            | RegInstr::ArrayPop(dst, _)
+            | RegInstr::ArrayLen(dst, _) => Some(dst),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RegInstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RegInstr::Move(d, s) => write!(f, "mov r{}, r{}", d, s),
+            RegInstr::LoadConst(d, idx) => write!(f, "ldc r{}, #{}", d, idx),
+            RegInstr::LoadImm(d, val) => write!(f, "ldi r{}, {}", d, val),
+            RegInstr::LoadGlobal(d, idx) => write!(f, "ldg r{}, g{}", d, idx),
+            RegInstr::StoreGlobal(idx, s) => write!(f, "stg g{}, r{}", idx, s),
+            RegInstr::Add(d, l, r) => write!(f, "add r{}, r{}, r{}", d, l, r),
+            RegInstr::Sub(d, l, r) => write!(f, "sub r{}, r{}, r{}", d, l, r),
+            RegInstr::Mul(d, l, r) => write!(f, "mul r{}, r{}, r{}", d, l, r),
+            RegInstr::Div(d, l, r) => write!(f, "div r{}, r{}, r{}", d, l, r),
+            RegInstr::DivInt(d, l, r) => write!(f, "divi r{}, r{}, r{}", d, l, r),
+            RegInstr::Mod(d, l, r) => write!(f, "mod r{}, r{}, r{}", d, l, r),
+            RegInstr::DivExact(d, l, r) => write!(f, "divx r{}, r{}, r{}", d, l, r),
+            RegInstr::AddWrap(d, l, r) => write!(f, "wadd r{}, r{}, r{}", d, l, r),
+            RegInstr::SubWrap(d, l, r) => write!(f, "wsub r{}, r{}, r{}", d, l, r),
+            RegInstr::MulWrap(d, l, r) => write!(f, "wmul r{}, r{}, r{}", d, l, r),
+            RegInstr::AddChecked(d, l, r) => write!(f, "cadd r{}, r{}, r{}", d, l, r),
+            RegInstr::SubChecked(d, l, r) => write!(f, "csub r{}, r{}, r{}", d, l, r),
+            RegInstr::MulChecked(d, l, r) => write!(f, "cmul r{}, r{}, r{}", d, l, r),
+            RegInstr::AddSat(d, l, r) => write!(f, "sadd r{}, r{}, r{}", d, l, r),
+            RegInstr::SubSat(d, l, r) => write!(f, "ssub r{}, r{}, r{}", d, l, r),
+            RegInstr::MulSat(d, l, r) => write!(f, "smul r{}, r{}, r{}", d, l, r),
+            RegInstr::Equal(d, l, r) => write!(f, "equl r{}, r{}, r{}", d, l, r),
+            RegInstr::NotEqual(d, l, r) => write!(f, "nteq r{}, r{}, r{}", d, l, r),
+            RegInstr::LessThan(d, l, r) => write!(f, "lsth r{}, r{}, r{}", d, l, r),
+            RegInstr::GreaterThan(d, l, r) => write!(f, "grth r{}, r{}, r{}", d, l, r),
+            RegInstr::GreaterEqual(d, l, r) => write!(f, "gteq r{}, r{}, r{}", d, l, r),
+            RegInstr::LessEqual(d, l, r) => write!(f, "lteq r{}, r{}, r{}", d, l, r),
+            RegInstr::LogicalAnd(d, l, r) => write!(f, "and r{}, r{}, r{}", d, l, r),
+            RegInstr::LogicalOr(d, l, r) => write!(f, "or r{}, r{}, r{}", d, l, r),
+            RegInstr::Identical(d, l, r) => write!(f, "ideq r{}, r{}, r{}", d, l, r),
+            RegInstr::Not(d, s) => write!(f, "not r{}, r{}", d, s),
+            RegInstr::BitAnd(d, l, r) => write!(f, "band r{}, r{}, r{}", d, l, r),
+            RegInstr::BitOr(d, l, r) => write!(f, "bor r{}, r{}, r{}", d, l, r),
+            RegInstr::BitXor(d, l, r) => write!(f, "bxor r{}, r{}, r{}", d, l, r),
+            RegInstr::BitNot(d, s) => write!(f, "bnot r{}, r{}", d, s),
+            RegInstr::Shl(d, l, r) => write!(f, "shl r{}, r{}, r{}", d, l, r),
+            RegInstr::Shr(d, l, r) => write!(f, "shr r{}, r{}, r{}", d, l, r),
+            RegInstr::UShr(d, l, r) => write!(f, "ushr r{}, r{}, r{}", d, l, r),
+            RegInstr::RotateLeft(d, l, r) => write!(f, "rotl r{}, r{}, r{}", d, l, r),
+            RegInstr::RotateRight(d, l, r) => write!(f, "rotr r{}, r{}, r{}", d, l, r),
+            RegInstr::PopCount(d, s) => write!(f, "popc r{}, r{}", d, s),
+            RegInstr::LeadingZeros(d, s) => write!(f, "clz r{}, r{}", d, s),
+            RegInstr::TrailingZeros(d, s) => write!(f, "ctz r{}, r{}", d, s),
+            RegInstr::Math(func, d, s) => write!(f, "{} r{}, r{}", func.mnemonic(), d, s),
+            RegInstr::ToInt(mode, d, s) => write!(f, "toint.{} r{}, r{}", mode.mnemonic(), d, s),
+            RegInstr::ToFloat(d, s) => write!(f, "toflt r{}, r{}", d, s),
+            RegInstr::Symbol(d, s) => write!(f, "sym r{}, r{}", d, s),
+            RegInstr::Substitute(d, e, s, v) => {
+                write!(f, "subs r{}, r{}, r{}, r{}", d, e, s, v)
+            }
+            RegInstr::Evaluate(d, s) => write!(f, "eval r{}, r{}", d, s),
+            RegInstr::ExprEqual(d, l, r) => write!(f, "exeq r{}, r{}, r{}", d, l, r),
+            RegInstr::Simplify(d, s) => write!(f, "simp r{}, r{}", d, s),
+            RegInstr::Differentiate(d, s, k) => write!(f, "diff r{}, r{}, #{}", d, s, k),
+            RegInstr::Box(d, s) => write!(f, "box r{}, r{}", d, s),
+            RegInstr::Unbox(d, s) => write!(f, "unbox r{}, r{}", d, s),
+            RegInstr::SetBox(b, s) => write!(f, "setbox r{}, r{}", b, s),
+            RegInstr::Array(d, first, n) => write!(f, "array r{}, r{}, {}", d, first, n),
+            RegInstr::ArraySet(a, i, s) => write!(f, "arrayset r{}, r{}, r{}", a, i, s),
+            RegInstr::ArrayGet(d, a, i) => write!(f, "arrayget r{}, r{}, r{}", d, a, i),
+            RegInstr::ArrayPush(a, s) => write!(f, "arraypush r{}, r{}", a, s),
+            RegInstr::ArrayPop(d, a) => write!(f, "arraypop r{}, r{}", d, a),
+            RegInstr::ArrayLen(d, a) => write!(f, "arraylen r{}, r{}", d, a),
+            RegInstr::Jump(t) => write!(f, "jump @{}", t),
+            RegInstr::JumpIfFalse(s, t) => write!(f, "jmpf r{}, @{}", s, t),
+            RegInstr::JumpIfTrue(s, t) => write!(f, "jmpt r{}, @{}", s, t),
+            RegInstr::Call(func, args, d) => write!(f, "callf f{}, r{}, r{}", func, args, d),
+            RegInstr::Return(s) => write!(f, "ret r{}", s),
+            RegInstr::Print(s) => write!(f, "prnt r{}", s),
+        }
+    }
+}
+
+// A function in register code. registers is the size of its window, locals included.
+#[derive(Debug, Copy, Clone)]
+pub struct RegFunction {
+    pub address: usize,
+    pub arity: u8,
+    pub registers: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct RegisterCode {
+    pub code: Vec<RegInstr>,
+    pub consts: Vec<Value>,
+    pub functions: Vec<RegFunction>,
+    pub entry: usize,
+    // Window size for the code run from the entry point, which has no locals
+    pub entry_registers: u16,
+}
+
+// Code reachable from one root: a function or the entry point
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    // Registers below base are locals, temporaries start at base
+    base: u16,
+    in_frame: bool,
+    max_depth: usize,
+}
+
+// Stack values popped and pushed by an instruction
+fn stack_effect(instr: Instr, bytecode: &Bytecode) -> (usize, usize) {
+    match instr {
+        Instr::PushConst(_)
+        | Instr::PushImmediate(_)
+        | Instr::PushLocal(_)
+        | Instr::PushGlobal(_) => (0, 1),
+        Instr::StoreLocal(_)
+        | Instr::StoreGlobal(_)
+        | Instr::Pop
+        | Instr::JumpIfFalse(_)
+        | Instr::JumpIfTrue(_)
+        | Instr::Print
+        | Instr::Return => (1, 0),
+        Instr::StoreLocalKeep(_)
+        | Instr::Box
+        | Instr::Unbox
+        | Instr::ArrayPop
+        | Instr::ArrayLen
+        | Instr::Not
+        | Instr::BitNot
+        | Instr::PopCount
+        | Instr::LeadingZeros
+        | Instr::TrailingZeros
+        | Instr::ToInt(_)
+        | Instr::ToFloat
+        | Instr::Symbol
+        | Instr::Evaluate
+        | Instr::Simplify
+        | Instr::Differentiate(_) => (1, 1),
+        Instr::SetBox | Instr::ArrayPush => (2, 0),
+        Instr::ArraySet => (3, 0),
+        Instr::Substitute => (3, 1),
+        Instr::Array(n) => (n as usize, 1),
+        Instr::Math(f) => (f.arity(), 1),
+        Instr::CallFunction(fidx) => (bytecode.functions[fidx as usize].arity as usize, 1),
+        Instr::Jump(_) | Instr::NoOp | Instr::IncLocal(..) | Instr::CompareLocalConstJump(..) => {
+            (0, 0)
+        }
+        Instr::AddLocalConst(..) => (0, 1),
+        Instr::PushLocalPair(..) => (0, 2),
+        _ => (2, 1),
+    }
+}
+
+struct Analysis {
+    // Index into regions of the root each instruction is reachable from
+    owner: Vec<Option<usize>>,
+    depth: Vec<usize>,
+    regions: Vec<Region>,
+}
+
+// Find which root owns each instruction and the stack depth before it. Every path to an
+// instruction has to agree on its depth, as it must for the stack VM to be well behaved.
+fn analyze(
+    instrs: &[Instr],
+    bytecode: &Bytecode,
+    roots: &[(usize, Region)],
+) -> Result<Analysis, VMError> {
+    let mut owner: Vec<Option<usize>> = vec![None; instrs.len()];
+    let mut depth: Vec<usize> = vec![0; instrs.len()];
+    let mut regions: Vec<Region> = Vec::new();
+    for (region_idx, (root, region)) in roots.iter().enumerate() {
+        let mut region = *region;
+        let mut work: Vec<(usize, usize)> = vec![(*root, 0)];
+        while let Some((idx, at_depth)) = work.pop() {
+            if idx >= instrs.len() {
+                continue;
+            }
+            match owner[idx] {
+                Some(other) if other != region_idx || depth[idx] != at_depth => {
+                    return Err(VMError::InconsistentStackDepth(idx));
+                }
+                Some(_) => continue,
+                None => {}
+            }
+            owner[idx] = Some(region_idx);
+            depth[idx] = at_depth;
+            let instr = instrs[idx];
+            if let Instr::CallFunction(fidx) = instr
+                && fidx as usize >= bytecode.functions.len()
+            {
+                return Err(VMError::InvalidFunctionIndex(fidx));
+            }
+            let (pops, pushes) = stack_effect(instr, bytecode);
+            if pops > at_depth {
+                return Err(VMError::StackUnderflow);
+            }
+            let after = at_depth - pops + pushes;
+            // Superinstructions with a constant operand load it into the next free temporary
+            let scratch = match instr {
+                Instr::IncLocal(..) | Instr::CompareLocalConstJump(..) => at_depth + 1,
+                _ => at_depth,
+            };
+            region.max_depth = region.max_depth.max(after).max(scratch);
+            match instr {
+                Instr::Jump(target) => work.push((target as usize, after)),
+                Instr::JumpIfFalse(target)
+                | Instr::JumpIfTrue(target)
+                | Instr::CompareLocalConstJump(_, _, _, target) => {
+                    work.push((target as usize, after));
+                    work.push((idx + 1, after));
+                }
+                Instr::Return => {}
+                _ => work.push((idx + 1, after)),
+            }
+        }
+        regions.push(region);
+    }
+    Ok(Analysis {
+        owner,
+        depth,
+        regions,
+    })
+}
+
+struct Translator<'a> {
+    bytecode: &'a Bytecode,
+    region: Region,
+    // Where each stack value currently lives: its own temporary, or a local it was loaded from
+    // and hasn't been copied out of yet
+    operands: Vec<Reg>,
+    code: Vec<RegInstr>,
+    // The last instruction's result register can still be redirected by a following store
+    retargetable: bool,
+}
+
+impl Translator<'_> {
+    fn temp(&self, depth: usize) -> Reg {
+        self.region.base + depth as Reg
+    }
+    fn emit(&mut self, instr: RegInstr) {
+        self.code.push(instr);
+        self.retargetable = false;
+    }
+    // Emit an instruction whose result becomes the new top of stack
+    fn emit_push(&mut self, make: impl FnOnce(Reg) -> RegInstr) {
+        let dst = self.temp(self.operands.len());
+        self.code.push(make(dst));
+        self.operands.push(dst);
+        self.retargetable = true;
+    }
+    fn pop(&mut self) -> Reg {
+        self.operands.pop().expect("depth checked by analyze")
+    }
+    // Copy stack values still living in locals into their own temporaries
+    fn materialize(&mut self, from_depth: usize, only: Option<Reg>) {
+        for depth in from_depth..self.operands.len() {
+            let reg = self.operands[depth];
+            let temp = self.temp(depth);
+            if reg != temp && only.is_none_or(|local| local == reg) {
+                self.emit(RegInstr::Move(temp, reg));
+                self.operands[depth] = temp;
+            }
+        }
+    }
+    // Put every stack value in its own temporary, as jump targets expect
+    fn flush(&mut self) {
+        self.materialize(0, None);
+    }
+    fn local(&self, idx: u8) -> Result<Reg, VMError> {
+        if !self.region.in_frame {
+            return Err(VMError::NotInFrame);
+        }
+        if idx as Reg >= self.region.base {
+            return Err(VMError::InvalidLocalIndex(idx as u16));
+        }
+        Ok(idx as Reg)
+    }
+    fn store_local(&mut self, idx: u8) -> Result<Option<Reg>, VMError> {
+        let src = self.pop();
+        if !self.region.in_frame {
+            // Like the stack VM, storing a local outside a function does nothing
+            return Ok(None);
+        }
+        let local = self.local(idx)?;
+        if src == local {
+            return Ok(Some(local));
+        }
+        self.materialize(0, Some(local));
+        let last = self.code.len().wrapping_sub(1);
+        let redirect = self.retargetable && src == self.temp(self.operands.len());
+        match self.code.get_mut(last).and_then(|i| i.dst_mut()) {
+            Some(dst) if redirect && *dst == src => *dst = local,
+            _ => self.emit(RegInstr::Move(local, src)),
+        }
+        self.retargetable = false;
+        Ok(Some(local))
+    }
+    fn binary(&mut self, make: fn(Reg, Reg, Reg) -> RegInstr) {
+        let rhs = self.pop();
+        let lhs = self.pop();
+        self.emit_push(|dst| make(dst, lhs, rhs));
+    }
+    fn unary(&mut self, make: fn(Reg, Reg) -> RegInstr) {
+        let src = self.pop();
+        self.emit_push(|dst| make(dst, src));
+    }
+
+    fn translate(&mut self, instr: Instr) -> Result<(), VMError> {
+        match instr {
+            Instr::Add => self.binary(RegInstr::Add),
+            Instr::Sub => self.binary(RegInstr::Sub),
+            Instr::Mul => self.binary(RegInstr::Mul),
+            Instr::Div => self.binary(RegInstr::Div),
+            Instr::DivInt => self.binary(RegInstr::DivInt),
+            Instr::Mod => self.binary(RegInstr::Mod),
+            Instr::DivExact => self.binary(RegInstr::DivExact),
+            Instr::AddWrap => self.binary(RegInstr::AddWrap),
+            Instr::SubWrap => self.binary(RegInstr::SubWrap),
+            Instr::MulWrap => self.binary(RegInstr::MulWrap),
+            Instr::AddChecked => self.binary(RegInstr::AddChecked),
+            Instr::SubChecked => self.binary(RegInstr::SubChecked),
+            Instr::MulChecked => self.binary(RegInstr::MulChecked),
+            Instr::AddSat => self.binary(RegInstr::AddSat),
+            Instr::SubSat => self.binary(RegInstr::SubSat),
+            Instr::MulSat => self.binary(RegInstr::MulSat),
+            Instr::Equal => self.binary(RegInstr::Equal),
+            Instr::NotEqual => self.binary(RegInstr::NotEqual),
+            Instr::LessThan => self.binary(RegInstr::LessThan),
+            Instr::GreaterThan => self.binary(RegInstr::GreaterThan),
+            Instr::GreaterEqual => self.binary(RegInstr::GreaterEqual),
+            Instr::LessEqual => self.binary(RegInstr::LessEqual),
+            Instr::LogicalAnd => self.binary(RegInstr::LogicalAnd),
+            Instr::LogicalOr => self.binary(RegInstr::LogicalOr),
+            Instr::Identical => self.binary(RegInstr::Identical),
+            Instr::Not => self.unary(RegInstr::Not),
+            Instr::BitAnd => self.binary(RegInstr::BitAnd),
+            Instr::BitOr => self.binary(RegInstr::BitOr),
+            Instr::BitXor => self.binary(RegInstr::BitXor),
+            Instr::BitNot => self.unary(RegInstr::BitNot),
+            Instr::Shl => self.binary(RegInstr::Shl),
+            Instr::Shr => self.binary(RegInstr::Shr),
+            Instr::UShr => self.binary(RegInstr::UShr),
+            Instr::RotateLeft => self.binary(RegInstr::RotateLeft),
+            Instr::RotateRight => self.binary(RegInstr::RotateRight),
+            Instr::PopCount => self.unary(RegInstr::PopCount),
+            Instr::LeadingZeros => self.unary(RegInstr::LeadingZeros),
+            Instr::TrailingZeros => self.unary(RegInstr::TrailingZeros),
+            Instr::ToFloat => self.unary(RegInstr::ToFloat),
+            Instr::ToInt(mode) => {
+                let src = self.pop();
+                self.emit_push(|dst| RegInstr::ToInt(mode, dst, src));
+            }
+            Instr::Box => self.unary(RegInstr::Box),
+            Instr::Unbox => self.unary(RegInstr::Unbox),
+            Instr::ArrayPop => self.unary(RegInstr::ArrayPop),
+            Instr::ArrayLen => self.unary(RegInstr::ArrayLen),
+            Instr::ArrayGet => self.binary(RegInstr::ArrayGet),
+            Instr::Symbol => self.unary(RegInstr::Symbol),
+            Instr::Evaluate => self.unary(RegInstr::Evaluate),
+            Instr::ExprEqual => self.binary(RegInstr::ExprEqual),
+            Instr::Simplify => self.unary(RegInstr::Simplify),
+            Instr::Differentiate(idx) => {
+                let src = self.pop();
+                self.emit_push(|dst| RegInstr::Differentiate(dst, src, idx));
+            }
+            Instr::Substitute => {
+                let val = self.pop();
+                let sym = self.pop();
+                let tree = self.pop();
+                self.emit_push(|dst| RegInstr::Substitute(dst, tree, sym, val));
+            }
+
+            Instr::PushConst(idx) => self.emit_push(|dst| RegInstr::LoadConst(dst, idx)),
+            Instr::PushImmediate(val) => self.emit_push(|dst| RegInstr::LoadImm(dst, val)),
+            Instr::PushGlobal(idx) => self.emit_push(|dst| RegInstr::LoadGlobal(dst, idx)),
+            Instr::PushLocal(idx) => {
+                let local = self.local(idx)?;
+                self.operands.push(local);
+            }
+            Instr::StoreLocal(idx) => {
+                self.store_local(idx)?;
+            }
+            Instr::StoreLocalKeep(idx) => {
+                let src = self.operands.last().copied();
+                match self.store_local(idx)? {
+                    Some(local) => self.operands.push(local),
+                    None => self.operands.push(src.expect("depth checked by analyze")),
+                }
+            }
+            Instr::StoreGlobal(idx) => {
+                let src = self.pop();
+                self.emit(RegInstr::StoreGlobal(idx, src));
+            }
+            Instr::Pop => {
+                self.pop();
+            }
+            Instr::SetBox => {
+                let src = self.pop();
+                let boxed = self.pop();
+                self.emit(RegInstr::SetBox(boxed, src));
+            }
+            Instr::Array(count) => {
+                let first = self.operands.len() - count as usize;
+                self.materialize(first, None);
+                self.operands.truncate(first);
+                self.emit_push(|dst| RegInstr::Array(dst, dst, count));
+            }
+            Instr::ArraySet => {
+                let src = self.pop();
+                let idx = self.pop();
+                let arr = self.pop();
+                self.emit(RegInstr::ArraySet(arr, idx, src));
+            }
+            Instr::Math(f) => {
+                let first = self.operands.len() - f.arity();
+                self.materialize(first, None);
+                self.operands.truncate(first);
+                let args = self.temp(first);
+                self.emit_push(|dst| RegInstr::Math(f, dst, args));
+            }
+            Instr::ArrayPush => {
+                let src = self.pop();
+                let arr = self.pop();
+                self.emit(RegInstr::ArrayPush(arr, src));
+            }
+
+            // Jump targets are patched to register code indices once everything is emitted
+            Instr::Jump(target) => {
+                self.flush();
+                self.emit(RegInstr::Jump(target));
+            }
+            Instr::JumpIfFalse(target) => {
+                let cond = self.pop();
+                self.flush();
+                self.emit(RegInstr::JumpIfFalse(cond, target));
+            }
+            Instr::JumpIfTrue(target) => {
+                let cond = self.pop();
+                self.flush();
+                self.emit(RegInstr::JumpIfTrue(cond, target));
+            }
+
+            Instr::CallFunction(fidx) => {
+                let arity = self.bytecode.functions[fidx as usize].arity as usize;
+                let first = self.operands.len() - arity;
+                self.materialize(first, None);
+                self.operands.truncate(first);
+                let args = self.temp(first);
+                self.emit_push(|dst| RegInstr::Call(fidx, args, dst));
+                self.retargetable = false;
+            }
+            Instr::Return => {
+                let src = self.pop();
+                self.emit(RegInstr::Return(src));
+            }
+            Instr::Print => {
+                let src = self.pop();
+                self.emit(RegInstr::Print(src));
+            }
+            Instr::NoOp => {}
+
+            Instr::AddLocalConst(idx, constant) => {
+                let local = self.local(idx)?;
+                let temp = self.temp(self.operands.len());
+                self.emit(RegInstr::LoadConst(temp, constant));
+                self.emit_push(|dst| RegInstr::Add(dst, local, temp));
+            }
+            Instr::IncLocal(idx, delta) => {
+                let local = self.local(idx)?;
+                let temp = self.temp(self.operands.len());
+                self.materialize(0, Some(local));
+                self.emit(RegInstr::LoadImm(temp, delta));
+                self.emit(RegInstr::Add(local, local, temp));
+            }
+            Instr::CompareLocalConstJump(idx, constant, cond, target) => {
+                let local = self.local(idx)?;
+                let Some((cmp, jump_if)) = Instr::split_condition(cond) else {
+                    return Err(VMError::InvalidCondition(cond));
+                };
+                let compare = match cmp {
+                    Instr::Equal => RegInstr::Equal,
+                    Instr::NotEqual => RegInstr::NotEqual,
+                    Instr::LessThan => RegInstr::LessThan,
+                    Instr::GreaterThan => RegInstr::GreaterThan,
+                    Instr::GreaterEqual => RegInstr::GreaterEqual,
+                    _ => RegInstr::LessEqual,
+                };
+                self.flush();
+                let temp = self.temp(self.operands.len());
+                self.emit(RegInstr::LoadConst(temp, constant));
+                self.emit(compare(temp, local, temp));
+                match jump_if {
+                    true => self.emit(RegInstr::JumpIfTrue(temp, target)),
+                    false => self.emit(RegInstr::JumpIfFalse(temp, target)),
+                }
+            }
+            Instr::PushLocalPair(first, second) => {
+                let first = self.local(first)?;
+                let second = self.local(second)?;
+                self.operands.push(first);
+                self.operands.push(second);
+            }
+
+            // Quickened forms only exist inside a running VM
+            Instr::AddInt
+            | Instr::SubInt
+            | Instr::MulInt
+            | Instr::EqualInt
+            | Instr::NotEqualInt
+            | Instr::LessThanInt
+            | Instr::GreaterThanInt
+            | Instr::GreaterEqualInt
+            | Instr::LessEqualInt => return self.translate(instr.generic()),
+        }
+        Ok(())
+    }
+}
+
+// Translate stack bytecode to register code. Locals become registers and stack slots become
+// temporaries, with loads of locals deferred until something needs the value in a temporary,
+// so `pshl a; pshl b; add; strl c` becomes a single `add rc, ra, rb`.
+pub fn translate(bytecode: &Bytecode) -> Result<RegisterCode, VMError> {
+    let indexed = IndexedCode::decode(&bytecode.code)?;
+    let code_len = bytecode.code.len();
+    let index_of = |address: usize| {
+        indexed
+            .index_of(address, code_len)
+            .ok_or(VMError::InvalidJumpTarget(address))
+    };
+    let mut roots: Vec<(usize, Region)> = Vec::new();
+    for func in &bytecode.functions {
+        let region = Region {
+            base: func.locals as Reg,
+            in_frame: true,
+            max_depth: 0,
+        };
+        roots.push((index_of(func.address)?, region));
+    }
+    let entry_region = Region {
+        base: 0,
+        in_frame: false,
+        max_depth: 0,
+    };
+    roots.push((index_of(bytecode.entry)?, entry_region));
+
+    let instrs = &indexed.instrs;
+    let Analysis {
+        owner,
+        depth,
+        regions,
+    } = analyze(instrs, bytecode, &roots)?;
+    let mut starts: Vec<usize> = vec![0; instrs.len() + 1];
+    let mut translator = Translator {
+        bytecode,
+        region: entry_region,
+        operands: Vec::new(),
+        code: Vec::new(),
+        retargetable: false,
+    };
+    let mut heads: Vec<bool> = vec![false; instrs.len() + 1];
+    for instr in instrs {
+        if let Some(target) = instr.jump_target() {
+            heads[target as usize] = true;
+        }
+    }
+    let mut current: Option<usize> = None;
+    for idx in 0..instrs.len() {
+        let Some(region_idx) = owner[idx] else {
+            starts[idx] = translator.code.len();
+            continue;
+        };
+        // Jumps arrive with the stack in its temporaries, so falling through into a jump
+        // target has to leave it there too
+        if heads[idx] || current != Some(region_idx) {
+            if current == Some(region_idx) {
+                translator.flush();
+            }
+            current = Some(region_idx);
+            translator.region = regions[region_idx];
+            translator.operands = (0..depth[idx]).map(|d| translator.temp(d)).collect();
+            translator.retargetable = false;
+        }
+        starts[idx] = translator.code.len();
+        translator.translate(instrs[idx])?;
+        if matches!(instrs[idx], Instr::Jump(_) | Instr::Return) {
+            current = None;
+        }
+    }
+    starts[instrs.len()] = translator.code.len();
+
+    let mut code = translator.code;
+    for instr in code.iter_mut() {
+        match instr {
+            RegInstr::Jump(target)
+            | RegInstr::JumpIfFalse(_, target)
+            | RegInstr::JumpIfTrue(_, target) => *target = starts[*target as usize] as u32,
+            _ => {}
+        }
+    }
+    let window = |region: &Region| region.base + region.max_depth as Reg;
+    let functions = bytecode
+        .functions
+        .iter()
+        .zip(&roots)
+        .zip(&regions)
+        .map(|((func, (root, _)), region)| RegFunction {
+            address: starts[*root],
+            arity: func.arity,
+            registers: window(region),
+        })
+        .collect();
+    Ok(RegisterCode {
+        code,
+        consts: bytecode.consts.clone(),
+        functions,
+        entry: starts[roots[roots.len() - 1].0],
+        entry_registers: window(&regions[regions.len() - 1]),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::assembler::assemble_source;
+
+    fn listing(source: &str) -> Vec<String> {
+        let bytecode = assemble_source(&format!("{}\nmain", source)).unwrap();
+        let code = translate(&bytecode).unwrap();
+        code.code.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn local_traffic_is_removed() {
+        let source = "func f 2\npshl arg0\npshl arg1\nadd\nstrl temp\npshl temp\nendf";
+        assert_eq!(listing(source), ["add r2, r0, r1", "ret r2"]);
+    }
+
+    #[test]
+    fn stale_local_reads_are_copied_first() {
+        // The old value of a is still on the stack when a is overwritten
+        let source = "func f 1\npshl arg0\npshi 1\nstrl arg0\npshl arg0\nadd\nendf";
+        assert_eq!(
+            listing(source),
+            [
+                "ldi r2, 1",
+                "mov r1, r0",
+                "mov r0, r2",
+                "add r1, r1, r0",
+                "ret r1"
+            ]
+        );
+    }
+
+    #[test]
+    fn jumps_target_register_code() {
+        let source = "func f 1\nlabel top\npshl arg0\npshi 0\ngrth\njmpf done\n\
+                      pshl arg0\npshi 1\nsub\nstrl arg0\njump top\nlabel done\npshl arg0\nendf";
+        assert_eq!(
+            listing(source),
+            [
+                "ldi r2, 0",
+                "grth r1, r0, r2",
+                "jmpf r1, @6",
+                "ldi r2, 1",
+                "sub r0, r0, r2",
+                "jump @0",
+                "ret r0"
+            ]
+        );
+    }
+
+    #[test]
+    fn inconsistent_depths_are_rejected() {
+        let source = "main\npshi 0\npshi 1\nlteq\njmpt skip\npshi 1\nlabel skip\nprnt";
+        let bytecode = assemble_source(source).unwrap();
+        assert!(matches!(
+            translate(&bytecode),
+            Err(VMError::InconsistentStackDepth(_))
+        ));
+    }
+}
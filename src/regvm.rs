@@ -0,0 +1,326 @@
+use crate::bytecode::Bytecode;
+use crate::error::VMError;
+use crate::expr;
+use crate::ops::{self, Overflow};
+use crate::regcode::{Reg, RegFunction, RegInstr, RegisterCode, translate};
+use crate::value::Value;
+use std::io::{self, Write};
+
+// The caller's state, restored on return
+struct Frame {
+    return_ip: usize,
+    base: usize,
+    dst: Reg,
+}
+
+// Runs register code translated from stack bytecode. Every call gets a window at the top of
+// one register file, so its registers are just an offset from base.
+pub struct RegisterVM {
+    code: Vec<RegInstr>,
+    consts: Vec<Value>,
+    globals: Vec<Value>,
+    functions: Vec<RegFunction>,
+    entry: usize,
+    entry_registers: u16,
+    registers: Vec<Value>,
+    frames: Vec<Frame>,
+    base: usize,
+    ip: usize,
+    overflow: Overflow,
+    output: Box<dyn Write>,
+}
+
+impl Default for RegisterVM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RegisterVM {
+    pub fn new() -> Self {
+        Self {
+            code: Vec::new(),
+            consts: Vec::new(),
+            globals: Vec::new(),
+            functions: Vec::new(),
+            entry: 0,
+            entry_registers: 0,
+            registers: Vec::new(),
+            frames: Vec::new(),
+            base: 0,
+            ip: 0,
+            overflow: Overflow::default(),
+            output: Box::new(io::stdout()),
+        }
+    }
+    // Translates the bytecode to register code
+    pub fn load_code(&mut self, bytecode: Bytecode) -> Result<(), VMError> {
+        self.load_register_code(translate(&bytecode)?);
+        Ok(())
+    }
+    pub fn load_register_code(&mut self, code: RegisterCode) {
+        self.code = code.code;
+        self.consts = code.consts;
+        self.functions = code.functions;
+        self.entry = code.entry;
+        self.entry_registers = code.entry_registers;
+    }
+    // Send Print output somewhere other than stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+    // What Int add, sub, mul and div do on overflow. Promote to BigInt by default.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    #[inline]
+    fn get(&self, reg: Reg) -> Value {
+        self.registers[self.base + reg as usize].clone()
+    }
+    #[inline]
+    fn set(&mut self, reg: Reg, val: Value) {
+        self.registers[self.base + reg as usize] = val;
+    }
+    #[inline]
+    fn binary(&mut self, dst: Reg, lhs: Reg, rhs: Reg, op: ops::BinaryOp) -> Result<(), VMError> {
+        let val = op(self.get(lhs), self.get(rhs))?;
+        self.set(dst, val);
+        Ok(())
+    }
+    fn arith(
+        &mut self,
+        dst: Reg,
+        lhs: Reg,
+        rhs: Reg,
+        mode: Overflow,
+        op: ops::BinaryOp,
+    ) -> Result<(), VMError> {
+        let val = ops::overflowing(mode, op, self.get(lhs), self.get(rhs))?;
+        self.set(dst, val);
+        Ok(())
+    }
+    fn condition(&self, reg: Reg) -> Result<bool, VMError> {
+        match self.get(reg) {
+            Value::Bool(v) => Ok(v),
+            val => Err(VMError::InvalidStackValueType(Value::Bool(true), val)),
+        }
+    }
+    fn index(&self, reg: Reg) -> Result<usize, VMError> {
+        match self.get(reg) {
+            Value::Int(id) => Ok(id as usize),
+            val => Err(VMError::InvalidUnaryOperandType(val)),
+        }
+    }
+
+    pub fn execute(&mut self) -> Result<(), VMError> {
+        self.registers = vec![Value::NULL; self.entry_registers as usize];
+        self.frames.clear();
+        self.base = 0;
+        self.ip = self.entry;
+        while self.ip < self.code.len() {
+            match self.code[self.ip] {
+                RegInstr::Move(dst, src) => self.set(dst, self.get(src)),
+                RegInstr::LoadConst(dst, idx) => self.set(dst, self.consts[idx as usize].clone()),
+                RegInstr::LoadImm(dst, val) => self.set(dst, Value::Int(val as i64)),
+                RegInstr::LoadGlobal(dst, idx) => match self.globals.get(idx as usize) {
+                    Some(val) => self.set(dst, val.clone()),
+                    None => return Err(VMError::InvalidGlobalIndex(idx)),
+                },
+                RegInstr::StoreGlobal(idx, src) => {
+                    let idx = idx as usize;
+                    if idx >= self.globals.len() {
+                        self.globals.resize(idx + 1, Value::NULL);
+                    }
+                    self.globals[idx] = self.get(src);
+                }
+
+                RegInstr::Add(d, l, r) => self.arith(d, l, r, self.overflow, ops::add)?,
+                RegInstr::Sub(d, l, r) => self.arith(d, l, r, self.overflow, ops::sub)?,
+                RegInstr::Mul(d, l, r) => self.arith(d, l, r, self.overflow, ops::mul)?,
+                RegInstr::Div(d, l, r) => self.arith(d, l, r, self.overflow, ops::div)?,
+                RegInstr::DivInt(d, l, r) => self.arith(d, l, r, self.overflow, ops::div_int)?,
+                RegInstr::Mod(d, l, r) => self.binary(d, l, r, ops::modulo)?,
+                RegInstr::DivExact(d, l, r) => self.binary(d, l, r, ops::div_exact)?,
+                RegInstr::AddWrap(d, l, r) => self.arith(d, l, r, Overflow::Wrapping, ops::add)?,
+                RegInstr::SubWrap(d, l, r) => self.arith(d, l, r, Overflow::Wrapping, ops::sub)?,
+                RegInstr::MulWrap(d, l, r) => self.arith(d, l, r, Overflow::Wrapping, ops::mul)?,
+                RegInstr::AddChecked(d, l, r) => {
+                    self.arith(d, l, r, Overflow::Checked, ops::add)?
+                }
+                RegInstr::SubChecked(d, l, r) => {
+                    self.arith(d, l, r, Overflow::Checked, ops::sub)?
+                }
+                RegInstr::MulChecked(d, l, r) => {
+                    self.arith(d, l, r, Overflow::Checked, ops::mul)?
+                }
+                RegInstr::AddSat(d, l, r) => self.arith(d, l, r, Overflow::Saturating, ops::add)?,
+                RegInstr::SubSat(d, l, r) => self.arith(d, l, r, Overflow::Saturating, ops::sub)?,
+                RegInstr::MulSat(d, l, r) => self.arith(d, l, r, Overflow::Saturating, ops::mul)?,
+                RegInstr::Equal(d, l, r) => self.binary(d, l, r, ops::equal)?,
+                RegInstr::NotEqual(d, l, r) => self.binary(d, l, r, ops::not_equal)?,
+                RegInstr::LessThan(d, l, r) => self.binary(d, l, r, ops::less_than)?,
+                RegInstr::GreaterThan(d, l, r) => self.binary(d, l, r, ops::greater_than)?,
+                RegInstr::GreaterEqual(d, l, r) => self.binary(d, l, r, ops::greater_equal)?,
+                RegInstr::LessEqual(d, l, r) => self.binary(d, l, r, ops::less_equal)?,
+                RegInstr::LogicalAnd(d, l, r) => self.binary(d, l, r, ops::logical_and)?,
+                RegInstr::LogicalOr(d, l, r) => self.binary(d, l, r, ops::logical_or)?,
+                RegInstr::Identical(d, l, r) => self.binary(d, l, r, ops::identical)?,
+                RegInstr::Not(dst, src) => {
+                    let val = ops::not(self.get(src))?;
+                    self.set(dst, val);
+                }
+                RegInstr::BitAnd(d, l, r) => self.binary(d, l, r, ops::bit_and)?,
+                RegInstr::BitOr(d, l, r) => self.binary(d, l, r, ops::bit_or)?,
+                RegInstr::BitXor(d, l, r) => self.binary(d, l, r, ops::bit_xor)?,
+                RegInstr::BitNot(dst, src) => {
+                    let val = ops::bit_not(self.get(src))?;
+                    self.set(dst, val);
+                }
+                RegInstr::Shl(d, l, r) => self.binary(d, l, r, ops::shl)?,
+                RegInstr::Shr(d, l, r) => self.binary(d, l, r, ops::shr)?,
+                RegInstr::UShr(d, l, r) => self.binary(d, l, r, ops::ushr)?,
+                RegInstr::RotateLeft(d, l, r) => self.binary(d, l, r, ops::rotate_left)?,
+                RegInstr::RotateRight(d, l, r) => self.binary(d, l, r, ops::rotate_right)?,
+                RegInstr::PopCount(dst, src) => {
+                    let val = ops::pop_count(self.get(src))?;
+                    self.set(dst, val);
+                }
+                RegInstr::LeadingZeros(dst, src) => {
+                    let val = ops::leading_zeros(self.get(src))?;
+                    self.set(dst, val);
+                }
+                RegInstr::TrailingZeros(dst, src) => {
+                    let val = ops::trailing_zeros(self.get(src))?;
+                    self.set(dst, val);
+                }
+                RegInstr::ToInt(mode, dst, src) => {
+                    let val = ops::to_int(self.get(src), mode)?;
+                    self.set(dst, val);
+                }
+                RegInstr::ToFloat(dst, src) => {
+                    let val = ops::to_float(self.get(src))?;
+                    self.set(dst, val);
+                }
+                RegInstr::Math(f, dst, first) => {
+                    let start = self.base + first as usize;
+                    let val = f.apply(&self.registers[start..start + f.arity()])?;
+                    self.set(dst, val);
+                }
+
+                RegInstr::Symbol(dst, src) => {
+                    let val = expr::symbol(self.get(src))?;
+                    self.set(dst, val);
+                }
+                RegInstr::Substitute(dst, tree, sym, src) => {
+                    let val = expr::substitute(self.get(tree), self.get(sym), self.get(src))?;
+                    self.set(dst, val);
+                }
+                RegInstr::Evaluate(dst, src) => {
+                    let val = expr::evaluate(self.get(src))?;
+                    self.set(dst, val);
+                }
+                RegInstr::ExprEqual(d, l, r) => self.binary(d, l, r, expr::structural_equal)?,
+                RegInstr::Simplify(dst, src) => {
+                    let val = expr::simplify(self.get(src))?;
+                    self.set(dst, val);
+                }
+                RegInstr::Differentiate(dst, src, idx) => {
+                    let val = expr::differentiate(self.get(src), &self.consts[idx as usize])?;
+                    self.set(dst, val);
+                }
+
+                RegInstr::Box(dst, src) => self.set(dst, Value::new_box(self.get(src))),
+                RegInstr::Unbox(dst, src) => match self.get(src) {
+                    Value::HeapValue(boxed) => {
+                        let val = boxed.borrow().clone();
+                        self.set(dst, val);
+                    }
+                    val => return Err(VMError::InvalidUnaryOperandType(val)),
+                },
+                RegInstr::SetBox(boxed, src) => {
+                    let val = self.get(src);
+                    match self.get(boxed) {
+                        Value::HeapValue(boxed) => *boxed.borrow_mut() = val,
+                        _ => return Err(VMError::InvalidUnaryOperandType(val)),
+                    }
+                }
+                RegInstr::Array(dst, first, count) => {
+                    let start = self.base + first as usize;
+                    let vals = self.registers[start..start + count as usize].to_vec();
+                    self.set(dst, Value::new_array(vals));
+                }
+                RegInstr::ArraySet(arr, idx, src) => {
+                    let idx = self.index(idx)?;
+                    Value::set_to_array(idx, self.get(src), self.get(arr))?;
+                }
+                RegInstr::ArrayGet(dst, arr, idx) => {
+                    let idx = self.index(idx)?;
+                    let val = Value::get_from_array(idx, self.get(arr))?;
+                    self.set(dst, val);
+                }
+                RegInstr::ArrayPush(arr, src) => {
+                    Value::push_to_array(self.get(src), self.get(arr))?;
+                }
+                RegInstr::ArrayPop(dst, arr) => {
+                    let val = Value::pop_from_array(self.get(arr))?;
+                    self.set(dst, val);
+                }
+                RegInstr::ArrayLen(dst, arr) => {
+                    let val = Value::array_len(self.get(arr))?;
+                    self.set(dst, val);
+                }
+
+                RegInstr::Jump(target) => {
+                    self.ip = target as usize;
+                    continue;
+                }
+                RegInstr::JumpIfFalse(src, target) => {
+                    if !self.condition(src)? {
+                        self.ip = target as usize;
+                        continue;
+                    }
+                }
+                RegInstr::JumpIfTrue(src, target) => {
+                    if self.condition(src)? {
+                        self.ip = target as usize;
+                        continue;
+                    }
+                }
+
+                RegInstr::Call(fidx, args, dst) => {
+                    let func = self.functions[fidx as usize];
+                    let base = self.registers.len();
+                    let args = self.base + args as usize;
+                    self.registers
+                        .extend_from_within(args..args + func.arity as usize);
+                    self.registers
+                        .resize(base + func.registers as usize, Value::NULL);
+                    self.frames.push(Frame {
+                        return_ip: self.ip + 1,
+                        base: self.base,
+                        dst,
+                    });
+                    self.base = base;
+                    self.ip = func.address;
+                    continue;
+                }
+                RegInstr::Return(src) => {
+                    let val = self.get(src);
+                    let frame = self.frames.pop().ok_or(VMError::StackUnderflow)?;
+                    self.registers.truncate(self.base);
+                    self.base = frame.base;
+                    self.set(frame.dst, val);
+                    self.ip = frame.return_ip;
+                    continue;
+                }
+
+                RegInstr::Print(src) => {
+                    writeln!(self.output, "printing: {:?}", self.get(src))?;
+                }
+            }
+            self.ip += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,464 @@
+use crate::bytecode::Bytecode;
+use crate::error::VMError;
+use crate::expr;
+use crate::function::Function;
+use crate::instr::{IndexedCode, Instr};
+use crate::memory::Stack;
+use crate::ops::{self, Overflow};
+use crate::value::Value;
+use std::io::{self, Write};
+
+// Where the run loop goes after a compiled instruction. Calls and returns are jumps, since
+// each closure already knows its own return address and the callee's.
+pub enum Control {
+    Next,
+    Jump(usize),
+    Error(VMError),
+}
+
+// Everything compiled instructions work on
+pub struct VMState {
+    pub stack: Stack,
+    pub globals: Vec<Value>,
+    pub output: Box<dyn Write>,
+    // Read as the ops run, since they're compiled before it can be set
+    pub overflow: Overflow,
+}
+
+// One instruction with its operands captured
+pub type Op = Box<dyn Fn(&mut VMState) -> Control>;
+
+// Runs bytecode compiled to a vector of closures, one per instruction, so dispatch is an
+// indirect call instead of a match on the instruction
+pub struct ThreadedVM {
+    state: VMState,
+    ops: Vec<Op>,
+    entry: usize,
+}
+
+fn op(f: impl Fn(&mut VMState) -> Result<Control, VMError> + 'static) -> Op {
+    Box::new(move |state| f(state).unwrap_or_else(Control::Error))
+}
+
+fn binary(f: impl Fn(Value, Value) -> Result<Value, VMError> + 'static) -> Op {
+    op(move |state| {
+        let rop = state.stack.pop()?;
+        let lop = state.stack.pop()?;
+        state.stack.push(f(lop, rop)?)?;
+        Ok(Control::Next)
+    })
+}
+
+// A binary op with a fast path for two Ints, the same one quickened instructions take
+fn binary_int(
+    int: impl Fn(i64, i64) -> Option<Value> + Copy + 'static,
+    f: impl Fn(Value, Value) -> Result<Value, VMError> + 'static,
+) -> Op {
+    op(move |state| {
+        if !state.stack.binary_int(int) {
+            let rop = state.stack.pop()?;
+            let lop = state.stack.pop()?;
+            state.stack.push(f(lop, rop)?)?;
+        }
+        Ok(Control::Next)
+    })
+}
+
+// Int arithmetic with binary_int's fast path, falling back to the VM's overflow mode
+fn arith(int: fn(i64, i64) -> Option<i64>, f: ops::BinaryOp) -> Op {
+    op(move |state| {
+        if !state.stack.binary_int(|l, r| int(l, r).map(Value::Int)) {
+            let rop = state.stack.pop()?;
+            let lop = state.stack.pop()?;
+            let val = ops::overflowing(state.overflow, f, lop, rop)?;
+            state.stack.push(val)?;
+        }
+        Ok(Control::Next)
+    })
+}
+
+fn unary(f: impl Fn(Value) -> Result<Value, VMError> + 'static) -> Op {
+    op(move |state| {
+        let val = state.stack.pop()?;
+        state.stack.push(f(val)?)?;
+        Ok(Control::Next)
+    })
+}
+
+fn branch(target: usize, jump_if: bool) -> Op {
+    op(move |state| {
+        let val = state.stack.pop()?;
+        match val {
+            Value::Bool(v) if v == jump_if => Ok(Control::Jump(target)),
+            Value::Bool(_) => Ok(Control::Next),
+            _ => Err(VMError::InvalidStackValueType(Value::Bool(true), val)),
+        }
+    })
+}
+
+// Compile the instruction at index idx. Operands are checked here, so the closures don't
+// have to.
+fn compile(
+    instr: Instr,
+    idx: usize,
+    consts: &[Value],
+    functions: &[Function],
+) -> Result<Op, VMError> {
+    let constant = |idx: u16| {
+        consts
+            .get(idx as usize)
+            .cloned()
+            .ok_or(VMError::InvalidConstantIndex(idx))
+    };
+    let compiled = match instr.generic() {
+        // Arithmetic
+        Instr::Add => arith(i64::checked_add, ops::add),
+        Instr::Sub => arith(i64::checked_sub, ops::sub),
+        Instr::Mul => arith(i64::checked_mul, ops::mul),
+        Instr::Div => arith(i64::checked_div, ops::div),
+        Instr::DivInt => arith(i64::checked_div, ops::div_int),
+        Instr::Mod => binary(ops::modulo),
+        Instr::DivExact => binary(ops::div_exact),
+        Instr::AddWrap
+        | Instr::SubWrap
+        | Instr::MulWrap
+        | Instr::AddChecked
+        | Instr::SubChecked
+        | Instr::MulChecked
+        | Instr::AddSat
+        | Instr::SubSat
+        | Instr::MulSat => {
+            let (mode, f) = instr.overflow().expect("fixed overflow mode op");
+            binary(move |lop, rop| ops::overflowing(mode, f, lop, rop))
+        }
+
+        // Memory/Stack Manipulation
+        Instr::PushConst(idx) => {
+            let val = constant(idx)?;
+            op(move |state| {
+                state.stack.push(val.clone())?;
+                Ok(Control::Next)
+            })
+        }
+        Instr::PushImmediate(val) => op(move |state| {
+            state.stack.push(Value::Int(val as i64))?;
+            Ok(Control::Next)
+        }),
+        Instr::PushLocal(local) => op(move |state| {
+            let val = state.stack.peek_local(local)?;
+            state.stack.push(val)?;
+            Ok(Control::Next)
+        }),
+        Instr::StoreLocal(local) => op(move |state| {
+            let val = state.stack.pop()?;
+            state.stack.set_local(val, local);
+            Ok(Control::Next)
+        }),
+        Instr::StoreLocalKeep(local) => op(move |state| {
+            let val = state.stack.peek()?;
+            state.stack.set_local(val, local);
+            Ok(Control::Next)
+        }),
+        Instr::StoreGlobal(arg) => op(move |state| {
+            let val = state.stack.pop()?;
+            let arg = arg as usize;
+            if arg >= state.globals.len() {
+                state.globals.resize(arg + 1, Value::NULL);
+            }
+            state.globals[arg] = val;
+            Ok(Control::Next)
+        }),
+        Instr::PushGlobal(arg) => op(move |state| match state.globals.get(arg as usize) {
+            Some(val) => {
+                let val = val.clone();
+                state.stack.push(val)?;
+                Ok(Control::Next)
+            }
+            None => Err(VMError::InvalidGlobalIndex(arg)),
+        }),
+        Instr::Pop => op(|state| {
+            state.stack.pop()?;
+            Ok(Control::Next)
+        }),
+        Instr::Box => unary(|val| Ok(Value::new_box(val))),
+        Instr::Unbox => unary(|val| match val {
+            Value::HeapValue(boxed) => Ok(boxed.borrow().clone()),
+            _ => Err(VMError::InvalidUnaryOperandType(val)),
+        }),
+        Instr::SetBox => op(|state| {
+            let val = state.stack.pop()?;
+            let box_item = state.stack.pop()?;
+            match box_item {
+                Value::HeapValue(boxed) => *boxed.borrow_mut() = val,
+                _ => return Err(VMError::InvalidUnaryOperandType(val)),
+            }
+            Ok(Control::Next)
+        }),
+        Instr::Array(count) => op(move |state| {
+            let mut vals: Vec<Value> = Vec::new();
+            for _ in 0..count {
+                vals.push(state.stack.pop()?);
+            }
+            vals.reverse();
+            state.stack.push(Value::new_array(vals))?;
+            Ok(Control::Next)
+        }),
+        Instr::ArraySet => op(|state| {
+            let val = state.stack.pop()?;
+            let idx = state.stack.pop()?;
+            let arr = state.stack.pop()?;
+            match idx {
+                Value::Int(id) => Value::set_to_array(id as usize, val, arr)?,
+                _ => return Err(VMError::InvalidUnaryOperandType(idx)),
+            }
+            Ok(Control::Next)
+        }),
+        Instr::ArrayGet => binary(|arr, idx| match idx {
+            Value::Int(id) => Value::get_from_array(id as usize, arr),
+            _ => Err(VMError::InvalidUnaryOperandType(idx)),
+        }),
+        Instr::ArrayPush => op(|state| {
+            let val = state.stack.pop()?;
+            let arr = state.stack.pop()?;
+            Value::push_to_array(val, arr)?;
+            Ok(Control::Next)
+        }),
+        Instr::ArrayPop => unary(Value::pop_from_array),
+        Instr::ArrayLen => unary(Value::array_len),
+
+        // Control Flow
+        Instr::Jump(target) => Box::new(move |_: &mut VMState| Control::Jump(target as usize)),
+        Instr::JumpIfFalse(target) => branch(target as usize, false),
+        Instr::JumpIfTrue(target) => branch(target as usize, true),
+
+        // Comparisons and other operators
+        Instr::Equal => binary_int(|l, r| Some(Value::Bool(l == r)), ops::equal),
+        Instr::NotEqual => binary_int(|l, r| Some(Value::Bool(l != r)), ops::not_equal),
+        Instr::LessThan => binary_int(|l, r| Some(Value::Bool(l < r)), ops::less_than),
+        Instr::GreaterThan => binary_int(|l, r| Some(Value::Bool(l > r)), ops::greater_than),
+        Instr::GreaterEqual => binary_int(|l, r| Some(Value::Bool(l >= r)), ops::greater_equal),
+        Instr::LessEqual => binary_int(|l, r| Some(Value::Bool(l <= r)), ops::less_equal),
+        Instr::Not => unary(ops::not),
+        Instr::LogicalAnd => binary(ops::logical_and),
+        Instr::LogicalOr => binary(ops::logical_or),
+        Instr::Identical => binary(ops::identical),
+        Instr::BitAnd => binary(ops::bit_and),
+        Instr::BitOr => binary(ops::bit_or),
+        Instr::BitXor => binary(ops::bit_xor),
+        Instr::BitNot => unary(ops::bit_not),
+        Instr::Shl => binary(ops::shl),
+        Instr::Shr => binary(ops::shr),
+        Instr::UShr => binary(ops::ushr),
+        Instr::RotateLeft => binary(ops::rotate_left),
+        Instr::RotateRight => binary(ops::rotate_right),
+        Instr::PopCount => unary(ops::pop_count),
+        Instr::LeadingZeros => unary(ops::leading_zeros),
+        Instr::TrailingZeros => unary(ops::trailing_zeros),
+
+        // Math
+        Instr::Math(f) => op(move |state| {
+            let mut args = Vec::with_capacity(f.arity());
+            for _ in 0..f.arity() {
+                args.push(state.stack.pop()?);
+            }
+            args.reverse();
+            state.stack.push(f.apply(&args)?)?;
+            Ok(Control::Next)
+        }),
+        Instr::ToInt(mode) => unary(move |val| ops::to_int(val, mode)),
+        Instr::ToFloat => unary(ops::to_float),
+
+        // Symbolic
+        Instr::Symbol => unary(expr::symbol),
+        Instr::Substitute => op(|state| {
+            let val = state.stack.pop()?;
+            let sym = state.stack.pop()?;
+            let tree = state.stack.pop()?;
+            state.stack.push(expr::substitute(tree, sym, val)?)?;
+            Ok(Control::Next)
+        }),
+        Instr::Evaluate => unary(expr::evaluate),
+        Instr::ExprEqual => binary(expr::structural_equal),
+        Instr::Simplify => unary(expr::simplify),
+        Instr::Differentiate(idx) => {
+            let var = constant(idx)?;
+            unary(move |val| expr::differentiate(val, &var))
+        }
+
+        // Functions
+        Instr::CallFunction(fidx) => {
+            let Some(func) = functions.get(fidx as usize).copied() else {
+                return Err(VMError::InvalidFunctionIndex(fidx));
+            };
+            let return_address = idx + 1;
+            op(move |state| {
+                let mut args: Vec<Value> = Vec::new();
+                for _ in 0..func.arity {
+                    args.push(state.stack.pop()?);
+                }
+                args.reverse();
+                state
+                    .stack
+                    .push_frame(args, func.locals as usize, return_address)?;
+                Ok(Control::Jump(func.address))
+            })
+        }
+        Instr::Return => op(|state| {
+            let ret_val = state.stack.pop()?;
+            let return_address = state.stack.pop_frame()?;
+            state.stack.push(ret_val)?;
+            Ok(Control::Jump(return_address))
+        }),
+
+        // Testing ops
+        Instr::Print => op(|state| {
+            let val = state.stack.pop()?;
+            writeln!(state.output, "printing: {:?}", val)?;
+            Ok(Control::Next)
+        }),
+
+        // Superinstructions
+        Instr::AddLocalConst(local, idx) => {
+            let k = constant(idx)?;
+            op(move |state| {
+                let val = state.stack.peek_local(local)?;
+                let val = ops::overflowing(state.overflow, ops::add, val, k.clone())?;
+                state.stack.push(val)?;
+                Ok(Control::Next)
+            })
+        }
+        Instr::IncLocal(local, delta) => op(move |state| {
+            let val = state.stack.peek_local(local)?;
+            let val = ops::overflowing(state.overflow, ops::add, val, Value::Int(delta as i64))?;
+            state.stack.set_local(val, local);
+            Ok(Control::Next)
+        }),
+        Instr::CompareLocalConstJump(local, idx, cond, target) => {
+            let k = constant(idx)?;
+            let Some((cmp, jump_if)) = Instr::split_condition(cond) else {
+                return Err(VMError::InvalidCondition(cond));
+            };
+            let compare = cmp.comparison().expect("split_condition gives comparisons");
+            op(move |state| {
+                let val = state.stack.peek_local(local)?;
+                match compare(val, k.clone())? == Value::Bool(jump_if) {
+                    true => Ok(Control::Jump(target as usize)),
+                    false => Ok(Control::Next),
+                }
+            })
+        }
+        Instr::PushLocalPair(first, second) => op(move |state| {
+            let val = state.stack.peek_local(first)?;
+            state.stack.push(val)?;
+            let val = state.stack.peek_local(second)?;
+            state.stack.push(val)?;
+            Ok(Control::Next)
+        }),
+
+        Instr::NoOp => Box::new(|_: &mut VMState| Control::Next),
+
+        // generic() never returns a quickened instruction
+        _ => unreachable!(),
+    };
+    Ok(compiled)
+}
+
+impl ThreadedVM {
+    pub fn new(init_stack_cap: usize) -> Self {
+        Self {
+            state: VMState {
+                stack: Stack::new(init_stack_cap, usize::MAX),
+                globals: Vec::new(),
+                output: Box::new(io::stdout()),
+                overflow: Overflow::default(),
+            },
+            ops: Vec::new(),
+            entry: 0,
+        }
+    }
+    // Compile the bytecode, with jump targets and function addresses as indices into ops
+    pub fn load_code(&mut self, bytecode: Bytecode) -> Result<(), VMError> {
+        let indexed = IndexedCode::decode(&bytecode.code)?;
+        let code_len = bytecode.code.len();
+        let index_of = |address: usize| {
+            indexed
+                .index_of(address, code_len)
+                .ok_or(VMError::InvalidJumpTarget(address))
+        };
+        self.entry = index_of(bytecode.entry)?;
+        let mut functions = Vec::with_capacity(bytecode.functions.len());
+        for func in &bytecode.functions {
+            functions.push(Function {
+                address: index_of(func.address)?,
+                ..*func
+            });
+        }
+        self.ops = Vec::with_capacity(indexed.instrs.len());
+        for (idx, instr) in indexed.instrs.iter().enumerate() {
+            self.ops
+                .push(compile(*instr, idx, &bytecode.consts, &functions)?);
+        }
+        Ok(())
+    }
+    // Send Print output somewhere other than stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.state.output = output;
+    }
+    // What Int add, sub, mul and div do on overflow. Promote to BigInt by default.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.state.overflow = overflow;
+    }
+    pub fn execute(&mut self) -> Result<(), VMError> {
+        let mut ip = self.entry;
+        while let Some(op) = self.ops.get(ip) {
+            match op(&mut self.state) {
+                Control::Next => ip += 1,
+                Control::Jump(target) => ip = target,
+                Control::Error(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::assembler::assemble_source;
+
+    #[test]
+    fn operands_are_checked_at_load() {
+        let bytecode = Bytecode {
+            entry: 0,
+            consts: Vec::new(),
+            functions: Vec::new(),
+            code: vec![0x10, 0, 0],
+        };
+        let mut vm = ThreadedVM::new(16);
+        assert!(matches!(
+            vm.load_code(bytecode),
+            Err(VMError::InvalidConstantIndex(0))
+        ));
+    }
+
+    #[test]
+    fn runtime_errors_stop_execution() {
+        let bytecode = assemble_source("main\npshi 1\npshi 0\ndiv\nprnt").unwrap();
+        let mut vm = ThreadedVM::new(16);
+        vm.set_output(Box::new(io::sink()));
+        vm.load_code(bytecode).unwrap();
+        assert!(matches!(vm.execute(), Err(VMError::DivisionByZero)));
+    }
+
+    #[test]
+    fn overflow_mode_is_per_vm() {
+        let source = "main\npshc 9223372036854775807\npshi 1\nadd\nprnt";
+        let mut vm = ThreadedVM::new(16);
+        vm.set_output(Box::new(io::sink()));
+        vm.load_code(assemble_source(source).unwrap()).unwrap();
+        vm.execute().unwrap();
+        vm.set_overflow(Overflow::Checked);
+        assert!(matches!(vm.execute(), Err(VMError::IntegerOverflow)));
+    }
+}
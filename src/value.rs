@@ -1,18 +1,35 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+use num_bigint::BigInt;
+use num_complex::Complex64;
+use num_rational::BigRational;
+use num_traits::ToPrimitive;
 
 use crate::error::VMError;
+use crate::expr::Expr;
 
 pub type HeapString = Rc<String>;
 pub type HeapVec = Rc<RefCell<Vec<Value>>>;
+// Not a Value variant yet, so no op makes, compares or orders maps
 pub type HeapMap = Rc<RefCell<HashMap<String, Value>>>;
 pub type HeapValue = Rc<RefCell<Value>>;
+pub type HeapBigInt = Rc<BigInt>;
+pub type HeapRational = Rc<BigRational>;
+pub type HeapExpr = Rc<Expr>;
 
-#[derive(Default, Debug, Clone, PartialEq)]
+#[derive(Default, Clone, PartialEq)]
 pub enum Value {
     #[default]
     NULL,
     Int(i64),
+    // Only ever holds integers outside the range of i64; smaller ones are always Int
+    BigInt(HeapBigInt),
+    // Reduced, with a denominator above 1; whole numbers are always Int or BigInt
+    Rational(HeapRational),
     Float(f64),
+    Complex(Complex64),
+    // Symbolic, for computer algebra; see expr.rs
+    Expr(HeapExpr),
     String(HeapString),
     Bool(bool),
     Ident(String),
@@ -22,9 +39,63 @@ pub enum Value {
 }
 
 impl Value {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+    // An integer as an Int if it fits, or a BigInt if not
+    pub fn from_bigint(val: BigInt) -> Value {
+        match val.to_i64() {
+            Some(v) => Value::Int(v),
+            None => Value::BigInt(Rc::new(val)),
+        }
+    }
+    // Int or BigInt as a BigInt
+    pub fn as_bigint(&self) -> Option<BigInt> {
+        match self {
+            Value::Int(v) => Some(BigInt::from(*v)),
+            Value::BigInt(v) => Some(BigInt::clone(v)),
+            _ => None,
+        }
+    }
+    // An exact number as a Rational, or as an integer if it's whole
+    pub fn from_rational(val: BigRational) -> Value {
+        if val.is_integer() {
+            return Value::from_bigint(val.to_integer());
+        }
+        Value::Rational(Rc::new(val))
+    }
+    // Int, BigInt or Rational as a BigRational
+    pub fn as_rational(&self) -> Option<BigRational> {
+        match self {
+            Value::Rational(v) => Some(BigRational::clone(v)),
+            _ => self.as_bigint().map(BigRational::from_integer),
+        }
+    }
+    // Any real number as a Float's f64
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int(v) => Some(*v as f64),
+            Value::BigInt(v) => v.to_f64(),
+            Value::Rational(v) => v.to_f64(),
+            Value::Float(v) => Some(*v),
+            _ => None,
+        }
+    }
+    // Any number as a Complex
+    pub fn as_complex(&self) -> Option<Complex64> {
+        match self {
+            Value::Complex(v) => Some(*v),
+            _ => self.as_f64().map(|re| Complex64::new(re, 0.0)),
+        }
+    }
+    #[allow(clippy::needless_return)]
     pub fn new_box(val: Value) -> Value {
         return Value::HeapValue(Rc::new(RefCell::new(val.clone())));
     }
+    #[allow(clippy::needless_return)]
     pub fn new_array(vals: Vec<Value>) -> Value {
         return Value::Array(Rc::new(RefCell::new(vals)));
     }
@@ -42,6 +113,7 @@ impl Value {
 
         Ok(())
     }
+    #[allow(clippy::needless_return)]
     pub fn get_from_array(idx: usize, arr: Value) -> Result<Value, VMError> {
         match arr {
             Value::Array(boxed_array) => {
@@ -54,6 +126,7 @@ impl Value {
             _ => return Err(VMError::InvalidUnaryOperandType(arr)),
         }
     }
+    #[allow(clippy::needless_return)]
     pub fn push_to_array(val: Value, arr: Value) -> Result<(), VMError> {
         match arr {
             Value::Array(boxed_array) => {
@@ -64,6 +137,7 @@ impl Value {
             _ => return Err(VMError::InvalidUnaryOperandType(arr)),
         }
     }
+    #[allow(clippy::needless_return)]
     pub fn pop_from_array(arr: Value) -> Result<Value, VMError> {
         match arr {
             Value::Array(boxed_array) => {
@@ -77,6 +151,7 @@ impl Value {
             _ => return Err(VMError::InvalidUnaryOperandType(arr)),
         }
     }
+    #[allow(clippy::needless_return)]
     pub fn array_len(arr: Value) -> Result<Value, VMError> {
         match arr {
             Value::Array(boxed_array) => {
@@ -87,3 +162,24 @@ impl Value {
         }
     }
 }
+
+// Derived, except that Rational, Complex and Expr show as 3/4, 2+3i and x + 1
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::NULL => f.write_str("NULL"),
+            Value::Int(v) => f.debug_tuple("Int").field(v).finish(),
+            Value::BigInt(v) => f.debug_tuple("BigInt").field(v).finish(),
+            Value::Rational(v) => write!(f, "Rational({})", v),
+            Value::Float(v) => f.debug_tuple("Float").field(v).finish(),
+            Value::Complex(v) => write!(f, "Complex({})", v),
+            Value::Expr(v) => write!(f, "Expr({})", v),
+            Value::String(v) => f.debug_tuple("String").field(v).finish(),
+            Value::Bool(v) => f.debug_tuple("Bool").field(v).finish(),
+            Value::Ident(v) => f.debug_tuple("Ident").field(v).finish(),
+            Value::HeapValue(v) => f.debug_tuple("HeapValue").field(v).finish(),
+            Value::Function(v) => f.debug_tuple("Function").field(v).finish(),
+            Value::Array(v) => f.debug_tuple("Array").field(v).finish(),
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use crate::bytecode::Bytecode;
+use crate::error::VerifyError;
+use crate::instr::Instr;
+use crate::math::{MathFn, Rounding};
+use crate::opcode::OpCode;
+
+// Check that the code decodes cleanly and every operand refers to something that exists:
+// const and function indices are in range, and jump targets, function addresses and the
+// entry point all land on the start of an instruction.
+pub fn verify(bytecode: &Bytecode) -> Result<(), VerifyError> {
+    let code = &bytecode.code;
+    let mut starts = vec![false; code.len()];
+    let mut jumps: Vec<(usize, usize)> = Vec::new();
+
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = OpCode::try_from(code[offset])
+            .map_err(|_| VerifyError::InvalidOpcode(offset, code[offset]))?;
+        let size = opcode.size();
+        if offset + size > code.len() {
+            return Err(VerifyError::TruncatedInstruction(offset));
+        }
+        starts[offset] = true;
+        let operand = &code[offset + 1..offset + size];
+        match opcode {
+            OpCode::PushConst | OpCode::Differentiate => {
+                let idx = u16::from_le_bytes([operand[0], operand[1]]);
+                if idx as usize >= bytecode.consts.len() {
+                    return Err(VerifyError::InvalidConstantIndex(offset, idx));
+                }
+            }
+            OpCode::AddLocalConst => {
+                let idx = u16::from_le_bytes([operand[1], operand[2]]);
+                if idx as usize >= bytecode.consts.len() {
+                    return Err(VerifyError::InvalidConstantIndex(offset, idx));
+                }
+            }
+            OpCode::CompareLocalConstJump => {
+                let idx = u16::from_le_bytes([operand[1], operand[2]]);
+                if idx as usize >= bytecode.consts.len() {
+                    return Err(VerifyError::InvalidConstantIndex(offset, idx));
+                }
+                if Instr::split_condition(operand[3]).is_none() {
+                    return Err(VerifyError::InvalidCondition(offset, operand[3]));
+                }
+                let target = u32::from_le_bytes([operand[4], operand[5], operand[6], operand[7]]);
+                jumps.push((offset, target as usize));
+            }
+            OpCode::CallFunction => {
+                let idx = u16::from_le_bytes([operand[0], operand[1]]);
+                if idx as usize >= bytecode.functions.len() {
+                    return Err(VerifyError::InvalidFunctionIndex(offset, idx));
+                }
+            }
+            OpCode::Math if MathFn::try_from(operand[0]).is_err() => {
+                return Err(VerifyError::InvalidMathFunction(offset, operand[0]));
+            }
+            OpCode::ToInt if Rounding::try_from(operand[0]).is_err() => {
+                return Err(VerifyError::InvalidRoundingMode(offset, operand[0]));
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue => {
+                let target = u32::from_le_bytes([operand[0], operand[1], operand[2], operand[3]]);
+                jumps.push((offset, target as usize));
+            }
+            _ => {}
+        }
+        offset += size;
+    }
+
+    let is_start = |addr: usize| addr < code.len() && starts[addr];
+    for (offset, target) in jumps {
+        // Jumping to the end of the code ends the program
+        if !is_start(target) && target != code.len() {
+            return Err(VerifyError::InvalidJumpTarget(offset, target));
+        }
+    }
+    for (idx, func) in bytecode.functions.iter().enumerate() {
+        if !is_start(func.address) || func.locals < func.arity {
+            return Err(VerifyError::InvalidFunction(idx));
+        }
+    }
+    if !code.is_empty() && !is_start(bytecode.entry) {
+        return Err(VerifyError::InvalidEntry(bytecode.entry));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::error::VerifyError;
+    use crate::value::Value;
+
+    fn bytecode(code: Vec<u8>) -> Bytecode {
+        Bytecode {
+            entry: 0,
+            consts: vec![Value::Int(1)],
+            functions: Vec::new(),
+            code,
+        }
+    }
+
+    #[test]
+    fn rejects_bad_operands() {
+        assert_eq!(
+            verify(&bytecode(vec![0x10, 1, 0])),
+            Err(VerifyError::InvalidConstantIndex(0, 1))
+        );
+        assert_eq!(
+            verify(&bytecode(vec![0x10, 0])),
+            Err(VerifyError::TruncatedInstruction(0))
+        );
+        assert_eq!(
+            verify(&bytecode(vec![0x61, 0, 0])),
+            Err(VerifyError::InvalidFunctionIndex(0, 0))
+        );
+        assert_eq!(
+            verify(&bytecode(vec![0x77])),
+            Err(VerifyError::InvalidOpcode(0, 0x77))
+        );
+        assert_eq!(
+            verify(&bytecode(vec![0x50, 0xFF])),
+            Err(VerifyError::InvalidMathFunction(0, 0xFF))
+        );
+        assert_eq!(
+            verify(&bytecode(vec![0x51, 0x04])),
+            Err(VerifyError::InvalidRoundingMode(0, 0x04))
+        );
+    }
+
+    #[test]
+    fn checks_superinstruction_operands() {
+        // pshl 0; pshc 0; lteq; jmpt 0 and pshl 0; pshc 0; add
+        assert_eq!(
+            verify(&bytecode(vec![0x82, 0, 0, 0, 0x45, 0, 0, 0, 0])),
+            Ok(())
+        );
+        assert_eq!(verify(&bytecode(vec![0x80, 0, 0, 0])), Ok(()));
+        assert_eq!(
+            verify(&bytecode(vec![0x80, 0, 1, 0])),
+            Err(VerifyError::InvalidConstantIndex(0, 1))
+        );
+        assert_eq!(
+            verify(&bytecode(vec![0x82, 0, 0, 0, 0x00, 0, 0, 0, 0])),
+            Err(VerifyError::InvalidCondition(0, 0x00))
+        );
+        assert_eq!(
+            verify(&bytecode(vec![0x82, 0, 0, 0, 0xC5, 3, 0, 0, 0])),
+            Err(VerifyError::InvalidJumpTarget(0, 3))
+        );
+    }
+
+    #[test]
+    fn jumps_must_land_on_instructions() {
+        assert_eq!(verify(&bytecode(vec![0xFF, 0x26, 0, 0, 0, 0])), Ok(()));
+        assert_eq!(
+            verify(&bytecode(vec![0xFF, 0x26, 2, 0, 0, 0])),
+            Err(VerifyError::InvalidJumpTarget(1, 2))
+        );
+    }
+}
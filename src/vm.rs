@@ -1,497 +1,722 @@
 use crate::bytecode::Bytecode;
 use crate::error::VMError;
+use crate::expr;
 use crate::function::Function;
+use crate::instr::{IndexedCode, Instr};
+#[cfg(feature = "jit")]
+use crate::jit::{JIT_THRESHOLD, Jit};
 use crate::memory::Stack;
-use crate::opcode::OpCode;
+use crate::ops::{self, Overflow};
 use crate::value::Value;
+use std::io::{self, Write};
+
+// Where execution goes after an instruction
+enum Flow {
+    Next,
+    Jump(usize),
+    Call(u16),
+    Return(usize),
+}
 
 pub struct VM {
     stack: Stack,
     consts: Vec<Value>,
     globals: Vec<Value>,
-    functions: Vec<Function>,
+    // Raw bytecode, run by execute_bytecode
     code: Vec<u8>,
+    entry: usize,
+    functions: Vec<Function>,
+    // Pre-decoded code, run by execute. Jump targets and function addresses are instruction
+    // indices.
+    instrs: Vec<Instr>,
+    instr_entry: usize,
+    instr_functions: Vec<Function>,
+    // Rewrite generic arithmetic and comparisons in instrs to int-specialized forms
+    quicken: bool,
+    // Compiles hot functions called from execute
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>,
     ip: usize,
+    // What Int arithmetic does on overflow
+    overflow: Overflow,
+    output: Box<dyn Write>,
 }
 
 impl VM {
-    fn u32_from_le(&mut self) -> u32 {
-        let val = u32::from_le_bytes([
-            self.code[self.ip + 1],
-            self.code[self.ip + 2],
-            self.code[self.ip + 3],
-            self.code[self.ip + 4],
-        ]);
-        self.ip += 4;
-        return val;
-    }
-    fn u16_from_le(&mut self) -> u16 {
-        let val = u16::from_le_bytes([self.code[self.ip + 1], self.code[self.ip + 2]]);
-        self.ip += 2;
-        return val;
-    }
-    #[allow(dead_code)] // unused for now, will be later
-    fn i16_from_le(&mut self) -> i16 {
-        let val = i16::from_le_bytes([self.code[self.ip + 1], self.code[self.ip + 2]]);
-        self.ip += 2;
-        return val;
-    }
-    #[allow(dead_code)] // unused for now, will be later
-    fn bool_from_byte(&mut self) -> bool {
-        let val = self.code[self.ip + 1];
-        self.ip += 1;
-        return val != 0;
-    }
-    fn u8_from_byte(&mut self) -> u8 {
-        let val = self.code[self.ip + 1];
-        self.ip += 1;
-        return val;
-    }
     pub fn new(init_stack_cap: usize) -> Self {
         Self {
             stack: Stack::new(init_stack_cap, usize::MAX),
             consts: Vec::new(),
             globals: Vec::new(),
-            functions: Vec::new(),
             code: Vec::new(),
+            entry: 0,
+            functions: Vec::new(),
+            instrs: Vec::new(),
+            instr_entry: 0,
+            instr_functions: Vec::new(),
+            quicken: true,
+            #[cfg(feature = "jit")]
+            jit: Some(Jit::new(JIT_THRESHOLD)),
             ip: 0,
+            overflow: Overflow::default(),
+            output: Box::new(io::stdout()),
         }
     }
-    pub fn load_code(&mut self, bytecode: Bytecode) {
-        self.ip = bytecode.entry;
+    // Decodes the code once up front, so execute never touches the raw bytes
+    pub fn load_code(&mut self, bytecode: Bytecode) -> Result<(), VMError> {
+        let indexed = IndexedCode::decode(&bytecode.code)?;
+        let code_len = bytecode.code.len();
+        let index_of = |address: usize| {
+            indexed
+                .index_of(address, code_len)
+                .ok_or(VMError::InvalidJumpTarget(address))
+        };
+        self.instr_entry = index_of(bytecode.entry)?;
+        self.instr_functions = Vec::with_capacity(bytecode.functions.len());
+        for func in &bytecode.functions {
+            self.instr_functions.push(Function {
+                address: index_of(func.address)?,
+                ..*func
+            });
+        }
+        self.instrs = indexed.instrs;
+        self.entry = bytecode.entry;
         self.code = bytecode.code;
         self.consts = bytecode.consts;
         self.functions = bytecode.functions;
+        Ok(())
     }
+    // Send Print output somewhere other than stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+    // What Int add, sub, mul and div do on overflow. Promote to BigInt by default.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+    // Quickening is on by default; turning it off is mostly useful for benchmarking
+    pub fn set_quickening(&mut self, quicken: bool) {
+        self.quicken = quicken;
+    }
+    // Calls to a function before execute compiles it, or None to never compile anything.
+    // Functions already compiled are forgotten.
+    #[cfg(feature = "jit")]
+    pub fn set_jit_threshold(&mut self, threshold: Option<u32>) {
+        self.jit = threshold.map(Jit::new);
+    }
+    // Run the pre-decoded instructions from the entry point. With quickening on, a generic op
+    // that finds two Ints on the stack is rewritten in place to its int-specialized form.
     pub fn execute(&mut self) -> Result<(), VMError> {
-        loop {
-            let opcode = OpCode::try_from(self.code[self.ip])?;
-            // println!("Stack before {:?}: {:?}", opcode, self.stack);
-            match opcode {
-                // Arithmetic
-                OpCode::Add => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-                    // println!("stack after pops: {:?}", self.stack);
-                    match (&lop, &rop) {
-                        (Value::Int(l), Value::Int(r)) => {
-                            let result = l + r;
-                            self.stack.push(Value::Int(result))?;
-                        }
-                        (Value::Float(l), Value::Float(r)) => {
-                            self.stack.push(Value::Float(l + r))?;
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                }
-                OpCode::Sub => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-                    // println!("stack after pops: {:?}", self.stack);
-                    match (&lop, &rop) {
-                        (Value::Int(l), Value::Int(r)) => {
-                            let result = l - r;
-                            self.stack.push(Value::Int(result))?;
-                        }
-                        (Value::Float(l), Value::Float(r)) => {
-                            self.stack.push(Value::Float(l - r))?;
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                }
-                OpCode::Mul => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-
-                    match (&lop, &rop) {
-                        (Value::Int(l), Value::Int(r)) => {
-                            self.stack.push(Value::Int(l * r))?;
-                        }
-                        (Value::Float(l), Value::Float(r)) => {
-                            self.stack.push(Value::Float(l * r))?;
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                }
-                OpCode::Div => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-
-                    match (&lop, &rop) {
-                        (_, Value::Float(0.0) | Value::Int(0)) => {
-                            return Err(VMError::DivisionByZero);
-                        }
-                        (Value::Int(l), Value::Int(r)) => {
-                            self.stack.push(Value::Int(l / r))?;
-                        }
-                        (Value::Float(l), Value::Float(r)) => {
-                            self.stack.push(Value::Float(l / r))?;
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                }
-                OpCode::DivInt => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-
-                    match (&lop, &rop) {
-                        (_, Value::Float(0.0) | Value::Int(0)) => {
-                            return Err(VMError::DivisionByZero);
-                        }
-                        (Value::Int(l), Value::Int(r)) => {
-                            self.stack.push(Value::Int(l / r))?;
-                        }
-                        (Value::Float(l), Value::Float(r)) => {
-                            self.stack.push(Value::Int((l / r).floor() as i64))?;
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
+        self.ip = self.instr_entry;
+        while self.ip < self.instrs.len() {
+            let instr = self.instrs[self.ip];
+            let flow = match instr {
+                Instr::AddInt => self.step_int(instr, |l, r| l.checked_add(r).map(Value::Int))?,
+                Instr::SubInt => self.step_int(instr, |l, r| l.checked_sub(r).map(Value::Int))?,
+                Instr::MulInt => self.step_int(instr, |l, r| l.checked_mul(r).map(Value::Int))?,
+                Instr::EqualInt => self.step_int(instr, |l, r| Some(Value::Bool(l == r)))?,
+                Instr::NotEqualInt => self.step_int(instr, |l, r| Some(Value::Bool(l != r)))?,
+                Instr::LessThanInt => self.step_int(instr, |l, r| Some(Value::Bool(l < r)))?,
+                Instr::GreaterThanInt => self.step_int(instr, |l, r| Some(Value::Bool(l > r)))?,
+                Instr::GreaterEqualInt => self.step_int(instr, |l, r| Some(Value::Bool(l >= r)))?,
+                Instr::LessEqualInt => self.step_int(instr, |l, r| Some(Value::Bool(l <= r)))?,
+                _ => {
+                    if self.quicken
+                        && let Some(quick) = instr.quickened()
+                        && self.stack.top_ints()
+                    {
+                        self.instrs[self.ip] = quick;
                     }
-                }
-                OpCode::Mod => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-
-                    match (&lop, &rop) {
-                        (_, Value::Float(0.0) | Value::Int(0)) => {
-                            return Err(VMError::DivisionByZero);
-                        }
-                        (Value::Int(l), Value::Int(r)) => {
-                            self.stack.push(Value::Int(l % r))?;
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
+                    self.step(instr)?
+                }
+            };
+            match flow {
+                Flow::Next => self.ip += 1,
+                Flow::Jump(target) | Flow::Return(target) => self.ip = target,
+                Flow::Call(fidx) => {
+                    let func = self.instr_functions[fidx as usize];
+                    #[cfg(feature = "jit")]
+                    if let Some(jit) = &mut self.jit
+                        && let Some(val) =
+                            jit.call(fidx, func, &self.instrs, &self.consts, &self.stack)
+                    {
+                        for _ in 0..func.arity {
+                            self.stack.pop()?;
+                        }
+                        self.stack.push(val)?;
+                        self.ip += 1;
+                        continue;
                     }
+                    self.call(func, self.ip + 1)?;
+                    self.ip = func.address;
                 }
-
-                // Memory/Stack Manipulation
-                OpCode::PushConst => {
-                    let val = self.u16_from_le();
-                    self.stack.push(self.consts[val as usize].clone())?;
+            }
+        }
+        Ok(())
+    }
+    // Run the raw bytecode from the entry point, decoding every instruction as it is reached.
+    // Kept as the baseline execute is benchmarked against.
+    pub fn execute_bytecode(&mut self) -> Result<(), VMError> {
+        self.ip = self.entry;
+        while self.ip < self.code.len() {
+            let instr = Instr::decode_at(&self.code, self.ip)?;
+            let next = self.ip + instr.size();
+            match self.step(instr)? {
+                Flow::Next => self.ip = next,
+                Flow::Jump(target) | Flow::Return(target) => self.ip = target,
+                Flow::Call(fidx) => {
+                    let func = self.functions[fidx as usize];
+                    self.call(func, next)?;
+                    self.ip = func.address;
                 }
-                OpCode::PushImmediate => {
-                    let val = self.u16_from_le();
+            }
+        }
+        Ok(())
+    }
+    // Fast path for a quickened op. If the operands aren't both Int any more, or the result
+    // overflows, deoptimize the instruction back to its generic form and run that instead.
+    fn step_int(
+        &mut self,
+        instr: Instr,
+        op: impl FnOnce(i64, i64) -> Option<Value>,
+    ) -> Result<Flow, VMError> {
+        if self.stack.binary_int(op) {
+            return Ok(Flow::Next);
+        }
+        let generic = instr.generic();
+        self.instrs[self.ip] = generic;
+        self.step(generic)
+    }
+    fn call(&mut self, func: Function, return_address: usize) -> Result<(), VMError> {
+        let mut args: Vec<Value> = Vec::new();
+        for _ in 0..func.arity {
+            args.push(self.stack.pop()?);
+        }
+        args.reverse();
+        self.stack
+            .push_frame(args, func.locals as usize, return_address)?;
+        Ok(())
+    }
+    // Execute a single instruction. Jump targets and return addresses are in whatever units
+    // the calling loop uses.
+    fn step(&mut self, instr: Instr) -> Result<Flow, VMError> {
+        // println!("Stack before {:?}: {:?}", instr, self.stack);
+        match instr {
+            // Arithmetic
+            Instr::Add => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                let val = ops::overflowing(self.overflow, ops::add, lop, rop)?;
+                self.stack.push(val)?;
+            }
+            Instr::Sub => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                let val = ops::overflowing(self.overflow, ops::sub, lop, rop)?;
+                self.stack.push(val)?;
+            }
+            Instr::Mul => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                let val = ops::overflowing(self.overflow, ops::mul, lop, rop)?;
+                self.stack.push(val)?;
+            }
+            Instr::Div => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                let val = ops::overflowing(self.overflow, ops::div, lop, rop)?;
+                self.stack.push(val)?;
+            }
+            Instr::DivInt => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                let val = ops::overflowing(self.overflow, ops::div_int, lop, rop)?;
+                self.stack.push(val)?;
+            }
+            Instr::Mod => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::modulo(lop, rop)?)?;
+            }
+            Instr::DivExact => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::div_exact(lop, rop)?)?;
+            }
+            Instr::AddWrap
+            | Instr::SubWrap
+            | Instr::MulWrap
+            | Instr::AddChecked
+            | Instr::SubChecked
+            | Instr::MulChecked
+            | Instr::AddSat
+            | Instr::SubSat
+            | Instr::MulSat => {
+                let (mode, op) = instr.overflow().expect("fixed overflow mode op");
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::overflowing(mode, op, lop, rop)?)?;
+            }
 
-                    self.stack.push(Value::Int(val as i64))?;
-                }
-                OpCode::StoreLocal => {
-                    let idx = self.u8_from_byte();
-                    let val = self.stack.pop()?;
-                    self.stack.set_local(val, idx);
-                }
-                OpCode::PushLocal => {
-                    let idx = self.u8_from_byte();
-                    let val = self.stack.peek_local(idx)?;
-                    self.stack.push(val)?;
+            // Memory/Stack Manipulation
+            Instr::PushConst(idx) => {
+                self.stack.push(self.consts[idx as usize].clone())?;
+            }
+            Instr::PushImmediate(val) => {
+                self.stack.push(Value::Int(val as i64))?;
+            }
+            Instr::StoreLocal(idx) => {
+                let val = self.stack.pop()?;
+                self.stack.set_local(val, idx);
+            }
+            Instr::StoreLocalKeep(idx) => {
+                let val = self.stack.peek()?;
+                self.stack.set_local(val, idx);
+            }
+            Instr::PushLocal(idx) => {
+                let val = self.stack.peek_local(idx)?;
+                self.stack.push(val)?;
+            }
+            Instr::StoreGlobal(arg) => {
+                let val = self.stack.pop()?;
+                let arg = arg as usize;
+                // Globals are numbered in assembly order, which need not match store order
+                if arg >= self.globals.len() {
+                    self.globals.resize(arg + 1, Value::NULL);
+                }
+                self.globals[arg] = val;
+            }
+            Instr::PushGlobal(arg) => {
+                if arg < self.globals.len() as u16 {
+                    self.stack.push(self.globals[arg as usize].clone())?;
+                } else {
+                    return Err(VMError::InvalidGlobalIndex(arg));
                 }
-                OpCode::StoreGlobal => {
-                    let val = self.stack.pop()?;
-                    let arg = self.u16_from_le();
-                    if arg == self.globals.len() as u16 {
-                        self.globals.push(val);
-                    } else {
-                        self.globals[arg as usize] = val;
+            }
+            Instr::Pop => {
+                self.stack.pop()?;
+            }
+            Instr::Box => {
+                let val = self.stack.pop()?;
+                self.stack.push(Value::new_box(val))?;
+            }
+            Instr::Unbox => {
+                let val = self.stack.pop()?;
+                match val {
+                    Value::HeapValue(boxed) => {
+                        self.stack.push(boxed.borrow().clone())?;
                     }
-                }
-                OpCode::PushGlobal => {
-                    let arg = self.u16_from_le();
-                    if arg < self.globals.len() as u16 {
-                        self.stack.push(self.globals[arg as usize].clone())?;
+                    _ => {
+                        return Err(VMError::InvalidUnaryOperandType(val));
                     }
                 }
-                OpCode::Pop => {
-                    self.stack.pop()?;
-                }
-                OpCode::Box => {
-                    let val = self.stack.pop()?;
-                    self.stack.push(Value::new_box(val))?;
-                }
-                OpCode::Unbox => {
-                    let val = self.stack.pop()?;
-                    match val {
-                        Value::HeapValue(boxed) => {
-                            self.stack.push(boxed.borrow().clone())?;
-                        }
-                        _ => {
-                            return Err(VMError::InvalidUnaryOperandType(val));
-                        }
+            }
+            Instr::SetBox => {
+                let val = self.stack.pop()?;
+                let box_item = self.stack.pop()?;
+                match box_item {
+                    Value::HeapValue(boxed) => {
+                        let mut borrowed = boxed.borrow_mut();
+                        *borrowed = val.clone();
                     }
-                }
-                OpCode::SetBox => {
-                    let val = self.stack.pop()?;
-                    let box_item = self.stack.pop()?;
-                    match box_item {
-                        Value::HeapValue(boxed) => {
-                            let mut borrowed = boxed.borrow_mut();
-                            *borrowed = val.clone();
-                        }
-                        _ => {
-                            return Err(VMError::InvalidUnaryOperandType(val));
-                        }
+                    _ => {
+                        return Err(VMError::InvalidUnaryOperandType(val));
                     }
                 }
-                OpCode::Array => {
-                    let arg = self.u8_from_byte();
-                    let mut vals: Vec<Value> = Vec::new();
-                    for _n in 0..arg {
-                        vals.push(self.stack.pop()?);
-                    }
-                    vals.reverse();
-                    self.stack.push(Value::new_array(vals))?;
+            }
+            Instr::Array(arg) => {
+                let mut vals: Vec<Value> = Vec::new();
+                for _n in 0..arg {
+                    vals.push(self.stack.pop()?);
                 }
-                OpCode::ArraySet => {
-                    let val = self.stack.pop()?;
-                    let idx = self.stack.pop()?;
-                    let arr = self.stack.pop()?;
-                    match &idx {
-                        Value::Int(id) => {
-                            Value::set_to_array(*id as usize, val, arr)?;
-                        }
-                        _ => return Err(VMError::InvalidUnaryOperandType(idx)),
+                vals.reverse();
+                self.stack.push(Value::new_array(vals))?;
+            }
+            Instr::ArraySet => {
+                let val = self.stack.pop()?;
+                let idx = self.stack.pop()?;
+                let arr = self.stack.pop()?;
+                match &idx {
+                    Value::Int(id) => {
+                        Value::set_to_array(*id as usize, val, arr)?;
                     }
+                    _ => return Err(VMError::InvalidUnaryOperandType(idx)),
                 }
-                OpCode::ArrayGet => {
-                    let idx = self.stack.pop()?;
-                    let arr = self.stack.pop()?;
-                    match &idx {
-                        Value::Int(id) => {
-                            self.stack.push(Value::get_from_array(*id as usize, arr)?)?;
-                        }
-                        _ => return Err(VMError::InvalidUnaryOperandType(idx)),
+            }
+            Instr::ArrayGet => {
+                let idx = self.stack.pop()?;
+                let arr = self.stack.pop()?;
+                match &idx {
+                    Value::Int(id) => {
+                        self.stack.push(Value::get_from_array(*id as usize, arr)?)?;
                     }
+                    _ => return Err(VMError::InvalidUnaryOperandType(idx)),
                 }
-                OpCode::ArrayPush => {
-                    let val = self.stack.pop()?;
-                    let arr = self.stack.pop()?;
-                    Value::push_to_array(val, arr)?;
-                }
-                OpCode::ArrayPop => {
-                    let arr = self.stack.pop()?;
-                    self.stack.push(Value::pop_from_array(arr)?)?;
-                }
-                OpCode::ArrayLen => {
-                    let arr = self.stack.pop()?;
-                    self.stack.push(Value::array_len(arr)?)?;
-                }
+            }
+            Instr::ArrayPush => {
+                let val = self.stack.pop()?;
+                let arr = self.stack.pop()?;
+                Value::push_to_array(val, arr)?;
+            }
+            Instr::ArrayPop => {
+                let arr = self.stack.pop()?;
+                self.stack.push(Value::pop_from_array(arr)?)?;
+            }
+            Instr::ArrayLen => {
+                let arr = self.stack.pop()?;
+                self.stack.push(Value::array_len(arr)?)?;
+            }
 
-                // Control Flow
-                OpCode::Jump => {
-                    let arg = self.u32_from_le() as usize;
-                    // println!("JUMP {}", arg);
-                    self.ip = arg;
-                }
-                OpCode::JumpIfFalse => {
-                    let val = self.stack.pop()?;
-                    let arg = self.u32_from_le() as usize;
-                    match val {
-                        Value::Bool(v) => {
-                            if !v {
-                                self.ip = arg;
-                            }
+            // Control Flow
+            Instr::Jump(target) => {
+                return Ok(Flow::Jump(target as usize));
+            }
+            Instr::JumpIfFalse(target) => {
+                let val = self.stack.pop()?;
+                match val {
+                    Value::Bool(v) => {
+                        if !v {
+                            return Ok(Flow::Jump(target as usize));
                         }
-                        _ => return Err(VMError::InvalidStackValueType(Value::Bool(true), val)),
                     }
+                    _ => return Err(VMError::InvalidStackValueType(Value::Bool(true), val)),
                 }
-                OpCode::JumpIfTrue => {
-                    let val = self.stack.pop()?;
-                    let arg = self.u32_from_le() as usize;
-                    match val {
-                        Value::Bool(true) => {
-                            self.ip = arg;
-                        }
-                        Value::Bool(false) => {}
-                        _ => return Err(VMError::InvalidStackValueType(Value::Bool(true), val)),
+            }
+            Instr::JumpIfTrue(target) => {
+                let val = self.stack.pop()?;
+                match val {
+                    Value::Bool(true) => {
+                        return Ok(Flow::Jump(target as usize));
                     }
+                    Value::Bool(false) => {}
+                    _ => return Err(VMError::InvalidStackValueType(Value::Bool(true), val)),
                 }
+            }
 
-                // Comparison and other operators
-                OpCode::Equal => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-                    let mut result: bool = false;
-                    match (&lop, &rop) {
-                        (Value::Int(l), Value::Int(r)) => {
-                            if l == r {
-                                result = true;
-                            }
-                        }
-                        (Value::Bool(l), Value::Bool(r)) => {
-                            if l == r {
-                                result = true;
-                            }
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                    self.stack.push(Value::Bool(result))?;
-                }
-                OpCode::NotEqual => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-                    let mut result: bool = false;
-                    match (&lop, &rop) {
-                        (Value::Int(l), Value::Int(r)) => {
-                            if l != r {
-                                result = true;
-                            }
-                        }
-                        (Value::Bool(l), Value::Bool(r)) => {
-                            if l != r {
-                                result = true;
-                            }
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                    self.stack.push(Value::Bool(result))?;
-                }
-                OpCode::LessThan => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-                    let mut result: bool = false;
-                    match (&lop, &rop) {
-                        (Value::Int(l), Value::Int(r)) => {
-                            if l < r {
-                                result = true;
-                            }
-                        }
-                        (Value::Float(l), Value::Float(r)) => {
-                            if l < r {
-                                result = true;
-                            }
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                    self.stack.push(Value::Bool(result))?;
-                }
-                OpCode::GreaterThan => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-                    let mut result: bool = false;
-                    match (&lop, &rop) {
-                        (Value::Int(l), Value::Int(r)) => {
-                            if l > r {
-                                result = true;
-                            }
-                        }
-                        (Value::Float(l), Value::Float(r)) => {
-                            if l > r {
-                                result = true;
-                            }
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                    self.stack.push(Value::Bool(result))?;
-                }
-                OpCode::GreaterEqual => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-                    let mut result: bool = false;
-                    match (&lop, &rop) {
-                        (Value::Int(l), Value::Int(r)) => {
-                            if l >= r {
-                                result = true;
-                            }
-                        }
-                        (Value::Float(l), Value::Float(r)) => {
-                            if l >= r {
-                                result = true;
-                            }
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                    self.stack.push(Value::Bool(result))?;
-                }
-                OpCode::LessEqual => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-                    let mut result: bool = false;
-                    match (&lop, &rop) {
-                        (Value::Int(l), Value::Int(r)) => {
-                            if l <= r {
-                                result = true;
-                            }
-                        }
-                        (Value::Float(l), Value::Float(r)) => {
-                            if l <= r {
-                                result = true;
-                            }
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                    self.stack.push(Value::Bool(result))?;
-                }
-                OpCode::Not => {
-                    let val = self.stack.pop()?;
-                    match &val {
-                        Value::Bool(v) => {
-                            self.stack.push(Value::Bool(!v))?;
-                        }
-                        _ => return Err(VMError::InvalidUnaryOperandType(val)),
-                    }
-                }
-                OpCode::LogicalAnd => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-                    let mut result: bool = false;
-                    match (&lop, &rop) {
-                        (Value::Bool(l), Value::Bool(r)) => {
-                            if *l && *r {
-                                result = true;
-                            }
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                    self.stack.push(Value::Bool(result))?;
-                }
-                OpCode::LogicalOr => {
-                    let rop = self.stack.pop()?;
-                    let lop = self.stack.pop()?;
-                    let mut result: bool = false;
-                    match (&lop, &rop) {
-                        (Value::Bool(l), Value::Bool(r)) => {
-                            if *l || *r {
-                                result = true;
-                            }
-                        }
-                        _ => return Err(VMError::InvalidOperandType(lop, rop)),
-                    }
-                    self.stack.push(Value::Bool(result))?;
-                }
+            // Comparison and other operators
+            Instr::Equal => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::equal(lop, rop)?)?;
+            }
+            Instr::NotEqual => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::not_equal(lop, rop)?)?;
+            }
+            Instr::LessThan => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::less_than(lop, rop)?)?;
+            }
+            Instr::GreaterThan => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::greater_than(lop, rop)?)?;
+            }
+            Instr::GreaterEqual => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::greater_equal(lop, rop)?)?;
+            }
+            Instr::LessEqual => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::less_equal(lop, rop)?)?;
+            }
+            Instr::Not => {
+                let val = self.stack.pop()?;
+                self.stack.push(ops::not(val)?)?;
+            }
+            Instr::LogicalAnd => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::logical_and(lop, rop)?)?;
+            }
+            Instr::LogicalOr => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::logical_or(lop, rop)?)?;
+            }
+            Instr::Identical => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::identical(lop, rop)?)?;
+            }
 
-                // Functions
-                OpCode::CallFunction => {
-                    // todo!();
-                    let fidx = self.u16_from_le();
-                    let func = self.functions[fidx as usize];
-                    let mut args: Vec<Value> = Vec::new();
-                    for _ in 0..func.arity {
-                        args.push(self.stack.pop()?);
-                    }
-                    args.reverse();
-                    self.stack.push_frame(args, func.locals as usize, self.ip)?;
-                    self.ip = func.address;
-                }
-                OpCode::Return => {
-                    let ret_val = self.stack.pop()?;
-                    self.ip = self.stack.pop_frame()?;
-                    self.stack.push(ret_val)?;
-                }
+            // Bitwise
+            Instr::BitAnd => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::bit_and(lop, rop)?)?;
+            }
+            Instr::BitOr => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::bit_or(lop, rop)?)?;
+            }
+            Instr::BitXor => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::bit_xor(lop, rop)?)?;
+            }
+            Instr::BitNot => {
+                let val = self.stack.pop()?;
+                self.stack.push(ops::bit_not(val)?)?;
+            }
+            Instr::Shl => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::shl(lop, rop)?)?;
+            }
+            Instr::Shr => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::shr(lop, rop)?)?;
+            }
+            Instr::UShr => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::ushr(lop, rop)?)?;
+            }
+            Instr::RotateLeft => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::rotate_left(lop, rop)?)?;
+            }
+            Instr::RotateRight => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(ops::rotate_right(lop, rop)?)?;
+            }
+            Instr::PopCount => {
+                let val = self.stack.pop()?;
+                self.stack.push(ops::pop_count(val)?)?;
+            }
+            Instr::LeadingZeros => {
+                let val = self.stack.pop()?;
+                self.stack.push(ops::leading_zeros(val)?)?;
+            }
+            Instr::TrailingZeros => {
+                let val = self.stack.pop()?;
+                self.stack.push(ops::trailing_zeros(val)?)?;
+            }
 
-                // Testing ops
-                OpCode::Print => {
-                    let val = self.stack.pop()?;
-                    println!("printing: {:?}", val);
-                }
+            // Math
+            Instr::Math(f) => {
+                let mut args = Vec::with_capacity(f.arity());
+                for _ in 0..f.arity() {
+                    args.push(self.stack.pop()?);
+                }
+                args.reverse();
+                self.stack.push(f.apply(&args)?)?;
+            }
+            Instr::ToInt(mode) => {
+                let val = self.stack.pop()?;
+                self.stack.push(ops::to_int(val, mode)?)?;
+            }
+            Instr::ToFloat => {
+                let val = self.stack.pop()?;
+                self.stack.push(ops::to_float(val)?)?;
+            }
+
+            // Symbolic
+            Instr::Symbol => {
+                let name = self.stack.pop()?;
+                self.stack.push(expr::symbol(name)?)?;
+            }
+            Instr::Substitute => {
+                let val = self.stack.pop()?;
+                let sym = self.stack.pop()?;
+                let tree = self.stack.pop()?;
+                self.stack.push(expr::substitute(tree, sym, val)?)?;
+            }
+            Instr::Evaluate => {
+                let val = self.stack.pop()?;
+                self.stack.push(expr::evaluate(val)?)?;
+            }
+            Instr::ExprEqual => {
+                let rop = self.stack.pop()?;
+                let lop = self.stack.pop()?;
+                self.stack.push(expr::structural_equal(lop, rop)?)?;
+            }
+            Instr::Simplify => {
+                let val = self.stack.pop()?;
+                self.stack.push(expr::simplify(val)?)?;
+            }
+            Instr::Differentiate(idx) => {
+                let val = self.stack.pop()?;
+                let var = &self.consts[idx as usize];
+                self.stack.push(expr::differentiate(val, var)?)?;
+            }
+
+            // Functions
+            Instr::CallFunction(fidx) => {
+                return Ok(Flow::Call(fidx));
+            }
+            Instr::Return => {
+                let ret_val = self.stack.pop()?;
+                let return_address = self.stack.pop_frame()?;
+                self.stack.push(ret_val)?;
+                return Ok(Flow::Return(return_address));
+            }
 
-                // No Op
-                OpCode::NoOp => {} // _ => {
-                                   //     panic!("Invalid opcode")
-                                   // }
+            // Superinstructions
+            Instr::AddLocalConst(local, idx) => {
+                let val = self.stack.peek_local(local)?;
+                let k = self.consts[idx as usize].clone();
+                let val = ops::overflowing(self.overflow, ops::add, val, k)?;
+                self.stack.push(val)?;
             }
-            // println!("Stack after {:?}: {:?}", opcode, self.stack);
-            // println!("locals {:?}", self.stack.frames);
-            self.ip += 1;
-            if self.ip >= self.code.len() {
-                break;
+            Instr::IncLocal(local, delta) => {
+                let val = self.stack.peek_local(local)?;
+                let val = ops::overflowing(self.overflow, ops::add, val, Value::Int(delta as i64))?;
+                self.stack.set_local(val, local);
             }
+            Instr::CompareLocalConstJump(local, idx, cond, target) => {
+                let Some((cmp, jump_if)) = Instr::split_condition(cond) else {
+                    return Err(VMError::InvalidCondition(cond));
+                };
+                let compare = cmp.comparison().expect("split_condition gives comparisons");
+                let val = self.stack.peek_local(local)?;
+                if compare(val, self.consts[idx as usize].clone())? == Value::Bool(jump_if) {
+                    return Ok(Flow::Jump(target as usize));
+                }
+            }
+            Instr::PushLocalPair(first, second) => {
+                let val = self.stack.peek_local(first)?;
+                self.stack.push(val)?;
+                let val = self.stack.peek_local(second)?;
+                self.stack.push(val)?;
+            }
+
+            // Testing ops
+            Instr::Print => {
+                let val = self.stack.pop()?;
+                writeln!(self.output, "printing: {:?}", val)?;
+            }
+
+            // No Op
+            Instr::NoOp => {}
+
+            // Only execute runs quickened instructions, and it handles them itself
+            Instr::AddInt
+            | Instr::SubInt
+            | Instr::MulInt
+            | Instr::EqualInt
+            | Instr::NotEqualInt
+            | Instr::LessThanInt
+            | Instr::GreaterThanInt
+            | Instr::GreaterEqualInt
+            | Instr::LessEqualInt => return self.step(instr.generic()),
         }
-        Ok(())
+        // println!("Stack after {:?}: {:?}", instr, self.stack);
+        // println!("locals {:?}", self.stack.frames);
+        Ok(Flow::Next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::assembler::assemble_source;
+    use num_bigint::BigInt;
+
+    // Sums 0..10 into the first global, with adds and a compare that only ever see Ints
+    const SUM_LOOP: &[&str] = &[
+        "main",
+        "pshi 0",
+        "strg total",
+        "pshi 0",
+        "strg i",
+        "label top",
+        "pshg i",
+        "pshi 10",
+        "lsth",
+        "jmpf done",
+        "pshg total",
+        "pshg i",
+        "add",
+        "strg total",
+        "pshg i",
+        "pshi 1",
+        "add",
+        "strg i",
+        "jump top",
+        "label done",
+    ];
+
+    fn run(lines: &[&str], quicken: bool) -> VM {
+        let mut vm = VM::new(64);
+        vm.set_output(Box::new(io::sink()));
+        vm.set_quickening(quicken);
+        // Keep calls in the interpreter, so the instructions under test are the ones that run
+        #[cfg(feature = "jit")]
+        vm.set_jit_threshold(None);
+        vm.load_code(assemble_source(&lines.join("\n")).unwrap())
+            .unwrap();
+        vm.execute().unwrap();
+        vm
+    }
+
+    fn count(vm: &VM, instr: Instr) -> usize {
+        vm.instrs.iter().filter(|i| **i == instr).count()
+    }
+
+    #[test]
+    fn int_loops_are_quickened() {
+        let vm = run(SUM_LOOP, true);
+        assert_eq!(vm.globals[0], Value::Int(45));
+        assert_eq!(count(&vm, Instr::AddInt), 2);
+        assert_eq!(count(&vm, Instr::LessThanInt), 1);
+        assert_eq!(count(&vm, Instr::Add), 0);
+        assert_eq!(count(&vm, Instr::LessThan), 0);
+
+        let vm = run(SUM_LOOP, false);
+        assert_eq!(vm.globals[0], Value::Int(45));
+        assert_eq!(count(&vm, Instr::AddInt), 0);
+        assert_eq!(count(&vm, Instr::Add), 2);
+    }
+
+    #[test]
+    fn type_change_deoptimizes() {
+        // The add in plus is quickened by calls with Ints, then one with a Float arrives
+        let mut lines = vec!["func plus 2", "pshl arg0", "pshl arg1", "add", "endf"];
+        lines.extend(["main", "pshi 0", "strg total"]);
+        for _ in 0..3 {
+            lines.extend(["pshg total", "pshi 1", "callf plus", "strg total"]);
+        }
+        let vm = run(&lines, true);
+        assert_eq!(vm.globals[0], Value::Int(3));
+        assert_eq!(count(&vm, Instr::AddInt), 1);
+
+        lines.extend(["pshg total", "pshc 0.5", "callf plus", "strg total"]);
+        let vm = run(&lines, true);
+        assert_eq!(vm.globals[0], Value::Float(3.5));
+        assert_eq!(count(&vm, Instr::AddInt), 0);
+        assert_eq!(count(&vm, Instr::Add), 1);
+    }
+
+    #[test]
+    fn overflow_deoptimizes() {
+        // The first call quickens the add and the second overflows it
+        let lines = [
+            "func inc 1",
+            "pshl arg0",
+            "pshi 1",
+            "add",
+            "endf",
+            "main",
+            "pshc 9223372036854775806",
+            "callf inc",
+            "callf inc",
+            "strg n",
+        ];
+        let vm = run(&lines, true);
+        let promoted = Value::from_bigint(BigInt::from(i64::MAX) + 1);
+        assert_eq!(vm.globals[0], promoted);
+        assert_eq!(count(&vm, Instr::AddInt), 0);
+        assert_eq!(count(&vm, Instr::Add), 1);
     }
 }
@@ -0,0 +1,164 @@
+use fvm::assembler::{AssemblerOptions, assemble_file};
+use fvm::bytecode::Bytecode;
+use fvm::error::VMError;
+use fvm::regvm::RegisterVM;
+use fvm::threaded::ThreadedVM;
+use fvm::vm::VM;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// Collects everything a program prints so runs can be compared
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+type Execute = fn(Bytecode, Output) -> Result<(), VMError>;
+
+fn stack_vm(
+    bytecode: Bytecode,
+    output: Output,
+    execute: fn(&mut VM) -> Result<(), VMError>,
+) -> Result<(), VMError> {
+    let mut vm = VM::new(256);
+    // The interpreter alone, so the JIT has something to be compared against
+    #[cfg(feature = "jit")]
+    vm.set_jit_threshold(None);
+    vm.set_output(Box::new(output));
+    vm.load_code(bytecode)?;
+    execute(&mut vm)
+}
+
+// Ways of running a program, all of which must print the same thing
+const BACKENDS: [(&str, Execute); 5] = [
+    ("decoded", |bytecode, output| {
+        stack_vm(bytecode, output, VM::execute)
+    }),
+    ("unquickened", |bytecode, output| {
+        stack_vm(bytecode, output, |vm| {
+            vm.set_quickening(false);
+            vm.execute()
+        })
+    }),
+    ("bytecode", |bytecode, output| {
+        stack_vm(bytecode, output, VM::execute_bytecode)
+    }),
+    ("closures", |bytecode, output| {
+        let mut vm = ThreadedVM::new(256);
+        vm.set_output(Box::new(output));
+        vm.load_code(bytecode)?;
+        vm.execute()
+    }),
+    ("register", |bytecode, output| {
+        let mut vm = RegisterVM::new();
+        vm.set_output(Box::new(output));
+        vm.load_code(bytecode)?;
+        vm.execute()
+    }),
+];
+
+fn run(path: &Path, optimize: bool, (name, execute): (&str, Execute)) -> (String, usize) {
+    let options = AssemblerOptions {
+        optimize,
+        ..Default::default()
+    };
+    let bytecode =
+        assemble_file(path, &options).unwrap_or_else(|e| panic!("{}: {:?}", path.display(), e));
+    let code_len = bytecode.code.len();
+    let output = Output::default();
+    execute(bytecode, output.clone())
+        .unwrap_or_else(|e| panic!("{} ({}): {:?}", path.display(), name, e));
+    let printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+    (printed, code_len)
+}
+
+fn programs() -> Vec<PathBuf> {
+    let mut programs: Vec<_> = fs::read_dir("tests/corpus")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "fasm"))
+        .collect();
+    programs.sort();
+    assert!(!programs.is_empty());
+    programs
+}
+
+// Each program prints what its .out file holds. The other backends are held to the first
+// below, so checking it covers them all.
+#[test]
+fn programs_print_what_is_expected() {
+    for path in programs() {
+        let expected_path = path.with_extension("out");
+        let expected = fs::read_to_string(&expected_path)
+            .unwrap_or_else(|e| panic!("{}: {}", expected_path.display(), e));
+        for optimize in [false, true] {
+            let (printed, _) = run(&path, optimize, BACKENDS[0]);
+            assert_eq!(expected, printed, "{}", path.display());
+        }
+    }
+}
+
+#[test]
+fn optimized_programs_behave_the_same() {
+    for path in programs() {
+        let (plain, plain_len) = run(&path, false, BACKENDS[0]);
+        let (optimized, optimized_len) = run(&path, true, BACKENDS[0]);
+        assert!(!plain.is_empty(), "{} printed nothing", path.display());
+        assert_eq!(plain, optimized, "{}", path.display());
+        assert!(optimized_len < plain_len, "{}", path.display());
+    }
+}
+
+#[test]
+fn backends_behave_the_same() {
+    for path in programs() {
+        for optimize in [false, true] {
+            let (expected, _) = run(&path, optimize, BACKENDS[0]);
+            for backend in &BACKENDS[1..] {
+                let (printed, _) = run(&path, optimize, *backend);
+                assert_eq!(expected, printed, "{} ({})", path.display(), backend.0);
+            }
+        }
+    }
+}
+
+// Every call compiled where it can be, and compiled after the interpreter has run it once
+#[cfg(feature = "jit")]
+#[test]
+fn jit_behaves_the_same() {
+    const JIT: [(&str, Execute); 2] = [
+        ("jit", |bytecode, output| {
+            stack_vm(bytecode, output, |vm| {
+                vm.set_jit_threshold(Some(0));
+                vm.execute()
+            })
+        }),
+        ("jit after one call", |bytecode, output| {
+            stack_vm(bytecode, output, |vm| {
+                vm.set_jit_threshold(Some(2));
+                vm.execute()
+            })
+        }),
+    ];
+    for path in programs() {
+        for optimize in [false, true] {
+            let (expected, _) = run(&path, optimize, BACKENDS[0]);
+            for backend in JIT {
+                let (printed, _) = run(&path, optimize, backend);
+                assert_eq!(expected, printed, "{} ({})", path.display(), backend.0);
+            }
+        }
+    }
+}